@@ -0,0 +1,605 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+
+use crate::commands::{CommandRequest, FunctionCommand, ToRESP};
+use crate::keyspace::Value;
+use crate::protocol::RESP;
+use crate::rdb::{self, Snapshot, SnapshotEntry};
+
+/// Elements per command when rewriting collections, as in Redis.
+const REWRITE_ITEMS_PER_COMMAND: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppendFsync {
+    Always,
+    EverySec,
+    No,
+}
+
+impl FromStr for AppendFsync {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<AppendFsync> {
+        match s {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::EverySec),
+            "no" => Ok(AppendFsync::No),
+            x => Err(anyhow!("argument must be 'always', 'everysec' or 'no', got '{x}'")),
+        }
+    }
+}
+
+impl fmt::Display for AppendFsync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppendFsync::Always => write!(f, "always"),
+            AppendFsync::EverySec => write!(f, "everysec"),
+            AppendFsync::No => write!(f, "no"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileKind {
+    /// Snapshot of the dataset, in RDB or AOF format
+    Base,
+    /// Commands executed after the base was written
+    Incr,
+    /// Leftover of a previous rewrite, waiting to be deleted
+    History,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AofFile {
+    pub name: String,
+    pub seq: u64,
+    pub kind: FileKind,
+}
+
+impl AofFile {
+    pub fn is_rdb(&self) -> bool {
+        self.name.ends_with(".rdb")
+    }
+}
+
+/// Lists the files that make up the AOF, in the same format as Redis:
+/// one `file <name> seq <seq> type <b|i|h>` line per file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+    pub base: Option<AofFile>,
+    /// In the order they have to be replayed
+    pub incrs: Vec<AofFile>,
+    pub history: Vec<AofFile>,
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> Result<Manifest> {
+        let mut manifest = Manifest::default();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (mut name, mut seq, mut kind) = (None, None, None);
+            for pair in words.chunks(2) {
+                match pair {
+                    ["file", value] => name = Some(value.trim_matches('"').to_string()),
+                    ["seq", value] => seq = value.parse::<u64>().ok(),
+                    ["type", "b"] => kind = Some(FileKind::Base),
+                    ["type", "i"] => kind = Some(FileKind::Incr),
+                    ["type", "h"] => kind = Some(FileKind::History),
+                    _ => (),
+                }
+            }
+            let (Some(name), Some(seq), Some(kind)) = (name, seq, kind) else {
+                return Err(anyhow!("Invalid AOF manifest file format: {line}"));
+            };
+            let file = AofFile { name, seq, kind };
+            match kind {
+                FileKind::Base if manifest.base.is_some() => return Err(anyhow!("Found duplicate base file information")),
+                FileKind::Base => manifest.base = Some(file),
+                FileKind::Incr => manifest.incrs.push(file),
+                FileKind::History => manifest.history.push(file),
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Every file, in the order they have to be loaded.
+    pub fn files(&self) -> impl Iterator<Item = &AofFile> {
+        self.base.iter().chain(self.incrs.iter())
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for file in self.base.iter().chain(&self.history).chain(&self.incrs) {
+            let kind = match file.kind {
+                FileKind::Base => "b",
+                FileKind::Incr => "i",
+                FileKind::History => "h",
+            };
+            writeln!(f, "file {} seq {} type {kind}", file.name, file.seq)?;
+        }
+        Ok(())
+    }
+}
+
+/// Path of the manifest of the AOF named `filename`, in `aof_dir`.
+pub fn manifest_path(aof_dir: &Path, filename: &str) -> PathBuf {
+    aof_dir.join(format!("{filename}.manifest"))
+}
+
+/// Whether there's an AOF to load, either in the multi part layout or as
+/// a single file left by an older version.
+pub fn exists(dir: &Path, dirname: &str, filename: &str) -> bool {
+    manifest_path(&dir.join(dirname), filename).exists() || dir.join(filename).exists()
+}
+
+/// How much of an AOF file could be read.
+#[derive(Debug, PartialEq)]
+pub struct Replay {
+    pub commands: usize,
+    /// Length of the prefix made of complete, well formed commands
+    pub valid_len: usize,
+    /// Whether reading stopped at something that isn't a command, rather
+    /// than at a command cut short
+    pub corrupted: bool,
+}
+
+/// Runs `apply` on every command of `data` until the end, or until
+/// something that isn't a complete command is found.
+pub fn replay(data: &[u8], mut apply: impl FnMut(RESP) -> Result<()>) -> Result<Replay> {
+    let mut replay = Replay { commands: 0, valid_len: 0, corrupted: false };
+    while replay.valid_len < data.len() {
+        match RESP::decode(&data[replay.valid_len..]) {
+            Ok(Some((command @ RESP::Array(_), len))) => {
+                apply(command)?;
+                replay.valid_len += len;
+                replay.commands += 1;
+            },
+            Ok(None) => break,
+            Ok(Some(_)) | Err(_) => {
+                replay.corrupted = true;
+                break;
+            },
+        }
+    }
+    Ok(replay)
+}
+
+/// The append only file, split in parts as Redis 7 does: a base file with
+/// a snapshot of the dataset, followed by incremental files with every
+/// write command since, in RESP form. A manifest in `dir` lists them.
+///
+/// Rewrites first switch appends to a new incremental file, then write a
+/// new base in the background. Only once it's complete does the manifest
+/// drop the files it replaces, so there is no tail to copy over, and a
+/// crash at any point leaves a manifest listing a consistent set of files.
+pub struct Aof {
+    dir: PathBuf,
+    filename: String,
+    fsync: AppendFsync,
+    rdb_preamble: bool,
+    state: Mutex<State>,
+    rewriting: AtomicBool,
+}
+
+/// The manifest along with the incremental file being appended to, which
+/// is always the last one listed.
+struct State {
+    manifest: Manifest,
+    file: File,
+}
+
+impl Aof {
+    /// Opens the AOF in `dir/dirname`, creating the directory and an
+    /// incremental file to append to if needed. A single file AOF found at
+    /// `dir/filename` becomes the base.
+    pub fn open(dir: &Path, dirname: &str, filename: &str, fsync: AppendFsync, rdb_preamble: bool) -> Result<Aof> {
+        let aof_dir = dir.join(dirname);
+        fs::create_dir_all(&aof_dir)?;
+        let manifest_path = manifest_path(&aof_dir, filename);
+        let mut manifest = match manifest_path.exists() {
+            true => Manifest::parse(&fs::read_to_string(&manifest_path)?)?,
+            false => Manifest::default(),
+        };
+        let mut changed = false;
+
+        let legacy = dir.join(filename);
+        if !manifest_path.exists() && legacy.exists() {
+            // Redis 4 to 6 could start the file with an RDB preamble
+            let mut magic = [0; 5];
+            let preamble = File::open(&legacy)?.read_exact(&mut magic).is_ok() && &magic == b"REDIS";
+            let name = format!("{filename}.1.base.{}", if preamble { "rdb" } else { "aof" });
+            info!(target: "aof", "moving {legacy:?} to {aof_dir:?} as {name}");
+            fs::rename(&legacy, aof_dir.join(&name))?;
+            manifest.base = Some(AofFile { name, seq: 1, kind: FileKind::Base });
+            changed = true;
+        }
+        for file in manifest.history.drain(..) {
+            let _ = fs::remove_file(aof_dir.join(&file.name));
+            changed = true;
+        }
+        if manifest.incrs.is_empty() {
+            manifest.incrs.push(incr_file(filename, 1));
+            changed = true;
+        }
+
+        let current = &manifest.incrs[manifest.incrs.len() - 1];
+        let file = OpenOptions::new().create(true).append(true).open(aof_dir.join(&current.name))?;
+        let aof = Aof {
+            dir: aof_dir,
+            filename: filename.to_string(),
+            fsync,
+            rdb_preamble,
+            state: Mutex::new(State { manifest, file }),
+            rewriting: AtomicBool::new(false),
+        };
+        if changed {
+            aof.persist(&aof.state.lock().unwrap().manifest)?;
+        }
+        Ok(aof)
+    }
+
+    pub fn fsync_policy(&self) -> AppendFsync {
+        self.fsync
+    }
+
+    pub fn manifest(&self) -> Manifest {
+        self.state.lock().unwrap().manifest.clone()
+    }
+
+    pub fn append(&self, command: &[u8]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.file.write_all(command)?;
+        if self.fsync == AppendFsync::Always {
+            state.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes appended commands to disk, which `everysec` does once a second.
+    pub fn fsync(&self) -> Result<()> {
+        Ok(self.state.lock().unwrap().file.sync_data()?)
+    }
+
+    /// Loads every file listed in the manifest, handing RDB bases to
+    /// `on_snapshot` and commands to `on_command`. Returns how many
+    /// commands there were.
+    ///
+    /// The last file can end with a command cut short, as left by a crash
+    /// in the middle of a write: it gets dropped and the file truncated.
+    pub fn load(
+        &self,
+        mut on_snapshot: impl FnMut(Snapshot) -> Result<()>,
+        mut on_command: impl FnMut(RESP) -> Result<()>,
+    ) -> Result<usize> {
+        let manifest = self.manifest();
+        let last = manifest.incrs.last();
+        let mut commands = 0;
+        for file in manifest.files() {
+            let path = self.dir.join(&file.name);
+            let data = fs::read(&path)?;
+            // commands can follow an RDB preamble in files from Redis 4 to 6
+            let start = match file.is_rdb() {
+                true => {
+                    let (snapshot, len) = rdb::parse_prefix(&data)?;
+                    on_snapshot(snapshot)?;
+                    len
+                },
+                false => 0,
+            };
+            let replay = replay(&data[start..], &mut on_command)?;
+            commands += replay.commands;
+            if start + replay.valid_len == data.len() {
+                continue;
+            }
+            if replay.corrupted || Some(file) != last {
+                return Err(anyhow!("Bad file format reading the append only file {}", file.name));
+            }
+            warn!(target: "aof", "{} ends with an incomplete command, truncating it from {} to {} bytes", file.name, data.len(), start + replay.valid_len);
+            OpenOptions::new().write(true).open(&path)?.set_len((start + replay.valid_len) as u64)?;
+        }
+        Ok(commands)
+    }
+
+    /// Replaces every file with a base holding `snapshot`.
+    pub fn rewrite(&self, snapshot: &Snapshot) -> Result<()> {
+        if self.rewriting.swap(true, Ordering::AcqRel) {
+            return Err(anyhow!("ERR Background append only file rewriting already in progress"));
+        }
+        let result = self.start_rewrite().and_then(|incr| self.finish_rewrite(snapshot, &incr));
+        self.rewriting.store(false, Ordering::Release);
+        result
+    }
+
+    /// Like `rewrite`, but writing the base from a blocking thread. Commands
+    /// appended after `snapshot` was taken go to a new incremental file,
+    /// which is kept.
+    pub fn rewrite_in_background(self: &Arc<Self>, snapshot: Snapshot) -> Result<()> {
+        if self.rewriting.swap(true, Ordering::AcqRel) {
+            return Err(anyhow!("ERR Background append only file rewriting already in progress"));
+        }
+        let incr = match self.start_rewrite() {
+            Ok(incr) => incr,
+            Err(err) => {
+                self.rewriting.store(false, Ordering::Release);
+                return Err(err);
+            },
+        };
+        let aof = self.clone();
+        tokio::task::spawn_blocking(move || {
+            match aof.finish_rewrite(&snapshot, &incr) {
+                Ok(()) => info!(target: "aof", "background AOF rewrite finished successfully"),
+                Err(err) => error!(target: "aof", "background AOF rewrite failed: {err}"),
+            }
+            aof.rewriting.store(false, Ordering::Release);
+        });
+        Ok(())
+    }
+
+    /// Switches appends to a new incremental file, returning it.
+    fn start_rewrite(&self) -> Result<AofFile> {
+        let mut state = self.state.lock().unwrap();
+        state.file.sync_data()?;
+        let seq = state.manifest.incrs.last().map_or(0, |incr| incr.seq) + 1;
+        let incr = incr_file(&self.filename, seq);
+        let file = OpenOptions::new().create(true).append(true).open(self.dir.join(&incr.name))?;
+
+        let mut manifest = state.manifest.clone();
+        manifest.incrs.push(incr.clone());
+        self.persist(&manifest)?;
+        *state = State { manifest, file };
+        Ok(incr)
+    }
+
+    /// Writes `snapshot` as the new base, dropping the files that came
+    /// before `incr`.
+    fn finish_rewrite(&self, snapshot: &Snapshot, incr: &AofFile) -> Result<()> {
+        let temp = self.dir.join(format!("temp-rewriteaof-bg-{}.aof", process::id()));
+        let written = match self.rdb_preamble {
+            true => Ok(rdb::serialize(snapshot)),
+            false => rewrite_commands(snapshot),
+        }.and_then(|contents| {
+            let mut file = File::create(&temp)?;
+            file.write_all(&contents)?;
+            Ok(file.sync_all()?)
+        });
+        if let Err(err) = written {
+            let _ = fs::remove_file(&temp);
+            return Err(err);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let mut manifest = state.manifest.clone();
+        let seq = manifest.base.as_ref().map_or(0, |base| base.seq) + 1;
+        let extension = if self.rdb_preamble { "rdb" } else { "aof" };
+        let base = AofFile { name: format!("{}.{seq}.base.{extension}", self.filename), seq, kind: FileKind::Base };
+        fs::rename(&temp, self.dir.join(&base.name))?;
+
+        let replaced: Vec<AofFile> = manifest.base.replace(base).into_iter()
+            .chain(manifest.incrs.iter().filter(|file| file.seq < incr.seq).cloned())
+            .collect();
+        manifest.incrs.retain(|file| file.seq >= incr.seq);
+        self.persist(&manifest)?;
+        state.manifest = manifest;
+        drop(state);
+
+        for file in replaced {
+            let _ = fs::remove_file(self.dir.join(&file.name));
+        }
+        Ok(())
+    }
+
+    /// Atomically replaces the manifest on disk.
+    fn persist(&self, manifest: &Manifest) -> Result<()> {
+        let temp = self.dir.join(format!("temp-{}.manifest", self.filename));
+        let mut file = File::create(&temp)?;
+        file.write_all(manifest.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, manifest_path(&self.dir, &self.filename))?;
+        Ok(())
+    }
+}
+
+fn incr_file(filename: &str, seq: u64) -> AofFile {
+    AofFile { name: format!("{filename}.{seq}.incr.aof"), seq, kind: FileKind::Incr }
+}
+
+/// The smallest set of commands that rebuilds `snapshot`.
+fn rewrite_commands(snapshot: &Snapshot) -> Result<Vec<u8>> {
+    let mut commands = vec![];
+    for code in &snapshot.functions {
+        commands.push(CommandRequest::FUNCTION(FunctionCommand::Load(code.to_string(), true)));
+    }
+    for (db, entries) in &snapshot.databases {
+        commands.push(CommandRequest::SELECT(*db as usize));
+        for entry in entries {
+            rewrite_entry(entry, &mut commands);
+        }
+    }
+
+    let mut buf = vec![];
+    for command in commands {
+        buf.extend(command.to_resp()?.encode());
+    }
+    Ok(buf)
+}
+
+/// Appends the commands that recreate `entry` to `commands`.
+fn rewrite_entry(entry: &SnapshotEntry, commands: &mut Vec<CommandRequest>) {
+    let key = entry.key.to_string();
+    match &*entry.value {
+        Value::String(s) => commands.push(CommandRequest::SET(key.clone(), s.to_string(), None)),
+        Value::List(list) => {
+            let elements: Vec<String> = list.iter().map(Cow::into_owned).collect();
+            commands.extend(elements.chunks(REWRITE_ITEMS_PER_COMMAND)
+                .map(|chunk| CommandRequest::RPUSH(key.clone(), chunk.to_vec())));
+        },
+        Value::Set(set) => {
+            let members: Vec<String> = set.iter().map(Cow::into_owned).collect();
+            commands.extend(members.chunks(REWRITE_ITEMS_PER_COMMAND)
+                .map(|chunk| CommandRequest::SADD(key.clone(), chunk.to_vec())));
+        },
+        Value::ZSet(zset) => {
+            let members: Vec<(f64, String)> = zset.iter().map(|(member, score)| (score, member.into_owned())).collect();
+            commands.extend(members.chunks(REWRITE_ITEMS_PER_COMMAND)
+                .map(|chunk| CommandRequest::ZADD(key.clone(), chunk.to_vec())));
+        },
+        Value::Hash(hash) => {
+            let fields: Vec<(String, String)> = hash.iter().map(|(field, value)| (field.into_owned(), value.into_owned())).collect();
+            commands.extend(fields.chunks(REWRITE_ITEMS_PER_COMMAND)
+                .map(|chunk| CommandRequest::HSET(key.clone(), chunk.to_vec())));
+        },
+    }
+    if let Some(expires_at) = entry.expires_at {
+        commands.push(CommandRequest::PEXPIREAT(key, expires_at));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::cell::RefCell;
+    use crate::commands::FromRESP;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aof-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn set(key: &str) -> Vec<u8> {
+        CommandRequest::SET(key.to_string(), "1".to_string(), None).to_resp().unwrap().encode()
+    }
+
+    fn command_names(aof: &Aof) -> Vec<String> {
+        let names = RefCell::new(vec![]);
+        aof.load(
+            |snapshot| {
+                names.borrow_mut().extend(snapshot.databases.values().flatten().map(|entry| format!("snapshot {}", entry.key)));
+                Ok(())
+            },
+            |command| {
+                let name = format!("{:?}", CommandRequest::from_resp(command)?);
+                names.borrow_mut().push(name.split('(').next().unwrap().to_string());
+                Ok(())
+            },
+        ).unwrap();
+        names.into_inner()
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let text = "file appendonly.aof.2.base.rdb seq 2 type b\n\
+                    file appendonly.aof.1.incr.aof seq 1 type h\n\
+                    file appendonly.aof.3.incr.aof seq 3 type i\n\
+                    file appendonly.aof.4.incr.aof seq 4 type i\n";
+        let manifest = Manifest::parse(text).unwrap();
+        assert_eq!(2, manifest.base.as_ref().unwrap().seq);
+        assert_eq!(vec![3, 4], manifest.incrs.iter().map(|file| file.seq).collect::<Vec<u64>>());
+        assert_eq!(text, manifest.to_string());
+
+        assert!(Manifest::parse("file appendonly.aof.1.incr.aof seq 1").is_err());
+    }
+
+    #[test]
+    fn test_replay_stops_at_incomplete_tail() {
+        let data = b"*1\r\n$4\r\nPING\r\n*3\r\n$3\r\nSET\r\n$1\r\na";
+        let mut loaded = vec![];
+        let replayed = replay(data, |command| {
+            loaded.push(command);
+            Ok(())
+        }).unwrap();
+        assert_eq!(Replay { commands: 1, valid_len: 14, corrupted: false }, replayed);
+        assert_eq!(vec![RESP::Array(vec![RESP::BulkString("PING".to_string())])], loaded);
+
+        assert!(replay(b"*1\r\n$4\r\nPING\r\n+OK\r\n", |_| Ok(())).unwrap().corrupted);
+    }
+
+    #[test]
+    fn test_load_truncates_last_incr() {
+        let dir = temp_dir("truncated");
+        let aof = Aof::open(&dir, "appendonlydir", "appendonly.aof", AppendFsync::No, true).unwrap();
+        aof.append(&set("a")).unwrap();
+        aof.append(b"*3\r\n$3\r\nSET\r\n$1\r\nb").unwrap();
+
+        assert_eq!(vec!["SET"], command_names(&aof));
+        let incr = dir.join("appendonlydir/appendonly.aof.1.incr.aof");
+        assert_eq!(set("a").len() as u64, fs::metadata(incr).unwrap().len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_replaces_base_and_older_incrs() {
+        let dir = temp_dir("rewrite");
+        let aof = Aof::open(&dir, "appendonlydir", "appendonly.aof", AppendFsync::No, true).unwrap();
+        aof.append(&set("old")).unwrap();
+
+        let mut snapshot = Snapshot::default();
+        snapshot.databases.insert(0, vec![SnapshotEntry {
+            key: "old".to_string(),
+            value: Arc::new(Value::String("1".into())),
+            expires_at: None,
+        }]);
+        let incr = aof.start_rewrite().unwrap();
+        aof.append(&set("new")).unwrap();
+        // a crash here leaves the old files along with the new increment
+        assert_eq!(vec!["SET", "SET"], command_names(&aof));
+
+        aof.finish_rewrite(&snapshot, &incr).unwrap();
+        let manifest = Manifest::parse(&fs::read_to_string(dir.join("appendonlydir/appendonly.aof.manifest")).unwrap()).unwrap();
+        assert_eq!("appendonly.aof.1.base.rdb", manifest.base.unwrap().name);
+        assert_eq!(vec![incr], manifest.incrs);
+        assert!(!dir.join("appendonlydir/appendonly.aof.1.incr.aof").exists());
+        assert_eq!(vec!["snapshot old", "SET"], command_names(&aof));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_without_preamble_writes_commands() {
+        let mut snapshot = Snapshot::default();
+        snapshot.databases.insert(0, vec![SnapshotEntry {
+            key: "list".to_string(),
+            value: Arc::new(Value::List((0..100).map(|i| i.to_string()).collect())),
+            expires_at: Some(4102444800000),
+        }]);
+        let mut names = vec![];
+        replay(&rewrite_commands(&snapshot).unwrap(), |command| {
+            let name = format!("{:?}", CommandRequest::from_resp(command)?);
+            names.push(name.split('(').next().unwrap().to_string());
+            Ok(())
+        }).unwrap();
+        assert_eq!(vec!["SELECT", "RPUSH", "RPUSH", "PEXPIREAT"], names);
+    }
+
+    #[test]
+    fn test_open_upgrades_legacy_file_with_preamble() {
+        let dir = temp_dir("legacy");
+        let mut snapshot = Snapshot::default();
+        snapshot.databases.insert(0, vec![SnapshotEntry {
+            key: "old".to_string(),
+            value: Arc::new(Value::String("1".into())),
+            expires_at: None,
+        }]);
+        // how Redis 4 to 6 left it after a rewrite and a write
+        let mut legacy = rdb::serialize(&snapshot);
+        legacy.extend(set("new"));
+        fs::write(dir.join("appendonly.aof"), legacy).unwrap();
+
+        let aof = Aof::open(&dir, "appendonlydir", "appendonly.aof", AppendFsync::No, true).unwrap();
+        assert_eq!("appendonly.aof.1.base.rdb", aof.manifest().base.unwrap().name);
+        assert_eq!(vec!["snapshot old", "SET"], command_names(&aof));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+//! Checks the files of an AOF and, with `--fix`, truncates the last one to
+//! its longest valid prefix, much like Redis' own tool.
+//!
+//!     redis-check-aof [--fix] <file.manifest|file.aof|file.rdb>
+
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::{env, process};
+
+use anyhow::{anyhow, Result};
+use redis_starter_rust::aof::{replay, Manifest};
+use redis_starter_rust::rdb;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (fix, path) = match args.as_slice() {
+        [path] => (false, path),
+        [flag, path] if flag == "--fix" => (true, path),
+        _ => {
+            eprintln!("Usage: redis-check-aof [--fix] <file.manifest|file.aof|file.rdb>");
+            process::exit(1);
+        },
+    };
+    if let Err(err) = check(Path::new(path), fix) {
+        eprintln!("{err}");
+        process::exit(1);
+    }
+}
+
+fn check(path: &Path, fix: bool) -> Result<()> {
+    let files = match path.extension().is_some_and(|extension| extension == "manifest") {
+        true => {
+            println!("Start checking Multi Part AOF");
+            let manifest = Manifest::parse(&fs::read_to_string(path)?)?;
+            let dir = path.parent().unwrap_or(Path::new("."));
+            manifest.files().map(|file| dir.join(&file.name)).collect()
+        },
+        false => {
+            println!("Start checking Old-Style AOF");
+            vec![path.to_path_buf()]
+        },
+    };
+    let Some(last) = files.last() else {
+        return Err(anyhow!("The manifest doesn't list any file"));
+    };
+    for file in &files {
+        check_file(file, fix && file == last, file == last)?;
+    }
+    println!("All AOF files and manifest are valid");
+    Ok(())
+}
+
+fn check_file(path: &PathBuf, fix: bool, is_last: bool) -> Result<()> {
+    let data = fs::read(path)?;
+    let mut start = 0;
+    if path.extension().is_some_and(|extension| extension == "rdb") {
+        let (_, len) = rdb::parse_prefix(&data).map_err(|err| anyhow!("RDB file {path:?} is not valid: {err}"))?;
+        println!("RDB file {path:?} is valid");
+        // a legacy AOF with an RDB preamble goes on with commands
+        if len == data.len() {
+            return Ok(());
+        }
+        start = len;
+    }
+
+    let replayed = replay(&data[start..], |_| Ok(()))?;
+    let valid_len = start + replayed.valid_len;
+    if valid_len == data.len() {
+        println!("AOF {path:?} is valid, with {} commands", replayed.commands);
+        return Ok(());
+    }
+    println!(
+        "AOF analyzed: filename={path:?}, size={}, ok_up_to={}, diff={}",
+        data.len(), valid_len, data.len() - valid_len
+    );
+    if !is_last {
+        return Err(anyhow!("AOF {path:?} is not valid, and only the last file can be fixed"));
+    }
+    if !fix {
+        return Err(anyhow!("AOF {path:?} is not valid. Use the --fix option to try fixing it."));
+    }
+    OpenOptions::new().write(true).open(path)?.set_len(valid_len as u64)?;
+    println!("Successfully truncated AOF {path:?}");
+    Ok(())
+}
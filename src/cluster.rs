@@ -0,0 +1,971 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+
+use crate::crc16::crc16;
+use crate::gossip::{Message, MessageType, NodeGossip, Target};
+use crate::keyspace::now_ms;
+use crate::replication::gen_replica_id;
+
+/// Number of hash slots keys are spread over.
+pub const SLOTS: u16 = 16384;
+
+/// The cluster bus listens on the client port plus this.
+pub const BUS_PORT_OFFSET: u16 = 10000;
+
+pub const DEFAULT_NODE_TIMEOUT: Duration = Duration::from_millis(15000);
+
+pub const FLAG_MYSELF: u16 = 1;
+pub const FLAG_MASTER: u16 = 2;
+/// We think the node is down, as it didn't answer in time.
+pub const FLAG_PFAIL: u16 = 8;
+/// A majority of masters think the node is down.
+pub const FLAG_FAIL: u16 = 16;
+
+/// How often each node gets pinged when everything is fine.
+const PING_PERIOD: u64 = 1000;
+
+/// Failure reports and FAIL flags last this many node timeouts.
+const FAIL_VALIDITY_MULT: u64 = 2;
+
+/// The slot of `key`, computed over its hash tag only when it has one: the
+/// part between the first `{` and the next `}`, unless that's empty.
+pub fn key_slot(key: &str) -> u16 {
+    let key = key.as_bytes();
+    let tagged = key.iter().position(|byte| *byte == b'{')
+        .and_then(|open| {
+            let tag = &key[open + 1..];
+            tag.iter().position(|byte| *byte == b'}').map(|close| &tag[..close])
+        })
+        .filter(|tag| !tag.is_empty());
+    crc16(tagged.unwrap_or(key)) % SLOTS
+}
+
+/// Parses a slot number as given to CLUSTER commands.
+pub fn parse_slot(slot: &str) -> Result<u16> {
+    match slot.parse::<u16>() {
+        Ok(slot) if slot < SLOTS => Ok(slot),
+        _ => Err(anyhow!("Invalid or out of range slot")),
+    }
+}
+
+fn flag_names(flags: u16) -> String {
+    let names: Vec<&str> = [(FLAG_MYSELF, "myself"), (FLAG_MASTER, "master"), (FLAG_PFAIL, "fail?"), (FLAG_FAIL, "fail")]
+        .into_iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| name)
+        .collect();
+    if names.is_empty() { "noflags".to_string() } else { names.join(",") }
+}
+
+fn parse_flags(names: &str) -> u16 {
+    names.split(',')
+        .map(|name| match name {
+            "myself" => FLAG_MYSELF,
+            "master" => FLAG_MASTER,
+            "fail" => FLAG_FAIL,
+            _ => 0,
+        })
+        .fold(0, |flags, flag| flags | flag)
+}
+
+#[derive(Debug, Clone)]
+pub struct ClusterNode {
+    pub id: String,
+    pub host: String,
+    pub port: u16,
+    /// Port of its cluster bus.
+    pub cport: u16,
+    pub flags: u16,
+    pub config_epoch: u64,
+    /// When the oldest ping still unanswered was sent, in ms.
+    pub ping_sent: Option<u64>,
+    pub pong_received: u64,
+    fail_time: u64,
+    /// Masters that told us the node looked down, and when.
+    fail_reports: BTreeMap<String, u64>,
+    /// Whether a ping is on its way.
+    pinging: bool,
+    last_ping: u64,
+}
+
+impl ClusterNode {
+    pub fn new(id: String, host: String, port: u16) -> ClusterNode {
+        ClusterNode {
+            id,
+            host,
+            port,
+            cport: port.wrapping_add(BUS_PORT_OFFSET),
+            flags: FLAG_MASTER,
+            config_epoch: 0,
+            ping_sent: None,
+            pong_received: 0,
+            fail_time: 0,
+            fail_reports: BTreeMap::new(),
+            pinging: false,
+            last_ping: 0,
+        }
+    }
+
+    fn gossip(&self) -> NodeGossip {
+        NodeGossip { id: self.id.clone(), host: self.host.clone(), port: self.port, cport: self.cport, flags: self.flags }
+    }
+}
+
+/// Why a command can't run on this node.
+#[derive(Debug, PartialEq)]
+pub enum Redirect {
+    /// The keys belong to different slots.
+    CrossSlot,
+    /// The slot is served by the node at the given address.
+    Moved(u16, String, u16),
+    /// The slot is being migrated to the node at the given address, which
+    /// already has the keys: ask it, once.
+    Ask(u16, String, u16),
+    /// Only some of the keys were migrated yet.
+    TryAgain,
+    /// No node serves the slot.
+    Down(u16),
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Redirect::CrossSlot => write!(f, "CROSSSLOT Keys in request don't hash to the same slot"),
+            Redirect::Moved(slot, host, port) => write!(f, "MOVED {slot} {host}:{port}"),
+            Redirect::Ask(slot, host, port) => write!(f, "ASK {slot} {host}:{port}"),
+            Redirect::TryAgain => write!(f, "TRYAGAIN Multiple keys request during rehashing of slot"),
+            Redirect::Down(_) => write!(f, "CLUSTERDOWN Hash slot not served"),
+        }
+    }
+}
+
+struct ClusterState {
+    nodes: BTreeMap<String, ClusterNode>,
+    /// The id of the node serving each slot.
+    slots: Vec<Option<String>>,
+    /// Our slots being moved to another node, with the id of that node.
+    migrating: BTreeMap<u16, String>,
+    /// Slots we're getting from another node, with the id of that node.
+    importing: BTreeMap<u16, String>,
+    current_epoch: u64,
+    /// Addresses given to CLUSTER MEET, to greet on the next cron.
+    meets: Vec<(String, u16)>,
+    /// Whether anything worth saving changed since the last save.
+    changed: bool,
+}
+
+/// This node's view of the cluster: which nodes exist, which slots each of
+/// them serves, and which ones look down. Kept up to date by the gossip
+/// exchanged over the cluster bus.
+pub struct Cluster {
+    myself: String,
+    node_timeout: Duration,
+    /// Where the configuration gets saved, as nodes.conf.
+    config_file: Option<PathBuf>,
+    state: Mutex<ClusterState>,
+}
+
+impl Cluster {
+    pub fn new(id: String, host: String, port: u16, node_timeout: Duration) -> Cluster {
+        let mut myself = ClusterNode::new(id.clone(), host, port);
+        myself.flags |= FLAG_MYSELF;
+        Cluster {
+            myself: id.clone(),
+            node_timeout,
+            config_file: None,
+            state: Mutex::new(ClusterState {
+                nodes: BTreeMap::from([(id, myself)]),
+                slots: vec![None; SLOTS as usize],
+                migrating: BTreeMap::new(),
+                importing: BTreeMap::new(),
+                current_epoch: 0,
+                meets: vec![],
+                changed: true,
+            }),
+        }
+    }
+
+    /// Loads the configuration saved in `config_file`, or starts as a new
+    /// node when there's none yet. Either way it's saved there from now on.
+    pub fn open(config_file: PathBuf, host: String, port: u16, node_timeout: Duration) -> Result<Cluster> {
+        let mut cluster = match fs::read_to_string(&config_file) {
+            Ok(config) => Cluster::parse(&config, host, port, node_timeout)
+                .map_err(|err| anyhow!("invalid cluster config file {config_file:?}: {err}"))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Cluster::new(gen_replica_id(), host, port, node_timeout),
+            Err(err) => return Err(err.into()),
+        };
+        cluster.config_file = Some(config_file);
+        cluster.save_if_changed()?;
+        Ok(cluster)
+    }
+
+    /// Parses a configuration in the format CLUSTER NODES uses, followed by
+    /// a `vars` line.
+    fn parse(config: &str, host: String, port: u16, node_timeout: Duration) -> Result<Cluster> {
+        let mut nodes = vec![];
+        let (mut migrating, mut importing) = (vec![], vec![]);
+        let mut current_epoch = 0;
+        for line in config.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let ["vars", vars @ ..] = fields.as_slice() {
+                for pair in vars.chunks(2) {
+                    if let ["currentEpoch", epoch] = pair {
+                        current_epoch = epoch.parse()?;
+                    }
+                }
+                continue;
+            }
+            let [id, address, flags, _master, _ping_sent, _pong_received, config_epoch, _link, slots @ ..] = fields.as_slice() else {
+                return Err(anyhow!("invalid line '{line}'"));
+            };
+            let (address, cport) = address.split_once('@').ok_or_else(|| anyhow!("invalid address '{address}'"))?;
+            let (node_host, node_port) = address.rsplit_once(':').ok_or_else(|| anyhow!("invalid address '{address}'"))?;
+            let mut node = ClusterNode::new(id.to_string(), node_host.to_string(), node_port.parse()?);
+            node.cport = cport.parse()?;
+            node.flags = parse_flags(flags);
+            node.config_epoch = config_epoch.parse()?;
+            let mut owned = vec![];
+            for range in slots {
+                if let Some(migration) = range.strip_prefix('[').and_then(|range| range.strip_suffix(']')) {
+                    if let Some((slot, target)) = migration.split_once("->-") {
+                        migrating.push((parse_slot(slot)?, target.to_string()));
+                    } else if let Some((slot, source)) = migration.split_once("-<-") {
+                        importing.push((parse_slot(slot)?, source.to_string()));
+                    } else {
+                        return Err(anyhow!("invalid slot migration '{range}'"));
+                    }
+                    continue;
+                }
+                let (start, end) = range.split_once('-').unwrap_or((range, range));
+                owned.extend(parse_slot(start)?..=parse_slot(end)?);
+            }
+            nodes.push((node, owned));
+        }
+        let myself = nodes.iter()
+            .find(|(node, _)| node.flags & FLAG_MYSELF != 0)
+            .map(|(node, _)| node.id.clone())
+            .ok_or_else(|| anyhow!("no node flagged myself"))?;
+        // routing a key in the slot looks the other node up
+        let known = |slot: &u16, id: &String| match nodes.iter().any(|(node, _)| node.id == *id) {
+            true => true,
+            false => {
+                warn!("Dropping the migration of slot {slot} to or from unknown node {id}");
+                false
+            },
+        };
+        migrating.retain(|(slot, target)| known(slot, target));
+        importing.retain(|(slot, source)| known(slot, source));
+
+        let cluster = Cluster::new(myself.clone(), host, port, node_timeout);
+        {
+            let mut state = cluster.state();
+            state.current_epoch = current_epoch;
+            state.migrating.extend(migrating);
+            state.importing.extend(importing);
+            for (mut node, owned) in nodes {
+                for slot in owned {
+                    state.slots[slot as usize] = Some(node.id.clone());
+                }
+                if node.id == myself {
+                    state.nodes.get_mut(&myself).unwrap().config_epoch = node.config_epoch;
+                } else {
+                    node.flags &= !FLAG_MYSELF;
+                    state.nodes.insert(node.id.clone(), node);
+                }
+            }
+        }
+        Ok(cluster)
+    }
+
+    pub fn myself(&self) -> &str {
+        &self.myself
+    }
+
+    pub fn node_timeout(&self) -> Duration {
+        self.node_timeout
+    }
+
+    fn state(&self) -> MutexGuard<'_, ClusterState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Checks that `keys` all live in one slot served by us, returning that
+    /// slot if there are any keys. While a slot migrates, keys that `exists`
+    /// says we no longer have are looked for on the target node, which
+    /// serves them to clients `asking` for them.
+    pub fn route(&self, keys: &[&str], asking: bool, exists: impl Fn(&str) -> bool) -> Result<Option<u16>, Redirect> {
+        let Some(slot) = keys.first().map(|key| key_slot(key)) else {
+            return Ok(None);
+        };
+        if keys[1..].iter().any(|key| key_slot(key) != slot) {
+            return Err(Redirect::CrossSlot);
+        }
+        let state = self.state();
+        let missing = || keys.iter().filter(|key| !exists(key)).count();
+        match &state.slots[slot as usize] {
+            Some(owner) if *owner == self.myself => match state.migrating.get(&slot) {
+                Some(target) => match missing() {
+                    0 => Ok(Some(slot)),
+                    missing if missing == keys.len() => {
+                        let node = &state.nodes[target];
+                        Err(Redirect::Ask(slot, node.host.clone(), node.port))
+                    },
+                    _ => Err(Redirect::TryAgain),
+                },
+                None => Ok(Some(slot)),
+            },
+            _ if asking && state.importing.contains_key(&slot) => match keys.len() > 1 && missing() > 0 {
+                true => Err(Redirect::TryAgain),
+                false => Ok(Some(slot)),
+            },
+            Some(owner) => {
+                let node = &state.nodes[owner];
+                Err(Redirect::Moved(slot, node.host.clone(), node.port))
+            },
+            None => Err(Redirect::Down(slot)),
+        }
+    }
+
+    /// Adds or updates a node we learnt about.
+    pub fn add_node(&self, node: ClusterNode) {
+        let mut state = self.state();
+        state.nodes.insert(node.id.clone(), node);
+        state.changed = true;
+    }
+
+    /// Greets the node at the given address on the next cron, so it joins
+    /// our cluster.
+    pub fn meet(&self, host: String, cport: u16) {
+        self.state().meets.push((host, cport));
+    }
+
+    /// Makes us serve `slots`, as long as nobody does yet.
+    pub fn add_slots(&self, slots: &[u16]) -> Result<()> {
+        let mut state = self.state();
+        for (i, slot) in slots.iter().enumerate() {
+            if state.slots[*slot as usize].is_some() || slots[..i].contains(slot) {
+                return Err(anyhow!("Slot {slot} is already busy"));
+            }
+        }
+        for slot in slots {
+            state.slots[*slot as usize] = Some(self.myself.clone());
+        }
+        state.changed = true;
+        Ok(())
+    }
+
+    /// Stops serving `slots`, which must all be assigned.
+    pub fn del_slots(&self, slots: &[u16]) -> Result<()> {
+        let mut state = self.state();
+        for (i, slot) in slots.iter().enumerate() {
+            if state.slots[*slot as usize].is_none() || slots[..i].contains(slot) {
+                return Err(anyhow!("Slot {slot} is already unassigned"));
+            }
+        }
+        for slot in slots {
+            state.slots[*slot as usize] = None;
+        }
+        state.changed = true;
+        Ok(())
+    }
+
+    /// Records `node` as serving `slot`.
+    pub fn assign(&self, slot: u16, node: &str) -> Result<()> {
+        let mut state = self.state();
+        if !state.nodes.contains_key(node) {
+            return Err(anyhow!("Unknown node {node}"));
+        }
+        state.slots[slot as usize] = Some(node.to_string());
+        state.changed = true;
+        Ok(())
+    }
+
+    /// Starts moving one of our slots to `node`: keys we don't have anymore
+    /// get asked for there.
+    pub fn set_slot_migrating(&self, slot: u16, node: &str) -> Result<()> {
+        let mut state = self.state();
+        if state.slots[slot as usize].as_ref() != Some(&self.myself) {
+            return Err(anyhow!("I'm not the owner of hash slot {slot}"));
+        }
+        if !state.nodes.contains_key(node) {
+            return Err(anyhow!("I don't know about node {node}"));
+        }
+        state.migrating.insert(slot, node.to_string());
+        state.changed = true;
+        Ok(())
+    }
+
+    /// Starts taking `slot` over from `node`: clients asking for its keys
+    /// get served by us.
+    pub fn set_slot_importing(&self, slot: u16, node: &str) -> Result<()> {
+        let mut state = self.state();
+        if state.slots[slot as usize].as_ref() == Some(&self.myself) {
+            return Err(anyhow!("I'm already the owner of hash slot {slot}"));
+        }
+        if !state.nodes.contains_key(node) {
+            return Err(anyhow!("I don't know about node {node}"));
+        }
+        state.importing.insert(slot, node.to_string());
+        state.changed = true;
+        Ok(())
+    }
+
+    /// Cancels any migration of `slot`.
+    pub fn set_slot_stable(&self, slot: u16) {
+        let mut state = self.state();
+        state.migrating.remove(&slot);
+        state.importing.remove(&slot);
+        state.changed = true;
+    }
+
+    /// Ends a migration by giving `slot` to `node`, given how many keys we
+    /// still have in it. When the slot comes to us, we take a new config
+    /// epoch so that every other node follows.
+    pub fn set_slot_node(&self, slot: u16, node: &str, keys: usize) -> Result<()> {
+        let mut state = self.state();
+        if !state.nodes.contains_key(node) {
+            return Err(anyhow!("Unknown node {node}"));
+        }
+        if state.slots[slot as usize].as_ref() == Some(&self.myself) && node != self.myself && keys > 0 {
+            return Err(anyhow!("Can't assign hashslot {slot} to a different node while I still hold keys for this hash slot."));
+        }
+        if keys == 0 {
+            state.migrating.remove(&slot);
+        }
+        if node == self.myself && state.importing.remove(&slot).is_some() {
+            self.bump_config_epoch(&mut state);
+        }
+        state.slots[slot as usize] = Some(node.to_string());
+        state.changed = true;
+        Ok(())
+    }
+
+    /// Takes a new config epoch unless ours already is the greatest, without
+    /// asking the other masters.
+    fn bump_config_epoch(&self, state: &mut ClusterState) {
+        let greatest = state.nodes.values().map(|node| node.config_epoch).max().unwrap_or(0);
+        let myself = &state.nodes[&self.myself];
+        if myself.config_epoch != 0 && myself.config_epoch == greatest {
+            return;
+        }
+        state.current_epoch = state.current_epoch.max(greatest) + 1;
+        let epoch = state.current_epoch;
+        state.nodes.get_mut(&self.myself).unwrap().config_epoch = epoch;
+        info!(target: "cluster", "took config epoch {epoch} for imported slots");
+    }
+
+    /// Contiguous ranges of slots, with the node serving each of them.
+    pub fn ranges(&self) -> Vec<(u16, u16, ClusterNode)> {
+        let state = self.state();
+        let mut ranges: Vec<(u16, u16, ClusterNode)> = vec![];
+        for (slot, owner) in state.slots.iter().enumerate() {
+            let Some(owner) = owner else { continue };
+            match ranges.last_mut() {
+                Some((_, end, node)) if node.id == *owner && *end as usize + 1 == slot => *end = slot as u16,
+                _ => ranges.push((slot as u16, slot as u16, state.nodes[owner].clone())),
+            }
+        }
+        ranges
+    }
+
+    /// Every known node with the ranges of slots it serves, us first.
+    pub fn shards(&self) -> Vec<(ClusterNode, Vec<(u16, u16)>)> {
+        let ranges = self.ranges();
+        let state = self.state();
+        let mut nodes: Vec<&ClusterNode> = state.nodes.values().collect();
+        nodes.sort_by_key(|node| node.id != self.myself);
+        nodes.into_iter()
+            .map(|node| {
+                let slots = ranges.iter()
+                    .filter(|(_, _, owner)| owner.id == node.id)
+                    .map(|(start, end, _)| (*start, *end))
+                    .collect();
+                (node.clone(), slots)
+            })
+            .collect()
+    }
+
+    /// The CLUSTER INFO report.
+    pub fn info(&self) -> String {
+        let shards = self.shards();
+        let state = self.state();
+        let owner_flags = |flag| state.slots.iter()
+            .filter(|owner| owner.as_ref().is_some_and(|owner| state.nodes[owner].flags & flag != 0))
+            .count();
+        let assigned = state.slots.iter().filter(|owner| owner.is_some()).count();
+        let (pfail, fail) = (owner_flags(FLAG_PFAIL), owner_flags(FLAG_FAIL));
+        let ok = assigned == SLOTS as usize && fail == 0;
+        [
+            format!("cluster_state:{}", if ok { "ok" } else { "fail" }),
+            format!("cluster_slots_assigned:{assigned}"),
+            format!("cluster_slots_ok:{}", assigned - pfail - fail),
+            format!("cluster_slots_pfail:{pfail}"),
+            format!("cluster_slots_fail:{fail}"),
+            format!("cluster_known_nodes:{}", state.nodes.len()),
+            format!("cluster_size:{}", shards.iter().filter(|(_, slots)| !slots.is_empty()).count()),
+            format!("cluster_current_epoch:{}", state.current_epoch),
+            format!("cluster_my_epoch:{}", state.nodes[&self.myself].config_epoch),
+        ].join("\r\n")
+    }
+
+    /// The CLUSTER NODES report, one line per node.
+    pub fn nodes(&self) -> String {
+        let migrations: Vec<String> = {
+            let state = self.state();
+            let migrating = state.migrating.iter().map(|(slot, target)| format!(" [{slot}->-{target}]"));
+            let importing = state.importing.iter().map(|(slot, source)| format!(" [{slot}-<-{source}]"));
+            migrating.chain(importing).collect()
+        };
+        self.shards().into_iter()
+            .map(|(node, slots)| {
+                let connected = node.id == self.myself || node.ping_sent.is_none();
+                let mut line = format!(
+                    "{} {}:{}@{} {} - {} {} {} {}",
+                    node.id, node.host, node.port, node.cport, flag_names(node.flags),
+                    node.ping_sent.unwrap_or(0), node.pong_received, node.config_epoch,
+                    if connected { "connected" } else { "disconnected" },
+                );
+                for (start, end) in slots {
+                    match start == end {
+                        true => line.push_str(&format!(" {start}")),
+                        false => line.push_str(&format!(" {start}-{end}")),
+                    }
+                }
+                if node.id == self.myself {
+                    migrations.iter().for_each(|migration| line.push_str(migration));
+                }
+                line + "\n"
+            })
+            .collect()
+    }
+
+    /// A message from us, with our slots and gossip about every other node.
+    pub fn message(&self, kind: MessageType, failing: Option<String>) -> Message {
+        let state = self.state();
+        let myself = &state.nodes[&self.myself];
+        Message {
+            kind,
+            current_epoch: state.current_epoch,
+            config_epoch: myself.config_epoch,
+            sender: myself.gossip(),
+            slots: (0..SLOTS).filter(|slot| state.slots[*slot as usize].as_ref() == Some(&self.myself)).collect(),
+            gossip: state.nodes.values().filter(|node| node.id != self.myself).map(ClusterNode::gossip).collect(),
+            failing,
+        }
+    }
+
+    /// Bus addresses of every other node.
+    pub fn peers(&self) -> Vec<(String, u16)> {
+        self.state().nodes.values()
+            .filter(|node| node.id != self.myself)
+            .map(|node| (node.host.clone(), node.cport))
+            .collect()
+    }
+
+    /// Updates our view of the cluster with what `message` tells, returning
+    /// the nodes we just flagged as failing, which every node should hear of.
+    pub fn receive(&self, message: &Message) -> Vec<String> {
+        let now = now_ms();
+        let mut state = self.state();
+        let sender = &message.sender;
+        if message.current_epoch > state.current_epoch {
+            state.current_epoch = message.current_epoch;
+            state.changed = true;
+        }
+
+        if !state.nodes.contains_key(&sender.id) {
+            // only nodes we met, or that met us, join the cluster
+            if !matches!(message.kind, MessageType::Meet | MessageType::Pong) || sender.id == self.myself {
+                return vec![];
+            }
+            info!(target: "cluster", "adding node {} at {}:{}", sender.id, sender.host, sender.port);
+            state.nodes.insert(sender.id.clone(), ClusterNode::new(sender.id.clone(), sender.host.clone(), sender.port));
+            state.changed = true;
+        }
+        let node = state.nodes.get_mut(&sender.id).unwrap();
+        if (node.host.as_str(), node.port, node.cport) != (sender.host.as_str(), sender.port, sender.cport) {
+            (node.host, node.port, node.cport) = (sender.host.clone(), sender.port, sender.cport);
+            state.changed = true;
+        }
+        if message.kind == MessageType::Pong {
+            self.pong(&mut state, &sender.id, now);
+        }
+        let node = state.nodes.get_mut(&sender.id).unwrap();
+        if node.config_epoch != message.config_epoch {
+            node.config_epoch = message.config_epoch;
+            state.changed = true;
+        }
+        self.update_slots(&mut state, &sender.id, &message.slots);
+        self.resolve_epoch_collision(&mut state, &sender.id);
+
+        let mut failed = vec![];
+        for gossip in &message.gossip {
+            if gossip.id == self.myself {
+                continue;
+            }
+            if !state.nodes.contains_key(&gossip.id) {
+                if gossip.flags & (FLAG_PFAIL | FLAG_FAIL) == 0 {
+                    info!(target: "cluster", "adding node {} at {}:{} heard of from {}", gossip.id, gossip.host, gossip.port, sender.id);
+                    let mut node = ClusterNode::new(gossip.id.clone(), gossip.host.clone(), gossip.port);
+                    node.cport = gossip.cport;
+                    state.nodes.insert(gossip.id.clone(), node);
+                    state.changed = true;
+                }
+                continue;
+            }
+            let node = state.nodes.get_mut(&gossip.id).unwrap();
+            if gossip.flags & (FLAG_PFAIL | FLAG_FAIL) != 0 {
+                node.fail_reports.insert(sender.id.clone(), now);
+                if self.mark_failing_if_needed(&mut state, &gossip.id, now) {
+                    failed.push(gossip.id.clone());
+                }
+            } else {
+                node.fail_reports.remove(&sender.id);
+            }
+        }
+
+        if let Some(failing) = message.failing.as_ref().filter(|failing| **failing != self.myself) {
+            if let Some(node) = state.nodes.get_mut(failing) {
+                if node.flags & FLAG_FAIL == 0 {
+                    warn!(target: "cluster", "node {failing} failing, as {} says", sender.id);
+                    node.flags = (node.flags | FLAG_FAIL) & !FLAG_PFAIL;
+                    node.fail_time = now;
+                    state.changed = true;
+                }
+            }
+        }
+        failed
+    }
+
+    fn pong(&self, state: &mut ClusterState, id: &str, now: u64) {
+        let owns_slots = state.slots.iter().any(|owner| owner.as_deref() == Some(id));
+        let node = state.nodes.get_mut(id).unwrap();
+        node.pong_received = now;
+        node.ping_sent = None;
+        if node.flags & FLAG_PFAIL != 0 {
+            info!(target: "cluster", "node {id} is reachable again");
+            node.flags &= !FLAG_PFAIL;
+            state.changed = true;
+        } else if node.flags & FLAG_FAIL != 0 {
+            // a master keeps its FAIL flag for a while, in case its slots
+            // moved elsewhere meanwhile
+            let validity = self.node_timeout.as_millis() as u64 * FAIL_VALIDITY_MULT;
+            if !owns_slots || now.saturating_sub(node.fail_time) > validity {
+                info!(target: "cluster", "clearing FAIL state of node {id}");
+                node.flags &= !FLAG_FAIL;
+                state.changed = true;
+            }
+        }
+    }
+
+    /// Takes the slots `sender` claims, unless their current owner got them
+    /// in a later epoch or we're importing them.
+    fn update_slots(&self, state: &mut ClusterState, sender: &str, slots: &[u16]) {
+        let epoch = state.nodes[sender].config_epoch;
+        for slot in slots {
+            if state.importing.contains_key(slot) {
+                continue;
+            }
+            let owner = &state.slots[*slot as usize];
+            let takes = match owner {
+                Some(owner) if owner == sender => false,
+                Some(owner) => state.nodes[owner].config_epoch < epoch,
+                None => true,
+            };
+            if takes {
+                if owner.as_deref() == Some(&self.myself) {
+                    warn!(target: "cluster", "slot {slot} now served by {sender}, which has a later config epoch");
+                    state.migrating.remove(slot);
+                }
+                state.slots[*slot as usize] = Some(sender.to_string());
+                state.changed = true;
+            }
+        }
+    }
+
+    /// Gives us a new config epoch when another master has the same one,
+    /// unless our id is the greater one, so that every master ends up with
+    /// its own epoch.
+    fn resolve_epoch_collision(&self, state: &mut ClusterState, sender: &str) {
+        let my_epoch = state.nodes[&self.myself].config_epoch;
+        if state.nodes[sender].config_epoch != my_epoch || sender <= self.myself.as_str() {
+            return;
+        }
+        state.current_epoch += 1;
+        let epoch = state.current_epoch;
+        state.nodes.get_mut(&self.myself).unwrap().config_epoch = epoch;
+        state.changed = true;
+        info!(target: "cluster", "config epoch collision with {sender}, now at epoch {epoch}");
+    }
+
+    /// Flags `id` as failing once a majority of the masters serving slots,
+    /// counting us, reported it down, returning whether it just happened.
+    fn mark_failing_if_needed(&self, state: &mut ClusterState, id: &str, now: u64) -> bool {
+        let validity = self.node_timeout.as_millis() as u64 * FAIL_VALIDITY_MULT;
+        let masters = state.nodes.keys()
+            .filter(|node| state.slots.iter().any(|owner| owner.as_ref() == Some(*node)))
+            .count();
+        let needed = masters / 2 + 1;
+        let node = state.nodes.get_mut(id).unwrap();
+        node.fail_reports.retain(|_, reported| now.saturating_sub(*reported) <= validity);
+        if node.flags & FLAG_PFAIL == 0 || node.flags & FLAG_FAIL != 0 {
+            return false;
+        }
+        let reports = node.fail_reports.len() + 1;
+        if reports < needed {
+            return false;
+        }
+        warn!(target: "cluster", "node {id} failing, {reports} masters agree");
+        node.flags = (node.flags | FLAG_FAIL) & !FLAG_PFAIL;
+        node.fail_time = now;
+        state.changed = true;
+        true
+    }
+
+    /// Finds who needs pinging or meeting, and flags nodes that didn't
+    /// answer pings for too long as possibly failing.
+    pub fn cron(&self) -> Vec<Target> {
+        let now = now_ms();
+        let timeout = self.node_timeout.as_millis() as u64;
+        let mut state = self.state();
+        let mut targets: Vec<Target> = state.meets.drain(..)
+            .map(|(host, cport)| Target { id: None, host, cport, kind: MessageType::Meet })
+            .collect();
+        let mut changed = false;
+        for node in state.nodes.values_mut().filter(|node| node.id != self.myself) {
+            if let Some(sent) = node.ping_sent {
+                if now.saturating_sub(sent) > timeout && node.flags & (FLAG_PFAIL | FLAG_FAIL) == 0 {
+                    warn!(target: "cluster", "node {} possibly failing, no pong for {}ms", node.id, now - sent);
+                    node.flags |= FLAG_PFAIL;
+                    changed = true;
+                }
+            }
+            let due = node.ping_sent.map_or(node.pong_received, |_| node.last_ping) + PING_PERIOD;
+            if !node.pinging && now >= due {
+                node.pinging = true;
+                node.last_ping = now;
+                node.ping_sent.get_or_insert(now);
+                targets.push(Target { id: Some(node.id.clone()), host: node.host.clone(), cport: node.cport, kind: MessageType::Ping });
+            }
+        }
+        state.changed |= changed;
+        targets
+    }
+
+    /// Allows pinging `id` again, whether the last ping got an answer or not.
+    pub fn ping_done(&self, id: &str) {
+        if let Some(node) = self.state().nodes.get_mut(id) {
+            node.pinging = false;
+        }
+    }
+
+    /// Writes the configuration to the config file if it changed since the
+    /// last time.
+    pub fn save_if_changed(&self) -> Result<()> {
+        let Some(config_file) = &self.config_file else {
+            return Ok(());
+        };
+        let current_epoch = {
+            let mut state = self.state();
+            if !std::mem::take(&mut state.changed) {
+                return Ok(());
+            }
+            state.current_epoch
+        };
+        let config = format!("{}vars currentEpoch {current_epoch} lastVoteEpoch 0\n", self.nodes());
+        let temp = config_file.with_file_name(format!("temp-{}.conf", process::id()));
+        let result = File::create(&temp)
+            .and_then(|mut file| file.write_all(config.as_bytes()).and_then(|()| file.sync_all()))
+            .and_then(|()| fs::rename(&temp, config_file));
+        if let Err(err) = result {
+            let _ = fs::remove_file(&temp);
+            self.state().changed = true;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn node(id: &str, port: u16) -> ClusterNode {
+        ClusterNode::new(id.repeat(40), "127.0.0.1".to_string(), port)
+    }
+
+    fn cluster(id: &str, port: u16) -> Cluster {
+        Cluster::new(id.repeat(40), "127.0.0.1".to_string(), port, DEFAULT_NODE_TIMEOUT)
+    }
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(12739, key_slot("123456789"));
+        assert_eq!(key_slot("user1000"), key_slot("{user1000}.following"));
+        assert_eq!(key_slot("user1000"), key_slot("foo{user1000}{bar}"));
+        assert_eq!(key_slot("{}foo"), crc16(b"{}foo") % SLOTS);
+        assert_eq!(key_slot("foo{"), crc16(b"foo{") % SLOTS);
+    }
+
+    #[test]
+    fn test_route() {
+        let cluster = cluster("a", 7000);
+        cluster.add_node(node("b", 7001));
+        let slot = key_slot("foo");
+        assert_eq!(Ok(None), cluster.route(&[], false, |_| true));
+        assert_eq!(Err(Redirect::Down(slot)), cluster.route(&["foo"], false, |_| true));
+
+        cluster.add_slots(&[slot]).unwrap();
+        assert_eq!(Ok(Some(slot)), cluster.route(&["foo", "{foo}bar"], false, |_| true));
+        assert_eq!(Err(Redirect::CrossSlot), cluster.route(&["foo", "bar"], false, |_| true));
+
+        cluster.assign(key_slot("bar"), &"b".repeat(40)).unwrap();
+        assert_eq!(
+            "MOVED 5061 127.0.0.1:7001",
+            cluster.route(&["bar"], false, |_| true).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_slot_migration() {
+        let (a, b) = (cluster("a", 7000), cluster("b", 7001));
+        a.add_slots(&[0]).unwrap();
+        a.receive(&b.message(MessageType::Meet, None));
+        b.receive(&a.message(MessageType::Meet, None));
+        let (a_id, b_id) = (a.myself().to_string(), b.myself().to_string());
+        assert!(b.set_slot_migrating(0, &a_id).is_err());
+        assert!(a.set_slot_importing(0, &b_id).is_err());
+        a.set_slot_migrating(0, &b_id).unwrap();
+        b.set_slot_importing(0, &a_id).unwrap();
+        assert!(a.nodes().contains(&format!(" 0 [0->-{b_id}]\n")));
+
+        // keys already moved are asked for on b, which serves them when asked
+        assert_eq!(Ok(Some(0)), a.route(&["fHh"], false, |_| true));
+        assert_eq!(Err(Redirect::Ask(0, "127.0.0.1".to_string(), 7001)), a.route(&["fHh"], false, |_| false));
+        assert_eq!(Err(Redirect::TryAgain), a.route(&["fHh", "{fHh}x"], false, |key| key == "fHh"));
+        assert!(matches!(b.route(&["fHh"], false, |_| true), Err(Redirect::Moved(0, _, 7000))));
+        assert_eq!(Ok(Some(0)), b.route(&["fHh"], true, |_| true));
+
+        assert!(a.set_slot_node(0, &b_id, 1).is_err());
+        b.set_slot_node(0, &b_id, 1).unwrap();
+        assert!(!b.nodes().contains("-<-"));
+
+        // b took a new epoch, so a's claim doesn't win the slot back, and a
+        // gives it up as soon as it hears of b
+        b.receive(&a.message(MessageType::Ping, None));
+        assert_eq!(Ok(Some(0)), b.route(&["fHh"], false, |_| true));
+        a.receive(&b.message(MessageType::Ping, None));
+        assert!(matches!(a.route(&["fHh"], false, |_| false), Err(Redirect::Moved(0, _, 7001))));
+        assert!(!a.nodes().contains("->-"));
+    }
+
+    #[test]
+    fn test_slot_ranges() {
+        let cluster = cluster("a", 7000);
+        cluster.add_slots(&[0, 1, 2, 5]).unwrap();
+        assert!(cluster.add_slots(&[2]).is_err());
+        assert!(cluster.add_slots(&[3, 3]).is_err());
+        let ranges: Vec<(u16, u16)> = cluster.ranges().into_iter().map(|(start, end, _)| (start, end)).collect();
+        assert_eq!(vec![(0, 2), (5, 5)], ranges);
+        assert!(cluster.nodes().ends_with("connected 0-2 5\n"));
+
+        cluster.del_slots(&[1]).unwrap();
+        assert!(cluster.del_slots(&[1]).is_err());
+        assert_eq!(3, cluster.ranges().len());
+    }
+
+    #[test]
+    fn test_meet_and_gossip() {
+        let (a, b) = (cluster("a", 7000), cluster("b", 7001));
+        a.add_slots(&[0, 1]).unwrap();
+        b.add_slots(&[2]).unwrap();
+        b.add_node(node("c", 7002));
+
+        // a ping from an unknown node is ignored, a meet isn't
+        a.receive(&b.message(MessageType::Ping, None));
+        assert_eq!(1, a.shards().len());
+        a.receive(&b.message(MessageType::Meet, None));
+        assert_eq!(3, a.shards().len());
+        assert_eq!(Err(Redirect::Moved(2, "127.0.0.1".to_string(), 7001)), a.route(&["aVD"], false, |_| true));
+
+        // both at epoch 0, the node with the smaller id moves on
+        b.receive(&a.message(MessageType::Pong, None));
+        assert!(a.info().contains("cluster_my_epoch:1"));
+        assert!(b.info().contains("cluster_my_epoch:0"));
+    }
+
+    #[test]
+    fn test_slots_follow_config_epochs() {
+        let (a, b) = (cluster("a", 7000), cluster("b", 7001));
+        a.add_slots(&[0]).unwrap();
+        a.receive(&b.message(MessageType::Meet, None));
+        let mut claim = b.message(MessageType::Ping, None);
+        claim.slots = vec![0];
+        a.receive(&claim);
+        assert_eq!(Ok(Some(0)), a.route(&["fHh"], false, |_| true));
+
+        claim.config_epoch = 5;
+        a.receive(&claim);
+        assert!(matches!(a.route(&["fHh"], false, |_| true), Err(Redirect::Moved(0, _, 7001))));
+    }
+
+    #[test]
+    fn test_failure_needs_majority() {
+        let (a, b, c) = (cluster("a", 7000), cluster("b", 7001), cluster("c", 7002));
+        a.add_slots(&[0]).unwrap();
+        b.add_slots(&[1]).unwrap();
+        c.add_slots(&[2]).unwrap();
+        a.receive(&b.message(MessageType::Meet, None));
+        a.receive(&c.message(MessageType::Meet, None));
+        c.receive(&a.message(MessageType::Meet, None));
+        c.receive(&b.message(MessageType::Meet, None));
+
+        // c lost b, and a hears of it, but didn't see b down itself yet
+        c.state().nodes.get_mut(&"b".repeat(40)).unwrap().flags |= FLAG_PFAIL;
+        assert!(a.receive(&c.message(MessageType::Ping, None)).is_empty());
+
+        a.state().nodes.get_mut(&"b".repeat(40)).unwrap().flags |= FLAG_PFAIL;
+        assert_eq!(vec!["b".repeat(40)], a.receive(&c.message(MessageType::Ping, None)));
+        assert!(a.info().contains("cluster_state:fail"));
+        assert!(a.nodes().contains("master,fail"));
+
+        let mut fail = a.message(MessageType::Fail, Some("b".repeat(40)));
+        fail.gossip.clear();
+        let d = cluster("d", 7003);
+        d.add_node(node("a", 7000));
+        d.add_node(node("b", 7001));
+        d.receive(&fail);
+        assert!(d.nodes().contains("master,fail"));
+    }
+
+    #[test]
+    fn test_config_round_trip() {
+        let a = cluster("a", 7000);
+        a.add_slots(&(0..100).chain([200]).collect::<Vec<_>>()).unwrap();
+        a.receive(&cluster("b", 7001).message(MessageType::Meet, None));
+        a.set_slot_migrating(200, &"b".repeat(40)).unwrap();
+        let config = format!("{}vars currentEpoch 3 lastVoteEpoch 0\n", a.nodes());
+
+        let loaded = Cluster::parse(&config, "127.0.0.1".to_string(), 7000, DEFAULT_NODE_TIMEOUT).unwrap();
+        assert_eq!(a.myself(), loaded.myself());
+        assert_eq!(a.nodes().lines().count(), loaded.nodes().lines().count());
+        assert!(loaded.nodes().contains(&format!("connected 0-99 200 [200->-{}]", "b".repeat(40))));
+        assert!(loaded.info().contains("cluster_current_epoch:3"));
+
+        // migrations to or from nodes the file doesn't list are dropped
+        let unknown = config.replace(&format!("[200->-{}]", "b".repeat(40)), &format!("[0->-{0}] [1-<-{0}]", "c".repeat(40)));
+        let loaded = Cluster::parse(&unknown, "127.0.0.1".to_string(), 7000, DEFAULT_NODE_TIMEOUT).unwrap();
+        assert!(!loaded.nodes().contains('['));
+        assert_eq!(Ok(Some(0)), loaded.route(&["fHh"], false, |_| false));
+    }
+}
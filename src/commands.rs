@@ -1,18 +1,164 @@
+use std::sync::Arc;
+
 use anyhow::{Result, anyhow};
 
+use crate::cluster::parse_slot;
+use crate::keyspace::SIZE_SAMPLES;
 use crate::protocol::RESP;
+use crate::replication::{LinkStatus, MasterLinkInfo, ReplicaSummary};
 
 pub trait FromRESP {
     fn from_resp(resp: RESP) -> Result<CommandRequest>;
 }
 
 pub trait ToRESP {
-    fn to_resp(self: &Self) -> Result<RESP>;
+    fn to_resp(&self) -> Result<RESP>;
 }
 
 #[derive(Debug)]
 pub enum InfoMode {
-    Replication
+    Replication,
+    Sentinel,
+}
+
+#[derive(Debug)]
+pub enum ScriptCommand {
+    Load(String),
+    Exists(Vec<String>),
+    Flush,
+    Kill,
+}
+
+#[derive(Debug)]
+pub enum RestorePolicy {
+    Append,
+    Replace,
+    Flush,
+}
+
+#[derive(Debug)]
+pub enum FunctionCommand {
+    Load(String, bool),
+    List(Option<String>, bool),
+    Delete(String),
+    Flush,
+    Dump,
+    Restore(Vec<u8>, RestorePolicy),
+    Kill,
+    Stats,
+}
+
+/// Introspection of the value stored at a key.
+#[derive(Debug)]
+pub enum ObjectCommand {
+    Encoding(String),
+    /// Logarithmic access counter, for LFU eviction.
+    Freq(String),
+    /// Seconds since the key was last accessed, for LRU eviction.
+    IdleTime(String),
+    RefCount(String),
+}
+
+#[derive(Debug)]
+pub enum MemoryCommand {
+    /// Bytes used by a key and its value, estimating the size of collections
+    /// from that many of their elements, or all of them with 0.
+    Usage(String, usize),
+    Stats,
+    Doctor,
+}
+
+#[derive(Debug)]
+pub enum ConfigCommand {
+    /// Parameters matching any of the glob patterns.
+    Get(Vec<String>),
+    Set(Vec<(String, String)>),
+    ResetStat,
+    /// Writes the running configuration back to the config file.
+    Rewrite,
+}
+
+#[derive(Debug)]
+pub enum ReplconfCommand {
+    ListeningPort(u16),
+    Capa(Vec<String>),
+    /// Asks a replica for its offset, the argument is ignored.
+    GetAck(String),
+    Ack(u64),
+}
+
+/// Queries understood by servers running in sentinel mode.
+#[derive(Debug)]
+pub enum SentinelCommand {
+    GetMasterAddrByName(String),
+    /// Asks whether the master at the given address is down, and for a vote
+    /// in the given epoch unless the run id is `*`.
+    IsMasterDownByAddr(String, u16, u64, String),
+    Master(String),
+    Replicas(String),
+    Sentinels(String),
+    MyId,
+}
+
+/// What CLUSTER SETSLOT makes of a slot.
+#[derive(Debug)]
+pub enum SlotState {
+    /// Moving our slot to the node with the given id.
+    Migrating(String),
+    /// Taking the slot over from the node with the given id.
+    Importing(String),
+    Stable,
+    /// Served by the node with the given id, ending any migration.
+    Node(String),
+}
+
+#[derive(Debug)]
+pub enum ClusterCommand {
+    Info,
+    MyId,
+    Nodes,
+    Slots,
+    Shards,
+    KeySlot(String),
+    CountKeysInSlot(u16),
+    GetKeysInSlot(u16, u64),
+    AddSlots(Vec<u16>),
+    AddSlotsRange(Vec<(u16, u16)>),
+    DelSlots(Vec<u16>),
+    /// Joins the node at the given host and port, its bus port defaulting
+    /// to port + 10000.
+    Meet(String, u16, Option<u16>),
+    SetSlot(u16, SlotState),
+}
+
+#[derive(Debug)]
+pub struct RestoreOptions {
+    /// Time to live in milliseconds, none with 0.
+    pub(crate) ttl: u64,
+    pub(crate) replace: bool,
+    /// Whether `ttl` is a unix time in milliseconds rather than a duration.
+    pub(crate) abs_ttl: bool,
+    /// Seconds since the key was last accessed, for LRU eviction.
+    pub(crate) idle_time: Option<u64>,
+    /// Access frequency counter, for LFU eviction.
+    pub(crate) freq: Option<u8>,
+    /// Sent as RESTORE-ASKING, so that a node importing the slot serves it.
+    pub(crate) asking: bool,
+}
+
+/// Where MIGRATE moves keys to, and how.
+#[derive(Debug)]
+pub struct Migrate {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) keys: Vec<String>,
+    pub(crate) db: usize,
+    /// Milliseconds to wait for the target at each step.
+    pub(crate) timeout: u64,
+    /// Whether to keep our keys.
+    pub(crate) copy: bool,
+    /// Whether to overwrite keys the target already has.
+    pub(crate) replace: bool,
 }
 
 #[derive(Debug)]
@@ -21,8 +167,132 @@ pub enum CommandRequest {
     ECHO(String),
     GET(String),
     SET(String, String, Option<u64>),
+    DEL(Vec<String>),
+    KEYS(String),
+    TYPE(String),
+    TTL(String),
+    PTTL(String),
+    PEXPIREAT(String, u64),
+    SELECT(usize),
+    SWAPDB(usize, usize),
+    MOVE(String, usize),
+    /// Removes every key of the selected database, in the background when
+    /// set.
+    FLUSHDB(bool),
+    /// Removes every key of every database, in the background when set.
+    FLUSHALL(bool),
+    RPUSH(String, Vec<String>),
+    SADD(String, Vec<String>),
+    ZADD(String, Vec<(f64, String)>),
+    HSET(String, Vec<(String, String)>),
+    SAVE,
+    BGSAVE,
+    LASTSAVE,
+    BGREWRITEAOF,
+    REPLCONF(ReplconfCommand),
+    PSYNC(String, i64),
+    WAIT(u64, u64),
+    /// Replicate the given host and port, or nothing anymore with `None`.
+    REPLICAOF(Option<(String, u16)>),
+    SENTINEL(SentinelCommand),
+    CLUSTER(ClusterCommand),
+    /// Lets the next command run against a slot being imported.
+    ASKING,
+    DUMP(String),
+    RESTORE(String, Vec<u8>, RestoreOptions),
+    MIGRATE(Migrate),
+    OBJECT(ObjectCommand),
+    MEMORY(MemoryCommand),
+    CONFIG(ConfigCommand),
     DOCS,
-    INFO(InfoMode)
+    INFO(InfoMode),
+    MULTI,
+    EXEC,
+    DISCARD,
+    WATCH(Vec<String>),
+    UNWATCH,
+    EVAL(String, Vec<String>, Vec<String>),
+    EVALSHA(String, Vec<String>, Vec<String>),
+    SCRIPT(ScriptCommand),
+    FUNCTION(FunctionCommand),
+    FCALL(String, Vec<String>, Vec<String>),
+    #[allow(non_camel_case_types)]
+    FCALL_RO(String, Vec<String>, Vec<String>),
+}
+
+impl CommandRequest {
+    /// Whether the command modifies the dataset.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            CommandRequest::SET(..) | CommandRequest::DEL(_) | CommandRequest::PEXPIREAT(..) |
+            CommandRequest::RPUSH(..) | CommandRequest::SADD(..) | CommandRequest::ZADD(..) |
+            CommandRequest::HSET(..) | CommandRequest::RESTORE(..) | CommandRequest::MIGRATE(_) |
+            CommandRequest::SWAPDB(..) | CommandRequest::MOVE(..) | CommandRequest::FLUSHDB(_) | CommandRequest::FLUSHALL(_) |
+            CommandRequest::FUNCTION(
+                FunctionCommand::Load(..) | FunctionCommand::Delete(_) |
+                FunctionCommand::Flush | FunctionCommand::Restore(..)
+            )
+        )
+    }
+
+    /// Whether the command may need more memory, which is refused once
+    /// nothing can be evicted to stay within maxmemory.
+    pub fn is_denyoom(&self) -> bool {
+        matches!(
+            self,
+            CommandRequest::SET(..) | CommandRequest::RPUSH(..) | CommandRequest::SADD(..) |
+            CommandRequest::ZADD(..) | CommandRequest::HSET(..) | CommandRequest::RESTORE(..) |
+            CommandRequest::FUNCTION(FunctionCommand::Load(..) | FunctionCommand::Restore(..))
+        )
+    }
+
+    /// The keys the command reads or writes, which cluster mode routes it by.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            CommandRequest::GET(key) | CommandRequest::SET(key, ..) | CommandRequest::TYPE(key) |
+            CommandRequest::TTL(key) | CommandRequest::PTTL(key) | CommandRequest::PEXPIREAT(key, _) |
+            CommandRequest::RPUSH(key, _) | CommandRequest::SADD(key, _) | CommandRequest::ZADD(key, _) |
+            CommandRequest::HSET(key, _) | CommandRequest::RESTORE(key, ..) |
+            CommandRequest::DUMP(key) | CommandRequest::MOVE(key, _) |
+            CommandRequest::OBJECT(
+                ObjectCommand::Encoding(key) | ObjectCommand::Freq(key) |
+                ObjectCommand::IdleTime(key) | ObjectCommand::RefCount(key)
+            ) |
+            CommandRequest::MEMORY(MemoryCommand::Usage(key, _)) => vec![key],
+            CommandRequest::DEL(keys) | CommandRequest::WATCH(keys) |
+            CommandRequest::EVAL(_, keys, _) | CommandRequest::EVALSHA(_, keys, _) |
+            CommandRequest::FCALL(_, keys, _) | CommandRequest::FCALL_RO(_, keys, _) |
+            CommandRequest::MIGRATE(Migrate { keys, .. }) => {
+                keys.iter().map(String::as_str).collect()
+            },
+            CommandRequest::PING | CommandRequest::ECHO(_) | CommandRequest::KEYS(_) |
+            CommandRequest::SAVE | CommandRequest::BGSAVE | CommandRequest::LASTSAVE | CommandRequest::BGREWRITEAOF |
+            CommandRequest::REPLCONF(_) | CommandRequest::PSYNC(..) | CommandRequest::WAIT(..) |
+            CommandRequest::REPLICAOF(_) | CommandRequest::SENTINEL(_) | CommandRequest::CLUSTER(_) |
+            CommandRequest::ASKING | CommandRequest::DOCS | CommandRequest::INFO(_) |
+            CommandRequest::MEMORY(MemoryCommand::Stats | MemoryCommand::Doctor) | CommandRequest::CONFIG(_) |
+            CommandRequest::SELECT(_) | CommandRequest::SWAPDB(..) | CommandRequest::FLUSHDB(_) | CommandRequest::FLUSHALL(_) |
+            CommandRequest::MULTI | CommandRequest::EXEC | CommandRequest::DISCARD | CommandRequest::UNWATCH |
+            CommandRequest::SCRIPT(_) | CommandRequest::FUNCTION(_) => vec![],
+        }
+    }
+
+    /// Whether scripts can run the command through `redis.call`.
+    pub fn allowed_in_script(&self) -> bool {
+        !matches!(
+            self,
+            CommandRequest::MULTI | CommandRequest::EXEC | CommandRequest::DISCARD |
+            CommandRequest::WATCH(_) | CommandRequest::UNWATCH |
+            CommandRequest::EVAL(..) | CommandRequest::EVALSHA(..) | CommandRequest::SCRIPT(_) |
+            CommandRequest::FUNCTION(_) | CommandRequest::FCALL(..) | CommandRequest::FCALL_RO(..) |
+            CommandRequest::SAVE | CommandRequest::BGSAVE | CommandRequest::BGREWRITEAOF |
+            CommandRequest::REPLCONF(_) | CommandRequest::PSYNC(..) | CommandRequest::WAIT(..) |
+            CommandRequest::REPLICAOF(_) | CommandRequest::SENTINEL(_) | CommandRequest::CLUSTER(_) |
+            CommandRequest::ASKING | CommandRequest::MIGRATE(_) | CommandRequest::SELECT(_) |
+            CommandRequest::CONFIG(_)
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -43,30 +313,27 @@ impl ReplicationRole {
 
 #[derive(Debug)]
 pub struct ReplicationInfo {
-    role: ReplicationRole,
-    connected_slaves: u8,
-    master_replid: String,
-    master_repl_offset: u8,
-    second_repl_offset: i8,
-    repl_backlog_active: u8,
-    repl_backlog_size: u64,
-    repl_backlog_first_byte_offset: u64,
-    repl_backlog_histlen: u64
-}
-impl ReplicationInfo {
-    pub(crate) fn new(role: ReplicationRole, master_replid: String, master_repl_offset: u8) -> ReplicationInfo {
-        ReplicationInfo {
-            role,
-            connected_slaves: 0,
-            master_replid,
-            master_repl_offset,
-            second_repl_offset: 0,
-            repl_backlog_active: 0,
-            repl_backlog_size: 0,
-            repl_backlog_first_byte_offset: 0,
-            repl_backlog_histlen: 0
-        }
-    }
+    pub(crate) role: ReplicationRole,
+    pub(crate) master: Option<MasterLinkInfo>,
+    pub(crate) connected_slaves: Vec<ReplicaSummary>,
+    pub(crate) master_replid: String,
+    pub(crate) master_replid2: String,
+    pub(crate) master_repl_offset: u64,
+    pub(crate) second_repl_offset: i64,
+    pub(crate) repl_backlog_active: bool,
+    pub(crate) repl_backlog_size: u64,
+    pub(crate) repl_backlog_first_byte_offset: u64,
+    pub(crate) repl_backlog_histlen: u64,
+}
+
+/// How an RDB snapshot sent to a replica is delimited.
+#[derive(Debug, Clone)]
+pub enum RdbFormat {
+    /// Size announced upfront, as for snapshots saved before sending them
+    Length,
+    /// Ends with the given 40 bytes mark, for snapshots streamed as they're
+    /// produced
+    EofMark(String),
 }
 
 #[derive(Debug)]
@@ -74,10 +341,23 @@ pub enum CommandResponse {
     PONG,
     ECHO(String),
     OK,
+    STATUS(String),
     STR(String),
+    BYTES(Vec<u8>),
     INFO(ReplicationInfo),
     DOCS,
     NIL,
+    INT(i64),
+    QUEUED,
+    ARRAY(Vec<CommandResponse>),
+    NILARRAY,
+    /// Reply to PSYNC with the replication id and offset, followed by the
+    /// RDB snapshot the replica starts from.
+    FULLRESYNC(String, u64, Arc<Vec<u8>>, RdbFormat),
+    /// Reply to PSYNC when the replica can continue from where it was, with
+    /// our current replication id.
+    CONTINUE(String),
+    ERR(String),
 }
 
 impl FromRESP for CommandRequest {
@@ -109,6 +389,257 @@ impl FromRESP for CommandRequest {
                          Ok(CommandRequest::SET(key.to_string(), value.to_string(), Some(expiry_long)))
                      },
                     [RESP::BulkString(i), RESP::BulkString(r)] if *i == "INFO" && r == "replication" => Ok(CommandRequest::INFO(InfoMode::Replication)),
+                    [RESP::BulkString(i), RESP::BulkString(s)] if *i == "INFO" && s == "sentinel" => Ok(CommandRequest::INFO(InfoMode::Sentinel)),
+                    [RESP::BulkString(d), keys @ ..] if *d == "DEL" && !keys.is_empty() => {
+                        Ok(CommandRequest::DEL(bulk_strings(keys)?))
+                    },
+                    [RESP::BulkString(k), RESP::BulkString(pattern)] if *k == "KEYS" => Ok(CommandRequest::KEYS(pattern.to_string())),
+                    [RESP::BulkString(t), RESP::BulkString(key)] if *t == "TYPE" => Ok(CommandRequest::TYPE(key.to_string())),
+                    [RESP::BulkString(t), RESP::BulkString(key)] if *t == "TTL" => Ok(CommandRequest::TTL(key.to_string())),
+                    [RESP::BulkString(t), RESP::BulkString(key)] if *t == "PTTL" => Ok(CommandRequest::PTTL(key.to_string())),
+                    [RESP::BulkString(p), RESP::BulkString(key), RESP::BulkString(at)] if *p == "PEXPIREAT" => {
+                        let at = at.parse::<u64>().map_err(|_| anyhow!("value is not an integer or out of range"))?;
+                        Ok(CommandRequest::PEXPIREAT(key.to_string(), at))
+                    },
+                    [RESP::BulkString(s), RESP::BulkString(index)] if *s == "SELECT" => Ok(CommandRequest::SELECT(db_index(index)?)),
+                    [RESP::BulkString(s), RESP::BulkString(first), RESP::BulkString(second)] if *s == "SWAPDB" => {
+                        let first = db_index(first).map_err(|err| if first.parse::<i64>().is_ok() { err } else { anyhow!("invalid first DB index") })?;
+                        let second = db_index(second).map_err(|err| if second.parse::<i64>().is_ok() { err } else { anyhow!("invalid second DB index") })?;
+                        Ok(CommandRequest::SWAPDB(first, second))
+                    },
+                    [RESP::BulkString(m), RESP::BulkString(key), RESP::BulkString(db)] if *m == "MOVE" => {
+                        Ok(CommandRequest::MOVE(key.to_string(), db_index(db)?))
+                    },
+                    [RESP::BulkString(f), mode @ ..] if (*f == "FLUSHDB" || *f == "FLUSHALL") && mode.len() <= 1 => {
+                        let lazy = match bulk_strings(mode)?.first().map(String::as_str) {
+                            None | Some("SYNC") => false,
+                            Some("ASYNC") => true,
+                            Some(_) => return Err(anyhow!("syntax error")),
+                        };
+                        Ok(if *f == "FLUSHDB" { CommandRequest::FLUSHDB(lazy) } else { CommandRequest::FLUSHALL(lazy) })
+                    },
+                    [RESP::BulkString(o), RESP::BulkString(subcommand), RESP::BulkString(key)] if *o == "OBJECT" => {
+                        let key = key.to_string();
+                        Ok(CommandRequest::OBJECT(match subcommand.as_str() {
+                            "ENCODING" => ObjectCommand::Encoding(key),
+                            "FREQ" => ObjectCommand::Freq(key),
+                            "IDLETIME" => ObjectCommand::IdleTime(key),
+                            "REFCOUNT" => ObjectCommand::RefCount(key),
+                            x => return Err(anyhow!("unknown subcommand '{x}'. Try OBJECT HELP.")),
+                        }))
+                    },
+                    [RESP::BulkString(m), RESP::BulkString(u), RESP::BulkString(key), options @ ..] if *m == "MEMORY" && *u == "USAGE" => {
+                        let samples = match bulk_strings(options)?.as_slice() {
+                            [] => SIZE_SAMPLES,
+                            [s, samples] if s == "SAMPLES" => {
+                                let samples = samples.parse::<i64>().map_err(|_| anyhow!("value is not an integer or out of range"))?;
+                                usize::try_from(samples).map_err(|_| anyhow!("syntax error"))?
+                            },
+                            _ => return Err(anyhow!("syntax error")),
+                        };
+                        Ok(CommandRequest::MEMORY(MemoryCommand::Usage(key.to_string(), samples)))
+                    },
+                    [RESP::BulkString(m), RESP::BulkString(s)] if *m == "MEMORY" && *s == "STATS" => {
+                        Ok(CommandRequest::MEMORY(MemoryCommand::Stats))
+                    },
+                    [RESP::BulkString(m), RESP::BulkString(d)] if *m == "MEMORY" && *d == "DOCTOR" => {
+                        Ok(CommandRequest::MEMORY(MemoryCommand::Doctor))
+                    },
+                    [RESP::BulkString(c), RESP::BulkString(g), patterns @ ..] if *c == "CONFIG" && *g == "GET" && !patterns.is_empty() => {
+                        Ok(CommandRequest::CONFIG(ConfigCommand::Get(bulk_strings(patterns)?)))
+                    },
+                    [RESP::BulkString(c), RESP::BulkString(s), pairs @ ..] if *c == "CONFIG" && *s == "SET" => {
+                        let pairs = bulk_strings(pairs)?;
+                        if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+                            return Err(anyhow!("wrong number of arguments for 'config|set' command"));
+                        }
+                        Ok(CommandRequest::CONFIG(ConfigCommand::Set(
+                            pairs.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect()
+                        )))
+                    },
+                    [RESP::BulkString(c), RESP::BulkString(r)] if *c == "CONFIG" && *r == "RESETSTAT" => {
+                        Ok(CommandRequest::CONFIG(ConfigCommand::ResetStat))
+                    },
+                    [RESP::BulkString(c), RESP::BulkString(r)] if *c == "CONFIG" && *r == "REWRITE" => {
+                        Ok(CommandRequest::CONFIG(ConfigCommand::Rewrite))
+                    },
+                    [RESP::BulkString(r), RESP::BulkString(key), elements @ ..] if *r == "RPUSH" && !elements.is_empty() => {
+                        Ok(CommandRequest::RPUSH(key.to_string(), bulk_strings(elements)?))
+                    },
+                    [RESP::BulkString(s), RESP::BulkString(key), members @ ..] if *s == "SADD" && !members.is_empty() => {
+                        Ok(CommandRequest::SADD(key.to_string(), bulk_strings(members)?))
+                    },
+                    [RESP::BulkString(z), RESP::BulkString(key), rest @ ..] if *z == "ZADD" && !rest.is_empty() => {
+                        let members = pairs(rest, "zadd")?.into_iter()
+                            .map(|(score, member)| match score.parse::<f64>() {
+                                Ok(score) if !score.is_nan() => Ok((score, member)),
+                                _ => Err(anyhow!("value is not a valid float")),
+                            })
+                            .collect::<Result<Vec<_>>>()?;
+                        Ok(CommandRequest::ZADD(key.to_string(), members))
+                    },
+                    [RESP::BulkString(h), RESP::BulkString(key), rest @ ..] if *h == "HSET" && !rest.is_empty() => {
+                        Ok(CommandRequest::HSET(key.to_string(), pairs(rest, "hset")?))
+                    },
+                    [RESP::BulkString(s)] if *s == "SAVE" => Ok(CommandRequest::SAVE),
+                    [RESP::BulkString(b)] if *b == "BGSAVE" => Ok(CommandRequest::BGSAVE),
+                    [RESP::BulkString(l)] if *l == "LASTSAVE" => Ok(CommandRequest::LASTSAVE),
+                    [RESP::BulkString(b)] if *b == "BGREWRITEAOF" => Ok(CommandRequest::BGREWRITEAOF),
+                    [RESP::BulkString(r), RESP::BulkString(l), RESP::BulkString(port)] if *r == "REPLCONF" && *l == "listening-port" => {
+                        let port = port.parse::<u16>().map_err(|_| anyhow!("value is not an integer or out of range"))?;
+                        Ok(CommandRequest::REPLCONF(ReplconfCommand::ListeningPort(port)))
+                    },
+                    [RESP::BulkString(r), RESP::BulkString(g), RESP::BulkString(x)] if *r == "REPLCONF" && *g == "GETACK" => {
+                        Ok(CommandRequest::REPLCONF(ReplconfCommand::GetAck(x.to_string())))
+                    },
+                    [RESP::BulkString(r), RESP::BulkString(a), RESP::BulkString(offset)] if *r == "REPLCONF" && *a == "ACK" => {
+                        let offset = offset.parse::<u64>().map_err(|_| anyhow!("value is not an integer or out of range"))?;
+                        Ok(CommandRequest::REPLCONF(ReplconfCommand::Ack(offset)))
+                    },
+                    [RESP::BulkString(r), options @ ..] if *r == "REPLCONF" && !options.is_empty() => {
+                        let capabilities = pairs(options, "replconf")?.into_iter()
+                            .map(|(option, capability)| match option.as_str() {
+                                "capa" => Ok(capability),
+                                x => Err(anyhow!("Unrecognized REPLCONF option: {x}")),
+                            })
+                            .collect::<Result<Vec<String>>>()?;
+                        Ok(CommandRequest::REPLCONF(ReplconfCommand::Capa(capabilities)))
+                    },
+                    [RESP::BulkString(p), RESP::BulkString(replid), RESP::BulkString(offset)] if *p == "PSYNC" => {
+                        let offset = offset.parse::<i64>().map_err(|_| anyhow!("value is not an integer or out of range"))?;
+                        Ok(CommandRequest::PSYNC(replid.to_string(), offset))
+                    },
+                    [RESP::BulkString(w), RESP::BulkString(numreplicas), RESP::BulkString(timeout)] if *w == "WAIT" => {
+                        let numreplicas = numreplicas.parse::<u64>().map_err(|_| anyhow!("value is not an integer or out of range"))?;
+                        if timeout.starts_with('-') {
+                            return Err(anyhow!("timeout is negative"));
+                        }
+                        let timeout = timeout.parse::<u64>().map_err(|_| anyhow!("timeout is not an integer or out of range"))?;
+                        Ok(CommandRequest::WAIT(numreplicas, timeout))
+                    },
+                    [RESP::BulkString(r), RESP::BulkString(n), RESP::BulkString(o)] if (*r == "REPLICAOF" || *r == "SLAVEOF") && *n == "NO" && *o == "ONE" => {
+                        Ok(CommandRequest::REPLICAOF(None))
+                    },
+                    [RESP::BulkString(r), RESP::BulkString(host), RESP::BulkString(port)] if *r == "REPLICAOF" || *r == "SLAVEOF" => {
+                        let port = port.parse::<u16>().map_err(|_| anyhow!("Invalid master port"))?;
+                        Ok(CommandRequest::REPLICAOF(Some((host.to_string(), port))))
+                    },
+                    [RESP::BulkString(s), RESP::BulkString(g), RESP::BulkString(name)] if *s == "SENTINEL" && *g == "get-master-addr-by-name" => {
+                        Ok(CommandRequest::SENTINEL(SentinelCommand::GetMasterAddrByName(name.to_string())))
+                    },
+                    [RESP::BulkString(s), RESP::BulkString(i), RESP::BulkString(ip), RESP::BulkString(port), RESP::BulkString(epoch), RESP::BulkString(runid)]
+                        if *s == "SENTINEL" && *i == "is-master-down-by-addr" => {
+                        let port = port.parse::<u16>().map_err(|_| anyhow!("value is not an integer or out of range"))?;
+                        let epoch = epoch.parse::<u64>().map_err(|_| anyhow!("value is not an integer or out of range"))?;
+                        Ok(CommandRequest::SENTINEL(SentinelCommand::IsMasterDownByAddr(ip.to_string(), port, epoch, runid.to_string())))
+                    },
+                    [RESP::BulkString(s), RESP::BulkString(m), RESP::BulkString(name)] if *s == "SENTINEL" && *m == "master" => {
+                        Ok(CommandRequest::SENTINEL(SentinelCommand::Master(name.to_string())))
+                    },
+                    [RESP::BulkString(s), RESP::BulkString(r), RESP::BulkString(name)] if *s == "SENTINEL" && (*r == "replicas" || *r == "slaves") => {
+                        Ok(CommandRequest::SENTINEL(SentinelCommand::Replicas(name.to_string())))
+                    },
+                    [RESP::BulkString(s), RESP::BulkString(ss), RESP::BulkString(name)] if *s == "SENTINEL" && *ss == "sentinels" => {
+                        Ok(CommandRequest::SENTINEL(SentinelCommand::Sentinels(name.to_string())))
+                    },
+                    [RESP::BulkString(s), RESP::BulkString(m)] if *s == "SENTINEL" && *m == "myid" => {
+                        Ok(CommandRequest::SENTINEL(SentinelCommand::MyId))
+                    },
+                    [RESP::BulkString(c), RESP::BulkString(sub), args @ ..] if *c == "CLUSTER" => {
+                        Ok(CommandRequest::CLUSTER(cluster_command(sub, &bulk_strings(args)?)?))
+                    },
+                    [RESP::BulkString(a)] if *a == "ASKING" => Ok(CommandRequest::ASKING),
+                    [RESP::BulkString(d), RESP::BulkString(key)] if *d == "DUMP" => Ok(CommandRequest::DUMP(key.to_string())),
+                    [RESP::BulkString(r), RESP::BulkString(key), RESP::BulkString(ttl), payload, options @ ..]
+                        if *r == "RESTORE" || *r == "RESTORE-ASKING" => {
+                        let ttl = ttl.parse::<i64>().map_err(|_| anyhow!("value is not an integer or out of range"))?;
+                        let ttl = u64::try_from(ttl).map_err(|_| anyhow!("Invalid TTL value, must be >= 0"))?;
+                        let payload = payload.bytes().ok_or_else(|| anyhow!("expected a bulk string payload"))?;
+                        let restore = restore_options(ttl, *r == "RESTORE-ASKING", &bulk_strings(options)?)?;
+                        Ok(CommandRequest::RESTORE(key.to_string(), payload, restore))
+                    },
+                    [RESP::BulkString(m), args @ ..] if *m == "MIGRATE" => {
+                        Ok(CommandRequest::MIGRATE(migrate_command(&bulk_strings(args)?)?))
+                    },
+                    [RESP::BulkString(m)] if *m == "MULTI" => Ok(CommandRequest::MULTI),
+                    [RESP::BulkString(e)] if *e == "EXEC" => Ok(CommandRequest::EXEC),
+                    [RESP::BulkString(d)] if *d == "DISCARD" => Ok(CommandRequest::DISCARD),
+                    [RESP::BulkString(w), keys @ ..] if *w == "WATCH" && !keys.is_empty() => {
+                        Ok(CommandRequest::WATCH(bulk_strings(keys)?))
+                    },
+                    [RESP::BulkString(u)] if *u == "UNWATCH" => Ok(CommandRequest::UNWATCH),
+                    [RESP::BulkString(e), RESP::BulkString(script), RESP::BulkString(numkeys), rest @ ..] if *e == "EVAL" => {
+                        let (keys, args) = keys_and_args(numkeys, rest)?;
+                        Ok(CommandRequest::EVAL(script.to_string(), keys, args))
+                    },
+                    [RESP::BulkString(e), RESP::BulkString(sha), RESP::BulkString(numkeys), rest @ ..] if *e == "EVALSHA" => {
+                        let (keys, args) = keys_and_args(numkeys, rest)?;
+                        Ok(CommandRequest::EVALSHA(sha.to_string(), keys, args))
+                    },
+                    [RESP::BulkString(s), RESP::BulkString(l), RESP::BulkString(script)] if *s == "SCRIPT" && *l == "LOAD" => {
+                        Ok(CommandRequest::SCRIPT(ScriptCommand::Load(script.to_string())))
+                    },
+                    [RESP::BulkString(s), RESP::BulkString(e), shas @ ..] if *s == "SCRIPT" && *e == "EXISTS" && !shas.is_empty() => {
+                        Ok(CommandRequest::SCRIPT(ScriptCommand::Exists(bulk_strings(shas)?)))
+                    },
+                    [RESP::BulkString(s), RESP::BulkString(f)] if *s == "SCRIPT" && *f == "FLUSH" => {
+                        Ok(CommandRequest::SCRIPT(ScriptCommand::Flush))
+                    },
+                    [RESP::BulkString(s), RESP::BulkString(f), RESP::BulkString(mode)] if *s == "SCRIPT" && *f == "FLUSH" && (mode == "ASYNC" || mode == "SYNC") => {
+                        Ok(CommandRequest::SCRIPT(ScriptCommand::Flush))
+                    },
+                    [RESP::BulkString(s), RESP::BulkString(k)] if *s == "SCRIPT" && *k == "KILL" => {
+                        Ok(CommandRequest::SCRIPT(ScriptCommand::Kill))
+                    },
+                    [RESP::BulkString(f), RESP::BulkString(l), RESP::BulkString(code)] if *f == "FUNCTION" && *l == "LOAD" => {
+                        Ok(CommandRequest::FUNCTION(FunctionCommand::Load(code.to_string(), false)))
+                    },
+                    [RESP::BulkString(f), RESP::BulkString(l), RESP::BulkString(r), RESP::BulkString(code)] if *f == "FUNCTION" && *l == "LOAD" && *r == "REPLACE" => {
+                        Ok(CommandRequest::FUNCTION(FunctionCommand::Load(code.to_string(), true)))
+                    },
+                    [RESP::BulkString(f), RESP::BulkString(l), options @ ..] if *f == "FUNCTION" && *l == "LIST" => {
+                        function_list(&bulk_strings(options)?)
+                    },
+                    [RESP::BulkString(f), RESP::BulkString(d), RESP::BulkString(name)] if *f == "FUNCTION" && *d == "DELETE" => {
+                        Ok(CommandRequest::FUNCTION(FunctionCommand::Delete(name.to_string())))
+                    },
+                    [RESP::BulkString(f), RESP::BulkString(fl)] if *f == "FUNCTION" && *fl == "FLUSH" => {
+                        Ok(CommandRequest::FUNCTION(FunctionCommand::Flush))
+                    },
+                    [RESP::BulkString(f), RESP::BulkString(fl), RESP::BulkString(mode)] if *f == "FUNCTION" && *fl == "FLUSH" && (mode == "ASYNC" || mode == "SYNC") => {
+                        Ok(CommandRequest::FUNCTION(FunctionCommand::Flush))
+                    },
+                    [RESP::BulkString(f), RESP::BulkString(d)] if *f == "FUNCTION" && *d == "DUMP" => {
+                        Ok(CommandRequest::FUNCTION(FunctionCommand::Dump))
+                    },
+                    [RESP::BulkString(f), RESP::BulkString(r), payload] if *f == "FUNCTION" && *r == "RESTORE" => {
+                        let payload = payload.bytes().ok_or_else(|| anyhow!("expected a bulk string payload"))?;
+                        Ok(CommandRequest::FUNCTION(FunctionCommand::Restore(payload, RestorePolicy::Append)))
+                    },
+                    [RESP::BulkString(f), RESP::BulkString(r), payload, RESP::BulkString(policy)] if *f == "FUNCTION" && *r == "RESTORE" => {
+                        let payload = payload.bytes().ok_or_else(|| anyhow!("expected a bulk string payload"))?;
+                        let policy = match policy.as_str() {
+                            "APPEND" => RestorePolicy::Append,
+                            "REPLACE" => RestorePolicy::Replace,
+                            "FLUSH" => RestorePolicy::Flush,
+                            _ => return Err(anyhow!("Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE.")),
+                        };
+                        Ok(CommandRequest::FUNCTION(FunctionCommand::Restore(payload, policy)))
+                    },
+                    [RESP::BulkString(f), RESP::BulkString(k)] if *f == "FUNCTION" && *k == "KILL" => {
+                        Ok(CommandRequest::FUNCTION(FunctionCommand::Kill))
+                    },
+                    [RESP::BulkString(f), RESP::BulkString(s)] if *f == "FUNCTION" && *s == "STATS" => {
+                        Ok(CommandRequest::FUNCTION(FunctionCommand::Stats))
+                    },
+                    [RESP::BulkString(f), RESP::BulkString(name), RESP::BulkString(numkeys), rest @ ..] if *f == "FCALL" => {
+                        let (keys, args) = keys_and_args(numkeys, rest)?;
+                        Ok(CommandRequest::FCALL(name.to_string(), keys, args))
+                    },
+                    [RESP::BulkString(f), RESP::BulkString(name), RESP::BulkString(numkeys), rest @ ..] if *f == "FCALL_RO" => {
+                        let (keys, args) = keys_and_args(numkeys, rest)?;
+                        Ok(CommandRequest::FCALL_RO(name.to_string(), keys, args))
+                    },
                     x => Err(anyhow!("unexpected RESP command: {:?}", x)),
                 }
             },
@@ -123,43 +654,430 @@ impl ToRESP for CommandResponse {
             CommandResponse::PONG => Ok(RESP::BulkString("PONG".to_string())),
             CommandResponse::ECHO(x) => Ok(RESP::BulkString(x.to_string())),
             CommandResponse::OK => Ok(RESP::SimpleString("OK".to_string())),
+            CommandResponse::STATUS(status) => Ok(RESP::SimpleString(status.to_string())),
             CommandResponse::STR(str) => Ok(RESP::BulkString(str.to_string())),
+            CommandResponse::BYTES(bytes) => Ok(RESP::BulkBytes(bytes.to_vec())),
             CommandResponse::DOCS => Ok(RESP::BulkString("welcome to redis".to_string())),
             CommandResponse::NIL => Ok(RESP::NullBulkString),
-            CommandResponse::INFO(r) => Ok(
-                RESP::BulkString(
-                    [
-                        ["role", r.role.to_str()].join(":"),
-                        ["connected_slaves", "0"].join(":"),
-                        ["master_replid", r.master_replid.as_str()].join(":"),
-                        ["master_repl_offset", r.master_repl_offset.to_string().as_str()].join(":"),
-                        ["second_repl_offset:", "1"].join(":"),
-                        ["repl_backlog_active", "0"].join(":"),
-                        ["repl_backlog_size", "1048576"].join(":"),
-                        ["repl_backlog_first_byte_offset", "0"].join(":"),
-                        ["repl_backlog", "histlen:"].join(":")
-                    ].join("\r\n")
-                )
-            ),
-            x => Err(anyhow!("unexpected command in ToRESP: {:?}", x))
+            CommandResponse::INFO(r) => {
+                let mut lines = vec![format!("role:{}", r.role.to_str())];
+                if let Some(master) = &r.master {
+                    lines.extend([
+                        format!("master_host:{}", master.host),
+                        format!("master_port:{}", master.port),
+                        format!("master_link_status:{}", if master.status == LinkStatus::Up { "up" } else { "down" }),
+                        format!("master_last_io_seconds_ago:{}", master.last_io_seconds_ago),
+                        format!("master_sync_in_progress:{}", (master.status == LinkStatus::Sync) as u8),
+                        format!("slave_repl_offset:{}", r.master_repl_offset),
+                        format!("slave_priority:{}", master.priority),
+                        format!("slave_read_only:{}", master.read_only as u8),
+                    ]);
+                }
+                lines.push(format!("connected_slaves:{}", r.connected_slaves.len()));
+                lines.extend(r.connected_slaves.iter().enumerate().map(|(i, slave)| format!(
+                    "slave{i}:ip={},port={},state={},offset={},lag=0",
+                    slave.ip, slave.port, slave.state.to_str(), slave.offset
+                )));
+                lines.extend([
+                    format!("master_replid:{}", r.master_replid),
+                    format!("master_replid2:{}", r.master_replid2),
+                    format!("master_repl_offset:{}", r.master_repl_offset),
+                    format!("second_repl_offset:{}", r.second_repl_offset),
+                    format!("repl_backlog_active:{}", r.repl_backlog_active as u8),
+                    format!("repl_backlog_size:{}", r.repl_backlog_size),
+                    format!("repl_backlog_first_byte_offset:{}", r.repl_backlog_first_byte_offset),
+                    format!("repl_backlog_histlen:{}", r.repl_backlog_histlen),
+                ]);
+                Ok(RESP::BulkString(lines.join("\r\n")))
+            },
+            CommandResponse::INT(i) => Ok(RESP::Integer(*i)),
+            CommandResponse::QUEUED => Ok(RESP::SimpleString("QUEUED".to_string())),
+            CommandResponse::ARRAY(responses) => Ok(RESP::Array(
+                responses.iter().map(|response| response.to_resp()).collect::<Result<Vec<RESP>>>()?
+            )),
+            CommandResponse::NILARRAY => Ok(RESP::NullArray),
+            CommandResponse::FULLRESYNC(replid, offset, _, _) => Ok(RESP::SimpleString(format!("FULLRESYNC {replid} {offset}"))),
+            CommandResponse::CONTINUE(replid) => Ok(RESP::SimpleString(format!("CONTINUE {replid}"))),
+            CommandResponse::ERR(message) => Ok(RESP::SimpleError(message.to_string())),
         }
     }
 }
 
 impl ToRESP for CommandRequest {
-    fn to_resp(self: &Self) -> Result<RESP> {
+    fn to_resp(&self) -> Result<RESP> {
         match self {
-            CommandRequest::PING => Ok(
-                RESP::Array(
-                    vec![
-                        RESP::BulkString("PING".to_string())                 ]
-                )
-            ),
-            CommandRequest::ECHO(_) => todo!(),
-            CommandRequest::GET(_) => todo!(),
-            CommandRequest::SET(_, _, _) => todo!(),
-            CommandRequest::DOCS => todo!(),
-            CommandRequest::INFO(_) => todo!(),
+            CommandRequest::PING => Ok(command(&["PING"])),
+            CommandRequest::ECHO(x) => Ok(command(&["ECHO", x])),
+            CommandRequest::GET(key) => Ok(command(&["GET", key])),
+            CommandRequest::SET(key, value, None) => Ok(command(&["SET", key, value])),
+            CommandRequest::SET(key, value, Some(expiry)) => Ok(command(&["SET", key, value, "px", &expiry.to_string()])),
+            CommandRequest::DEL(keys) => Ok(command_with("DEL", keys)),
+            CommandRequest::KEYS(pattern) => Ok(command(&["KEYS", pattern])),
+            CommandRequest::TYPE(key) => Ok(command(&["TYPE", key])),
+            CommandRequest::TTL(key) => Ok(command(&["TTL", key])),
+            CommandRequest::PTTL(key) => Ok(command(&["PTTL", key])),
+            CommandRequest::PEXPIREAT(key, at) => Ok(command(&["PEXPIREAT", key, &at.to_string()])),
+            CommandRequest::SELECT(db) => Ok(command(&["SELECT", &db.to_string()])),
+            CommandRequest::SWAPDB(first, second) => Ok(command(&["SWAPDB", &first.to_string(), &second.to_string()])),
+            CommandRequest::MOVE(key, db) => Ok(command(&["MOVE", key, &db.to_string()])),
+            CommandRequest::FLUSHDB(lazy) => Ok(command(&["FLUSHDB", if *lazy { "ASYNC" } else { "SYNC" }])),
+            CommandRequest::FLUSHALL(lazy) => Ok(command(&["FLUSHALL", if *lazy { "ASYNC" } else { "SYNC" }])),
+            CommandRequest::RPUSH(key, elements) => Ok(command_with("RPUSH", &[&[key.to_string()], elements.as_slice()].concat())),
+            CommandRequest::SADD(key, members) => Ok(command_with("SADD", &[&[key.to_string()], members.as_slice()].concat())),
+            CommandRequest::ZADD(key, members) => {
+                let mut args = vec![key.to_string()];
+                for (score, member) in members {
+                    args.extend([score.to_string(), member.to_string()]);
+                }
+                Ok(command_with("ZADD", &args))
+            },
+            CommandRequest::HSET(key, fields) => {
+                let mut args = vec![key.to_string()];
+                for (field, value) in fields {
+                    args.extend([field.to_string(), value.to_string()]);
+                }
+                Ok(command_with("HSET", &args))
+            },
+            CommandRequest::SAVE => Ok(command(&["SAVE"])),
+            CommandRequest::BGSAVE => Ok(command(&["BGSAVE"])),
+            CommandRequest::LASTSAVE => Ok(command(&["LASTSAVE"])),
+            CommandRequest::BGREWRITEAOF => Ok(command(&["BGREWRITEAOF"])),
+            CommandRequest::REPLCONF(ReplconfCommand::ListeningPort(port)) => Ok(command(&["REPLCONF", "listening-port", &port.to_string()])),
+            CommandRequest::REPLCONF(ReplconfCommand::Capa(capabilities)) => {
+                let args: Vec<String> = capabilities.iter().flat_map(|capability| ["capa".to_string(), capability.to_string()]).collect();
+                Ok(command_with("REPLCONF", &args))
+            },
+            CommandRequest::REPLCONF(ReplconfCommand::GetAck(x)) => Ok(command(&["REPLCONF", "GETACK", x])),
+            CommandRequest::REPLCONF(ReplconfCommand::Ack(offset)) => Ok(command(&["REPLCONF", "ACK", &offset.to_string()])),
+            CommandRequest::PSYNC(replid, offset) => Ok(command(&["PSYNC", replid, &offset.to_string()])),
+            CommandRequest::WAIT(numreplicas, timeout) => Ok(command(&["WAIT", &numreplicas.to_string(), &timeout.to_string()])),
+            CommandRequest::REPLICAOF(None) => Ok(command(&["REPLICAOF", "NO", "ONE"])),
+            CommandRequest::REPLICAOF(Some((host, port))) => Ok(command(&["REPLICAOF", host, &port.to_string()])),
+            CommandRequest::SENTINEL(SentinelCommand::GetMasterAddrByName(name)) => Ok(command(&["SENTINEL", "get-master-addr-by-name", name])),
+            CommandRequest::SENTINEL(SentinelCommand::IsMasterDownByAddr(ip, port, epoch, runid)) => {
+                Ok(command(&["SENTINEL", "is-master-down-by-addr", ip, &port.to_string(), &epoch.to_string(), runid]))
+            },
+            CommandRequest::SENTINEL(SentinelCommand::Master(name)) => Ok(command(&["SENTINEL", "master", name])),
+            CommandRequest::SENTINEL(SentinelCommand::Replicas(name)) => Ok(command(&["SENTINEL", "replicas", name])),
+            CommandRequest::SENTINEL(SentinelCommand::Sentinels(name)) => Ok(command(&["SENTINEL", "sentinels", name])),
+            CommandRequest::SENTINEL(SentinelCommand::MyId) => Ok(command(&["SENTINEL", "myid"])),
+            CommandRequest::CLUSTER(ClusterCommand::Info) => Ok(command(&["CLUSTER", "INFO"])),
+            CommandRequest::CLUSTER(ClusterCommand::MyId) => Ok(command(&["CLUSTER", "MYID"])),
+            CommandRequest::CLUSTER(ClusterCommand::Nodes) => Ok(command(&["CLUSTER", "NODES"])),
+            CommandRequest::CLUSTER(ClusterCommand::Slots) => Ok(command(&["CLUSTER", "SLOTS"])),
+            CommandRequest::CLUSTER(ClusterCommand::Shards) => Ok(command(&["CLUSTER", "SHARDS"])),
+            CommandRequest::CLUSTER(ClusterCommand::KeySlot(key)) => Ok(command(&["CLUSTER", "KEYSLOT", key])),
+            CommandRequest::CLUSTER(ClusterCommand::CountKeysInSlot(slot)) => Ok(command(&["CLUSTER", "COUNTKEYSINSLOT", &slot.to_string()])),
+            CommandRequest::CLUSTER(ClusterCommand::GetKeysInSlot(slot, count)) => {
+                Ok(command(&["CLUSTER", "GETKEYSINSLOT", &slot.to_string(), &count.to_string()]))
+            },
+            CommandRequest::CLUSTER(ClusterCommand::AddSlots(slots)) => {
+                Ok(command_with("CLUSTER", &[&["ADDSLOTS".to_string()], &slots.iter().map(u16::to_string).collect::<Vec<_>>()[..]].concat()))
+            },
+            CommandRequest::CLUSTER(ClusterCommand::AddSlotsRange(ranges)) => {
+                let args: Vec<String> = std::iter::once("ADDSLOTSRANGE".to_string())
+                    .chain(ranges.iter().flat_map(|(start, end)| [start.to_string(), end.to_string()]))
+                    .collect();
+                Ok(command_with("CLUSTER", &args))
+            },
+            CommandRequest::CLUSTER(ClusterCommand::DelSlots(slots)) => {
+                Ok(command_with("CLUSTER", &[&["DELSLOTS".to_string()], &slots.iter().map(u16::to_string).collect::<Vec<_>>()[..]].concat()))
+            },
+            CommandRequest::CLUSTER(ClusterCommand::Meet(host, port, None)) => Ok(command(&["CLUSTER", "MEET", host, &port.to_string()])),
+            CommandRequest::CLUSTER(ClusterCommand::Meet(host, port, Some(cport))) => {
+                Ok(command(&["CLUSTER", "MEET", host, &port.to_string(), &cport.to_string()]))
+            },
+            CommandRequest::CLUSTER(ClusterCommand::SetSlot(slot, state)) => {
+                let slot = slot.to_string();
+                match state {
+                    SlotState::Migrating(node) => Ok(command(&["CLUSTER", "SETSLOT", &slot, "MIGRATING", node])),
+                    SlotState::Importing(node) => Ok(command(&["CLUSTER", "SETSLOT", &slot, "IMPORTING", node])),
+                    SlotState::Stable => Ok(command(&["CLUSTER", "SETSLOT", &slot, "STABLE"])),
+                    SlotState::Node(node) => Ok(command(&["CLUSTER", "SETSLOT", &slot, "NODE", node])),
+                }
+            },
+            CommandRequest::ASKING => Ok(command(&["ASKING"])),
+            CommandRequest::DUMP(key) => Ok(command(&["DUMP", key])),
+            CommandRequest::OBJECT(ObjectCommand::Encoding(key)) => Ok(command(&["OBJECT", "ENCODING", key])),
+            CommandRequest::OBJECT(ObjectCommand::Freq(key)) => Ok(command(&["OBJECT", "FREQ", key])),
+            CommandRequest::OBJECT(ObjectCommand::IdleTime(key)) => Ok(command(&["OBJECT", "IDLETIME", key])),
+            CommandRequest::OBJECT(ObjectCommand::RefCount(key)) => Ok(command(&["OBJECT", "REFCOUNT", key])),
+            CommandRequest::MEMORY(MemoryCommand::Usage(key, samples)) => Ok(command(&["MEMORY", "USAGE", key, "SAMPLES", &samples.to_string()])),
+            CommandRequest::MEMORY(MemoryCommand::Stats) => Ok(command(&["MEMORY", "STATS"])),
+            CommandRequest::MEMORY(MemoryCommand::Doctor) => Ok(command(&["MEMORY", "DOCTOR"])),
+            CommandRequest::CONFIG(ConfigCommand::Get(patterns)) => {
+                let args: Vec<String> = std::iter::once("GET".to_string()).chain(patterns.iter().cloned()).collect();
+                Ok(command_with("CONFIG", &args))
+            },
+            CommandRequest::CONFIG(ConfigCommand::Set(pairs)) => {
+                let mut args = vec!["SET".to_string()];
+                args.extend(pairs.iter().flat_map(|(name, value)| [name.clone(), value.clone()]));
+                Ok(command_with("CONFIG", &args))
+            },
+            CommandRequest::CONFIG(ConfigCommand::ResetStat) => Ok(command(&["CONFIG", "RESETSTAT"])),
+            CommandRequest::CONFIG(ConfigCommand::Rewrite) => Ok(command(&["CONFIG", "REWRITE"])),
+            CommandRequest::RESTORE(key, payload, options) => {
+                let name = if options.asking { "RESTORE-ASKING" } else { "RESTORE" };
+                let mut parts = vec![
+                    RESP::BulkString(name.to_string()),
+                    RESP::BulkString(key.to_string()),
+                    RESP::BulkString(options.ttl.to_string()),
+                    RESP::BulkBytes(payload.to_vec()),
+                ];
+                let mut flags = vec![];
+                if options.replace {
+                    flags.push("REPLACE".to_string());
+                }
+                if options.abs_ttl {
+                    flags.push("ABSTTL".to_string());
+                }
+                if let Some(idle_time) = options.idle_time {
+                    flags.extend(["IDLETIME".to_string(), idle_time.to_string()]);
+                }
+                if let Some(freq) = options.freq {
+                    flags.extend(["FREQ".to_string(), freq.to_string()]);
+                }
+                parts.extend(flags.into_iter().map(RESP::BulkString));
+                Ok(RESP::Array(parts))
+            },
+            CommandRequest::MIGRATE(migrate) => {
+                let key = if migrate.keys.len() == 1 { migrate.keys[0].as_str() } else { "" };
+                let mut args = vec![migrate.host.to_string(), migrate.port.to_string(), key.to_string(), migrate.db.to_string(), migrate.timeout.to_string()];
+                if migrate.copy {
+                    args.push("COPY".to_string());
+                }
+                if migrate.replace {
+                    args.push("REPLACE".to_string());
+                }
+                if migrate.keys.len() != 1 {
+                    args.push("KEYS".to_string());
+                    args.extend(migrate.keys.iter().cloned());
+                }
+                Ok(command_with("MIGRATE", &args))
+            },
+            CommandRequest::DOCS => Ok(command(&["COMMAND", "DOCS"])),
+            CommandRequest::INFO(InfoMode::Replication) => Ok(command(&["INFO", "replication"])),
+            CommandRequest::INFO(InfoMode::Sentinel) => Ok(command(&["INFO", "sentinel"])),
+            CommandRequest::MULTI => Ok(command(&["MULTI"])),
+            CommandRequest::EXEC => Ok(command(&["EXEC"])),
+            CommandRequest::DISCARD => Ok(command(&["DISCARD"])),
+            CommandRequest::WATCH(keys) => Ok(command_with("WATCH", keys)),
+            CommandRequest::UNWATCH => Ok(command(&["UNWATCH"])),
+            CommandRequest::EVAL(script, keys, args) => Ok(command_with("EVAL", &script_args(script, keys, args))),
+            CommandRequest::EVALSHA(sha, keys, args) => Ok(command_with("EVALSHA", &script_args(sha, keys, args))),
+            CommandRequest::SCRIPT(ScriptCommand::Load(script)) => Ok(command(&["SCRIPT", "LOAD", script])),
+            CommandRequest::SCRIPT(ScriptCommand::Exists(shas)) => Ok(command_with("SCRIPT", &[&["EXISTS".to_string()], shas.as_slice()].concat())),
+            CommandRequest::SCRIPT(ScriptCommand::Flush) => Ok(command(&["SCRIPT", "FLUSH"])),
+            CommandRequest::SCRIPT(ScriptCommand::Kill) => Ok(command(&["SCRIPT", "KILL"])),
+            CommandRequest::FUNCTION(FunctionCommand::Load(code, false)) => Ok(command(&["FUNCTION", "LOAD", code])),
+            CommandRequest::FUNCTION(FunctionCommand::Load(code, true)) => Ok(command(&["FUNCTION", "LOAD", "REPLACE", code])),
+            CommandRequest::FUNCTION(FunctionCommand::List(pattern, with_code)) => {
+                let mut parts = vec!["FUNCTION", "LIST"];
+                if let Some(pattern) = pattern {
+                    parts.extend(["LIBRARYNAME", pattern]);
+                }
+                if *with_code {
+                    parts.push("WITHCODE");
+                }
+                Ok(command(&parts))
+            },
+            CommandRequest::FUNCTION(FunctionCommand::Delete(name)) => Ok(command(&["FUNCTION", "DELETE", name])),
+            CommandRequest::FUNCTION(FunctionCommand::Flush) => Ok(command(&["FUNCTION", "FLUSH"])),
+            CommandRequest::FUNCTION(FunctionCommand::Dump) => Ok(command(&["FUNCTION", "DUMP"])),
+            CommandRequest::FUNCTION(FunctionCommand::Restore(payload, policy)) => {
+                let policy = match policy {
+                    RestorePolicy::Append => "APPEND",
+                    RestorePolicy::Replace => "REPLACE",
+                    RestorePolicy::Flush => "FLUSH",
+                };
+                Ok(RESP::Array(vec![
+                    RESP::BulkString("FUNCTION".to_string()),
+                    RESP::BulkString("RESTORE".to_string()),
+                    RESP::BulkBytes(payload.to_vec()),
+                    RESP::BulkString(policy.to_string()),
+                ]))
+            },
+            CommandRequest::FUNCTION(FunctionCommand::Kill) => Ok(command(&["FUNCTION", "KILL"])),
+            CommandRequest::FUNCTION(FunctionCommand::Stats) => Ok(command(&["FUNCTION", "STATS"])),
+            CommandRequest::FCALL(name, keys, args) => Ok(command_with("FCALL", &script_args(name, keys, args))),
+            CommandRequest::FCALL_RO(name, keys, args) => Ok(command_with("FCALL_RO", &script_args(name, keys, args))),
+        }
+    }
+}
+
+fn command(parts: &[&str]) -> RESP {
+    RESP::Array(
+        parts.iter().map(|part| RESP::BulkString(part.to_string())).collect()
+    )
+}
+
+fn command_with(name: &str, args: &[String]) -> RESP {
+    RESP::Array(
+        std::iter::once(name)
+            .chain(args.iter().map(String::as_str))
+            .map(|part| RESP::BulkString(part.to_string()))
+            .collect()
+    )
+}
+
+fn function_list(options: &[String]) -> Result<CommandRequest> {
+    let mut pattern = None;
+    let mut with_code = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "WITHCODE" => with_code = true,
+            "LIBRARYNAME" => {
+                let name = options.next()
+                    .ok_or_else(|| anyhow!("library name argument was not given"))?;
+                pattern = Some(name.to_string());
+            },
+            x => return Err(anyhow!("Unknown argument {x}")),
+        }
+    }
+    Ok(CommandRequest::FUNCTION(FunctionCommand::List(pattern, with_code)))
+}
+
+fn cluster_command(sub: &str, args: &[String]) -> Result<ClusterCommand> {
+    let slots = |slots: &[String]| slots.iter().map(|slot| parse_slot(slot)).collect::<Result<Vec<u16>>>();
+    match (sub, args) {
+        ("INFO", []) => Ok(ClusterCommand::Info),
+        ("MYID", []) => Ok(ClusterCommand::MyId),
+        ("NODES", []) => Ok(ClusterCommand::Nodes),
+        ("SLOTS", []) => Ok(ClusterCommand::Slots),
+        ("SHARDS", []) => Ok(ClusterCommand::Shards),
+        ("KEYSLOT", [key]) => Ok(ClusterCommand::KeySlot(key.to_string())),
+        ("COUNTKEYSINSLOT", [slot]) => Ok(ClusterCommand::CountKeysInSlot(parse_slot(slot)?)),
+        ("GETKEYSINSLOT", [slot, count]) => {
+            let count = count.parse::<u64>().map_err(|_| anyhow!("Invalid number of keys"))?;
+            Ok(ClusterCommand::GetKeysInSlot(parse_slot(slot)?, count))
+        },
+        ("ADDSLOTS", slots_args @ [_, ..]) => Ok(ClusterCommand::AddSlots(slots(slots_args)?)),
+        ("ADDSLOTSRANGE", ranges @ [_, _, ..]) if ranges.len() % 2 == 0 => {
+            let ranges = slots(ranges)?.chunks(2)
+                .map(|range| match range {
+                    [start, end] if start <= end => Ok((*start, *end)),
+                    _ => Err(anyhow!("start slot number {} is greater than end slot number {}", range[0], range[1])),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(ClusterCommand::AddSlotsRange(ranges))
+        },
+        ("DELSLOTS", slots_args @ [_, ..]) => Ok(ClusterCommand::DelSlots(slots(slots_args)?)),
+        ("MEET", [host, port, cport @ ..]) if cport.len() <= 1 => {
+            let invalid = || anyhow!("Invalid base port specified: {port}");
+            let port = port.parse::<u16>().map_err(|_| invalid())?;
+            let cport = cport.first()
+                .map(|cport| cport.parse::<u16>().map_err(|_| anyhow!("Invalid bus port specified: {cport}")))
+                .transpose()?;
+            Ok(ClusterCommand::Meet(host.to_string(), port, cport))
+        },
+        ("SETSLOT", [slot, state, node @ ..]) => {
+            let slot = parse_slot(slot)?;
+            let state = match (state.as_str(), node) {
+                ("MIGRATING", [node]) => SlotState::Migrating(node.to_string()),
+                ("IMPORTING", [node]) => SlotState::Importing(node.to_string()),
+                ("STABLE", []) => SlotState::Stable,
+                ("NODE", [node]) => SlotState::Node(node.to_string()),
+                _ => return Err(anyhow!("Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP")),
+            };
+            Ok(ClusterCommand::SetSlot(slot, state))
+        },
+        (sub, _) => Err(anyhow!("unknown subcommand or wrong number of arguments for 'CLUSTER {sub}'")),
+    }
+}
+
+/// Parses the options following the payload of RESTORE.
+fn restore_options(ttl: u64, asking: bool, options: &[String]) -> Result<RestoreOptions> {
+    let mut restore = RestoreOptions { ttl, replace: false, abs_ttl: false, idle_time: None, freq: None, asking };
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "REPLACE" => restore.replace = true,
+            "ABSTTL" => restore.abs_ttl = true,
+            "IDLETIME" if restore.freq.is_none() => {
+                let idle_time = options.next().ok_or_else(|| anyhow!("syntax error"))?;
+                let idle_time = idle_time.parse::<i64>().map_err(|_| anyhow!("value is not an integer or out of range"))?;
+                restore.idle_time = Some(u64::try_from(idle_time).map_err(|_| anyhow!("Invalid IDLETIME value, must be >= 0"))?);
+            },
+            "FREQ" if restore.idle_time.is_none() => {
+                let freq = options.next().ok_or_else(|| anyhow!("syntax error"))?;
+                let freq = freq.parse::<i64>().map_err(|_| anyhow!("value is not an integer or out of range"))?;
+                restore.freq = Some(u8::try_from(freq).map_err(|_| anyhow!("Invalid FREQ value, must be >= 0 and <= 255"))?);
+            },
+            _ => return Err(anyhow!("syntax error")),
         }
     }
+    Ok(restore)
+}
+
+/// Parses `host port key|"" db timeout [COPY] [REPLACE] [KEYS key ...]`.
+fn migrate_command(args: &[String]) -> Result<Migrate> {
+    let [host, port, key, db, timeout, options @ ..] = args else {
+        return Err(anyhow!("wrong number of arguments for 'migrate' command"));
+    };
+    let integer = |value: &str| value.parse::<i64>().map_err(|_| anyhow!("value is not an integer or out of range"));
+    let port = port.parse::<u16>().map_err(|_| anyhow!("value is not an integer or out of range"))?;
+    let db = db_index(db)?;
+    let timeout = match integer(timeout)? {
+        timeout if timeout <= 0 => 1000,
+        timeout => timeout as u64,
+    };
+    let mut migrate = Migrate { host: host.to_string(), port, keys: vec![key.to_string()], db, timeout, copy: false, replace: false };
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "COPY" => migrate.copy = true,
+            "REPLACE" => migrate.replace = true,
+            "KEYS" => {
+                if !key.is_empty() {
+                    return Err(anyhow!("When using MIGRATE KEYS option, the key argument must be set to the empty string"));
+                }
+                migrate.keys = options.by_ref().cloned().collect();
+            },
+            _ => return Err(anyhow!("syntax error")),
+        }
+    }
+    Ok(migrate)
+}
+
+/// Parses the number of a database, which can't be negative.
+fn db_index(index: &str) -> Result<usize> {
+    let index = index.parse::<i64>().map_err(|_| anyhow!("value is not an integer or out of range"))?;
+    usize::try_from(index).map_err(|_| anyhow!("DB index is out of range"))
+}
+
+fn script_args(script: &str, keys: &[String], args: &[String]) -> Vec<String> {
+    [&[script.to_string(), keys.len().to_string()], keys, args].concat()
+}
+
+/// Splits the arguments of EVAL-like commands into keys and the rest, with
+/// `numkeys` telling how many keys come first.
+fn keys_and_args(numkeys: &str, rest: &[RESP]) -> Result<(Vec<String>, Vec<String>)> {
+    let numkeys = numkeys.parse::<i64>()
+        .map_err(|_| anyhow!("value is not an integer or out of range"))?;
+    let numkeys = usize::try_from(numkeys)
+        .map_err(|_| anyhow!("Number of keys can't be negative"))?;
+    if numkeys > rest.len() {
+        return Err(anyhow!("Number of keys can't be greater than number of args"));
+    }
+    let (keys, args) = rest.split_at(numkeys);
+    Ok((bulk_strings(keys)?, bulk_strings(args)?))
+}
+
+/// Splits `rest` into pairs, as taken by commands like HSET.
+fn pairs(rest: &[RESP], name: &str) -> Result<Vec<(String, String)>> {
+    if !rest.len().is_multiple_of(2) {
+        return Err(anyhow!("wrong number of arguments for '{name}' command"));
+    }
+    let strings = bulk_strings(rest)?;
+    Ok(strings.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect())
+}
+
+fn bulk_strings(resps: &[RESP]) -> Result<Vec<String>> {
+    resps.iter()
+        .map(|resp| match resp {
+            RESP::BulkString(s) => Ok(s.to_string()),
+            x => Err(anyhow!("expected a bulk string, got: {:?}", x)),
+        })
+        .collect()
 }
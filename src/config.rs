@@ -0,0 +1,704 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::aof::AppendFsync;
+use crate::encoding::EncodingConfig;
+use crate::eviction::{parse_memory, EvictionPolicy, MemoryConfig};
+use crate::glob::glob_match;
+use crate::saver::{parse_save_rules, DEFAULT_SAVE_RULES};
+use crate::sentinel::SentinelConfig;
+
+/// Marks where CONFIG REWRITE appends parameters that weren't in the file.
+const REWRITE_SIGNATURE: &str = "# Generated by CONFIG REWRITE";
+
+/// How deep config files can include one another.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// How values of a parameter are checked, and written back by CONFIG GET.
+#[derive(Clone, Copy)]
+enum Kind {
+    /// `yes` or `no`
+    Bool,
+    /// An integer within bounds, inclusive
+    Int(i64, i64),
+    /// A number of bytes, with units such as 100mb, shown in bytes
+    Memory,
+    Enum(&'static [&'static str]),
+    String,
+    /// Several words, checked and normalized by a parser
+    Words(fn(&str) -> Result<String>),
+}
+
+impl Kind {
+    /// Checks `value`, returning it the way CONFIG GET shows it.
+    fn normalize(self, value: &str) -> Result<String> {
+        match self {
+            Kind::Bool => match value.to_ascii_lowercase().as_str() {
+                "yes" => Ok("yes".to_string()),
+                "no" => Ok("no".to_string()),
+                _ => Err(anyhow!("argument must be 'yes' or 'no'")),
+            },
+            Kind::Int(min, max) => match value.parse::<i64>() {
+                Ok(n) if (min..=max).contains(&n) => Ok(n.to_string()),
+                Ok(_) => Err(anyhow!("argument must be between {min} and {max} inclusive")),
+                Err(_) => Err(anyhow!("argument couldn't be parsed into an integer")),
+            },
+            Kind::Memory => match parse_memory(value) {
+                Ok(bytes) => Ok(bytes.to_string()),
+                Err(_) => Err(anyhow!("argument must be a memory value")),
+            },
+            Kind::Enum(names) => match names.iter().find(|name| name.eq_ignore_ascii_case(value)) {
+                Some(name) => Ok(name.to_string()),
+                None => Err(anyhow!("argument(s) must be one of the following: {}", names.join(", "))),
+            },
+            Kind::String => Ok(value.to_string()),
+            Kind::Words(parse) => parse(value),
+        }
+    }
+}
+
+struct Param {
+    name: &'static str,
+    /// Older names, mostly from before Redis renamed slaves and ziplists.
+    aliases: &'static [&'static str],
+    kind: Kind,
+    default: &'static str,
+    /// Whether CONFIG SET can change it while running.
+    mutable: bool,
+    /// Whether it can be given several times, each adding to the value,
+    /// like `save` rules.
+    repeatable: bool,
+}
+
+const fn param(name: &'static str, kind: Kind, default: &'static str) -> Param {
+    Param { name, aliases: &[], kind, default, mutable: false, repeatable: false }
+}
+
+impl Param {
+    const fn alias(self, aliases: &'static [&'static str]) -> Param {
+        Param { aliases, ..self }
+    }
+
+    const fn mutable(self) -> Param {
+        Param { mutable: true, ..self }
+    }
+
+    const fn repeatable(self) -> Param {
+        Param { repeatable: true, ..self }
+    }
+}
+
+const MAX_INT: i64 = i32::MAX as i64;
+
+const LOG_LEVELS: &[&str] = &["debug", "verbose", "notice", "warning", "nothing"];
+
+/// Every parameter the server knows, named and bounded as in Redis.
+const PARAMS: &[Param] = &[
+    param("port", Kind::Int(0, 65535), "6379"),
+    param("bind", Kind::Words(bind_addresses), "127.0.0.1 -::1"),
+    param("loglevel", Kind::Enum(LOG_LEVELS), "notice"),
+    param("daemonize", Kind::Bool, "no"),
+    param("pidfile", Kind::String, ""),
+    param("replicaof", Kind::Words(host_and_port), "").alias(&["slaveof"]),
+    param("replica-read-only", Kind::Bool, "yes").alias(&["slave-read-only"]).mutable(),
+    param("replica-serve-stale-data", Kind::Bool, "yes").alias(&["slave-serve-stale-data"]).mutable(),
+    param("replica-priority", Kind::Int(0, MAX_INT), "100").alias(&["slave-priority"]).mutable(),
+    param("dir", Kind::String, "."),
+    param("dbfilename", Kind::String, "dump.rdb"),
+    param("save", Kind::Words(save_rules), DEFAULT_SAVE_RULES).mutable().repeatable(),
+    param("appendonly", Kind::Bool, "no"),
+    param("appendfilename", Kind::String, "appendonly.aof"),
+    param("appenddirname", Kind::String, "appendonlydir"),
+    param("aof-use-rdb-preamble", Kind::Bool, "yes"),
+    param("appendfsync", Kind::Words(append_fsync), "everysec"),
+    param("repl-backlog-size", Kind::Memory, "1048576"),
+    param("repl-diskless-sync", Kind::Bool, "no"),
+    param("repl-diskless-sync-delay", Kind::Int(0, MAX_INT), "5"),
+    param("repl-diskless-load", Kind::Enum(&["disabled", "on-empty-db", "swapdb"]), "disabled"),
+    param("cluster-enabled", Kind::Bool, "no"),
+    param("cluster-config-file", Kind::String, "nodes.conf"),
+    param("cluster-node-timeout", Kind::Int(1, i64::MAX), "15000"),
+    param("databases", Kind::Int(1, MAX_INT), "16"),
+    param("maxmemory", Kind::Memory, "0").mutable(),
+    param("maxmemory-policy", Kind::Words(eviction_policy), "noeviction").mutable(),
+    param("maxmemory-samples", Kind::Int(1, 64), "5").mutable(),
+    param("lfu-log-factor", Kind::Int(0, MAX_INT), "10").mutable(),
+    param("lfu-decay-time", Kind::Int(0, MAX_INT), "1").mutable(),
+    param("hash-max-listpack-entries", Kind::Int(0, i64::MAX), "128").alias(&["hash-max-ziplist-entries"]).mutable(),
+    param("hash-max-listpack-value", Kind::Int(0, i64::MAX), "64").alias(&["hash-max-ziplist-value"]).mutable(),
+    param("list-max-listpack-size", Kind::Int(i32::MIN as i64, MAX_INT), "-2").alias(&["list-max-ziplist-size"]).mutable(),
+    param("set-max-intset-entries", Kind::Int(0, i64::MAX), "512").mutable(),
+    param("set-max-listpack-entries", Kind::Int(0, i64::MAX), "128").mutable(),
+    param("set-max-listpack-value", Kind::Int(0, i64::MAX), "64").mutable(),
+    param("zset-max-listpack-entries", Kind::Int(0, i64::MAX), "128").alias(&["zset-max-ziplist-entries"]).mutable(),
+    param("zset-max-listpack-value", Kind::Int(0, i64::MAX), "64").alias(&["zset-max-ziplist-value"]).mutable(),
+    param("sentinel", Kind::Bool, "no"),
+    param("monitor", Kind::Words(monitor), ""),
+    param("down-after-milliseconds", Kind::Int(1, i64::MAX), "30000"),
+    param("failover-timeout", Kind::Int(1, i64::MAX), "180000"),
+    param("known-sentinel", Kind::Words(host_and_port_pairs), "").repeatable(),
+];
+
+fn find_param(name: &str) -> Option<&'static Param> {
+    PARAMS.iter().find(|param| param.name.eq_ignore_ascii_case(name) || param.aliases.iter().any(|alias| alias.eq_ignore_ascii_case(name)))
+}
+
+/// Checks words are `<host> <port>` pairs, putting them back together with
+/// single spaces.
+fn host_and_port_pairs(value: &str) -> Result<String> {
+    let words: Vec<&str> = value.split_whitespace().collect();
+    if !words.len().is_multiple_of(2) || words.chunks(2).any(|pair| pair[1].parse::<u16>().is_err()) {
+        return Err(anyhow!("argument must be '<host> <port>'"));
+    }
+    Ok(words.join(" "))
+}
+
+/// A single `<host> <port>`, or nothing.
+fn host_and_port(value: &str) -> Result<String> {
+    match host_and_port_pairs(value)? {
+        pair if pair.split(' ').count() > 2 => Err(anyhow!("argument must be '<host> <port>'")),
+        pair => Ok(pair),
+    }
+}
+
+fn bind_addresses(value: &str) -> Result<String> {
+    let addresses: Vec<&str> = value.split_whitespace().collect();
+    if addresses.is_empty() {
+        return Err(anyhow!("argument must be one or more addresses"));
+    }
+    for address in &addresses {
+        address.parse::<BindAddress>()?;
+    }
+    Ok(addresses.join(" "))
+}
+
+fn save_rules(value: &str) -> Result<String> {
+    let rules = parse_save_rules(value)?;
+    Ok(rules.iter().map(|rule| format!("{} {}", rule.seconds, rule.changes)).collect::<Vec<_>>().join(" "))
+}
+
+fn append_fsync(value: &str) -> Result<String> {
+    Ok(value.to_ascii_lowercase().parse::<AppendFsync>()?.to_string())
+}
+
+fn eviction_policy(value: &str) -> Result<String> {
+    Ok(value.to_ascii_lowercase().parse::<EvictionPolicy>()?.to_string())
+}
+
+fn monitor(value: &str) -> Result<String> {
+    match value.trim() {
+        "" => Ok(String::new()),
+        monitor => SentinelConfig::monitor(monitor).map(|_| monitor.split_whitespace().collect::<Vec<_>>().join(" ")),
+    }
+}
+
+/// An address to listen on, where `*` and `::*` stand for every IPv4 and
+/// IPv6 interface. Addresses prefixed with `-` are optional: failing to
+/// listen on them isn't an error, as when IPv6 is disabled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BindAddress {
+    pub ip: IpAddr,
+    pub optional: bool,
+}
+
+impl FromStr for BindAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<BindAddress> {
+        let (address, optional) = match s.strip_prefix('-') {
+            Some(address) => (address, true),
+            None => (s, false),
+        };
+        let ip = match address {
+            "*" => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            "::*" => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            address => address.parse().map_err(|_| anyhow!("invalid bind address '{s}'"))?,
+        };
+        Ok(BindAddress { ip, optional })
+    }
+}
+
+/// Splits a config line into words the way Redis does: words are separated
+/// by spaces and can be quoted, with escapes such as `\n` or `\x41` between
+/// double quotes and only `\'` between single ones.
+pub fn split_args(line: &str) -> Result<Vec<String>> {
+    let mut args = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else {
+            return Ok(args);
+        };
+        let mut arg = String::new();
+        match first {
+            '"' => loop {
+                match chars.next().ok_or_else(|| anyhow!("unbalanced quotes"))? {
+                    '"' => break,
+                    '\\' => match chars.next().ok_or_else(|| anyhow!("unbalanced quotes"))? {
+                        'n' => arg.push('\n'),
+                        'r' => arg.push('\r'),
+                        't' => arg.push('\t'),
+                        'b' => arg.push('\u{8}'),
+                        'a' => arg.push('\u{7}'),
+                        'x' => {
+                            let hex: String = [chars.next(), chars.next()].into_iter().flatten().collect();
+                            match u8::from_str_radix(&hex, 16) {
+                                Ok(byte) if hex.len() == 2 => arg.push(byte as char),
+                                _ => arg.extend(['x'].into_iter().chain(hex.chars())),
+                            }
+                        },
+                        c => arg.push(c),
+                    },
+                    c => arg.push(c),
+                }
+            },
+            '\'' => loop {
+                match chars.next().ok_or_else(|| anyhow!("unbalanced quotes"))? {
+                    '\'' => break,
+                    '\\' if chars.peek() == Some(&'\'') => arg.push(chars.next().unwrap()),
+                    c => arg.push(c),
+                }
+            },
+            c => {
+                arg.push(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    arg.push(c);
+                }
+            },
+        }
+        // a closing quote has to end the word
+        if matches!(first, '"' | '\'') && chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            return Err(anyhow!("unbalanced quotes"));
+        }
+        args.push(arg);
+    }
+}
+
+/// Quotes `value` if it wouldn't be read back as a single word otherwise.
+fn quote(value: &str) -> String {
+    let plain = !value.is_empty() && value.chars().all(|c| c.is_ascii_graphic() && !matches!(c, '"' | '\'' | '\\'));
+    if plain {
+        return value.to_string();
+    }
+    let mut quoted = "\"".to_string();
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\u{7}' => quoted.push_str("\\a"),
+            '\u{8}' => quoted.push_str("\\b"),
+            c if c.is_control() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The line of a config file setting `param` to `value`.
+fn config_line(param: &Param, value: &str) -> String {
+    match param.kind {
+        Kind::Words(_) if !value.is_empty() => format!("{} {value}", param.name),
+        _ => format!("{} {}", param.name, quote(value)),
+    }
+}
+
+/// The server configuration: every parameter along with its current value,
+/// which is checked against the kind of the parameter whenever it's set.
+///
+/// Values come from a file in the `redis.conf` syntax, then the command line
+/// where each `--name value` is read as a `name value` line, and may later
+/// be changed with CONFIG SET.
+pub struct Config {
+    values: RwLock<BTreeMap<&'static str, String>>,
+    /// The file the configuration was loaded from, which CONFIG REWRITE
+    /// updates.
+    path: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            values: RwLock::new(PARAMS.iter().map(|param| (param.name, param.default.to_string())).collect()),
+            path: None,
+        }
+    }
+}
+
+/// Directives seen while loading a configuration, so that repeatable ones
+/// replace their default the first time and add to it afterwards.
+#[derive(Default)]
+struct Loading {
+    seen: HashSet<&'static str>,
+    depth: usize,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config::default()
+    }
+
+    /// Loads the configuration from the server arguments: an optional
+    /// config file path first, then `--name value...` options.
+    pub fn from_args(args: &[String]) -> Result<Config> {
+        let (path, options) = match args.first() {
+            Some(path) if !path.starts_with("--") => (Some(PathBuf::from(path)), &args[1..]),
+            _ => (None, args),
+        };
+        let mut config = Config::new();
+        let mut loading = Loading::default();
+        if let Some(path) = &path {
+            config.load_file(path, &mut loading)?;
+        }
+        config.path = path;
+
+        let mut directives: Vec<Vec<String>> = vec![];
+        for arg in options {
+            match (arg.strip_prefix("--"), directives.last_mut()) {
+                (Some(name), _) => directives.push(vec![name.to_string()]),
+                (None, Some(directive)) => directive.push(arg.to_string()),
+                (None, None) => return Err(anyhow!("unexpected argument '{arg}', options start with --")),
+            }
+        }
+        for directive in directives {
+            if find_param(&directive[0]).is_none() {
+                return Err(anyhow!("unknown option '--{}'", directive[0]));
+            }
+            config.apply(&directive, &mut loading)
+                .with_context(|| format!("invalid option '--{}'", directive.join(" ")))?;
+        }
+        Ok(config)
+    }
+
+    fn load_file(&mut self, path: &Path, loading: &mut Loading) -> Result<()> {
+        if loading.depth >= MAX_INCLUDE_DEPTH {
+            return Err(anyhow!("too many nested includes in {path:?}"));
+        }
+        let text = fs::read_to_string(path).with_context(|| format!("failed reading config file {path:?}"))?;
+        loading.depth += 1;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            split_args(line)
+                .and_then(|args| self.apply(&args, loading))
+                .with_context(|| format!("invalid config at line {} of {path:?}: '{line}'", number + 1))?;
+        }
+        loading.depth -= 1;
+        Ok(())
+    }
+
+    /// Applies a `name arg...` directive while loading.
+    fn apply(&mut self, directive: &[String], loading: &mut Loading) -> Result<()> {
+        let [name, args @ ..] = directive else {
+            return Ok(());
+        };
+        if name.eq_ignore_ascii_case("include") {
+            return match args {
+                [path] => self.load_file(Path::new(path), loading),
+                _ => Err(anyhow!("include takes a single file")),
+            };
+        }
+        let param = find_param(name).ok_or_else(|| anyhow!("bad directive '{name}'"))?;
+        let value = match (param.kind, args) {
+            (Kind::Words(_), args) => args.join(" "),
+            // a flag on its own turns it on, like --sentinel
+            (Kind::Bool, []) => "yes".to_string(),
+            (_, [value]) => value.to_string(),
+            _ => return Err(anyhow!("wrong number of arguments for '{}'", param.name)),
+        };
+        let values = self.values.get_mut().unwrap();
+        let value = match param.repeatable && !loading.seen.insert(param.name) {
+            true => format!("{} {value}", values[param.name]),
+            false => value,
+        };
+        values.insert(param.name, param.kind.normalize(&value)?);
+        Ok(())
+    }
+
+    /// Current value of the parameter `name`, as CONFIG GET shows it.
+    pub fn get(&self, name: &str) -> String {
+        let param = find_param(name).unwrap_or_else(|| panic!("no such config parameter {name}"));
+        self.values.read().unwrap()[param.name].clone()
+    }
+
+    /// The value of a `yes` or `no` parameter.
+    pub fn flag(&self, name: &str) -> bool {
+        self.get(name) == "yes"
+    }
+
+    /// The value of a numeric parameter, whose bounds have to fit `T`.
+    pub fn number<T: FromStr>(&self, name: &str) -> T {
+        self.get(name).parse().unwrap_or_else(|_| panic!("config parameter {name} doesn't fit its type"))
+    }
+
+    pub fn is_default(&self, name: &str) -> bool {
+        find_param(name).is_some_and(|param| self.get(name) == param.kind.normalize(param.default).unwrap_or_default())
+    }
+
+    /// Parameters matching any of the glob `patterns` and their values.
+    /// Old names only show up when they match but the current one doesn't.
+    pub fn matching(&self, patterns: &[String]) -> Vec<(String, String)> {
+        let matches = |name: &str| patterns.iter().any(|pattern| glob_match(&pattern.to_ascii_lowercase(), name));
+        let values = self.values.read().unwrap();
+        let mut matching = vec![];
+        for param in PARAMS {
+            let names = match matches(param.name) {
+                true => vec![param.name],
+                false => param.aliases.iter().copied().filter(|alias| matches(alias)).collect(),
+            };
+            matching.extend(names.into_iter().map(|name| (name.to_string(), values[param.name].clone())));
+        }
+        matching.sort();
+        matching
+    }
+
+    /// Changes parameters at runtime, either all of them or none if any
+    /// can't be set, with the errors of CONFIG SET.
+    pub fn set(&self, changes: &[(String, String)]) -> Result<()> {
+        let mut normalized: Vec<(&'static str, String)> = vec![];
+        for (name, value) in changes {
+            let param = find_param(name)
+                .ok_or_else(|| anyhow!("Unknown option or number of arguments for CONFIG SET - '{name}'"))?;
+            let failed = |reason: String| anyhow!("CONFIG SET failed (possibly related to argument '{name}') - {reason}");
+            if !param.mutable {
+                return Err(failed("can't set immutable config".to_string()));
+            }
+            if normalized.iter().any(|(set, _)| *set == param.name) {
+                return Err(failed("duplicate parameter".to_string()));
+            }
+            normalized.push((param.name, param.kind.normalize(value).map_err(|err| failed(err.to_string()))?));
+        }
+        self.values.write().unwrap().extend(normalized);
+        Ok(())
+    }
+
+    /// Sets a parameter that changes by other means than CONFIG SET, such
+    /// as `replicaof` with the REPLICAOF command.
+    pub fn update(&self, name: &'static str, value: String) {
+        self.values.write().unwrap().insert(name, value);
+    }
+
+    /// Writes the current configuration back to the file it was loaded
+    /// from. Lines of parameters are updated in place, keeping comments and
+    /// everything else as they were, and parameters that aren't in the file
+    /// but differ from their default are appended.
+    pub fn rewrite(&self) -> Result<()> {
+        let path = self.path.as_ref().ok_or_else(|| anyhow!("The server is running without a config file"))?;
+        let original = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let values = self.values.read().unwrap();
+        let mut written = HashSet::new();
+        let mut lines = vec![];
+        for line in original.lines() {
+            let param = split_args(line.trim()).ok()
+                .filter(|_| !line.trim_start().starts_with('#'))
+                .and_then(|args| find_param(args.first()?));
+            match param {
+                // the first line of a parameter holds the whole value
+                Some(param) if written.insert(param.name) => lines.push(config_line(param, &values[param.name])),
+                Some(_) => (),
+                None => lines.push(line.to_string()),
+            }
+        }
+        let missing: Vec<String> = PARAMS.iter()
+            .filter(|param| !written.contains(param.name) && !self.is_default(param.name))
+            .map(|param| config_line(param, &values[param.name]))
+            .collect();
+        if !missing.is_empty() && !lines.iter().any(|line| line.trim() == REWRITE_SIGNATURE) {
+            lines.push(REWRITE_SIGNATURE.to_string());
+        }
+        lines.extend(missing);
+
+        let temp_path = path.with_extension("rewrite.tmp");
+        fs::write(&temp_path, lines.join("\n") + "\n")?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    pub fn memory_config(&self) -> MemoryConfig {
+        MemoryConfig {
+            maxmemory: self.number("maxmemory"),
+            policy: self.get("maxmemory-policy").parse().expect("maxmemory-policy was checked"),
+            samples: self.number("maxmemory-samples"),
+            lfu_log_factor: self.number("lfu-log-factor"),
+            lfu_decay_time: self.number("lfu-decay-time"),
+        }
+    }
+
+    pub fn bind_addresses(&self) -> Vec<BindAddress> {
+        self.get("bind").split(' ').map(|address| address.parse().expect("bind addresses were checked")).collect()
+    }
+
+    pub fn encoding_config(&self) -> EncodingConfig {
+        EncodingConfig {
+            hash_max_listpack_entries: self.number("hash-max-listpack-entries"),
+            hash_max_listpack_value: self.number("hash-max-listpack-value"),
+            list_max_listpack_size: self.number("list-max-listpack-size"),
+            set_max_intset_entries: self.number("set-max-intset-entries"),
+            set_max_listpack_entries: self.number("set-max-listpack-entries"),
+            set_max_listpack_value: self.number("set-max-listpack-value"),
+            zset_max_listpack_entries: self.number("zset-max-listpack-entries"),
+            zset_max_listpack_value: self.number("zset-max-listpack-value"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("config-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(args(&["save", "900", "1"]), split_args("  save 900   1 ").unwrap());
+        assert_eq!(args(&["dir", "a b", "it's", "A\n"]), split_args(r#"dir "a b" 'it\'s' "\x41\n""#).unwrap());
+        assert_eq!(args(&["dir", ""]), split_args(r#"dir """#).unwrap());
+        assert!(split_args(r#"dir "a b"#).is_err());
+        assert!(split_args(r#"dir "a"b"#).is_err());
+    }
+
+    #[test]
+    fn test_load_file_and_args() {
+        let dir = temp_dir("load");
+        fs::write(dir.join("extra.conf"), "maxmemory 1mb\nsave 60 5\n").unwrap();
+        let main = format!(
+            "# main config\nport 7000\nslave-read-only no\nsave 900 1\ninclude {}\nhash-max-ziplist-entries 10\n",
+            dir.join("extra.conf").display(),
+        );
+        fs::write(dir.join("redis.conf"), main).unwrap();
+
+        let path = dir.join("redis.conf").display().to_string();
+        let config = Config::from_args(&args(&[&path, "--port", "7001", "--replicaof", "localhost", "6379"])).unwrap();
+        assert_eq!(7001, config.number::<u16>("port"));
+        assert!(!config.flag("replica-read-only"));
+        assert_eq!("900 1 60 5", config.get("save"));
+        assert_eq!(1024 * 1024, config.memory_config().maxmemory);
+        assert_eq!(10, config.encoding_config().hash_max_listpack_entries);
+        assert_eq!("localhost 6379", config.get("replicaof"));
+        assert!(config.is_default("dir"));
+        assert!(!config.is_default("port"));
+
+        let config = Config::from_args(&args(&["--sentinel", "--monitor", "mymaster", "127.0.0.1", "6379", "2"])).unwrap();
+        assert!(config.flag("sentinel"));
+        assert_eq!("mymaster 127.0.0.1 6379 2", config.get("monitor"));
+
+        let config = Config::from_args(&args(&["--bind", "10.0.0.1", "-::1", "--replicaof", " localhost   6380 ", "--daemonize"])).unwrap();
+        assert_eq!(
+            vec![
+                BindAddress { ip: "10.0.0.1".parse().unwrap(), optional: false },
+                BindAddress { ip: "::1".parse().unwrap(), optional: true },
+            ],
+            config.bind_addresses(),
+        );
+        assert_eq!("localhost 6380", config.get("replicaof"));
+        assert!(config.flag("daemonize"));
+
+        assert_eq!(
+            BindAddress { ip: IpAddr::V6(Ipv6Addr::UNSPECIFIED), optional: true },
+            "-::*".parse::<BindAddress>().unwrap(),
+        );
+        assert_eq!(IpAddr::V4(Ipv4Addr::UNSPECIFIED), "*".parse::<BindAddress>().unwrap().ip);
+        assert!(Config::from_args(&args(&["--bind", "localhost"])).is_err());
+        assert!(Config::from_args(&args(&["--loglevel", "loud"])).is_err());
+        assert!(Config::from_args(&args(&["--port", "70000"])).is_err());
+        let err = Config::from_args(&args(&["--port", "7000", "--no-such-option", "1"])).map(|_| ()).unwrap_err();
+        assert_eq!("unknown option '--no-such-option'", err.to_string());
+        assert!(Config::from_args(&args(&["--port", "1", "2"])).is_err());
+        fs::write(dir.join("bad.conf"), "port 1\nbogus yes\n").unwrap();
+        let err = Config::from_args(&args(&[&dir.join("bad.conf").display().to_string()])).map(|_| ()).unwrap_err();
+        assert!(format!("{err:#}").contains("line 2"));
+    }
+
+    #[test]
+    fn test_get_and_set() {
+        let config = Config::new();
+        assert_eq!(
+            pairs(&[("maxmemory", "0"), ("maxmemory-policy", "noeviction"), ("maxmemory-samples", "5")]),
+            config.matching(&args(&["maxmemory*"])),
+        );
+        assert_eq!(pairs(&[("slave-priority", "100")]), config.matching(&args(&["slave-pri*"])));
+        assert_eq!(pairs(&[("port", "6379")]), config.matching(&args(&["PORT", "nothing"])));
+
+        config.set(&pairs(&[("maxmemory", "2kb"), ("maxmemory-policy", "ALLKEYS-LRU")])).unwrap();
+        assert_eq!("2048", config.get("maxmemory"));
+        assert_eq!("allkeys-lru", config.get("maxmemory-policy"));
+
+        // nothing is changed when any parameter fails
+        let err = config.set(&pairs(&[("maxmemory", "1"), ("maxmemory", "2")])).unwrap_err();
+        assert!(err.to_string().ends_with("duplicate parameter"));
+        let err = config.set(&pairs(&[("maxmemory", "1"), ("port", "7000")])).unwrap_err();
+        assert_eq!("CONFIG SET failed (possibly related to argument 'port') - can't set immutable config", err.to_string());
+        let err = config.set(&pairs(&[("maxmemory", "1"), ("maxmemory-samples", "0")])).unwrap_err();
+        assert!(err.to_string().contains("argument must be between 1 and 64"));
+        assert!(config.set(&pairs(&[("nope", "1")])).is_err());
+        assert!(config.set(&pairs(&[("save", "60")])).is_err());
+        assert_eq!("2048", config.get("maxmemory"));
+
+        config.set(&pairs(&[("save", ""), ("slave-read-only", "NO")])).unwrap();
+        assert_eq!("", config.get("save"));
+        assert!(!config.flag("replica-read-only"));
+    }
+
+    #[test]
+    fn test_rewrite_keeps_comments() {
+        let dir = temp_dir("rewrite");
+        let path = dir.join("redis.conf");
+        fs::write(&path, "# memory\nmaxmemory 100\n\n# snapshots\nsave 900 1\nsave 60 5\ndir \"my dir\"\n").unwrap();
+        let config = Config::from_args(&args(&[&path.display().to_string(), "--port", "7000"])).unwrap();
+        config.set(&pairs(&[("maxmemory", "200"), ("save", "10 1"), ("maxmemory-policy", "allkeys-lfu")])).unwrap();
+        config.rewrite().unwrap();
+        assert_eq!(
+            "# memory\nmaxmemory 200\n\n# snapshots\nsave 10 1\ndir \"my dir\"\n\
+                # Generated by CONFIG REWRITE\nport 7000\nmaxmemory-policy allkeys-lfu\n",
+            fs::read_to_string(&path).unwrap(),
+        );
+
+        // rewriting again changes nothing and reads back the same values
+        config.rewrite().unwrap();
+        let reloaded = Config::from_args(&args(&[&path.display().to_string()])).unwrap();
+        assert_eq!(config.matching(&args(&["*"])), reloaded.matching(&args(&["*"])));
+        assert_eq!(1, fs::read_to_string(&path).unwrap().matches(REWRITE_SIGNATURE).count());
+
+        // parameters set after the first rewrite are appended below it too
+        config.set(&pairs(&[("maxmemory-samples", "10")])).unwrap();
+        config.rewrite().unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.ends_with("# Generated by CONFIG REWRITE\nport 7000\nmaxmemory-policy allkeys-lfu\nmaxmemory-samples 10\n"));
+        assert_eq!(1, text.matches(REWRITE_SIGNATURE).count());
+        let reloaded = Config::from_args(&args(&[&path.display().to_string()])).unwrap();
+        assert_eq!("10", reloaded.get("maxmemory-samples"));
+        assert_eq!("allkeys-lfu", reloaded.get("maxmemory-policy"));
+
+        let err = Config::new().rewrite().unwrap_err();
+        assert_eq!("The server is running without a config file", err.to_string());
+    }
+}
@@ -0,0 +1,38 @@
+/// CRC-16/XMODEM as used by Redis Cluster to map keys to slots: polynomial
+/// 0x1021, no reflection, no initial value and no final xor.
+const POLY: u16 = 0x1021;
+
+const TABLE: [u16; 256] = build_table();
+
+const fn build_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ POLY } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, byte| {
+        TABLE[((crc >> 8) as u8 ^ *byte) as usize] ^ (crc << 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_check_value() {
+        assert_eq!(0x31c3, crc16(b"123456789"));
+    }
+}
@@ -0,0 +1,44 @@
+/// CRC-64/Jones as used by Redis for RDB files and DUMP payloads: reflected,
+/// with polynomial 0xad93d23594c935a9, no initial value and no final xor.
+const POLY_REFLECTED: u64 = 0x95ac9329ac4bc9b5;
+
+const TABLE: [u64; 256] = build_table();
+
+const fn build_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLY_REFLECTED } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continues the checksum `crc` over `data`, starting from 0.
+pub fn crc64(crc: u64, data: &[u8]) -> u64 {
+    data.iter().fold(crc, |crc, byte| {
+        TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_check_value() {
+        assert_eq!(0xe9c6d914c4b8d9ca, crc64(0, b"123456789"));
+    }
+
+    #[test]
+    fn test_incremental() {
+        assert_eq!(crc64(0, b"123456789"), crc64(crc64(0, b"1234"), b"56789"));
+    }
+}
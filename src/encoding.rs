@@ -0,0 +1,1068 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::mem::size_of;
+
+use anyhow::{anyhow, Result};
+
+/// Longest string Redis stores along with its object header, which is what
+/// the embstr encoding stands for.
+const EMBSTR_MAX_LEN: usize = 44;
+
+/// Total bytes and number of entries, in front of every listpack.
+const LISTPACK_HEADER_SIZE: usize = 6;
+
+const LISTPACK_EOF: u8 = 0xff;
+
+/// Integer width and number of entries, in front of every intset.
+const INTSET_HEADER_SIZE: usize = 8;
+
+/// Parses `s` if it's an integer written the way Redis writes them back,
+/// without a plus sign, leading zeros or spaces.
+pub fn canonical_int(s: &str) -> Option<i64> {
+    s.parse::<i64>().ok().filter(|n| n.to_string() == s)
+}
+
+/// Number of characters of `n` once written in decimal.
+fn digits(n: i64) -> usize {
+    n.unsigned_abs().checked_ilog10().map_or(1, |log| log as usize + 1) + (n < 0) as usize
+}
+
+/// Sums `size` over `items`, extrapolating from the first `samples` of them
+/// unless that's 0.
+fn estimate<I: ExactSizeIterator>(items: I, samples: usize, size: impl Fn(I::Item) -> usize) -> usize {
+    let len = items.len();
+    let samples = if samples == 0 { len } else { samples.min(len) };
+    if samples == 0 {
+        return 0;
+    }
+    items.take(samples).map(size).sum::<usize>() * len / samples
+}
+
+/// How small collections have to be to get a compact encoding, named after
+/// the Redis parameters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncodingConfig {
+    pub hash_max_listpack_entries: usize,
+    pub hash_max_listpack_value: usize,
+    /// Entries of a listpack list when positive, otherwise its size from -1
+    /// for 4kb up to -5 for 64kb.
+    pub list_max_listpack_size: i64,
+    pub set_max_intset_entries: usize,
+    pub set_max_listpack_entries: usize,
+    pub set_max_listpack_value: usize,
+    pub zset_max_listpack_entries: usize,
+    pub zset_max_listpack_value: usize,
+}
+
+impl Default for EncodingConfig {
+    fn default() -> EncodingConfig {
+        EncodingConfig {
+            hash_max_listpack_entries: 128,
+            hash_max_listpack_value: 64,
+            list_max_listpack_size: -2,
+            set_max_intset_entries: 512,
+            set_max_listpack_entries: 128,
+            set_max_listpack_value: 64,
+            zset_max_listpack_entries: 128,
+            zset_max_listpack_value: 64,
+        }
+    }
+}
+
+impl EncodingConfig {
+    fn list_fits(&self, listpack: &Listpack) -> bool {
+        match self.list_max_listpack_size {
+            size if size > 0 => listpack.len() <= size as usize,
+            size => listpack.size() <= 4096 << (size.unsigned_abs().clamp(1, 5) - 1),
+        }
+    }
+}
+
+/// A string value, kept as an integer when it's the canonical form of one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Str {
+    Int(i64),
+    Raw(String),
+}
+
+impl Str {
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Str::Int(_) => "int",
+            Str::Raw(s) if s.len() <= EMBSTR_MAX_LEN => "embstr",
+            Str::Raw(_) => "raw",
+        }
+    }
+
+    /// Bytes used on top of the value itself.
+    pub(crate) fn memory_usage(&self) -> usize {
+        match self {
+            Str::Int(_) => 0,
+            Str::Raw(s) => s.len(),
+        }
+    }
+}
+
+impl From<String> for Str {
+    fn from(s: String) -> Str {
+        match canonical_int(&s) {
+            Some(n) => Str::Int(n),
+            None => Str::Raw(s),
+        }
+    }
+}
+
+impl From<&str> for Str {
+    fn from(s: &str) -> Str {
+        match canonical_int(s) {
+            Some(n) => Str::Int(n),
+            None => Str::Raw(s.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Str {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Str::Int(n) => write!(f, "{n}"),
+            Str::Raw(s) => f.write_str(s),
+        }
+    }
+}
+
+/// An element of a listpack.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListpackEntry<'a> {
+    Int(i64),
+    Str(&'a [u8]),
+}
+
+impl<'a> ListpackEntry<'a> {
+    /// The entry as text, invalid UTF-8 being replaced.
+    pub fn to_cow(self) -> Cow<'a, str> {
+        match self {
+            ListpackEntry::Int(n) => Cow::Owned(n.to_string()),
+            ListpackEntry::Str(s) => String::from_utf8_lossy(s),
+        }
+    }
+
+    /// Length of the entry as text.
+    fn len(&self) -> usize {
+        match self {
+            ListpackEntry::Int(n) => digits(*n),
+            ListpackEntry::Str(s) => s.len(),
+        }
+    }
+
+    fn to_f64(self) -> f64 {
+        match self {
+            ListpackEntry::Int(n) => n as f64,
+            ListpackEntry::Str(s) => std::str::from_utf8(s).ok().and_then(|s| s.parse().ok()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Tells whether entries are equal to `element`, which is only parsed once.
+fn matching(element: &str) -> impl Fn(&ListpackEntry) -> bool + '_ {
+    let int = canonical_int(element);
+    move |entry| match entry {
+        ListpackEntry::Int(n) => int == Some(*n),
+        ListpackEntry::Str(s) => *s == element.as_bytes(),
+    }
+}
+
+/// Number of bytes used to store the length of a listpack entry.
+fn backlen_size(entry_len: usize) -> usize {
+    match entry_len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// Appends `element` as a listpack entry: integers take from 1 to 9 bytes
+/// depending on their magnitude, strings their length plus 1, 2 or 5 bytes.
+/// Then comes the length of the entry, written backwards in groups of 7 bits
+/// so the listpack can be walked from the tail.
+fn encode_entry(buf: &mut Vec<u8>, element: &str) {
+    let start = buf.len();
+    let len = element.len();
+    match canonical_int(element) {
+        Some(n @ 0..=127) => buf.push(n as u8),
+        Some(n @ -4096..=4095) => {
+            let n = n as u16 & 0x1fff;
+            buf.extend([0xc0 | (n >> 8) as u8, n as u8]);
+        },
+        Some(n @ -32768..=32767) => {
+            buf.push(0xf1);
+            buf.extend((n as i16).to_le_bytes());
+        },
+        Some(n @ -8388608..=8388607) => {
+            buf.push(0xf2);
+            buf.extend(&(n as i32).to_le_bytes()[..3]);
+        },
+        Some(n) if i32::try_from(n).is_ok() => {
+            buf.push(0xf3);
+            buf.extend((n as i32).to_le_bytes());
+        },
+        Some(n) => {
+            buf.push(0xf4);
+            buf.extend(n.to_le_bytes());
+        },
+        None if len < 1 << 6 => {
+            buf.push(0x80 | len as u8);
+            buf.extend(element.as_bytes());
+        },
+        None if len < 1 << 12 => {
+            buf.extend([0xe0 | (len >> 8) as u8, len as u8]);
+            buf.extend(element.as_bytes());
+        },
+        None => {
+            buf.push(0xf0);
+            buf.extend((len as u32).to_le_bytes());
+            buf.extend(element.as_bytes());
+        },
+    }
+    let entry_len = buf.len() - start;
+    let size = backlen_size(entry_len);
+    for group in (0..size).rev() {
+        let bits = (entry_len >> (7 * group)) as u8 & 0x7f;
+        buf.push(if group == size - 1 { bits } else { bits | 0x80 });
+    }
+}
+
+/// Decodes the entry at `pos`, returning it along with where the next one
+/// starts. `None` at the end, or when the entry doesn't fit in `bytes`.
+fn decode_entry(bytes: &[u8], pos: usize) -> Option<(ListpackEntry<'_>, usize)> {
+    let encoding = *bytes.get(pos)?;
+    let data = |offset: usize, len: usize| bytes.get(pos + offset..pos + offset + len);
+    // sign extends little endian integers of `len` bytes
+    let int = |len: usize| {
+        let mut buf = [0; 8];
+        buf[8 - len..].copy_from_slice(data(1, len)?);
+        Some(i64::from_le_bytes(buf) >> (64 - 8 * len))
+    };
+    let (entry, len) = match encoding {
+        0x00..=0x7f => (ListpackEntry::Int(encoding as i64), 1),
+        0x80..=0xbf => {
+            let len = (encoding & 0x3f) as usize;
+            (ListpackEntry::Str(data(1, len)?), 1 + len)
+        },
+        0xc0..=0xdf => {
+            let value = (((encoding & 0x1f) as i64) << 8) | *bytes.get(pos + 1)? as i64;
+            (ListpackEntry::Int(if value >= 1 << 12 { value - (1 << 13) } else { value }), 2)
+        },
+        0xe0..=0xef => {
+            let len = (((encoding & 0x0f) as usize) << 8) | *bytes.get(pos + 1)? as usize;
+            (ListpackEntry::Str(data(2, len)?), 2 + len)
+        },
+        0xf0 => {
+            let len = u32::from_le_bytes(data(1, 4)?.try_into().ok()?) as usize;
+            (ListpackEntry::Str(data(5, len)?), 5 + len)
+        },
+        0xf1 => (ListpackEntry::Int(int(2)?), 3),
+        0xf2 => (ListpackEntry::Int(int(3)?), 4),
+        0xf3 => (ListpackEntry::Int(int(4)?), 5),
+        0xf4 => (ListpackEntry::Int(int(8)?), 9),
+        _ => return None,
+    };
+    let next = pos + len + backlen_size(len);
+    (next <= bytes.len()).then_some((entry, next))
+}
+
+/// Elements stored one after the other in a single buffer, laid out the way
+/// Redis does so it can be written to RDB files as is: a header with the
+/// total size and count, the entries, and a 0xFF terminator.
+///
+/// Lookups walk the whole buffer, so listpacks are only used while small.
+#[derive(Clone, PartialEq)]
+pub struct Listpack {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Default for Listpack {
+    fn default() -> Listpack {
+        let mut listpack = Listpack { bytes: vec![0; LISTPACK_HEADER_SIZE], len: 0 };
+        listpack.bytes.push(LISTPACK_EOF);
+        listpack.write_header();
+        listpack
+    }
+}
+
+impl Listpack {
+    pub fn new() -> Listpack {
+        Listpack::default()
+    }
+
+    /// Checks that `bytes` is a well formed listpack.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Listpack> {
+        let mut pos = LISTPACK_HEADER_SIZE;
+        let mut len = 0;
+        while let Some((_, next)) = decode_entry(&bytes, pos) {
+            pos = next;
+            len += 1;
+        }
+        if bytes.get(pos) != Some(&LISTPACK_EOF) || pos + 1 != bytes.len() {
+            return Err(anyhow!("invalid listpack"));
+        }
+        Ok(Listpack { bytes, len })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Total bytes of the listpack.
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn iter(&self) -> ListpackIter<'_> {
+        ListpackIter { bytes: &self.bytes, pos: LISTPACK_HEADER_SIZE }
+    }
+
+    pub fn push(&mut self, element: &str) {
+        self.bytes.pop();
+        encode_entry(&mut self.bytes, element);
+        self.bytes.push(LISTPACK_EOF);
+        self.len += 1;
+        self.write_header();
+    }
+
+    /// Replaces the entry at `index`, which must be less than `len`.
+    pub fn replace(&mut self, index: usize, element: &str) {
+        let mut start = LISTPACK_HEADER_SIZE;
+        for _ in 0..index {
+            start = decode_entry(&self.bytes, start).expect("listpack index out of range").1;
+        }
+        let end = decode_entry(&self.bytes, start).expect("listpack index out of range").1;
+        let mut entry = vec![];
+        encode_entry(&mut entry, element);
+        self.bytes.splice(start..end, entry);
+        self.write_header();
+    }
+
+    /// Position of the first entry equal to `element`.
+    pub fn position(&self, element: &str) -> Option<usize> {
+        let matches = matching(element);
+        self.iter().position(|entry| matches(&entry))
+    }
+
+    /// For listpacks of pairs, the pair starting with `key` and its index.
+    fn pair(&self, key: &str) -> Option<(usize, ListpackEntry<'_>)> {
+        let matches = matching(key);
+        pairs(self.iter()).enumerate()
+            .find(|(_, (entry, _))| matches(entry))
+            .map(|(index, (_, value))| (index, value))
+    }
+
+    fn write_header(&mut self) {
+        let size = self.bytes.len() as u32;
+        self.bytes[..4].copy_from_slice(&size.to_le_bytes());
+        // the count saturates, it then takes a walk to know it
+        self.bytes[4..6].copy_from_slice(&(self.len.min(u16::MAX as usize) as u16).to_le_bytes());
+    }
+}
+
+impl<'a> FromIterator<&'a str> for Listpack {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(elements: I) -> Listpack {
+        let mut listpack = Listpack::new();
+        elements.into_iter().for_each(|element| listpack.push(element));
+        listpack
+    }
+}
+
+impl fmt::Debug for Listpack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+pub struct ListpackIter<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for ListpackIter<'a> {
+    type Item = ListpackEntry<'a>;
+
+    fn next(&mut self) -> Option<ListpackEntry<'a>> {
+        let (entry, next) = decode_entry(self.bytes, self.pos)?;
+        self.pos = next;
+        Some(entry)
+    }
+}
+
+/// Pairs up consecutive entries, for hashes and sorted sets.
+fn pairs<'a>(mut entries: ListpackIter<'a>) -> impl Iterator<Item = (ListpackEntry<'a>, ListpackEntry<'a>)> {
+    std::iter::from_fn(move || Some((entries.next()?, entries.next()?)))
+}
+
+/// Bytes needed to store `n` in an intset.
+fn intset_width(n: i64) -> usize {
+    if i16::try_from(n).is_ok() {
+        2
+    } else if i32::try_from(n).is_ok() {
+        4
+    } else {
+        8
+    }
+}
+
+/// Sorted integers, all stored with the width of the widest one, laid out
+/// the way Redis does: a header with the width and count, followed by the
+/// little endian integers.
+#[derive(Clone, PartialEq)]
+pub struct Intset {
+    bytes: Vec<u8>,
+}
+
+impl Default for Intset {
+    fn default() -> Intset {
+        let mut bytes = vec![0; INTSET_HEADER_SIZE];
+        bytes[..4].copy_from_slice(&2u32.to_le_bytes());
+        Intset { bytes }
+    }
+}
+
+impl Intset {
+    pub fn new() -> Intset {
+        Intset::default()
+    }
+
+    /// Checks that `bytes` is a well formed intset.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Intset> {
+        let header = |range: std::ops::Range<usize>| {
+            bytes.get(range).map(|field| u32::from_le_bytes(field.try_into().unwrap()) as usize)
+        };
+        match (header(0..4), header(4..8)) {
+            (Some(width @ (2 | 4 | 8)), Some(len)) if bytes.len() == INTSET_HEADER_SIZE + width * len => {
+                let intset = Intset { bytes };
+                // lookups are binary searches
+                let sorted = intset.iter().zip(intset.iter().skip(1)).all(|(a, b)| a < b);
+                match sorted {
+                    true => Ok(intset),
+                    false => Err(anyhow!("intset isn't sorted or has duplicates")),
+                }
+            },
+            (Some(width @ (2 | 4 | 8)), _) => Err(anyhow!("invalid intset of {width} bytes integers")),
+            (width, _) => Err(anyhow!("invalid intset encoding {width:?}")),
+        }
+    }
+
+    fn width(&self) -> usize {
+        u32::from_le_bytes(self.bytes[..4].try_into().unwrap()) as usize
+    }
+
+    pub fn len(&self) -> usize {
+        (self.bytes.len() - INTSET_HEADER_SIZE) / self.width()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total bytes of the intset.
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn get(&self, index: usize) -> i64 {
+        let width = self.width();
+        let bytes = &self.bytes[INTSET_HEADER_SIZE + index * width..][..width];
+        match width {
+            2 => i16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            4 => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            _ => i64::from_le_bytes(bytes.try_into().unwrap()),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = i64> + '_ {
+        (0..self.len()).map(|index| self.get(index))
+    }
+
+    fn to_listpack(&self) -> Listpack {
+        let mut listpack = Listpack::new();
+        self.iter().for_each(|n| listpack.push(&n.to_string()));
+        listpack
+    }
+
+    /// Where `n` is, or should be inserted to keep the set sorted.
+    fn search(&self, n: i64) -> Result<usize, usize> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.get(mid).cmp(&n) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(low)
+    }
+
+    pub fn contains(&self, n: i64) -> bool {
+        self.search(n).is_ok()
+    }
+
+    /// Adds `n`, returning whether it wasn't there yet. Every integer gets
+    /// wider if `n` doesn't fit the current width.
+    pub fn insert(&mut self, n: i64) -> bool {
+        let width = intset_width(n).max(self.width());
+        if width > self.width() {
+            let mut wider = Intset { bytes: vec![0; INTSET_HEADER_SIZE] };
+            wider.bytes[..4].copy_from_slice(&(width as u32).to_le_bytes());
+            self.iter().for_each(|existing| wider.bytes.extend(&existing.to_le_bytes()[..width]));
+            *self = wider;
+        }
+        let Err(index) = self.search(n) else {
+            return false;
+        };
+        let at = INTSET_HEADER_SIZE + index * width;
+        self.bytes.splice(at..at, n.to_le_bytes()[..width].iter().copied());
+        let len = self.len() as u32;
+        self.bytes[4..8].copy_from_slice(&len.to_le_bytes());
+        true
+    }
+}
+
+impl fmt::Debug for Intset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// Iterators over collections, whatever their encoding.
+pub type Iter<'a, T> = Box<dyn Iterator<Item = T> + 'a>;
+
+#[derive(Debug, Clone)]
+pub enum List {
+    Listpack(Listpack),
+    /// Reported as a quicklist, which are listpacks linked together
+    Quicklist(VecDeque<String>),
+}
+
+impl Default for List {
+    fn default() -> List {
+        List::Listpack(Listpack::new())
+    }
+}
+
+impl List {
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            List::Listpack(_) => "listpack",
+            List::Quicklist(_) => "quicklist",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            List::Listpack(listpack) => listpack.len(),
+            List::Quicklist(list) => list.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_, Cow<'_, str>> {
+        match self {
+            List::Listpack(listpack) => Box::new(listpack.iter().map(ListpackEntry::to_cow)),
+            List::Quicklist(list) => Box::new(list.iter().map(|element| Cow::Borrowed(element.as_str()))),
+        }
+    }
+
+    pub fn push_back(&mut self, element: String) {
+        match self {
+            List::Listpack(listpack) => listpack.push(&element),
+            List::Quicklist(list) => list.push_back(element),
+        }
+    }
+
+    pub(crate) fn memory_usage(&self, samples: usize) -> usize {
+        match self {
+            List::Listpack(listpack) => listpack.size(),
+            List::Quicklist(list) => estimate(list.iter(), samples, |element| size_of::<String>() + element.len()),
+        }
+    }
+
+    /// Switches to the encoding `config` calls for, only away from the
+    /// listpack unless `shrink`.
+    pub(crate) fn convert(&mut self, config: &EncodingConfig, shrink: bool) {
+        match self {
+            List::Listpack(listpack) if !config.list_fits(listpack) => {
+                *self = List::Quicklist(listpack.iter().map(|entry| entry.to_cow().into_owned()).collect());
+            },
+            List::Quicklist(list) if shrink => {
+                let mut listpack = Listpack::new();
+                for element in list.iter() {
+                    listpack.push(element);
+                    if !config.list_fits(&listpack) {
+                        return;
+                    }
+                }
+                *self = List::Listpack(listpack);
+            },
+            _ => (),
+        }
+    }
+}
+
+impl PartialEq for List {
+    fn eq(&self, other: &List) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl Extend<String> for List {
+    fn extend<I: IntoIterator<Item = String>>(&mut self, elements: I) {
+        elements.into_iter().for_each(|element| self.push_back(element));
+    }
+}
+
+impl FromIterator<String> for List {
+    fn from_iter<I: IntoIterator<Item = String>>(elements: I) -> List {
+        List::Quicklist(elements.into_iter().collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Set {
+    Intset(Intset),
+    Listpack(Listpack),
+    Hashtable(HashSet<String>),
+}
+
+impl Default for Set {
+    fn default() -> Set {
+        Set::Intset(Intset::new())
+    }
+}
+
+impl Set {
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Set::Intset(_) => "intset",
+            Set::Listpack(_) => "listpack",
+            Set::Hashtable(_) => "hashtable",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Set::Intset(intset) => intset.len(),
+            Set::Listpack(listpack) => listpack.len(),
+            Set::Hashtable(set) => set.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_, Cow<'_, str>> {
+        match self {
+            Set::Intset(intset) => Box::new(intset.iter().map(|n| Cow::Owned(n.to_string()))),
+            Set::Listpack(listpack) => Box::new(listpack.iter().map(ListpackEntry::to_cow)),
+            Set::Hashtable(set) => Box::new(set.iter().map(|member| Cow::Borrowed(member.as_str()))),
+        }
+    }
+
+    pub fn contains(&self, member: &str) -> bool {
+        match self {
+            Set::Intset(intset) => canonical_int(member).is_some_and(|n| intset.contains(n)),
+            Set::Listpack(listpack) => listpack.position(member).is_some(),
+            Set::Hashtable(set) => set.contains(member),
+        }
+    }
+
+    /// Adds `member`, returning whether it wasn't there yet. Intsets become
+    /// listpacks on the first member that isn't an integer.
+    pub fn insert(&mut self, member: String) -> bool {
+        if let Set::Intset(intset) = self {
+            match canonical_int(&member) {
+                Some(n) => return intset.insert(n),
+                None => *self = Set::Listpack(intset.to_listpack()),
+            }
+        }
+        match self {
+            Set::Listpack(listpack) if listpack.position(&member).is_some() => false,
+            Set::Listpack(listpack) => {
+                listpack.push(&member);
+                true
+            },
+            Set::Hashtable(set) => set.insert(member),
+            Set::Intset(_) => unreachable!("intsets were converted above"),
+        }
+    }
+
+    pub(crate) fn memory_usage(&self, samples: usize) -> usize {
+        match self {
+            Set::Intset(intset) => intset.size(),
+            Set::Listpack(listpack) => listpack.size(),
+            Set::Hashtable(set) => estimate(set.iter(), samples, |member| size_of::<String>() + member.len() + 1),
+        }
+    }
+
+    /// Switches to the encoding `config` calls for, only towards less compact
+    /// ones unless `shrink`.
+    pub(crate) fn convert(&mut self, config: &EncodingConfig, shrink: bool) {
+        let len = self.len();
+        let fits_listpack = |longest: usize| len <= config.set_max_listpack_entries && longest <= config.set_max_listpack_value;
+        match self {
+            Set::Intset(intset) if len > config.set_max_intset_entries => {
+                // the longest integers are the smallest and the largest
+                let longest = [intset.iter().next(), intset.iter().last()].into_iter().flatten().map(digits).max().unwrap_or(0);
+                *self = match fits_listpack(longest) {
+                    true => Set::Listpack(intset.to_listpack()),
+                    false => Set::Hashtable(intset.iter().map(|n| n.to_string()).collect()),
+                };
+            },
+            Set::Listpack(listpack) if !fits_listpack(listpack.iter().map(|entry| entry.len()).max().unwrap_or(0)) => {
+                *self = Set::Hashtable(listpack.iter().map(|entry| entry.to_cow().into_owned()).collect());
+            },
+            Set::Hashtable(set) if shrink && len <= config.set_max_intset_entries.max(config.set_max_listpack_entries) => {
+                let ints: Option<Vec<i64>> = set.iter().map(|member| canonical_int(member)).collect();
+                match ints {
+                    Some(ints) if len <= config.set_max_intset_entries => {
+                        let mut intset = Intset::new();
+                        ints.into_iter().for_each(|n| { intset.insert(n); });
+                        *self = Set::Intset(intset);
+                    },
+                    _ if fits_listpack(set.iter().map(String::len).max().unwrap_or(0)) => {
+                        *self = Set::Listpack(set.iter().map(String::as_str).collect());
+                    },
+                    _ => (),
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+impl PartialEq for Set {
+    fn eq(&self, other: &Set) -> bool {
+        self.len() == other.len() && self.iter().all(|member| other.contains(&member))
+    }
+}
+
+impl FromIterator<String> for Set {
+    fn from_iter<I: IntoIterator<Item = String>>(members: I) -> Set {
+        Set::Hashtable(members.into_iter().collect())
+    }
+}
+
+/// Members of a sorted set along with their scores.
+#[derive(Debug, Clone)]
+pub enum ZSet {
+    /// Members each followed by their score
+    Listpack(Listpack),
+    /// Reported as a skiplist, which keeps members ordered by score
+    Skiplist(HashMap<String, f64>),
+}
+
+impl Default for ZSet {
+    fn default() -> ZSet {
+        ZSet::Listpack(Listpack::new())
+    }
+}
+
+impl ZSet {
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            ZSet::Listpack(_) => "listpack",
+            ZSet::Skiplist(_) => "skiplist",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            ZSet::Listpack(listpack) => listpack.len() / 2,
+            ZSet::Skiplist(zset) => zset.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_, (Cow<'_, str>, f64)> {
+        match self {
+            ZSet::Listpack(listpack) => Box::new(pairs(listpack.iter()).map(|(member, score)| (member.to_cow(), score.to_f64()))),
+            ZSet::Skiplist(zset) => Box::new(zset.iter().map(|(member, score)| (Cow::Borrowed(member.as_str()), *score))),
+        }
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        match self {
+            ZSet::Listpack(listpack) => listpack.pair(member).map(|(_, score)| score.to_f64()),
+            ZSet::Skiplist(zset) => zset.get(member).copied(),
+        }
+    }
+
+    /// Sets the score of `member`, returning whether it wasn't there yet.
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        match self {
+            ZSet::Listpack(listpack) => match listpack.pair(&member).map(|(index, _)| index) {
+                Some(index) => {
+                    listpack.replace(2 * index + 1, &score.to_string());
+                    false
+                },
+                None => {
+                    listpack.push(&member);
+                    listpack.push(&score.to_string());
+                    true
+                },
+            },
+            ZSet::Skiplist(zset) => zset.insert(member, score).is_none(),
+        }
+    }
+
+    pub(crate) fn memory_usage(&self, samples: usize) -> usize {
+        match self {
+            ZSet::Listpack(listpack) => listpack.size(),
+            ZSet::Skiplist(zset) => estimate(zset.iter(), samples, |(member, _)| size_of::<(String, f64)>() + member.len() + 1),
+        }
+    }
+
+    /// Switches to the encoding `config` calls for, only away from the
+    /// listpack unless `shrink`.
+    pub(crate) fn convert(&mut self, config: &EncodingConfig, shrink: bool) {
+        let len = self.len();
+        let fits = |longest: usize| len <= config.zset_max_listpack_entries && longest <= config.zset_max_listpack_value;
+        match self {
+            ZSet::Listpack(listpack) if !fits(pairs(listpack.iter()).map(|(member, _)| member.len()).max().unwrap_or(0)) => {
+                *self = ZSet::Skiplist(self.iter().map(|(member, score)| (member.into_owned(), score)).collect());
+            },
+            ZSet::Skiplist(zset) if shrink && fits(zset.keys().map(String::len).max().unwrap_or(0)) => {
+                let mut listpack = Listpack::new();
+                for (member, score) in zset.iter() {
+                    listpack.push(member);
+                    listpack.push(&score.to_string());
+                }
+                *self = ZSet::Listpack(listpack);
+            },
+            _ => (),
+        }
+    }
+}
+
+impl PartialEq for ZSet {
+    fn eq(&self, other: &ZSet) -> bool {
+        self.len() == other.len() && self.iter().all(|(member, score)| other.score(&member) == Some(score))
+    }
+}
+
+impl FromIterator<(String, f64)> for ZSet {
+    fn from_iter<I: IntoIterator<Item = (String, f64)>>(members: I) -> ZSet {
+        ZSet::Skiplist(members.into_iter().collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Hash {
+    /// Fields each followed by their value
+    Listpack(Listpack),
+    Hashtable(HashMap<String, String>),
+}
+
+impl Default for Hash {
+    fn default() -> Hash {
+        Hash::Listpack(Listpack::new())
+    }
+}
+
+impl Hash {
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Hash::Listpack(_) => "listpack",
+            Hash::Hashtable(_) => "hashtable",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Hash::Listpack(listpack) => listpack.len() / 2,
+            Hash::Hashtable(hash) => hash.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_, (Cow<'_, str>, Cow<'_, str>)> {
+        match self {
+            Hash::Listpack(listpack) => Box::new(pairs(listpack.iter()).map(|(field, value)| (field.to_cow(), value.to_cow()))),
+            Hash::Hashtable(hash) => Box::new(hash.iter().map(|(field, value)| (Cow::Borrowed(field.as_str()), Cow::Borrowed(value.as_str())))),
+        }
+    }
+
+    pub fn get(&self, field: &str) -> Option<Cow<'_, str>> {
+        match self {
+            Hash::Listpack(listpack) => listpack.pair(field).map(|(_, value)| value.to_cow()),
+            Hash::Hashtable(hash) => hash.get(field).map(|value| Cow::Borrowed(value.as_str())),
+        }
+    }
+
+    /// Sets `field` to `value`, returning whether the field wasn't there yet.
+    pub fn insert(&mut self, field: String, value: String) -> bool {
+        match self {
+            Hash::Listpack(listpack) => match listpack.pair(&field).map(|(index, _)| index) {
+                Some(index) => {
+                    listpack.replace(2 * index + 1, &value);
+                    false
+                },
+                None => {
+                    listpack.push(&field);
+                    listpack.push(&value);
+                    true
+                },
+            },
+            Hash::Hashtable(hash) => hash.insert(field, value).is_none(),
+        }
+    }
+
+    pub(crate) fn memory_usage(&self, samples: usize) -> usize {
+        match self {
+            Hash::Listpack(listpack) => listpack.size(),
+            Hash::Hashtable(hash) => estimate(hash.iter(), samples, |(field, value)| size_of::<(String, String)>() + field.len() + value.len() + 1),
+        }
+    }
+
+    /// Switches to the encoding `config` calls for, only away from the
+    /// listpack unless `shrink`.
+    pub(crate) fn convert(&mut self, config: &EncodingConfig, shrink: bool) {
+        let len = self.len();
+        let fits = |longest: usize| len <= config.hash_max_listpack_entries && longest <= config.hash_max_listpack_value;
+        match self {
+            Hash::Listpack(listpack) if !fits(listpack.iter().map(|entry| entry.len()).max().unwrap_or(0)) => {
+                *self = Hash::Hashtable(self.iter().map(|(field, value)| (field.into_owned(), value.into_owned())).collect());
+            },
+            Hash::Hashtable(hash) if shrink && fits(hash.iter().map(|(field, value)| field.len().max(value.len())).max().unwrap_or(0)) => {
+                let mut listpack = Listpack::new();
+                for (field, value) in hash.iter() {
+                    listpack.push(field);
+                    listpack.push(value);
+                }
+                *self = Hash::Listpack(listpack);
+            },
+            _ => (),
+        }
+    }
+}
+
+impl PartialEq for Hash {
+    fn eq(&self, other: &Hash) -> bool {
+        self.len() == other.len() && self.iter().all(|(field, value)| other.get(&field) == Some(value))
+    }
+}
+
+impl FromIterator<(String, String)> for Hash {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(fields: I) -> Hash {
+        Hash::Hashtable(fields.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_listpack_layout_matches_redis() {
+        let listpack: Listpack = ["f", "v", "g", "5"].into_iter().collect();
+        assert_eq!(&[18, 0, 0, 0, 4, 0, 0x81, b'f', 2, 0x81, b'v', 2, 0x81, b'g', 2, 0x05, 1, 0xff], listpack.as_bytes());
+        assert_eq!(listpack, Listpack::from_bytes(listpack.as_bytes().to_vec()).unwrap());
+        assert!(Listpack::from_bytes(listpack.as_bytes()[..10].to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_listpack_entries() {
+        let long = "x".repeat(5000);
+        let elements = [
+            "0", "127", "128", "-1", "-4096", "4095", "-32768", "32767", "-8388608", "8388607",
+            "-2147483648", "2147483647", "9223372036854775807", "-9223372036854775808",
+            "007", "+1", "", "short", &long[..100], &long,
+        ];
+        let mut listpack: Listpack = elements.into_iter().collect();
+        let decoded: Vec<Cow<str>> = listpack.iter().map(ListpackEntry::to_cow).collect();
+        assert_eq!(elements.to_vec(), decoded);
+        assert_eq!(Some(ListpackEntry::Int(-4096)), listpack.iter().nth(4));
+        assert_eq!(Some(14), listpack.position("007"));
+
+        listpack.replace(17, &long);
+        listpack.replace(19, "1");
+        assert_eq!(Some(Cow::Borrowed(long.as_str())), listpack.iter().nth(17).map(ListpackEntry::to_cow));
+        assert_eq!(Some(ListpackEntry::Int(1)), listpack.iter().nth(19));
+        assert_eq!(listpack, Listpack::from_bytes(listpack.as_bytes().to_vec()).unwrap());
+    }
+
+    #[test]
+    fn test_intset() {
+        let mut intset = Intset::new();
+        assert!(intset.insert(300));
+        assert!(intset.insert(1));
+        assert!(!intset.insert(300));
+        assert_eq!(&[2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0x2c, 0x01], intset.as_bytes());
+
+        assert!(intset.insert(-1 << 40));
+        assert_eq!(vec![-1 << 40, 1, 300], intset.iter().collect::<Vec<_>>());
+        assert_eq!(8 + 3 * 8, intset.size());
+        assert!(intset.contains(1) && !intset.contains(2));
+        assert!(Intset::from_bytes(intset.as_bytes()[..20].to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_string_encodings() {
+        assert_eq!(Str::Int(-42), Str::from("-42"));
+        assert_eq!(Str::Raw("042".to_string()), Str::from("042"));
+        assert_eq!("042", Str::from("042").to_string());
+        assert_eq!("embstr", Str::from("x".repeat(44)).encoding());
+        assert_eq!("raw", Str::from("x".repeat(45)).encoding());
+    }
+
+    #[test]
+    fn test_hash_conversions() {
+        let config = EncodingConfig { hash_max_listpack_entries: 2, hash_max_listpack_value: 8, ..Default::default() };
+        let mut hash = Hash::default();
+        assert!(hash.insert("a".to_string(), "1".to_string()));
+        assert!(!hash.insert("a".to_string(), "one".to_string()));
+        hash.convert(&config, false);
+        assert_eq!("listpack", hash.encoding());
+        assert_eq!(Some(Cow::Borrowed("one")), hash.get("a"));
+
+        hash.insert("b".to_string(), "long value".to_string());
+        hash.convert(&config, false);
+        assert_eq!("hashtable", hash.encoding());
+        let expected: Hash = [("a", "one"), ("b", "long value")].map(|(f, v)| (f.to_string(), v.to_string())).into_iter().collect();
+        assert_eq!(expected, hash);
+
+        let mut zset = ZSet::default();
+        zset.insert("m".to_string(), 1.5);
+        zset.insert("n".to_string(), 2.0);
+        assert!(!zset.insert("m".to_string(), -3.0));
+        assert_eq!(vec![(Cow::Borrowed("m"), -3.0), (Cow::Borrowed("n"), 2.0)], zset.iter().collect::<Vec<_>>());
+        zset.convert(&EncodingConfig { zset_max_listpack_entries: 1, ..config }, false);
+        assert_eq!("skiplist", zset.encoding());
+        assert_eq!(Some(-3.0), zset.score("m"));
+    }
+}
@@ -0,0 +1,265 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use rand::{thread_rng, Rng};
+
+use crate::keyspace::{Databases, Sample};
+
+/// Counter new keys start with, so they get a chance to be used before
+/// being the least frequently used ones.
+pub const LFU_INIT_VAL: u8 = 5;
+
+/// How many of the best candidates seen while sampling are kept between
+/// evictions, same as Redis.
+const POOL_SIZE: usize = 16;
+
+const MILLIS_PER_MINUTE: u64 = 60_000;
+
+/// Which keys get evicted when the dataset uses more than `maxmemory`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictionPolicy {
+    /// Evict nothing, refuse writes that need more memory instead
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    /// Evict the keys that expire the soonest
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    /// Whether only keys with an expiry time can be evicted.
+    fn volatile(self) -> bool {
+        matches!(
+            self,
+            EvictionPolicy::VolatileLru | EvictionPolicy::VolatileLfu |
+            EvictionPolicy::VolatileRandom | EvictionPolicy::VolatileTtl
+        )
+    }
+
+    pub fn is_lfu(self) -> bool {
+        matches!(self, EvictionPolicy::AllKeysLfu | EvictionPolicy::VolatileLfu)
+    }
+
+    /// How good a candidate for eviction `sample` is, the higher the better.
+    fn score(self, sample: &Sample) -> u64 {
+        match self {
+            EvictionPolicy::AllKeysLfu | EvictionPolicy::VolatileLfu => (u8::MAX - sample.frequency) as u64,
+            EvictionPolicy::VolatileTtl => u64::MAX - sample.expires_at.unwrap_or(u64::MAX),
+            _ => sample.idle,
+        }
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<EvictionPolicy> {
+        match s {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "volatile-lru" => Ok(EvictionPolicy::VolatileLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "volatile-lfu" => Ok(EvictionPolicy::VolatileLfu),
+            "allkeys-random" => Ok(EvictionPolicy::AllKeysRandom),
+            "volatile-random" => Ok(EvictionPolicy::VolatileRandom),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            x => Err(anyhow!("invalid maxmemory-policy '{x}'")),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::VolatileLru => "volatile-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileLfu => "volatile-lfu",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+            EvictionPolicy::VolatileRandom => "volatile-random",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        })
+    }
+}
+
+/// The memory budget and how to stay within it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryConfig {
+    /// Bytes the dataset may use, 0 for no limit.
+    pub maxmemory: u64,
+    pub policy: EvictionPolicy,
+    /// How many keys of each database are sampled to find one to evict.
+    pub samples: usize,
+    /// The higher, the more accesses it takes to increment LFU counters.
+    pub lfu_log_factor: u64,
+    /// Minutes after which an unused key's LFU counter is decremented, 0 to
+    /// never decay.
+    pub lfu_decay_time: u64,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> MemoryConfig {
+        MemoryConfig {
+            maxmemory: 0,
+            policy: EvictionPolicy::NoEviction,
+            samples: 5,
+            lfu_log_factor: 10,
+            lfu_decay_time: 1,
+        }
+    }
+}
+
+/// Parses an amount of memory such as `100mb`, with the units Redis
+/// understands: k, m and g are powers of 1000, kb, mb and gb of 1024.
+pub fn parse_memory(value: &str) -> Result<u64> {
+    let lower = value.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(anyhow!("invalid memory amount '{value}'")),
+    };
+    digits.parse::<u64>().ok()
+        .and_then(|amount| amount.checked_mul(unit))
+        .ok_or_else(|| anyhow!("invalid memory amount '{value}'"))
+}
+
+/// Counts an access in a logarithmic LFU `counter`: the higher it already
+/// is, the less likely it is to grow, so that 255 takes about a million
+/// accesses with the default `log_factor` of 10.
+pub fn lfu_increment(counter: u8, log_factor: u64) -> u8 {
+    if counter == u8::MAX {
+        return counter;
+    }
+    let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+    let probability = 1.0 / (base * log_factor as f64 + 1.0);
+    match thread_rng().gen::<f64>() < probability {
+        true => counter + 1,
+        false => counter,
+    }
+}
+
+/// Decrements an LFU `counter` once for every `decay_time` minutes passed
+/// between `accessed_at` and `now`, both in milliseconds.
+pub fn lfu_decay(counter: u8, accessed_at: u64, now: u64, decay_time: u64) -> u8 {
+    if decay_time == 0 {
+        return counter;
+    }
+    let periods = now.saturating_sub(accessed_at) / MILLIS_PER_MINUTE / decay_time;
+    counter.saturating_sub(periods.min(u8::MAX as u64) as u8)
+}
+
+struct Candidate {
+    score: u64,
+    db: usize,
+    key: String,
+}
+
+/// Picks the keys to evict, approximating LRU, LFU and TTL orders by
+/// sampling a few keys of each database rather than keeping them sorted.
+///
+/// Like Redis, the best candidates seen are pooled across evictions, which
+/// brings the approximation much closer to the real order.
+#[derive(Default)]
+pub struct Evictor {
+    /// Candidates sorted by increasing score.
+    pool: Mutex<Vec<Candidate>>,
+}
+
+impl Evictor {
+    pub fn new() -> Evictor {
+        Evictor::default()
+    }
+
+    /// The database and key to evict next under `config`, if any key can be.
+    pub fn pick(&self, databases: &Databases, config: &MemoryConfig) -> Option<(usize, String)> {
+        let policy = config.policy;
+        let count = databases.count();
+        match policy {
+            EvictionPolicy::NoEviction => None,
+            EvictionPolicy::AllKeysRandom | EvictionPolicy::VolatileRandom => {
+                let start = thread_rng().gen_range(0..count);
+                (0..count).map(|i| (start + i) % count).find_map(|db| {
+                    let sample = databases.get(db).sample(1, policy.volatile()).pop()?;
+                    Some((db, sample.key))
+                })
+            },
+            _ => {
+                let mut pool = self.pool.lock().unwrap();
+                for db in 0..count {
+                    for sample in databases.get(db).sample(config.samples, policy.volatile()) {
+                        let score = policy.score(&sample);
+                        pool.retain(|candidate| candidate.db != db || candidate.key != sample.key);
+                        let position = pool.partition_point(|candidate| candidate.score < score);
+                        if pool.len() >= POOL_SIZE && position == 0 {
+                            continue;
+                        }
+                        pool.insert(position, Candidate { score, db, key: sample.key });
+                        if pool.len() > POOL_SIZE {
+                            pool.remove(0);
+                        }
+                    }
+                }
+                // candidates may have been removed since they were pooled
+                while let Some(candidate) = pool.pop() {
+                    if databases.get(candidate.db).expires_at(&candidate.key).is_some() {
+                        return Some((candidate.db, candidate.key));
+                    }
+                }
+                None
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::keyspace::Value;
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(100, parse_memory("100").unwrap());
+        assert_eq!(2 * 1024 * 1024, parse_memory("2mb").unwrap());
+        assert_eq!(3_000_000_000, parse_memory("3G").unwrap());
+        assert!(parse_memory("10xb").is_err());
+        assert!(parse_memory("-1").is_err());
+    }
+
+    #[test]
+    fn test_lfu_counter() {
+        assert_eq!(LFU_INIT_VAL + 1, lfu_increment(LFU_INIT_VAL, 10));
+        assert_eq!(u8::MAX, lfu_increment(u8::MAX, 0));
+        assert_eq!(7, lfu_decay(10, 0, 3 * MILLIS_PER_MINUTE, 1));
+        assert_eq!(9, lfu_decay(10, 0, 3 * MILLIS_PER_MINUTE, 2));
+        assert_eq!(10, lfu_decay(10, 0, 3 * MILLIS_PER_MINUTE, 0));
+    }
+
+    #[test]
+    fn test_pick_least_recently_used() {
+        let databases = Databases::new(2);
+        let config = MemoryConfig { policy: EvictionPolicy::AllKeysLru, samples: 10, ..Default::default() };
+        databases.get(1).set("old".to_string(), Value::String("1".into()), None);
+        databases.get(1).set_idle_time("old", 60_000);
+        databases.get(0).set("new".to_string(), Value::String("1".into()), None);
+
+        let evictor = Evictor::new();
+        assert_eq!(Some((1, "old".to_string())), evictor.pick(&databases, &config));
+
+        let volatile = MemoryConfig { policy: EvictionPolicy::VolatileTtl, ..config };
+        assert_eq!(None, Evictor::new().pick(&databases, &volatile));
+    }
+}
@@ -1,33 +1,41 @@
-use std::{sync::Arc, time::Duration};
+use std::{sync::{Arc, Weak}, time::Duration};
 
-use dashmap::DashMap;
-use tokio::{sync::{mpsc::Receiver, Mutex, RwLock}, time::sleep};
+use tokio::{sync::{mpsc::UnboundedReceiver, Mutex}, time::sleep};
 
 use log::info;
 
+use crate::keyspace::Keyspace;
+
+/// A key to remove from a database once the duration elapsed. The database
+/// is referred to directly rather than by number, as SWAPDB may move it.
+pub type Expiry = (Weak<Keyspace>, String, Duration);
+
 #[derive(Clone)]
 pub struct Expirator {
-    rx: Arc<Mutex<Receiver<(String, Duration)>>>,
-    cache: Arc<DashMap<String, String>>
+    rx: Arc<Mutex<UnboundedReceiver<Expiry>>>,
 }
 
 impl Expirator {
-    pub(crate) fn new(rx: Arc<Mutex<Receiver<(String, Duration)>>>, cache: Arc<DashMap<String, String>>) -> Expirator {
-        Expirator { rx, cache }
+    pub fn new(rx: Arc<Mutex<UnboundedReceiver<Expiry>>>) -> Expirator {
+        Expirator { rx }
     }
 
-    pub async fn listen(self: &Self) {
+    pub async fn listen(&self) {
         loop {
             let mut receiver = self.rx.lock().await;
             match receiver.recv().await {
-                Some((key, expiry)) => {
-                    let cache_clone = self.cache.clone();
+                Some((keyspace, key, expiry)) => {
                     tokio::spawn(async move {
-                        let _ = sleep(expiry).await;
-                        info!(target: "expirator", "deleting key {key:?}");
-                        cache_clone.remove(key.as_str());
+                        sleep(expiry).await;
+                        // the database may be gone after a flush, and the key
+                        // may have been overwritten with a later expiry since
+                        let Some(keyspace) = keyspace.upgrade() else {
+                            return;
+                        };
+                        if keyspace.remove_if_expired(key.as_str()) {
+                            info!(target: "expirator", "deleted key {key:?}");
+                        }
                     });
-                    ()
                 },
                 None => todo!(),
             }
@@ -0,0 +1,371 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use mlua::{Function, Lua, MultiValue, RegistryKey, Table, Value};
+
+use crate::commands::{CommandRequest, CommandResponse, RestorePolicy};
+use crate::glob::glob_match;
+use crate::rdb::{dump_payload, verify_payload, write_string, RdbReader, RDB_OPCODE_FUNCTION2};
+use crate::scripting::{first_line, Scripting};
+
+const FUNCTION_FLAGS: [&str; 5] = ["no-writes", "allow-oom", "allow-stale", "no-cluster", "allow-cross-slot-keys"];
+
+struct Library {
+    code: String,
+    functions: Vec<String>,
+}
+
+struct RegisteredFunction {
+    callback: RegistryKey,
+    flags: Vec<String>,
+    description: Option<String>,
+}
+
+/// A Lua interpreter with a set of libraries loaded into it.
+struct Engine {
+    lua: Lua,
+    libraries: BTreeMap<String, Library>,
+    functions: HashMap<String, RegisteredFunction>,
+}
+
+/// Registry of function libraries loaded with FUNCTION LOAD.
+///
+/// Libraries share a Lua interpreter, separate from the one used by EVAL,
+/// which gets rebuilt from the library sources whenever they change. That
+/// way a library that fails to load, or a RESTORE that conflicts with what
+/// is already there, leaves the registry untouched.
+pub struct Functions {
+    scripting: Arc<Scripting>,
+    engine: Mutex<Engine>,
+}
+
+impl Functions {
+    pub fn new(scripting: Arc<Scripting>) -> Functions {
+        let engine = Engine::new(scripting.create_lua());
+        Functions {
+            scripting,
+            engine: Mutex::new(engine),
+        }
+    }
+
+    /// Loads the library in `code`, returning its name.
+    pub fn load(&self, code: String, replace: bool) -> Result<String> {
+        let name = library_name(&code)?;
+        let mut engine = self.engine.lock().unwrap();
+        if !replace && engine.libraries.contains_key(&name) {
+            return Err(anyhow!("ERR Library '{name}' already exists"));
+        }
+        let codes: Vec<String> = engine.codes_except(&[name.as_str()]).chain([code]).collect();
+        *engine = Engine::build(self.scripting.create_lua(), codes)?;
+        Ok(name)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<()> {
+        let mut engine = self.engine.lock().unwrap();
+        if !engine.libraries.contains_key(name) {
+            return Err(anyhow!("ERR Library not found"));
+        }
+        let codes: Vec<String> = engine.codes_except(&[name]).collect();
+        *engine = Engine::build(self.scripting.create_lua(), codes)?;
+        Ok(())
+    }
+
+    pub fn flush(&self) {
+        *self.engine.lock().unwrap() = Engine::new(self.scripting.create_lua());
+    }
+
+    /// Sources of every library, ordered by name.
+    pub fn codes(&self) -> Vec<String> {
+        self.engine.lock().unwrap().codes_except(&[]).collect()
+    }
+
+    /// Serializes every library the way FUNCTION DUMP does, which is also
+    /// how they are stored in RDB files.
+    pub fn dump(&self) -> Vec<u8> {
+        let mut payload = vec![];
+        for code in self.codes() {
+            payload.push(RDB_OPCODE_FUNCTION2);
+            write_string(&mut payload, code.as_bytes());
+        }
+        dump_payload(payload)
+    }
+
+    pub fn restore(&self, payload: &[u8], policy: RestorePolicy) -> Result<()> {
+        let body = verify_payload(payload).map_err(|err| anyhow!("ERR {err}"))?;
+        let mut reader = RdbReader::new(body);
+        let mut restored = vec![];
+        while !reader.is_empty() {
+            if reader.read_u8()? != RDB_OPCODE_FUNCTION2 {
+                return Err(anyhow!("ERR given type is not a function"));
+            }
+            restored.push(String::from_utf8_lossy(&reader.read_string()?).into_owned());
+        }
+
+        let mut engine = self.engine.lock().unwrap();
+        let kept: Vec<String> = match policy {
+            RestorePolicy::Flush => vec![],
+            RestorePolicy::Append => engine.codes_except(&[]).collect(),
+            RestorePolicy::Replace => {
+                let names = restored.iter()
+                    .map(|code| library_name(code))
+                    .collect::<Result<Vec<String>>>()?;
+                let names: Vec<&str> = names.iter().map(String::as_str).collect();
+                engine.codes_except(&names).collect()
+            },
+        };
+        *engine = Engine::build(self.scripting.create_lua(), kept.into_iter().chain(restored))?;
+        Ok(())
+    }
+
+    /// Describes the libraries whose name matches `pattern`, in the shape
+    /// of FUNCTION LIST.
+    pub fn list(&self, pattern: Option<&str>, with_code: bool) -> CommandResponse {
+        let engine = self.engine.lock().unwrap();
+        let str = |s: &str| CommandResponse::STR(s.to_string());
+        let libraries = engine.libraries.iter()
+            .filter(|(name, _)| pattern.is_none_or(|pattern| glob_match(pattern, name)))
+            .map(|(name, library)| {
+                let functions = library.functions.iter()
+                    .map(|function_name| {
+                        let function = &engine.functions[function_name];
+                        CommandResponse::ARRAY(vec![
+                            str("name"),
+                            str(function_name),
+                            str("description"),
+                            function.description.as_deref().map_or(CommandResponse::NIL, str),
+                            str("flags"),
+                            CommandResponse::ARRAY(function.flags.iter().map(|flag| str(flag)).collect()),
+                        ])
+                    })
+                    .collect();
+                let mut fields = vec![
+                    str("library_name"),
+                    str(name),
+                    str("engine"),
+                    str("LUA"),
+                    str("functions"),
+                    CommandResponse::ARRAY(functions),
+                ];
+                if with_code {
+                    fields.extend([str("library_code"), str(&library.code)]);
+                }
+                CommandResponse::ARRAY(fields)
+            })
+            .collect();
+        CommandResponse::ARRAY(libraries)
+    }
+
+    /// Counts for FUNCTION STATS.
+    pub fn stats(&self) -> (usize, usize) {
+        let engine = self.engine.lock().unwrap();
+        (engine.libraries.len(), engine.functions.len())
+    }
+
+    /// Runs the function `name` with FCALL, or FCALL_RO when `read_only` is
+    /// set, routing `redis.call` through `call`.
+    pub fn call<F>(&self, name: &str, keys: Vec<String>, args: Vec<String>, read_only: bool, call: F) -> CommandResponse
+    where
+        F: Fn(CommandRequest) -> Result<CommandResponse>,
+    {
+        let engine = self.engine.lock().unwrap();
+        let Some(function) = engine.functions.get(name) else {
+            return CommandResponse::ERR("ERR Function not found".to_string());
+        };
+        let no_writes = function.flags.iter().any(|flag| flag == "no-writes");
+        if read_only && !no_writes {
+            return CommandResponse::ERR("ERR Can not execute a script with write flag using *_ro command.".to_string());
+        }
+
+        let lua = &engine.lua;
+        self.scripting.run(lua, name, no_writes, call, || {
+            let callback: Function = lua.registry_value(&function.callback)?;
+            callback.call((keys, args))
+        })
+    }
+}
+
+impl Engine {
+    fn new(lua: Lua) -> Engine {
+        Engine {
+            lua,
+            libraries: BTreeMap::new(),
+            functions: HashMap::new(),
+        }
+    }
+
+    fn build(lua: Lua, codes: impl IntoIterator<Item = String>) -> Result<Engine> {
+        let mut engine = Engine::new(lua);
+        for code in codes {
+            engine.load(code)?;
+        }
+        Ok(engine)
+    }
+
+    fn codes_except<'a>(&'a self, names: &'a [&str]) -> impl Iterator<Item = String> + 'a {
+        self.libraries.iter()
+            .filter(|(name, _)| !names.contains(&name.as_str()))
+            .map(|(_, library)| library.code.to_owned())
+    }
+
+    /// Runs the library code, which is expected to call
+    /// `redis.register_function` for each of its functions.
+    fn load(&mut self, code: String) -> Result<()> {
+        let name = library_name(&code)?;
+        if self.libraries.contains_key(&name) {
+            return Err(anyhow!("ERR Library '{name}' already exists"));
+        }
+
+        let registered = RefCell::new(vec![]);
+        let lua = &self.lua;
+        lua.scope(|scope| {
+            let redis: Table = lua.globals().get("redis")?;
+            redis.set("register_function", scope.create_function(|lua, values: MultiValue| {
+                registered.borrow_mut().push(registration(lua, values)?);
+                Ok(())
+            })?)?;
+            // Lua doesn't understand the shebang, keep its newline so line numbers still match
+            let body = code.find('\n').map_or("", |newline| &code[newline..]);
+            lua.load(body).set_name("@user_function").exec()
+        }).map_err(|err| anyhow!("ERR Error registering functions: {}", first_line(&err)))?;
+
+        let registered = registered.into_inner();
+        if registered.is_empty() {
+            return Err(anyhow!("ERR No functions registered"));
+        }
+        let mut functions = vec![];
+        for (function_name, callback, flags, description) in registered {
+            if self.functions.contains_key(&function_name) {
+                return Err(anyhow!("ERR Function {function_name} already exists"));
+            }
+            functions.push(function_name.clone());
+            self.functions.insert(function_name, RegisteredFunction {
+                callback,
+                flags,
+                description,
+            });
+        }
+        self.libraries.insert(name, Library { code, functions });
+        Ok(())
+    }
+}
+
+type Registration = (String, RegistryKey, Vec<String>, Option<String>);
+
+/// Reads the arguments of `redis.register_function`, which are either a
+/// name and a callback, or a table with named fields.
+fn registration(lua: &Lua, values: MultiValue) -> mlua::Result<Registration> {
+    let values: Vec<Value> = values.into_iter().collect();
+    let (name, callback, flags, description) = match values.as_slice() {
+        [Value::String(name), Value::Function(callback)] => {
+            (name.to_str()?.to_string(), callback.clone(), vec![], None)
+        },
+        [Value::Table(table)] => {
+            let name: String = table.get("function_name")?;
+            let callback: Function = table.get("callback")
+                .map_err(|_| mlua::Error::RuntimeError("callback argument given to redis.register_function must be a function".to_string()))?;
+            let flags: Option<Vec<String>> = table.get("flags")?;
+            let description: Option<String> = table.get("description")?;
+            (name, callback, flags.unwrap_or_default(), description)
+        },
+        _ => return Err(mlua::Error::RuntimeError("wrong arguments given to redis.register_function".to_string())),
+    };
+
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(mlua::Error::RuntimeError(
+            "Function names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string()
+        ));
+    }
+    if let Some(flag) = flags.iter().find(|flag| !FUNCTION_FLAGS.contains(&flag.as_str())) {
+        return Err(mlua::Error::RuntimeError(format!("unknown flag given: {flag}")));
+    }
+    Ok((name, lua.create_registry_value(callback)?, flags, description))
+}
+
+/// Reads the library name from the `#!lua name=<name>` shebang that must
+/// start every library.
+fn library_name(code: &str) -> Result<String> {
+    let shebang = code.lines().next()
+        .and_then(|line| line.strip_prefix("#!"))
+        .ok_or_else(|| anyhow!("ERR Missing library metadata"))?;
+    let mut parts = shebang.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(anyhow!("ERR Engine '{engine}' not found"));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(n) => name = Some(n.to_string()),
+            None => return Err(anyhow!("ERR Invalid metadata value given: {part}")),
+        }
+    }
+    name.ok_or_else(|| anyhow!("ERR Library name was not given"))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const LIBRARY: &str = "#!lua name=mylib\n\
+        redis.register_function('echo_key', function(keys, args) return keys[1] end)\n\
+        redis.register_function{function_name='ro', callback=function() return 1 end, flags={'no-writes'}}";
+
+    fn no_call(_: CommandRequest) -> Result<CommandResponse> {
+        Ok(CommandResponse::NIL)
+    }
+
+    #[test]
+    fn test_load_and_call() {
+        let functions = Functions::new(Arc::new(Scripting::new()));
+        assert_eq!("mylib", functions.load(LIBRARY.to_string(), false).unwrap());
+        assert!(functions.load(LIBRARY.to_string(), false).is_err());
+
+        assert!(matches!(
+            functions.call("echo_key", vec!["k".to_string()], vec![], false, no_call),
+            CommandResponse::STR(key) if key == "k"
+        ));
+        assert!(matches!(
+            functions.call("echo_key", vec![], vec![], true, no_call),
+            CommandResponse::ERR(_)
+        ));
+        assert!(matches!(functions.call("ro", vec![], vec![], true, no_call), CommandResponse::INT(1)));
+    }
+
+    #[test]
+    fn test_dump_and_restore() {
+        let functions = Functions::new(Arc::new(Scripting::new()));
+        functions.load(LIBRARY.to_string(), false).unwrap();
+        let payload = functions.dump();
+
+        assert!(functions.restore(&payload, RestorePolicy::Append).is_err());
+        functions.restore(&payload, RestorePolicy::Replace).unwrap();
+
+        functions.flush();
+        assert_eq!((0, 0), functions.stats());
+        functions.restore(&payload, RestorePolicy::Append).unwrap();
+        assert_eq!((1, 2), functions.stats());
+    }
+
+    #[test]
+    fn test_restore_rejects_huge_compressed_code() {
+        let functions = Functions::new(Arc::new(Scripting::new()));
+        // LZF compressed code claiming to decompress to 2^50 bytes
+        let mut payload = vec![RDB_OPCODE_FUNCTION2, 0xc3];
+        crate::rdb::write_length(&mut payload, 2);
+        crate::rdb::write_length(&mut payload, 1 << 50);
+        payload.extend([0, b'a']);
+        assert!(functions.restore(&dump_payload(payload), RestorePolicy::Append).is_err());
+        assert_eq!((0, 0), functions.stats());
+    }
+
+    #[test]
+    fn test_library_name() {
+        assert_eq!("lib", library_name("#!lua name=lib\nreturn").unwrap());
+        assert!(library_name("#!js name=lib\n").is_err());
+        assert!(library_name("#!lua\n").is_err());
+        assert!(library_name("return 1").is_err());
+    }
+}
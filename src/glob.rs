@@ -0,0 +1,108 @@
+/// Glob-style matching as done by Redis for KEYS-like patterns: `*`, `?`,
+/// `[...]` classes (with `^` negation and `a-z` ranges) and `\` escapes.
+pub fn glob_match(pattern: &str, string: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let string: Vec<char> = string.chars().collect();
+    matches(&pattern, &string)
+}
+
+/// Only ever goes back to the last `*` seen when the rest doesn't match,
+/// which is enough as everything else matches a single character, so it
+/// takes at most pattern × string steps. Recursing on every `*` instead
+/// takes exponential time on patterns like `*a*a*a*a*b`.
+fn matches(pattern: &[char], string: &[char]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // pattern past the last `*`, and where in the string it's being tried
+    let mut star = None;
+    while s < string.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, s));
+            continue;
+        }
+        if let Some(rest) = match_one(&pattern[p..], string[s]) {
+            p = pattern.len() - rest.len();
+            s += 1;
+            continue;
+        }
+        match star {
+            Some((after_star, tried)) => {
+                // let the `*` take one more character
+                star = Some((after_star, tried + 1));
+                (p, s) = (after_star, tried + 1);
+            },
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `c` against the element `pattern` starts with, other than `*`,
+/// returning the pattern that follows it.
+fn match_one(pattern: &[char], c: char) -> Option<&[char]> {
+    match pattern.first()? {
+        '?' => Some(&pattern[1..]),
+        '[' => match match_class(&pattern[1..], c) {
+            (true, rest) => Some(rest),
+            (false, _) => None,
+        },
+        '\\' if pattern.len() > 1 => (pattern[1] == c).then(|| &pattern[2..]),
+        p => (*p == c).then(|| &pattern[1..]),
+    }
+}
+
+/// Matches `c` against the class starting right after `[`, returning the
+/// pattern that follows the closing `]`.
+fn match_class(pattern: &[char], c: char) -> (bool, &[char]) {
+    let (negated, mut i) = match pattern.first() {
+        Some('^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != ']' {
+        if pattern[i] == '\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            let (start, end) = (pattern[i].min(pattern[i + 2]), pattern[i].max(pattern[i + 2]));
+            matched |= start <= c && c <= end;
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    let rest = pattern.get(i + 1..).unwrap_or_default();
+    (matched != negated, rest)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(glob_match("h*llo", "heeeello"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-b]llo", "hbllo"));
+        assert!(glob_match("maxmemory-*", "maxmemory-policy"));
+        assert!(glob_match("a\\*", "a*"));
+        assert!(!glob_match("a\\*", "ab"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("**", ""));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+        assert!(glob_match("*[0-9]", "key9"));
+    }
+
+    #[test]
+    fn test_glob_match_many_stars() {
+        let pattern = format!("{}b", "*a".repeat(30));
+        assert!(!glob_match(&pattern, &"a".repeat(10_000)));
+        assert!(glob_match(&pattern, &format!("{}b", "a".repeat(10_000))));
+    }
+}
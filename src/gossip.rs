@@ -0,0 +1,346 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::cluster::{Cluster, SLOTS};
+
+const SIGNATURE: &[u8; 4] = b"RCmb";
+
+const VERSION: u16 = 1;
+
+const NODE_ID_LEN: usize = 40;
+
+/// Room for any textual IPv6 address, as in Redis.
+const IP_LEN: usize = 46;
+
+const SLOT_BITMAP_LEN: usize = SLOTS as usize / 8;
+
+const HEADER_LEN: usize = 4 + 4 + 2 + 2 + 8 + 8 + NODE_ID_LEN + SLOT_BITMAP_LEN + IP_LEN + 2 + 2 + 2 + 2;
+
+const GOSSIP_LEN: usize = NODE_ID_LEN + IP_LEN + 2 + 2 + 2;
+
+/// Generous bound on a message, so a garbled length can't make us allocate
+/// anything huge.
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// How often the cluster cron runs: sending pings, detecting failures and
+/// saving the configuration.
+const CRON_PERIOD: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageType {
+    Ping,
+    Pong,
+    /// A ping that also asks the receiver to add us to its nodes.
+    Meet,
+    /// Tells that the node in the message is down for the majority.
+    Fail,
+}
+
+impl MessageType {
+    fn code(self) -> u16 {
+        match self {
+            MessageType::Ping => 0,
+            MessageType::Pong => 1,
+            MessageType::Meet => 2,
+            MessageType::Fail => 3,
+        }
+    }
+
+    fn from_code(code: u16) -> Result<MessageType> {
+        match code {
+            0 => Ok(MessageType::Ping),
+            1 => Ok(MessageType::Pong),
+            2 => Ok(MessageType::Meet),
+            3 => Ok(MessageType::Fail),
+            x => Err(anyhow!("unknown cluster message type {x}")),
+        }
+    }
+}
+
+/// A node as described in messages, about the sender or in gossip sections.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeGossip {
+    pub id: String,
+    pub host: String,
+    pub port: u16,
+    pub cport: u16,
+    pub flags: u16,
+}
+
+/// What nodes send each other over the cluster bus: the sender's view of
+/// itself and of a few other nodes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub kind: MessageType,
+    pub current_epoch: u64,
+    pub config_epoch: u64,
+    pub sender: NodeGossip,
+    /// The slots the sender serves.
+    pub slots: Vec<u16>,
+    pub gossip: Vec<NodeGossip>,
+    /// The failing node, for FAIL messages.
+    pub failing: Option<String>,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.gossip.len() * GOSSIP_LEN + NODE_ID_LEN);
+        bytes.extend_from_slice(SIGNATURE);
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&VERSION.to_be_bytes());
+        bytes.extend_from_slice(&self.kind.code().to_be_bytes());
+        bytes.extend_from_slice(&self.current_epoch.to_be_bytes());
+        bytes.extend_from_slice(&self.config_epoch.to_be_bytes());
+        put_fixed(&mut bytes, &self.sender.id, NODE_ID_LEN);
+        let mut bitmap = [0u8; SLOT_BITMAP_LEN];
+        for slot in &self.slots {
+            bitmap[*slot as usize / 8] |= 1 << (slot % 8);
+        }
+        bytes.extend_from_slice(&bitmap);
+        put_fixed(&mut bytes, &self.sender.host, IP_LEN);
+        bytes.extend_from_slice(&self.sender.port.to_be_bytes());
+        bytes.extend_from_slice(&self.sender.cport.to_be_bytes());
+        bytes.extend_from_slice(&self.sender.flags.to_be_bytes());
+        bytes.extend_from_slice(&(self.gossip.len() as u16).to_be_bytes());
+        for node in &self.gossip {
+            put_fixed(&mut bytes, &node.id, NODE_ID_LEN);
+            put_fixed(&mut bytes, &node.host, IP_LEN);
+            bytes.extend_from_slice(&node.port.to_be_bytes());
+            bytes.extend_from_slice(&node.cport.to_be_bytes());
+            bytes.extend_from_slice(&node.flags.to_be_bytes());
+        }
+        if let Some(failing) = &self.failing {
+            put_fixed(&mut bytes, failing, NODE_ID_LEN);
+        }
+        let len = (bytes.len() as u32).to_be_bytes();
+        bytes[4..8].copy_from_slice(&len);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Message> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != SIGNATURE {
+            return Err(anyhow!("not a cluster bus message"));
+        }
+        let mut reader = Reader { bytes, position: 8 };
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(anyhow!("unsupported cluster bus version {version}"));
+        }
+        let kind = MessageType::from_code(reader.u16()?)?;
+        let current_epoch = reader.u64()?;
+        let config_epoch = reader.u64()?;
+        let id = reader.fixed(NODE_ID_LEN)?;
+        let bitmap = reader.take(SLOT_BITMAP_LEN)?;
+        let slots = (0..SLOTS).filter(|slot| bitmap[*slot as usize / 8] & (1 << (slot % 8)) != 0).collect();
+        let sender = NodeGossip { id, host: reader.fixed(IP_LEN)?, port: reader.u16()?, cport: reader.u16()?, flags: reader.u16()? };
+        let count = reader.u16()?;
+        let gossip = (0..count)
+            .map(|_| Ok(NodeGossip {
+                id: reader.fixed(NODE_ID_LEN)?,
+                host: reader.fixed(IP_LEN)?,
+                port: reader.u16()?,
+                cport: reader.u16()?,
+                flags: reader.u16()?,
+            }))
+            .collect::<Result<Vec<_>>>()?;
+        let failing = match kind {
+            MessageType::Fail => Some(reader.fixed(NODE_ID_LEN)?),
+            _ => None,
+        };
+        Ok(Message { kind, current_epoch, config_epoch, sender, slots, gossip, failing })
+    }
+}
+
+/// Writes `value` padded with zeros to `len` bytes.
+fn put_fixed(bytes: &mut Vec<u8>, value: &str, len: usize) {
+    let value = &value.as_bytes()[..value.len().min(len)];
+    bytes.extend_from_slice(value);
+    bytes.resize(bytes.len() + len - value.len(), 0);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.bytes.get(self.position..self.position + len)
+            .ok_or_else(|| anyhow!("truncated cluster bus message"))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    /// Reads a zero padded string.
+    fn fixed(&mut self, len: usize) -> Result<String> {
+        let bytes = self.take(len)?;
+        let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(len);
+        Ok(String::from_utf8(bytes[..end].to_vec())?)
+    }
+}
+
+async fn read_message(stream: &mut TcpStream) -> Result<Message> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).await?;
+    let len = u32::from_be_bytes(header[4..8].try_into()?) as usize;
+    if &header[..4] != SIGNATURE || !(HEADER_LEN..=MAX_MESSAGE_LEN).contains(&len) {
+        return Err(anyhow!("not a cluster bus message"));
+    }
+    let mut bytes = header.to_vec();
+    bytes.resize(len, 0);
+    stream.read_exact(&mut bytes[8..]).await?;
+    Message::decode(&bytes)
+}
+
+/// Where the cron wants a message sent: to a known node, or to an address
+/// we were asked to meet.
+#[derive(Debug)]
+pub struct Target {
+    pub id: Option<String>,
+    pub host: String,
+    pub cport: u16,
+    pub kind: MessageType,
+}
+
+/// Talks to the other nodes of the cluster: answers their pings on the bus
+/// port, and pings them in turn so failures get noticed.
+pub struct ClusterBus {
+    cluster: Arc<Cluster>,
+}
+
+impl ClusterBus {
+    pub fn new(cluster: Arc<Cluster>) -> ClusterBus {
+        ClusterBus { cluster }
+    }
+
+    pub async fn run(self: Arc<Self>, listener: TcpListener) {
+        let cron = self.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(CRON_PERIOD);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                for target in cron.cluster.cron() {
+                    cron.send(target);
+                }
+                if let Err(err) = cron.cluster.save_if_changed() {
+                    warn!(target: "cluster", "failed saving the cluster configuration: {err}");
+                }
+            }
+        });
+
+        loop {
+            let (stream, address) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(target: "cluster", "failed accepting a bus connection: {err}");
+                    continue;
+                },
+            };
+            let bus = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = bus.serve(stream).await {
+                    debug!(target: "cluster", "closing bus connection with {address}: {err}");
+                }
+            });
+        }
+    }
+
+    /// Handles messages from another node, answering its pings.
+    async fn serve(&self, mut stream: TcpStream) -> Result<()> {
+        loop {
+            let message = read_message(&mut stream).await?;
+            let failed = self.cluster.receive(&message);
+            if let MessageType::Ping | MessageType::Meet = message.kind {
+                stream.write_all(&self.cluster.message(MessageType::Pong, None).encode()).await?;
+            }
+            self.broadcast_fail(failed);
+        }
+    }
+
+    /// Sends a ping or meet in the background, handling the pong it gets.
+    fn send(self: &Arc<Self>, target: Target) {
+        let bus = self.clone();
+        tokio::spawn(async move {
+            let timeout = bus.cluster.node_timeout();
+            let exchange = async {
+                let mut stream = TcpStream::connect((target.host.as_str(), target.cport)).await?;
+                stream.write_all(&bus.cluster.message(target.kind, None).encode()).await?;
+                read_message(&mut stream).await
+            };
+            let result = time::timeout(timeout, exchange).await.unwrap_or_else(|_| Err(anyhow!("timed out")));
+            match result {
+                Ok(pong) if pong.kind == MessageType::Pong => {
+                    if target.id.is_none() {
+                        info!(target: "cluster", "met node {} at {}:{}", pong.sender.id, target.host, target.cport);
+                    }
+                    let failed = bus.cluster.receive(&pong);
+                    bus.broadcast_fail(failed);
+                },
+                Ok(reply) => debug!(target: "cluster", "unexpected reply from {}:{}: {:?}", target.host, target.cport, reply.kind),
+                Err(err) => debug!(target: "cluster", "failed pinging {}:{}: {err}", target.host, target.cport),
+            }
+            if let Some(id) = &target.id {
+                bus.cluster.ping_done(id);
+            }
+        });
+    }
+
+    /// Tells every node about nodes we just flagged as failing.
+    fn broadcast_fail(&self, failed: Vec<String>) {
+        for id in failed {
+            let message = self.cluster.message(MessageType::Fail, Some(id)).encode();
+            for (host, cport) in self.cluster.peers() {
+                let message = message.clone();
+                tokio::spawn(async move {
+                    let sent = async {
+                        let mut stream = TcpStream::connect((host.as_str(), cport)).await?;
+                        stream.write_all(&message).await?;
+                        anyhow::Ok(())
+                    };
+                    if let Err(err) = sent.await {
+                        debug!(target: "cluster", "failed sending FAIL to {host}:{cport}: {err}");
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_message_round_trip() {
+        let node = |id: &str, port| NodeGossip { id: id.repeat(40), host: "127.0.0.1".to_string(), port, cport: port + 10000, flags: 2 };
+        let message = Message {
+            kind: MessageType::Fail,
+            current_epoch: 7,
+            config_epoch: 3,
+            sender: node("a", 7000),
+            slots: vec![0, 1, 5000, 16383],
+            gossip: vec![node("b", 7001), node("c", 7002)],
+            failing: Some("c".repeat(40)),
+        };
+        let bytes = message.encode();
+        assert_eq!(HEADER_LEN + 2 * GOSSIP_LEN + NODE_ID_LEN, bytes.len());
+        assert_eq!(message, Message::decode(&bytes).unwrap());
+        assert!(Message::decode(&bytes[..HEADER_LEN - 1]).is_err());
+    }
+}
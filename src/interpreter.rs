@@ -1,66 +1,1556 @@
-use dashmap::DashMap;
-use tokio::sync::{mpsc::Sender, Mutex};
-use std::{sync::Arc, time::Duration};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc::UnboundedSender, RwLock};
+use tokio::time::timeout;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{sync::{Arc, Mutex}, time::Duration};
 use anyhow::{Result, anyhow};
-use crate::commands::{CommandRequest, CommandResponse, InfoMode, ReplicationInfo, ReplicationRole};
+use log::{error, info, warn};
+use crate::aof::Aof;
+use crate::cluster::{key_slot, Cluster, BUS_PORT_OFFSET};
+use crate::config::Config;
+use crate::commands::{FromRESP, ToRESP, ClusterCommand, CommandRequest, ConfigCommand, CommandResponse, FunctionCommand, InfoMode, MemoryCommand, Migrate, ObjectCommand, RdbFormat, ReplconfCommand, ReplicationRole, RestoreOptions, ScriptCommand, SlotState};
+use crate::functions::Functions;
+use crate::glob::glob_match;
+use crate::encoding::{Hash, List, Set, ZSet};
+use crate::eviction::{EvictionPolicy, Evictor};
+use crate::expirator::Expiry;
+use crate::keyspace::{now_ms, Databases, Keyspace, Value, KEY_OVERHEAD, WRONGTYPE};
+use crate::protocol::RESP;
+use crate::rdb::{self, Snapshot, SnapshotEntry};
+use crate::replication::{gen_replica_id, DisklessSync, LinkStatus, MasterLink, ReplicaFeed, Replicas};
+use crate::saver::{parse_save_rules, Saver};
+use crate::scripting::Scripting;
+use crate::session::Session;
+use crate::stream::CommandStream;
+
+/// How often clients waiting for their turn check whether a script became busy.
+const BUSY_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+const BUSY_ERROR: &str = "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.";
+
+const READONLY_ERROR: &str = "READONLY You can't write against a read only replica.";
+
+const OOM_ERROR: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+const LRU_NOT_SELECTED_ERROR: &str = "ERR An LRU maxmemory policy is not selected, access time not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.";
+
+const LFU_NOT_SELECTED_ERROR: &str = "ERR An LFU maxmemory policy is not selected, access frequency not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.";
+
+/// Below this many bytes MEMORY DOCTOR has nothing to say.
+const DOCTOR_MIN_MEMORY: u64 = 5 * 1024 * 1024;
+
+const MASTERDOWN_ERROR: &str = "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.";
 
 #[derive(Clone)]
 pub struct Interpreter {
-    role: ReplicationRole,
-    replica_id: String,
-    master_repl_offset: u8,
-    cache: Arc<DashMap<String, String>>,
-    tx: Arc<Mutex<Sender<(String, Duration)>>>,
+    databases: Arc<Databases>,
+    evictor: Arc<Evictor>,
+    tx: UnboundedSender<Expiry>,
+    scripting: Arc<Scripting>,
+    functions: Arc<Functions>,
+    saver: Arc<Saver>,
+    aof: Option<Arc<Aof>>,
+    replicas: Arc<Replicas>,
+    link: Arc<MasterLink>,
+    /// Which slots we serve, in cluster mode.
+    cluster: Option<Arc<Cluster>>,
+    config: Arc<Config>,
+    /// Held by write commands while they run and get propagated, so the AOF
+    /// and replicas see them in the order they were applied.
+    write_order: Arc<Mutex<SelectedDbs>>,
+    /// Database our master's replication stream last SELECTed.
+    replicated_db: Arc<AtomicUsize>,
+    /// Commands run holding this for reading, while transactions and scripts
+    /// hold it for writing so nothing can interleave with them.
+    exec_lock: Arc<RwLock<()>>,
 }
 
 impl Interpreter {
-    pub async fn respond(self: &Self, cmd: CommandRequest) -> Result<CommandResponse> {
+    /// Entry point for client connections: takes care of MULTI/EXEC and
+    /// WATCH, which depend on the `session`, and runs anything else through
+    /// `respond`.
+    pub async fn handle(&self, session: &mut Session, cmd: CommandRequest) -> Result<CommandResponse> {
+        let offset = self.replicas.offset();
+        let waits = matches!(cmd, CommandRequest::WAIT(..));
+        let response = self.dispatch(session, cmd).await;
+        // the offset may also have moved because of other clients, which
+        // only makes WAIT stricter
+        if !waits && self.replicas.offset() != offset {
+            session.write_offset = self.replicas.offset();
+        }
+        response
+    }
+
+    async fn dispatch(&self, session: &mut Session, cmd: CommandRequest) -> Result<CommandResponse> {
+        if let CommandRequest::SCRIPT(ScriptCommand::Kill) | CommandRequest::FUNCTION(FunctionCommand::Kill) = cmd {
+            return Ok(match self.scripting.kill() {
+                Ok(()) => CommandResponse::OK,
+                Err(err) => CommandResponse::ERR(err.to_string()),
+            });
+        }
+        if self.scripting.is_busy() {
+            return Ok(CommandResponse::ERR(BUSY_ERROR.to_string()));
+        }
+        // ASKING lasts for one command, or for a whole transaction
+        let asking = match session.in_transaction() && !matches!(cmd, CommandRequest::EXEC) {
+            true => session.asking,
+            false => std::mem::take(&mut session.asking),
+        };
+        if self.link.refuses_stale_data() && !matches!(
+            cmd,
+            CommandRequest::PING | CommandRequest::INFO(_) | CommandRequest::REPLICAOF(_) |
+            CommandRequest::REPLCONF(_) | CommandRequest::PSYNC(..)
+        ) {
+            session.fail_transaction();
+            return Ok(CommandResponse::ERR(MASTERDOWN_ERROR.to_string()));
+        }
+        if let Some(cluster) = &self.cluster {
+            let keyspace = self.databases.get(session.db);
+            let exists = |key: &str| keyspace.expires_at(key).is_some();
+            let asking = asking || matches!(cmd, CommandRequest::RESTORE(_, _, RestoreOptions { asking: true, .. }));
+            // a transaction runs on one node, so its keys must share a slot
+            let routed = match &cmd {
+                CommandRequest::EXEC => {
+                    let keys: Vec<&str> = session.transaction.iter().flatten().flat_map(CommandRequest::keys).collect();
+                    cluster.route(&keys, asking, exists)
+                },
+                // keys of a migrating slot are moved from here, whether or
+                // not some already were
+                CommandRequest::MIGRATE(_) => cluster.route(&cmd.keys(), asking, |_| true),
+                cmd => cluster.route(&cmd.keys(), asking, exists),
+            };
+            if let Err(redirect) = routed {
+                if let CommandRequest::EXEC = cmd {
+                    session.transaction = None;
+                    session.transaction_failed = false;
+                    self.unwatch(session);
+                } else {
+                    session.fail_transaction();
+                }
+                return Ok(CommandResponse::ERR(redirect.to_string()));
+            }
+        }
+
+        match cmd {
+            CommandRequest::MULTI if session.in_transaction() => {
+                Ok(CommandResponse::ERR("ERR MULTI calls can not be nested".to_string()))
+            },
+            CommandRequest::MULTI => {
+                session.transaction = Some(vec![]);
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::EXEC => self.exec(session).await,
+            CommandRequest::DISCARD if !session.in_transaction() => {
+                Ok(CommandResponse::ERR("ERR DISCARD without MULTI".to_string()))
+            },
+            CommandRequest::DISCARD => {
+                session.transaction = None;
+                session.transaction_failed = false;
+                self.unwatch(session);
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::WATCH(_) if session.in_transaction() => {
+                Ok(CommandResponse::ERR("ERR WATCH inside MULTI is not allowed".to_string()))
+            },
+            CommandRequest::WATCH(keys) => {
+                let keyspace = self.databases.get(session.db);
+                for key in keys {
+                    let version = keyspace.watch(&key);
+                    session.watched.push((keyspace.clone(), key, version));
+                }
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::ASKING if self.cluster.is_none() => {
+                Ok(CommandResponse::ERR("ERR This instance has cluster support disabled".to_string()))
+            },
+            CommandRequest::ASKING => {
+                session.asking = true;
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::REPLCONF(ReplconfCommand::ListeningPort(port)) => {
+                session.replica_listening_port = Some(port);
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::REPLCONF(ReplconfCommand::Capa(capabilities)) => {
+                session.replica_capabilities.extend(capabilities);
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::REPLCONF(ReplconfCommand::GetAck(_) | ReplconfCommand::Ack(_)) => Ok(CommandResponse::OK),
+            CommandRequest::PSYNC(replid, offset) => {
+                let Some(address) = session.address else {
+                    return Ok(CommandResponse::ERR("ERR PSYNC is only allowed from client connections".to_string()));
+                };
+                let port = session.replica_listening_port.unwrap_or(address.port());
+                if let Some(feed) = self.replicas.register_continue(address.ip(), port, &replid, offset) {
+                    info!(target: "interpreter", "replica {}:{port} continues from offset {offset} of {replid}", address.ip());
+                    session.replica_feed = Some(feed);
+                    return Ok(CommandResponse::CONTINUE(self.replicas.replid()));
+                }
+                // a replica only has a consistent snapshot to give while in sync
+                if self.link.master().is_some() && self.link.status() != LinkStatus::Up {
+                    return Ok(CommandResponse::ERR("NOMASTERLINK Can't SYNC while not connected with my master".to_string()));
+                }
+                info!(target: "interpreter", "replica {}:{port} asked for PSYNC {replid} {offset}, starting a full resync", address.ip());
+                let diskless = self.replicas.diskless_sync_delay()
+                    .filter(|_| session.replica_capabilities.iter().any(|capability| capability == "eof"));
+                if let Some(delay) = diskless {
+                    let sync = self.diskless_sync(address.ip(), port, delay).await?;
+                    session.replica_feed = Some(sync.feed);
+                    return Ok(CommandResponse::FULLRESYNC(sync.replid, sync.offset, sync.rdb, RdbFormat::EofMark(gen_replica_id())));
+                }
+                // registered while nothing else runs, so every write after the
+                // snapshot reaches the replica; serializing it can wait
+                let (snapshot, replid, offset) = {
+                    let _guard = self.exec_lock.write().await;
+                    session.replica_feed = Some(self.replicas.register(address.ip(), port));
+                    self.write_order.lock().unwrap().replicas = None;
+                    (self.snapshot(), self.replicas.replid(), self.replicas.offset())
+                };
+                let rdb = tokio::task::spawn_blocking(move || rdb::serialize(&snapshot)).await?;
+                Ok(CommandResponse::FULLRESYNC(replid, offset, Arc::new(rdb), RdbFormat::Length))
+            },
+            cmd if session.in_transaction() => {
+                session.transaction.get_or_insert_with(Vec::new).push(cmd);
+                Ok(CommandResponse::QUEUED)
+            },
+            CommandRequest::UNWATCH => {
+                self.unwatch(session);
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::SELECT(db) => Ok(self.select(&mut session.db, db)),
+            CommandRequest::WAIT(..) if matches!(self.link.role(), ReplicationRole::Slave) => {
+                Ok(CommandResponse::ERR("ERR WAIT cannot be used with replica instances.".to_string()))
+            },
+            CommandRequest::WAIT(numreplicas, timeout) => {
+                let timeout = (timeout > 0).then(|| Duration::from_millis(timeout));
+                let acked = self.replicas.wait(numreplicas as usize, session.write_offset, timeout).await;
+                Ok(CommandResponse::INT(acked as i64))
+            },
+            cmd @ (CommandRequest::EVAL(..) | CommandRequest::EVALSHA(..) |
+                   CommandRequest::FCALL(..) | CommandRequest::FCALL_RO(..) |
+                   CommandRequest::SAVE | CommandRequest::BGSAVE | CommandRequest::BGREWRITEAOF |
+                   CommandRequest::REPLICAOF(_)) => {
+                let Some(_guard) = self.wait_turn(|| self.exec_lock.write()).await else {
+                    return Ok(CommandResponse::ERR(BUSY_ERROR.to_string()));
+                };
+                tokio::task::block_in_place(|| self.respond(session.db, cmd))
+            },
+            CommandRequest::MIGRATE(migrate) => {
+                let Some(_guard) = self.wait_turn(|| self.exec_lock.write()).await else {
+                    return Ok(CommandResponse::ERR(BUSY_ERROR.to_string()));
+                };
+                self.migrate(session.db, migrate).await
+            },
+            cmd => {
+                let Some(_guard) = self.wait_turn(|| self.exec_lock.read()).await else {
+                    return Ok(CommandResponse::ERR(BUSY_ERROR.to_string()));
+                };
+                self.respond(session.db, cmd)
+            },
+        }
+    }
+
+    /// Runs `cmd` against database `db`, logging it to the AOF and sending
+    /// it to replicas if it changed anything. Writes are refused while we're
+    /// a read only replica, and so are writes needing more memory once keys
+    /// can't be evicted to stay within maxmemory.
+    pub fn respond(&self, db: usize, cmd: CommandRequest) -> Result<CommandResponse> {
+        if cmd.is_write() && self.link.is_read_only() {
+            return Ok(CommandResponse::ERR(READONLY_ERROR.to_string()));
+        }
+        if !self.free_memory()? && cmd.is_denyoom() {
+            return Ok(CommandResponse::ERR(OOM_ERROR.to_string()));
+        }
+        self.run(db, cmd, true)
+    }
+
+    /// Evicts keys until the dataset fits in maxmemory, returning whether it
+    /// does. Evictions reach the AOF and replicas as DELs, replicas leaving
+    /// it to their master.
+    fn free_memory(&self) -> Result<bool> {
+        let config = self.databases.memory_config();
+        if config.maxmemory == 0 || self.link.master().is_some() {
+            return Ok(true);
+        }
+        while self.databases.used_memory() > config.maxmemory {
+            let Some((db, key)) = self.evictor.pick(&self.databases, &config) else {
+                return Ok(false);
+            };
+            self.run(db, CommandRequest::DEL(vec![key]), true)?;
+        }
+        Ok(true)
+    }
+
+    /// Runs `cmd` against database `db`, logging it to the AOF if it changed
+    /// anything, and sending it to replicas too when `propagate` is set.
+    fn run(&self, db: usize, cmd: CommandRequest, propagate: bool) -> Result<CommandResponse> {
+        if !cmd.is_write() {
+            return self.execute(db, cmd);
+        }
+        let mut selected = self.write_order.lock().unwrap();
+        let effects = self.effects(&cmd)?;
+        let response = self.execute(db, cmd)?;
+        if !matches!(response, CommandResponse::ERR(_)) {
+            let select = CommandRequest::SELECT(db).to_resp()?.encode();
+            for effect in effects {
+                let bytes = effect.encode();
+                if let Some(aof) = &self.aof {
+                    if let Err(err) = aof.append(&selecting(&mut selected.aof, db, &select, &bytes)) {
+                        error!(target: "interpreter", "failed writing to the AOF: {err}");
+                    }
+                }
+                // writes to a writable replica stay local, its replicas
+                // only get what its master sends
+                if propagate && self.link.master().is_none() {
+                    self.replicas.propagate(&selecting(&mut selected.replicas, db, &select, &bytes));
+                }
+            }
+        }
+        Ok(response)
+    }
+
+    /// Runs a command received from our master. Replies are dropped, and
+    /// running scripts are waited for rather than answered with BUSY. Our
+    /// replicas get the master's stream through `forward_replicated` instead.
+    pub async fn apply_replicated(&self, cmd: CommandRequest) -> Result<()> {
+        let _guard = self.exec_lock.read().await;
+        if let CommandRequest::SELECT(db) = cmd {
+            match db < self.databases.count() {
+                true => self.replicated_db.store(db, Ordering::Relaxed),
+                false => warn!(target: "interpreter", "master selected db {db}, which is out of range"),
+            }
+            return Ok(());
+        }
+        let db = self.replicated_db.load(Ordering::Relaxed);
+        if let CommandResponse::ERR(err) = tokio::task::block_in_place(|| self.run(db, cmd, false))? {
+            warn!(target: "interpreter", "command from the master failed: {err}");
+        }
+        Ok(())
+    }
+
+    /// Offset of the replication stream, produced by us as a master or
+    /// processed from our master as a replica.
+    pub fn repl_offset(&self) -> u64 {
+        self.replicas.offset()
+    }
+
+    /// Where to ask a master to continue replicating from, if anywhere.
+    pub fn psync_position(&self) -> Option<(String, i64)> {
+        self.replicas.psync_position()
+    }
+
+    pub fn switch_replid(&self, replid: String) {
+        self.replicas.switch_replid(replid);
+    }
+
+    /// Waits for the next diskless sync, started `delay` after the first
+    /// replica asks for it so that others asking meanwhile share it.
+    async fn diskless_sync(&self, ip: IpAddr, port: u16, delay: Duration) -> Result<DisklessSync> {
+        let (first, sync) = self.replicas.join_diskless_sync(ip, port);
+        if first {
+            let interpreter = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                // only taking the snapshot and registering the replicas needs
+                // nothing else to run, not serializing it
+                let (snapshot, started) = {
+                    let _guard = interpreter.exec_lock.write().await;
+                    interpreter.write_order.lock().unwrap().replicas = None;
+                    (interpreter.snapshot(), interpreter.replicas.start_diskless_sync())
+                };
+                match tokio::task::spawn_blocking(move || rdb::serialize(&snapshot)).await {
+                    Ok(rdb) => interpreter.replicas.finish_diskless_sync(started, rdb),
+                    Err(err) => error!(target: "interpreter", "failed serializing the snapshot for a diskless sync: {err}"),
+                }
+            });
+        }
+        Ok(sync.await?)
+    }
+
+    /// Pings our replicas through the replication stream, unless we're a
+    /// replica ourselves and just forward our master's.
+    pub fn ping_replicas(&self) -> Result<()> {
+        if self.link.master().is_none() && !self.replicas.is_empty() {
+            self.replicas.propagate(&CommandRequest::PING.to_resp()?.encode());
+        }
+        Ok(())
+    }
+
+    /// Adds bytes of our master's stream to our own, as is.
+    pub fn forward_replicated(&self, bytes: &[u8]) {
+        self.replicas.propagate(bytes);
+    }
+
+    /// Streams writes to a client that became a replica through PSYNC.
+    pub async fn serve_replica(&self, command_stream: &mut CommandStream, feed: ReplicaFeed) -> Result<()> {
+        self.replicas.serve(command_stream, feed).await
+    }
+
+    /// Commands that reproduce what `cmd` does to the dataset. Relative
+    /// expiry times are made absolute, so replaying them later gives the
+    /// same result.
+    fn effects(&self, cmd: &CommandRequest) -> Result<Vec<RESP>> {
+        match cmd {
+            CommandRequest::SET(key, value, Some(millis)) => Ok(vec![
+                CommandRequest::SET(key.clone(), value.clone(), None).to_resp()?,
+                CommandRequest::PEXPIREAT(key.clone(), now_ms() + millis).to_resp()?,
+            ]),
+            CommandRequest::RESTORE(key, payload, options) => {
+                let ttl = match options.ttl {
+                    ttl if ttl > 0 && !options.abs_ttl => now_ms() + ttl,
+                    ttl => ttl,
+                };
+                let options = RestoreOptions { ttl, abs_ttl: ttl > 0, asking: false, ..*options };
+                Ok(vec![CommandRequest::RESTORE(key.clone(), payload.clone(), options).to_resp()?])
+            },
+            cmd if cmd.is_write() => Ok(vec![cmd.to_resp()?]),
+            _ => Ok(vec![]),
+        }
+    }
+
+    fn execute(&self, db: usize, cmd: CommandRequest) -> Result<CommandResponse> {
+        let keyspace = self.databases.get(db);
         match cmd {
             CommandRequest::PING => Ok(CommandResponse::PONG),
             CommandRequest::ECHO(x) => Ok(CommandResponse::ECHO(x)),
             CommandRequest::SET(key, value, expiry) => {
-                self.cache.insert(key.clone(), value);
+                keyspace.set(key.clone(), Value::String(value.into()), expiry.map(|millis| now_ms() + millis));
 
-                match expiry {
-                    Some(millis) => {
-                        let tx = self.tx.lock().await;
-                        let _ = tx.send((key, Duration::from_millis(millis))).await;
-                    },
-                    None => (),
+                if let Some(millis) = expiry {
+                    let _ = self.tx.send((Arc::downgrade(&keyspace), key, Duration::from_millis(millis)));
                 }
-                
+
                 Ok(CommandResponse::OK)
             },
             CommandRequest::GET(key) => {
-                match self.cache.get(key.as_str()) {
-                    Some(value) => Ok(CommandResponse::STR(value.to_owned())),
+                match keyspace.get(key.as_str()) {
+                    Some(Value::String(value)) => Ok(CommandResponse::STR(value.to_string())),
+                    Some(_) => Ok(CommandResponse::ERR(WRONGTYPE.to_string())),
                     None => Ok(CommandResponse::NIL),
                 }
             },
+            CommandRequest::DEL(keys) => {
+                let removed = keys.iter()
+                    .filter(|key| keyspace.remove(key).is_some())
+                    .count();
+                Ok(CommandResponse::INT(removed as i64))
+            },
+            CommandRequest::RESTORE(key, payload, options) => {
+                if !options.replace && keyspace.view(&key, |_| ()).is_some() {
+                    return Ok(CommandResponse::ERR("BUSYKEY Target key name already exists.".to_string()));
+                }
+                let value = match rdb::restore_value(&payload) {
+                    Ok(value) => value,
+                    Err(err) => return Ok(CommandResponse::ERR(format!("ERR {err}"))),
+                };
+                let now = now_ms();
+                let expires_at = match options.ttl {
+                    0 => None,
+                    ttl if options.abs_ttl => Some(ttl),
+                    ttl => Some(now + ttl),
+                };
+                if expires_at.is_some_and(|at| at <= now) {
+                    keyspace.remove(&key);
+                    return Ok(CommandResponse::OK);
+                }
+                keyspace.set(key.clone(), value, expires_at);
+                // like OBJECT, only what the current policy goes by applies
+                match (self.databases.memory_config().policy.is_lfu(), options.idle_time, options.freq) {
+                    (true, _, Some(freq)) => keyspace.set_frequency(&key, freq),
+                    (false, Some(idle_time), _) => keyspace.set_idle_time(&key, idle_time.saturating_mul(1000)),
+                    _ => (),
+                }
+                if let Some(at) = expires_at {
+                    let _ = self.tx.send((Arc::downgrade(&keyspace), key, Duration::from_millis(at - now)));
+                }
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::DUMP(key) => Ok(
+                keyspace.view(&key, rdb::dump_value).map_or(CommandResponse::NIL, CommandResponse::BYTES)
+            ),
+            CommandRequest::MOVE(_, _) if self.cluster.is_some() => {
+                Ok(CommandResponse::ERR("ERR MOVE is not allowed in cluster mode".to_string()))
+            },
+            CommandRequest::MOVE(_, target) if target >= self.databases.count() => {
+                Ok(CommandResponse::ERR("ERR DB index is out of range".to_string()))
+            },
+            CommandRequest::MOVE(_, target) if target == db => {
+                Ok(CommandResponse::ERR("ERR source and destination objects are the same".to_string()))
+            },
+            CommandRequest::MOVE(key, target) => {
+                let destination = self.databases.get(target);
+                let Some(expires_at) = keyspace.expires_at(&key) else {
+                    return Ok(CommandResponse::INT(0));
+                };
+                if destination.view(&key, |_| ()).is_some() {
+                    return Ok(CommandResponse::INT(0));
+                }
+                let Some(value) = keyspace.remove(&key) else {
+                    return Ok(CommandResponse::INT(0));
+                };
+                destination.set(key.clone(), value, expires_at);
+                if let Some(at) = expires_at {
+                    let _ = self.tx.send((Arc::downgrade(&destination), key, Duration::from_millis(at.saturating_sub(now_ms()))));
+                }
+                Ok(CommandResponse::INT(1))
+            },
+            CommandRequest::SWAPDB(..) if self.cluster.is_some() => {
+                Ok(CommandResponse::ERR("ERR SWAPDB is not allowed in cluster mode".to_string()))
+            },
+            CommandRequest::SWAPDB(first, second) => {
+                if first.max(second) >= self.databases.count() {
+                    return Ok(CommandResponse::ERR("ERR DB index is out of range".to_string()));
+                }
+                self.databases.swap(first, second);
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::FLUSHDB(lazy) => {
+                self.databases.flush(db, lazy);
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::FLUSHALL(lazy) => {
+                for db in 0..self.databases.count() {
+                    self.databases.flush(db, lazy);
+                }
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::OBJECT(command) => Ok(self.object(&keyspace, command)),
+            CommandRequest::MEMORY(MemoryCommand::Usage(key, samples)) => Ok(
+                keyspace.memory_usage(&key, samples).map_or(CommandResponse::NIL, |bytes| CommandResponse::INT(bytes as i64))
+            ),
+            CommandRequest::MEMORY(MemoryCommand::Stats) => Ok(self.memory_stats()),
+            CommandRequest::MEMORY(MemoryCommand::Doctor) => Ok(CommandResponse::STR(self.memory_doctor())),
+            CommandRequest::CONFIG(command) => Ok(self.config(command)),
+            CommandRequest::KEYS(pattern) => Ok(CommandResponse::ARRAY(
+                keyspace.keys().into_iter()
+                    .filter(|key| glob_match(&pattern, key))
+                    .map(CommandResponse::STR)
+                    .collect()
+            )),
+            CommandRequest::TYPE(key) => Ok(CommandResponse::STATUS(
+                keyspace.view(&key, |value| value.type_name()).unwrap_or("none").to_string()
+            )),
+            CommandRequest::TTL(key) => Ok(CommandResponse::INT(
+                keyspace.ttl_ms(&key).map(|ttl| if ttl < 0 { ttl } else { (ttl + 500) / 1000 }).unwrap_or(-2)
+            )),
+            CommandRequest::PTTL(key) => Ok(CommandResponse::INT(keyspace.ttl_ms(&key).unwrap_or(-2))),
+            CommandRequest::PEXPIREAT(key, at) => {
+                let now = now_ms();
+                if at <= now {
+                    return Ok(CommandResponse::INT(keyspace.remove(&key).is_some() as i64));
+                }
+                if !keyspace.set_expiry(&key, Some(at)) {
+                    return Ok(CommandResponse::INT(0));
+                }
+                let _ = self.tx.send((Arc::downgrade(&keyspace), key, Duration::from_millis(at - now)));
+                Ok(CommandResponse::INT(1))
+            },
+            CommandRequest::RPUSH(key, elements) => Ok(
+                keyspace.update(&key, || Value::List(List::default()), |value| match value {
+                    Value::List(list) => {
+                        list.extend(elements);
+                        (CommandResponse::INT(list.len() as i64), true)
+                    },
+                    _ => (CommandResponse::ERR(WRONGTYPE.to_string()), false),
+                })
+            ),
+            CommandRequest::SADD(key, members) => Ok(
+                keyspace.update(&key, || Value::Set(Set::default()), |value| match value {
+                    Value::Set(set) => {
+                        let added = members.into_iter().filter(|member| set.insert(member.to_string())).count();
+                        (CommandResponse::INT(added as i64), added > 0)
+                    },
+                    _ => (CommandResponse::ERR(WRONGTYPE.to_string()), false),
+                })
+            ),
+            CommandRequest::ZADD(key, members) => Ok(
+                keyspace.update(&key, || Value::ZSet(ZSet::default()), |value| match value {
+                    Value::ZSet(zset) => {
+                        let (mut added, mut changed) = (0, false);
+                        for (score, member) in members {
+                            // a new score for a member is a change too
+                            changed |= zset.score(&member) != Some(score);
+                            added += zset.insert(member, score) as i64;
+                        }
+                        (CommandResponse::INT(added), changed)
+                    },
+                    _ => (CommandResponse::ERR(WRONGTYPE.to_string()), false),
+                })
+            ),
+            CommandRequest::HSET(key, fields) => Ok(
+                keyspace.update(&key, || Value::Hash(Hash::default()), |value| match value {
+                    // like Redis, HSET counts as a write even if values stay the same
+                    Value::Hash(hash) => {
+                        let added = fields.into_iter().filter(|(field, value)| hash.insert(field.to_string(), value.to_string())).count();
+                        (CommandResponse::INT(added as i64), true)
+                    },
+                    _ => (CommandResponse::ERR(WRONGTYPE.to_string()), false),
+                })
+            ),
+            CommandRequest::BGREWRITEAOF => Ok(match &self.aof {
+                Some(aof) => match self.rewrite_aof_with(|snapshot| aof.rewrite_in_background(snapshot)) {
+                    Ok(()) => CommandResponse::STATUS("Background append only file rewriting started".to_string()),
+                    Err(err) => CommandResponse::ERR(err.to_string()),
+                },
+                None => CommandResponse::ERR("ERR Background append only file rewriting needs appendonly yes".to_string()),
+            }),
+            CommandRequest::SAVE => Ok(
+                match self.saver.save(&self.snapshot(), self.databases.dirty()) {
+                    Ok(()) => CommandResponse::OK,
+                    Err(err) => CommandResponse::ERR(err.to_string()),
+                }
+            ),
+            CommandRequest::BGSAVE => Ok(
+                match self.saver.save_in_background(self.snapshot(), self.databases.dirty()) {
+                    Ok(()) => CommandResponse::STATUS("Background saving started".to_string()),
+                    Err(err) => CommandResponse::ERR(err.to_string()),
+                }
+            ),
+            CommandRequest::LASTSAVE => Ok(CommandResponse::INT(self.saver.last_save() as i64)),
             CommandRequest::DOCS => Ok(CommandResponse::DOCS),
-            CommandRequest::INFO(InfoMode::Replication) => Ok(CommandResponse::INFO(
-                ReplicationInfo::new(
-                    self.role.clone(),
-                    self.replica_id.to_owned(),
-                    self.master_repl_offset
-                )
+            CommandRequest::INFO(InfoMode::Replication) => Ok(CommandResponse::INFO(self.replicas.info(&self.link))),
+            // only sentinels have anything to say there
+            CommandRequest::INFO(InfoMode::Sentinel) => Ok(CommandResponse::STR(String::new())),
+            CommandRequest::SENTINEL(_) => Ok(CommandResponse::ERR("ERR unknown command 'SENTINEL'".to_string())),
+            CommandRequest::CLUSTER(command) => match &self.cluster {
+                Some(cluster) => Ok(self.cluster_command(cluster, command)),
+                None => Ok(CommandResponse::ERR("ERR This instance has cluster support disabled".to_string())),
+            },
+            CommandRequest::REPLICAOF(None) => {
+                if self.link.master().is_some() {
+                    info!(target: "interpreter", "no longer replicating, now a master");
+                    self.link.set_master(None);
+                    self.replicas.shift_replid();
+                }
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::REPLICAOF(Some(master)) => {
+                if self.link.master().as_ref() == Some(&master) {
+                    return Ok(CommandResponse::STATUS("OK Already connected to specified master".to_string()));
+                }
+                info!(target: "interpreter", "now replicating {}:{}", master.0, master.1);
+                self.link.set_master(Some(master));
+                Ok(CommandResponse::OK)
+            },
+            // inside a transaction WAIT can't block
+            CommandRequest::WAIT(..) => Ok(CommandResponse::INT(self.replicas.acked(self.replicas.offset()) as i64)),
+            // EXEC already dropped every watch by the time a queued UNWATCH runs
+            CommandRequest::UNWATCH => Ok(CommandResponse::OK),
+            CommandRequest::EVAL(script, keys, args) => Ok(
+                self.scripting.eval(script, keys, args, |cmd| self.respond(db, cmd))
+            ),
+            CommandRequest::EVALSHA(sha, keys, args) => Ok(
+                self.scripting.eval_sha(&sha, keys, args, |cmd| self.respond(db, cmd))
+            ),
+            CommandRequest::SCRIPT(ScriptCommand::Load(script)) => Ok(CommandResponse::STR(self.scripting.load(script))),
+            CommandRequest::SCRIPT(ScriptCommand::Exists(shas)) => Ok(CommandResponse::ARRAY(
+                shas.iter().map(|sha| CommandResponse::INT(self.scripting.exists(sha) as i64)).collect()
             )),
+            CommandRequest::SCRIPT(ScriptCommand::Flush) => {
+                self.scripting.flush();
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::FCALL(name, keys, args) => Ok(
+                self.functions.call(&name, keys, args, false, |cmd| self.respond(db, cmd))
+            ),
+            CommandRequest::FCALL_RO(name, keys, args) => Ok(
+                self.functions.call(&name, keys, args, true, |cmd| self.respond(db, cmd))
+            ),
+            CommandRequest::FUNCTION(FunctionCommand::Load(code, replace)) => Ok(
+                match self.functions.load(code, replace) {
+                    Ok(name) => CommandResponse::STR(name),
+                    Err(err) => CommandResponse::ERR(err.to_string()),
+                }
+            ),
+            CommandRequest::FUNCTION(FunctionCommand::List(pattern, with_code)) => Ok(
+                self.functions.list(pattern.as_deref(), with_code)
+            ),
+            CommandRequest::FUNCTION(FunctionCommand::Delete(name)) => Ok(
+                match self.functions.delete(&name) {
+                    Ok(()) => CommandResponse::OK,
+                    Err(err) => CommandResponse::ERR(err.to_string()),
+                }
+            ),
+            CommandRequest::FUNCTION(FunctionCommand::Flush) => {
+                self.functions.flush();
+                Ok(CommandResponse::OK)
+            },
+            CommandRequest::FUNCTION(FunctionCommand::Dump) => Ok(CommandResponse::BYTES(self.functions.dump())),
+            CommandRequest::FUNCTION(FunctionCommand::Restore(payload, policy)) => Ok(
+                match self.functions.restore(&payload, policy) {
+                    Ok(()) => CommandResponse::OK,
+                    Err(err) => CommandResponse::ERR(err.to_string()),
+                }
+            ),
+            CommandRequest::FUNCTION(FunctionCommand::Stats) => {
+                let (libraries, functions) = self.functions.stats();
+                Ok(CommandResponse::ARRAY(vec![
+                    CommandResponse::STR("running_script".to_string()),
+                    CommandResponse::NIL,
+                    CommandResponse::STR("engines".to_string()),
+                    CommandResponse::ARRAY(vec![
+                        CommandResponse::STR("LUA".to_string()),
+                        CommandResponse::ARRAY(vec![
+                            CommandResponse::STR("libraries_count".to_string()),
+                            CommandResponse::INT(libraries as i64),
+                            CommandResponse::STR("functions_count".to_string()),
+                            CommandResponse::INT(functions as i64),
+                        ]),
+                    ]),
+                ]))
+            },
             x => Err(anyhow!("unexpected command: {:?}", x))
         }
     }
 
-    pub(crate) fn new(
-        replica_id: String,
-        role: ReplicationRole,
-        cache: Arc<DashMap<String, String>>,
-        tx: Arc<Mutex<Sender<(String, Duration)>>>
+    fn cluster_command(&self, cluster: &Cluster, command: ClusterCommand) -> CommandResponse {
+        // cluster mode only has db 0
+        let slot_keys = |slot| self.databases.get(0).keys().into_iter().filter(move |key| key_slot(key) == slot);
+        let done = |result: Result<()>| match result {
+            Ok(()) => CommandResponse::OK,
+            Err(err) => CommandResponse::ERR(format!("ERR {err}")),
+        };
+        match command {
+            ClusterCommand::Info => CommandResponse::STR(cluster.info()),
+            ClusterCommand::MyId => CommandResponse::STR(cluster.myself().to_string()),
+            ClusterCommand::Nodes => CommandResponse::STR(cluster.nodes()),
+            ClusterCommand::Slots => CommandResponse::ARRAY(
+                cluster.ranges().into_iter()
+                    .map(|(start, end, node)| CommandResponse::ARRAY(vec![
+                        CommandResponse::INT(start as i64),
+                        CommandResponse::INT(end as i64),
+                        CommandResponse::ARRAY(vec![
+                            CommandResponse::STR(node.host),
+                            CommandResponse::INT(node.port as i64),
+                            CommandResponse::STR(node.id),
+                        ]),
+                    ]))
+                    .collect()
+            ),
+            ClusterCommand::Shards => CommandResponse::ARRAY(
+                cluster.shards().into_iter()
+                    .map(|(node, slots)| {
+                        let offset = if node.id == cluster.myself() { self.replicas.offset() } else { 0 };
+                        CommandResponse::ARRAY(vec![
+                            CommandResponse::STR("slots".to_string()),
+                            CommandResponse::ARRAY(slots.into_iter()
+                                .flat_map(|(start, end)| [CommandResponse::INT(start as i64), CommandResponse::INT(end as i64)])
+                                .collect()),
+                            CommandResponse::STR("nodes".to_string()),
+                            CommandResponse::ARRAY(vec![CommandResponse::ARRAY(vec![
+                                CommandResponse::STR("id".to_string()),
+                                CommandResponse::STR(node.id),
+                                CommandResponse::STR("port".to_string()),
+                                CommandResponse::INT(node.port as i64),
+                                CommandResponse::STR("ip".to_string()),
+                                CommandResponse::STR(node.host.clone()),
+                                CommandResponse::STR("endpoint".to_string()),
+                                CommandResponse::STR(node.host),
+                                CommandResponse::STR("role".to_string()),
+                                CommandResponse::STR("master".to_string()),
+                                CommandResponse::STR("replication-offset".to_string()),
+                                CommandResponse::INT(offset as i64),
+                                CommandResponse::STR("health".to_string()),
+                                CommandResponse::STR("online".to_string()),
+                            ])]),
+                        ])
+                    })
+                    .collect()
+            ),
+            ClusterCommand::KeySlot(key) => CommandResponse::INT(key_slot(&key) as i64),
+            ClusterCommand::CountKeysInSlot(slot) => CommandResponse::INT(slot_keys(slot).count() as i64),
+            ClusterCommand::GetKeysInSlot(slot, count) => CommandResponse::ARRAY(
+                slot_keys(slot).take(count as usize).map(CommandResponse::STR).collect()
+            ),
+            ClusterCommand::AddSlots(slots) => done(cluster.add_slots(&slots)),
+            ClusterCommand::AddSlotsRange(ranges) => {
+                let slots: Vec<u16> = ranges.into_iter().flat_map(|(start, end)| start..=end).collect();
+                done(cluster.add_slots(&slots))
+            },
+            ClusterCommand::DelSlots(slots) => done(cluster.del_slots(&slots)),
+            ClusterCommand::Meet(host, port, cport) => {
+                if host.parse::<IpAddr>().is_err() {
+                    return CommandResponse::ERR(format!("ERR Invalid node address specified: {host}:{port}"));
+                }
+                let Some(cport) = cport.or(port.checked_add(BUS_PORT_OFFSET)) else {
+                    return CommandResponse::ERR(format!("ERR Invalid base port specified: {port}"));
+                };
+                cluster.meet(host, cport);
+                CommandResponse::OK
+            },
+            ClusterCommand::SetSlot(slot, state) => done(match state {
+                SlotState::Migrating(node) => cluster.set_slot_migrating(slot, &node),
+                SlotState::Importing(node) => cluster.set_slot_importing(slot, &node),
+                SlotState::Stable => {
+                    cluster.set_slot_stable(slot);
+                    Ok(())
+                },
+                SlotState::Node(node) => cluster.set_slot_node(slot, &node, slot_keys(slot).count()),
+            }),
+        }
+    }
+
+    /// Sends keys to another instance with RESTORE, then deletes the ones it
+    /// accepted unless copying. Runs while holding the execution lock for
+    /// writing, so keys never exist on both sides for other clients. Like
+    /// Redis, the first error of the target is reported after that.
+    ///
+    /// The round trip is awaited rather than blocked on, and only the DEL
+    /// of the moved keys goes through `run`, so that's all the AOF and
+    /// replicas get.
+    async fn migrate(&self, db: usize, migrate: Migrate) -> Result<CommandResponse> {
+        if !migrate.copy && self.link.is_read_only() {
+            return Ok(CommandResponse::ERR(READONLY_ERROR.to_string()));
+        }
+        let keyspace = self.databases.get(db);
+        let restores: Vec<CommandRequest> = migrate.keys.iter()
+            .filter_map(|key| {
+                let payload = rdb::dump_value(&keyspace.get(key)?);
+                let ttl = match keyspace.ttl_ms(key) {
+                    Some(ttl) if ttl >= 0 => ttl.max(1) as u64,
+                    _ => 0,
+                };
+                let options = RestoreOptions {
+                    ttl,
+                    replace: migrate.replace,
+                    abs_ttl: false,
+                    idle_time: None,
+                    freq: None,
+                    asking: self.cluster.is_some(),
+                };
+                Some(CommandRequest::RESTORE(key.clone(), payload, options))
+            })
+            .collect();
+        if restores.is_empty() {
+            return Ok(CommandResponse::STATUS("NOKEY".to_string()));
+        }
+        let moved: Vec<String> = restores.iter().flat_map(|request| request.keys()).map(str::to_string).collect();
+        let requests = std::iter::once(CommandRequest::SELECT(migrate.db)).chain(restores).collect();
+
+        let timeout = Duration::from_millis(migrate.timeout);
+        let replies = match send_requests(&migrate.host, migrate.port, requests, timeout).await {
+            Ok(replies) => replies,
+            Err(err) => return Ok(CommandResponse::ERR(format!("IOERR error or timeout talking to target instance: {err}"))),
+        };
+        let target_error = |err| CommandResponse::ERR(format!("ERR Target instance replied with error: {err}"));
+        // keys would have been restored in the wrong database
+        if let Some(RESP::SimpleError(err)) = replies.first() {
+            return Ok(target_error(err));
+        }
+        let mut error = None;
+        let mut accepted = vec![];
+        for (key, reply) in moved.into_iter().zip(&replies[1..]) {
+            match reply {
+                RESP::SimpleError(err) => {
+                    error.get_or_insert(err);
+                },
+                _ => accepted.push(key),
+            }
+        }
+        if !migrate.copy && !accepted.is_empty() {
+            self.run(db, CommandRequest::DEL(accepted), true)?;
+        }
+        Ok(match error {
+            Some(err) => target_error(err),
+            None => CommandResponse::OK,
+        })
+    }
+
+    /// Answers OBJECT, which doesn't count as an access to the key. Like
+    /// Redis, access times and frequencies are only given under the
+    /// policies that evict by them.
+    fn object(&self, keyspace: &Keyspace, command: ObjectCommand) -> CommandResponse {
+        let lfu = self.databases.memory_config().policy.is_lfu();
+        let response = match command {
+            ObjectCommand::Encoding(key) => keyspace.peek(&key, |value| CommandResponse::STR(value.encoding().to_string())),
+            // values are never shared between keys
+            ObjectCommand::RefCount(key) => keyspace.peek(&key, |_| CommandResponse::INT(1)),
+            ObjectCommand::IdleTime(key) => keyspace.idle_time(&key).map(|idle| match lfu {
+                true => CommandResponse::ERR(LRU_NOT_SELECTED_ERROR.to_string()),
+                false => CommandResponse::INT((idle / 1000) as i64),
+            }),
+            ObjectCommand::Freq(key) => keyspace.frequency(&key).map(|frequency| match lfu {
+                true => CommandResponse::INT(frequency as i64),
+                false => CommandResponse::ERR(LFU_NOT_SELECTED_ERROR.to_string()),
+            }),
+        };
+        response.unwrap_or(CommandResponse::NIL)
+    }
+
+    fn config(&self, command: ConfigCommand) -> CommandResponse {
+        // REPLICAOF and failovers change our master behind the config's back
+        let master = self.link.master().map(|(host, port)| format!("{host} {port}"));
+        self.config.update("replicaof", master.unwrap_or_default());
+        match command {
+            ConfigCommand::Get(patterns) => CommandResponse::ARRAY(
+                self.config.matching(&patterns).into_iter()
+                    .flat_map(|(name, value)| [CommandResponse::STR(name), CommandResponse::STR(value)])
+                    .collect()
+            ),
+            ConfigCommand::Set(changes) => match self.config.set(&changes) {
+                Ok(()) => {
+                    self.apply_config();
+                    CommandResponse::OK
+                },
+                Err(err) => CommandResponse::ERR(format!("ERR {err}")),
+            },
+            ConfigCommand::ResetStat => {
+                self.databases.reset_peak_memory();
+                CommandResponse::OK
+            },
+            ConfigCommand::Rewrite => match self.config.rewrite() {
+                Ok(()) => CommandResponse::OK,
+                Err(err) => CommandResponse::ERR(format!("ERR {err}")),
+            },
+        }
+    }
+
+    /// Makes the parameters CONFIG SET can change take effect.
+    fn apply_config(&self) {
+        let config = &self.config;
+        self.databases.set_memory_config(config.memory_config());
+        self.databases.set_encoding_config(config.encoding_config());
+        self.saver.set_rules(parse_save_rules(&config.get("save")).expect("save rules were checked"));
+        self.link.configure(config.flag("replica-read-only"), config.flag("replica-serve-stale-data"), config.number("replica-priority"));
+    }
+
+    /// Answers MEMORY STATS with the fields of Redis that apply here: the
+    /// dataset and the replication backlog.
+    fn memory_stats(&self) -> CommandResponse {
+        let backlog = self.replicas.info(&self.link).repl_backlog_histlen;
+        let used = self.databases.used_memory();
+        let total = used + backlog;
+        let peak = self.databases.peak_memory().max(used) + backlog;
+
+        let mut stats = vec![];
+        let mut stat = |name: String, value: CommandResponse| {
+            stats.push(CommandResponse::STR(name));
+            stats.push(value);
+        };
+        stat("peak.allocated".to_string(), CommandResponse::INT(peak as i64));
+        stat("total.allocated".to_string(), CommandResponse::INT(total as i64));
+        stat("startup.allocated".to_string(), CommandResponse::INT(0));
+        stat("replication.backlog".to_string(), CommandResponse::INT(backlog as i64));
+        let (mut keys, mut overhead) = (0, backlog);
+        for db in 0..self.databases.count() {
+            let (count, _) = self.databases.get(db).counts();
+            if count == 0 {
+                continue;
+            }
+            // expiry times are kept along with the keys
+            let main = (count * KEY_OVERHEAD) as u64;
+            stat(format!("db.{db}"), CommandResponse::ARRAY(vec![
+                CommandResponse::STR("overhead.hashtable.main".to_string()),
+                CommandResponse::INT(main as i64),
+                CommandResponse::STR("overhead.hashtable.expires".to_string()),
+                CommandResponse::INT(0),
+            ]));
+            keys += count as u64;
+            overhead += main;
+        }
+        let dataset = total.saturating_sub(overhead);
+        let percentage = |part: u64, whole: u64| if whole == 0 { 0.0 } else { part as f64 * 100.0 / whole as f64 };
+        stat("overhead.total".to_string(), CommandResponse::INT(overhead as i64));
+        stat("keys.count".to_string(), CommandResponse::INT(keys as i64));
+        stat("keys.bytes-per-key".to_string(), CommandResponse::INT(total.checked_div(keys).unwrap_or(0) as i64));
+        stat("dataset.bytes".to_string(), CommandResponse::INT(dataset as i64));
+        stat("dataset.percentage".to_string(), CommandResponse::STR(percentage(dataset, total).to_string()));
+        stat("peak.percentage".to_string(), CommandResponse::STR(percentage(total, peak).to_string()));
+        CommandResponse::ARRAY(stats)
+    }
+
+    /// Answers MEMORY DOCTOR, in the voice Redis uses for it.
+    fn memory_doctor(&self) -> String {
+        let used = self.databases.used_memory();
+        if used < DOCTOR_MIN_MEMORY {
+            return "Hi Sam, this instance is empty or is using very little memory, my issues detector can't be used in these conditions. \
+                Please, leave for your mission on Earth and fill it with some data. \
+                The new Sam and I will be back to our programming as soon as I finished rebooting.".to_string();
+        }
+        let config = self.databases.memory_config();
+        let mut issues = vec![];
+        if self.databases.peak_memory() > used / 2 * 3 {
+            issues.push("Peak memory: In the past this instance used more than 150% the memory that is currently using. \
+                The allocator is normally not able to release memory after a peak, \
+                so the process may keep holding it until more data fills the instance again.");
+        }
+        if config.maxmemory > 0 && config.policy == EvictionPolicy::NoEviction && used > config.maxmemory / 10 * 9 {
+            issues.push("Memory limit: More than 90% of maxmemory is used and maxmemory-policy is noeviction, \
+                so writes needing more memory will soon be refused with OOM errors. \
+                Consider raising maxmemory or choosing an eviction policy.");
+        }
+        if issues.is_empty() {
+            return "Hi Sam, I can't find any memory issue in your instance. I can only account for what occurs on this base.".to_string();
+        }
+        let issues: String = issues.iter().map(|issue| format!(" * {issue}\n\n")).collect();
+        format!("Sam, I detected a few issues in this Redis instance memory implants:\n\n{issues}I'm here to keep you safe, Sam. I want to help you.\n")
+    }
+
+    /// Runs the commands queued since MULTI, unless one of the keys WATCHed
+    /// by `session` changed in the meantime.
+    async fn exec(&self, session: &mut Session) -> Result<CommandResponse> {
+        let Some(queued) = session.transaction.take() else {
+            return Ok(CommandResponse::ERR("ERR EXEC without MULTI".to_string()));
+        };
+        let failed = std::mem::take(&mut session.transaction_failed);
+
+        let Some(_guard) = self.wait_turn(|| self.exec_lock.write()).await else {
+            self.unwatch(session);
+            return Ok(CommandResponse::ERR(BUSY_ERROR.to_string()));
+        };
+        let touched = session.watched.iter()
+            .any(|(keyspace, key, version)| keyspace.version(key) != *version);
+        self.unwatch(session);
+
+        if failed {
+            return Ok(CommandResponse::ERR("EXECABORT Transaction discarded because of previous errors.".to_string()));
+        }
+        if touched {
+            return Ok(CommandResponse::NILARRAY);
+        }
+
+        let mut responses = Vec::with_capacity(queued.len());
+        for cmd in queued {
+            let response = match cmd {
+                CommandRequest::SELECT(db) => self.select(&mut session.db, db),
+                CommandRequest::MIGRATE(migrate) => self.migrate(session.db, migrate).await
+                    .unwrap_or_else(|err| CommandResponse::ERR(format!("ERR {err}"))),
+                cmd => {
+                    let db = session.db;
+                    tokio::task::block_in_place(|| self.respond(db, cmd))
+                        .unwrap_or_else(|err| CommandResponse::ERR(format!("ERR {err}")))
+                },
+            };
+            responses.push(response);
+        }
+        Ok(CommandResponse::ARRAY(responses))
+    }
+
+    /// Waits until `acquire` gets hold of the execution lock, giving up if a
+    /// script becomes busy in the meantime.
+    async fn wait_turn<G, F: Future<Output = G>>(&self, acquire: impl Fn() -> F) -> Option<G> {
+        loop {
+            if self.scripting.is_busy() {
+                return None;
+            }
+            if let Ok(guard) = timeout(BUSY_CHECK_INTERVAL, acquire()).await {
+                return Some(guard);
+            }
+        }
+    }
+
+    /// Drops every WATCH held by `session`. Also called when its connection
+    /// goes away.
+    pub fn unwatch(&self, session: &mut Session) {
+        for (keyspace, key, _) in session.watched.drain(..) {
+            keyspace.unwatch(&key);
+        }
+    }
+
+    /// Switches a connection whose database is `selected` to `db`.
+    fn select(&self, selected: &mut usize, db: usize) -> CommandResponse {
+        if self.cluster.is_some() && db != 0 {
+            return CommandResponse::ERR("ERR SELECT is not allowed in cluster mode".to_string());
+        }
+        if db >= self.databases.count() {
+            return CommandResponse::ERR("ERR DB index is out of range".to_string());
+        }
+        *selected = db;
+        CommandResponse::OK
+    }
+
+    /// Copies the dataset and function libraries. Callers hold the
+    /// execution lock for writing so the copy is consistent.
+    fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot {
+            aux: vec![
+                ("redis-ver".to_string(), "7.2.4".to_string()),
+                ("redis-bits".to_string(), "64".to_string()),
+                ("ctime".to_string(), (now_ms() / 1000).to_string()),
+            ],
+            functions: self.functions.codes(),
+            ..Default::default()
+        };
+        for db in 0..self.databases.count() {
+            let entries: Vec<SnapshotEntry> = self.databases.get(db).entries().into_iter()
+                .map(|(key, value, expires_at)| SnapshotEntry { key, value, expires_at })
+                .collect();
+            if !entries.is_empty() {
+                snapshot.databases.insert(db as u64, entries);
+            }
+        }
+        snapshot
+    }
+
+    /// Starts a background save if any of the save rules is met. Called
+    /// periodically.
+    pub async fn save_if_needed(&self) {
+        if !self.saver.should_save(self.databases.dirty()) {
+            return;
+        }
+        let Some(_guard) = self.wait_turn(|| self.exec_lock.write()).await else {
+            return;
+        };
+        let dirty = self.databases.dirty();
+        info!(target: "interpreter", "{dirty} changes since startup, saving");
+        if let Err(err) = self.saver.save_in_background(self.snapshot(), dirty) {
+            warn!(target: "interpreter", "{err}");
+        }
+    }
+
+    /// Writes the whole dataset to the AOF, replacing what it had.
+    pub fn rewrite_aof(&self) -> Result<()> {
+        match &self.aof {
+            Some(aof) => self.rewrite_aof_with(|snapshot| aof.rewrite(&snapshot)),
+            None => Ok(()),
+        }
+    }
+
+    /// Rewrites the AOF from a snapshot with `rewrite`. The rewritten file
+    /// SELECTs its own databases, so the next write SELECTs again.
+    fn rewrite_aof_with(&self, rewrite: impl FnOnce(Snapshot) -> Result<()>) -> Result<()> {
+        let mut selected = self.write_order.lock().unwrap();
+        rewrite(self.snapshot())?;
+        selected.aof = None;
+        Ok(())
+    }
+
+    /// Runs a command read back from the AOF, where `db` is the database
+    /// the file last SELECTed.
+    pub fn load_command(&self, db: &mut usize, command: RESP) -> Result<()> {
+        match CommandRequest::from_resp(command)? {
+            CommandRequest::SELECT(index) if index < self.databases.count() => *db = index,
+            CommandRequest::SELECT(index) => return Err(anyhow!("DB index {index} is out of range")),
+            cmd => {
+                self.execute(*db, cmd)?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Replaces the whole dataset with a snapshot received from a master,
+    /// taken at `offset` of its replication stream `replid`.
+    pub async fn full_sync(&self, snapshot: Snapshot, replid: String, offset: u64) -> Result<usize> {
+        let _guard = self.exec_lock.write().await;
+        self.replicas.reset(replid, offset);
+        self.replicated_db.store(0, Ordering::Relaxed);
+        tokio::task::block_in_place(|| {
+            for db in 0..self.databases.count() {
+                self.databases.flush(db, false);
+            }
+            self.functions.flush();
+            let loaded = self.load_snapshot(snapshot)?;
+            if let Some(aof) = &self.aof {
+                self.rewrite_aof_with(|snapshot| aof.rewrite_in_background(snapshot))?;
+            }
+            Ok(loaded)
+        })
+    }
+
+    /// Fills the keyspace and function libraries from an RDB snapshot,
+    /// scheduling the expiry of keys that have a TTL. Returns how many keys
+    /// were loaded.
+    pub fn load_snapshot(&self, snapshot: Snapshot) -> Result<usize> {
+        for code in snapshot.functions {
+            self.functions.load(code, true)?;
+        }
+        let mut loaded = 0;
+        for (db, entries) in snapshot.databases {
+            if db as usize >= self.databases.count() {
+                warn!(target: "interpreter", "skipping {} keys of db {db}, only {} databases are configured", entries.len(), self.databases.count());
+                continue;
+            }
+            let keyspace = self.databases.get(db as usize);
+            for entry in entries {
+                if let Some(expires_at) = entry.expires_at {
+                    let _ = self.tx.send((Arc::downgrade(&keyspace), entry.key.clone(), Duration::from_millis(expires_at.saturating_sub(now_ms()))));
+                }
+                keyspace.set(entry.key, Arc::unwrap_or_clone(entry.value), entry.expires_at);
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        databases: Arc<Databases>,
+        tx: UnboundedSender<Expiry>,
+        saver: Arc<Saver>,
+        aof: Option<Arc<Aof>>,
+        replicas: Arc<Replicas>,
+        link: Arc<MasterLink>,
+        cluster: Option<Arc<Cluster>>,
+        config: Arc<Config>,
     ) -> Interpreter {
+        let scripting = Arc::new(Scripting::new());
         Interpreter{
-            replica_id,
-            master_repl_offset: 0,
-            role,
-            cache,
-            tx
+            databases,
+            evictor: Arc::new(Evictor::new()),
+            tx,
+            functions: Arc::new(Functions::new(scripting.clone())),
+            scripting,
+            saver,
+            aof,
+            replicas,
+            link,
+            cluster,
+            config,
+            write_order: Arc::new(Mutex::new(SelectedDbs::default())),
+            replicated_db: Arc::new(AtomicUsize::new(0)),
+            exec_lock: Arc::new(RwLock::new(())),
         }
     }
 }
+
+/// Which database the AOF and the replication stream last SELECTed, `None`
+/// when they start afresh, as after an AOF rewrite or a full resync.
+#[derive(Default)]
+struct SelectedDbs {
+    aof: Option<usize>,
+    replicas: Option<usize>,
+}
+
+/// The encoded command `bytes` for database `db`, preceded by `select` when
+/// the stream whose database is `selected` is on another one.
+fn selecting(selected: &mut Option<usize>, db: usize, select: &[u8], bytes: &[u8]) -> Vec<u8> {
+    if selected.replace(db) == Some(db) {
+        return bytes.to_vec();
+    }
+    [select, bytes].concat()
+}
+
+/// Sends every request to the instance at `host` and `port`, then reads
+/// their replies, each step taking no longer than `timeout`.
+async fn send_requests(host: &str, port: u16, requests: Vec<CommandRequest>, timeout: Duration) -> Result<Vec<RESP>> {
+    let timed_out = |_| anyhow!("timed out");
+    let tcp_stream = tokio::time::timeout(timeout, TcpStream::connect((host, port))).await.map_err(timed_out)??;
+    let mut command_stream = CommandStream::from_tcp_stream(tcp_stream);
+    let count = requests.len();
+    for request in requests {
+        tokio::time::timeout(timeout, command_stream.write_request(request)).await.map_err(timed_out)??;
+    }
+    let mut replies = Vec::with_capacity(count);
+    for _ in 0..count {
+        replies.push(tokio::time::timeout(timeout, command_stream.receive_response()).await.map_err(timed_out)??);
+    }
+    Ok(replies)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use crate::aof::AppendFsync;
+    use crate::config::Config;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("interpreter-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn interpreter() -> Interpreter {
+        interpreter_with(None)
+    }
+
+    fn interpreter_with(aof: Option<Arc<Aof>>) -> Interpreter {
+        let (tx, _) = mpsc::unbounded_channel();
+        Interpreter::new(
+            Arc::new(Databases::new(16)),
+            tx,
+            Arc::new(Saver::new(std::env::temp_dir().join("interpreter-dump.rdb"), vec![])),
+            aof,
+            Arc::new(Replicas::new(gen_replica_id(), 1024, None)),
+            Arc::new(MasterLink::new(None, true, true, 100)),
+            None,
+            Arc::new(Config::new()),
+        )
+    }
+
+    async fn run(interpreter: &Interpreter, session: &mut Session, parts: &[&str]) -> RESP {
+        let request = RESP::Array(parts.iter().map(|part| RESP::BulkString(part.to_string())).collect());
+        let cmd = CommandRequest::from_resp(request).unwrap();
+        interpreter.handle(session, cmd).await.unwrap().to_resp().unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_migrate_deletes_only_accepted_keys() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let received = tokio::spawn(async move {
+            let (stream, _) = target.accept().await.unwrap();
+            let mut stream = CommandStream::from_tcp_stream(stream);
+            let mut received = vec![];
+            for reply in [CommandResponse::OK, CommandResponse::OK, CommandResponse::ERR("BUSYKEY Target key name already exists.".to_string())] {
+                received.push(format!("{:?}", stream.receive_request().await.unwrap()));
+                stream.write_response(reply).await.unwrap();
+            }
+            received
+        });
+
+        let dir = temp_dir("migrate");
+        let aof = Arc::new(Aof::open(&dir, "appendonlydir", "appendonly.aof", AppendFsync::Always, true).unwrap());
+        let interpreter = interpreter_with(Some(aof.clone()));
+        let mut session = Session::default();
+        run(&interpreter, &mut session, &["SET", "a", "1"]).await;
+        run(&interpreter, &mut session, &["SET", "b", "2"]).await;
+        let reply = run(&interpreter, &mut session, &["MIGRATE", "127.0.0.1", &port.to_string(), "", "0", "1000", "KEYS", "a", "b"]).await;
+        assert_eq!(RESP::SimpleError("ERR Target instance replied with error: BUSYKEY Target key name already exists.".to_string()), reply);
+        let received = received.await.unwrap();
+        assert!(received[0].starts_with("SELECT"));
+        assert!(received[1].starts_with("RESTORE(\"a\""));
+
+        // the key the target took is gone, the one it refused is still ours
+        assert_eq!(RESP::NullBulkString, run(&interpreter, &mut session, &["GET", "a"]).await);
+        assert_eq!(RESP::BulkString("2".to_string()), run(&interpreter, &mut session, &["GET", "b"]).await);
+        // and so is the only one the AOF deletes
+        let mut commands = vec![];
+        aof.load(|_| Ok(()), |command| {
+            commands.push(format!("{:?}", CommandRequest::from_resp(command)?));
+            Ok(())
+        }).unwrap();
+        assert_eq!(Some(&"DEL([\"a\"])".to_string()), commands.last());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bgrewriteaof_keeps_writes_made_while_rewriting() {
+        let dir = temp_dir("bgrewriteaof");
+        let open = || Arc::new(Aof::open(&dir, "appendonlydir", "appendonly.aof", AppendFsync::Always, true).unwrap());
+        let aof = open();
+        let interpreter = interpreter_with(Some(aof.clone()));
+        let mut session = Session::default();
+        run(&interpreter, &mut session, &["SET", "before", "1"]).await;
+        run(&interpreter, &mut session, &["SET", "changed", "old"]).await;
+        let base = aof.manifest().base.map(|base| base.seq);
+
+        let reply = run(&interpreter, &mut session, &["BGREWRITEAOF"]).await;
+        assert_eq!(RESP::SimpleString("Background append only file rewriting started".to_string()), reply);
+        run(&interpreter, &mut session, &["SET", "changed", "new"]).await;
+        run(&interpreter, &mut session, &["SET", "after", "2"]).await;
+        for _ in 0..100 {
+            if aof.manifest().base.map(|base| base.seq) != base {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_ne!(base, aof.manifest().base.map(|base| base.seq));
+
+        // the new base and the commands that came after it add up to everything
+        let reloaded = interpreter_with(None);
+        let mut db = 0;
+        open().load(|snapshot| reloaded.load_snapshot(snapshot).map(|_| ()), |command| reloaded.load_command(&mut db, command)).unwrap();
+        for (key, value) in [("before", "1"), ("changed", "new"), ("after", "2")] {
+            assert_eq!(RESP::BulkString(value.to_string()), run(&reloaded, &mut session, &["GET", key]).await);
+        }
+    }
+
+    /// Runs `RESTORE key ttl <payload> options...` given as `[RESTORE, key, ttl, options...]`.
+    async fn run_restore(interpreter: &Interpreter, session: &mut Session, payload: &[u8], parts: &[&str]) -> RESP {
+        let mut request: Vec<RESP> = parts[..3].iter().map(|part| RESP::BulkString(part.to_string())).collect();
+        request.push(RESP::BulkBytes(payload.to_vec()));
+        request.extend(parts[3..].iter().map(|part| RESP::BulkString(part.to_string())));
+        let cmd = CommandRequest::from_resp(RESP::Array(request)).unwrap();
+        interpreter.handle(session, cmd).await.unwrap().to_resp().unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_restore_ttl_and_replace() {
+        let interpreter = interpreter();
+        let mut session = Session::default();
+        run(&interpreter, &mut session, &["SET", "src", "hello"]).await;
+        let payload = run(&interpreter, &mut session, &["DUMP", "src"]).await.bytes().unwrap();
+        let mut restore = async |parts: &[&str]| run_restore(&interpreter, &mut session, &payload, parts).await;
+        let ok = RESP::SimpleString("OK".to_string());
+
+        assert_eq!(ok, restore(&["RESTORE", "k", "0"]).await);
+        let busy = RESP::SimpleError("BUSYKEY Target key name already exists.".to_string());
+        assert_eq!(busy, restore(&["RESTORE", "k", "0"]).await);
+
+        let at = (now_ms() + 100_000).to_string();
+        assert_eq!(ok, restore(&["RESTORE", "k", &at, "REPLACE", "ABSTTL"]).await);
+        // a TTL already in the past leaves no key behind, even one being replaced
+        assert_eq!(ok, restore(&["RESTORE", "gone", "1", "ABSTTL"]).await);
+        assert_eq!(ok, restore(&["RESTORE", "src", "1", "ABSTTL", "REPLACE"]).await);
+
+        let ttl = match run(&interpreter, &mut session, &["PTTL", "k"]).await {
+            RESP::Integer(ttl) => ttl,
+            reply => panic!("unexpected reply {reply:?}"),
+        };
+        assert!(ttl > 90_000 && ttl <= 100_000);
+        assert_eq!(RESP::BulkString("hello".to_string()), run(&interpreter, &mut session, &["GET", "k"]).await);
+        for key in ["gone", "src"] {
+            assert_eq!(RESP::NullBulkString, run(&interpreter, &mut session, &["GET", key]).await);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_restore_idle_time_and_frequency() {
+        let interpreter = interpreter();
+        let mut session = Session::default();
+        run(&interpreter, &mut session, &["SET", "src", "hello"]).await;
+        let payload = run(&interpreter, &mut session, &["DUMP", "src"]).await.bytes().unwrap();
+        let ok = RESP::SimpleString("OK".to_string());
+
+        assert_eq!(ok, run_restore(&interpreter, &mut session, &payload, &["RESTORE", "idle", "0", "IDLETIME", "1000"]).await);
+        match run(&interpreter, &mut session, &["OBJECT", "IDLETIME", "idle"]).await {
+            RESP::Integer(idle) => assert!((1000..1010).contains(&idle)),
+            reply => panic!("unexpected reply {reply:?}"),
+        }
+
+        run(&interpreter, &mut session, &["CONFIG", "SET", "maxmemory-policy", "allkeys-lfu"]).await;
+        assert_eq!(ok, run_restore(&interpreter, &mut session, &payload, &["RESTORE", "freq", "0", "FREQ", "100"]).await);
+        assert_eq!(RESP::Integer(100), run(&interpreter, &mut session, &["OBJECT", "FREQ", "freq"]).await);
+        // IDLETIME doesn't apply under an LFU policy, so the counter starts as usual
+        assert_eq!(ok, run_restore(&interpreter, &mut session, &payload, &["RESTORE", "fresh", "0", "IDLETIME", "1000"]).await);
+        assert_eq!(RESP::Integer(5), run(&interpreter, &mut session, &["OBJECT", "FREQ", "fresh"]).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_watch_aborts_exec_after_writes_and_expiry() {
+        let interpreter = interpreter();
+        let (mut client, mut other) = (Session::default(), Session::default());
+        let nil = RESP::NullArray;
+        let committed = RESP::Array(vec![RESP::SimpleString("OK".to_string())]);
+        let transaction = async |session: &mut Session| {
+            run(&interpreter, session, &["MULTI"]).await;
+            assert_eq!(RESP::SimpleString("QUEUED".to_string()), run(&interpreter, session, &["SET", "stock", "9"]).await);
+            run(&interpreter, session, &["EXEC"]).await
+        };
+
+        run(&interpreter, &mut client, &["WATCH", "stock"]).await;
+        run(&interpreter, &mut other, &["SET", "stock", "5"]).await;
+        assert_eq!(nil, transaction(&mut client).await);
+        assert_eq!(RESP::BulkString("5".to_string()), run(&interpreter, &mut other, &["GET", "stock"]).await);
+        // EXEC dropped the watch, even though it aborted
+        run(&interpreter, &mut other, &["SET", "stock", "5"]).await;
+        assert_eq!(committed, transaction(&mut client).await);
+
+        run(&interpreter, &mut client, &["WATCH", "stock"]).await;
+        run(&interpreter, &mut client, &["MULTI"]).await;
+        run(&interpreter, &mut client, &["DISCARD"]).await;
+        run(&interpreter, &mut other, &["SET", "stock", "5"]).await;
+        assert_eq!(committed, transaction(&mut client).await);
+
+        run(&interpreter, &mut client, &["WATCH", "stock"]).await;
+        run(&interpreter, &mut client, &["UNWATCH"]).await;
+        run(&interpreter, &mut other, &["SET", "stock", "5"]).await;
+        assert_eq!(committed, transaction(&mut client).await);
+
+        run(&interpreter, &mut other, &["SET", "stock", "5", "px", "20"]).await;
+        run(&interpreter, &mut client, &["WATCH", "stock"]).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // what the Expirator does once the key's time is up
+        assert!(interpreter.databases.get(0).remove_if_expired("stock"));
+        assert_eq!(nil, transaction(&mut client).await);
+
+        // failed writes change nothing
+        run(&interpreter, &mut other, &["SET", "stock", "5"]).await;
+        run(&interpreter, &mut client, &["WATCH", "stock"]).await;
+        let reply = run(&interpreter, &mut other, &["SADD", "stock", "x"]).await;
+        assert_eq!(RESP::SimpleError(WRONGTYPE.to_string()), reply);
+        assert_eq!(committed, transaction(&mut client).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_inspect_loaded_snapshot() {
+        let entry = |key: &str, value: Value, expires_at: Option<u64>| SnapshotEntry { key: key.to_string(), value: Arc::new(value), expires_at };
+        let mut snapshot = Snapshot::default();
+        snapshot.databases.insert(0, vec![
+            entry("session", Value::String("abc".into()), Some(now_ms() + 100_000)),
+            entry("queue", Value::List(["a".to_string()].into_iter().collect()), None),
+            entry("user", Value::Hash([("name".to_string(), "x".to_string())].into_iter().collect()), None),
+        ]);
+        let interpreter = interpreter();
+        let mut session = Session::default();
+        interpreter.load_snapshot(snapshot).unwrap();
+
+        let keys = match run(&interpreter, &mut session, &["KEYS", "*"]).await {
+            RESP::Array(keys) => keys.into_iter().filter_map(|key| key.bytes()).collect::<Vec<_>>(),
+            reply => panic!("unexpected reply {reply:?}"),
+        };
+        assert_eq!(3, keys.len());
+        for (key, type_name) in [("session", "string"), ("queue", "list"), ("user", "hash"), ("missing", "none")] {
+            assert_eq!(RESP::SimpleString(type_name.to_string()), run(&interpreter, &mut session, &["TYPE", key]).await);
+        }
+        assert_eq!(RESP::Integer(100), run(&interpreter, &mut session, &["TTL", "session"]).await);
+        match run(&interpreter, &mut session, &["PTTL", "session"]).await {
+            RESP::Integer(ttl) => assert!(ttl > 90_000 && ttl <= 100_000),
+            reply => panic!("unexpected reply {reply:?}"),
+        }
+        assert_eq!(RESP::Integer(-1), run(&interpreter, &mut session, &["TTL", "queue"]).await);
+        assert_eq!(RESP::Integer(-2), run(&interpreter, &mut session, &["PTTL", "missing"]).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_object_and_memory_replies() {
+        let interpreter = interpreter();
+        let mut session = Session::default();
+        run(&interpreter, &mut session, &["SET", "k", "v"]).await;
+
+        // access times and frequencies are only given under the policies that use them
+        let lfu_error = RESP::SimpleError(LFU_NOT_SELECTED_ERROR.to_string());
+        assert_eq!(lfu_error, run(&interpreter, &mut session, &["OBJECT", "FREQ", "k"]).await);
+        assert_eq!(RESP::Integer(0), run(&interpreter, &mut session, &["OBJECT", "IDLETIME", "k"]).await);
+        run(&interpreter, &mut session, &["CONFIG", "SET", "maxmemory-policy", "volatile-lfu"]).await;
+        let lru_error = RESP::SimpleError(LRU_NOT_SELECTED_ERROR.to_string());
+        assert_eq!(lru_error, run(&interpreter, &mut session, &["OBJECT", "IDLETIME", "k"]).await);
+        assert!(matches!(run(&interpreter, &mut session, &["OBJECT", "FREQ", "k"]).await, RESP::Integer(_)));
+        assert_eq!(RESP::NullBulkString, run(&interpreter, &mut session, &["OBJECT", "FREQ", "missing"]).await);
+
+        // collections are sized from their first elements, or all of them with SAMPLES 0
+        let large = "x".repeat(100);
+        let rpush: Vec<&str> = ["RPUSH", "list", "a"].into_iter().chain(std::iter::repeat_n(large.as_str(), 200)).collect();
+        run(&interpreter, &mut session, &rpush).await;
+        let usage = async |options: &[&str]| {
+            let mut session = Session::default();
+            let parts: Vec<&str> = ["MEMORY", "USAGE", "list"].iter().chain(options).copied().collect();
+            match run(&interpreter, &mut session, &parts).await {
+                RESP::Integer(bytes) => bytes,
+                reply => panic!("unexpected reply {reply:?}"),
+            }
+        };
+        assert_eq!(usage(&["SAMPLES", "5"]).await, usage(&[]).await);
+        assert!(usage(&["SAMPLES", "1"]).await < usage(&["SAMPLES", "5"]).await);
+        assert!(usage(&["SAMPLES", "5"]).await < usage(&["SAMPLES", "0"]).await);
+        assert!(usage(&["SAMPLES", "0"]).await > 200 * 100);
+        assert_eq!(RESP::NullBulkString, run(&interpreter, &mut session, &["MEMORY", "USAGE", "missing", "SAMPLES", "0"]).await);
+
+        let stats = match run(&interpreter, &mut session, &["MEMORY", "STATS"]).await {
+            RESP::Array(stats) => stats,
+            reply => panic!("unexpected reply {reply:?}"),
+        };
+        let fields: Vec<String> = stats.iter().step_by(2).map(|name| String::from_utf8(name.bytes().unwrap()).unwrap()).collect();
+        assert_eq!(vec![
+            "peak.allocated", "total.allocated", "startup.allocated", "replication.backlog", "db.0", "overhead.total",
+            "keys.count", "keys.bytes-per-key", "dataset.bytes", "dataset.percentage", "peak.percentage",
+        ], fields);
+        let db = RESP::Array(vec![
+            RESP::BulkString("overhead.hashtable.main".to_string()),
+            RESP::Integer(2 * KEY_OVERHEAD as i64),
+            RESP::BulkString("overhead.hashtable.expires".to_string()),
+            RESP::Integer(0),
+        ]);
+        assert_eq!(db, stats[9]);
+        assert_eq!(RESP::Integer(2), stats[13]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_restore_rejects_huge_lengths() {
+        let interpreter = interpreter();
+        let mut session = Session::default();
+        // a hash claiming 2^50 fields, which used to abort on allocating them
+        let mut payload = vec![rdb::RDB_TYPE_HASH];
+        rdb::write_length(&mut payload, 1 << 50);
+        let payload = rdb::dump_payload(payload);
+
+        let reply = run_restore(&interpreter, &mut session, &payload, &["RESTORE", "k", "0"]).await;
+        assert_eq!(RESP::SimpleError("ERR Bad data format".to_string()), reply);
+        assert_eq!(RESP::NullBulkString, run(&interpreter, &mut session, &["GET", "k"]).await);
+    }
+}
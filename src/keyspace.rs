@@ -0,0 +1,763 @@
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dashmap::mapref::entry::Entry as MapEntry;
+use dashmap::DashMap;
+use rand::{thread_rng, Rng};
+
+use crate::encoding::{EncodingConfig, Hash, List, Set, Str, ZSet};
+use crate::eviction::{lfu_decay, lfu_increment, MemoryConfig, LFU_INIT_VAL};
+
+pub const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// How many databases clients can SELECT from by default.
+pub const DEFAULT_DATABASES: usize = 16;
+
+/// How many elements of a collection are looked at to estimate its size,
+/// the default of MEMORY USAGE.
+pub const SIZE_SAMPLES: usize = 5;
+
+/// Milliseconds since the unix epoch, which is how expiry times are kept.
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Str),
+    List(List),
+    Set(Set),
+    ZSet(ZSet),
+    Hash(Hash),
+}
+
+impl Value {
+    /// Name of the type as reported by TYPE.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Hash(_) => "hash",
+        }
+    }
+
+    /// How the value is stored, as OBJECT ENCODING reports it.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(s) => s.encoding(),
+            Value::List(list) => list.encoding(),
+            Value::Set(set) => set.encoding(),
+            Value::ZSet(zset) => zset.encoding(),
+            Value::Hash(hash) => hash.encoding(),
+        }
+    }
+
+    /// Estimated bytes used by the value. The size of collections is
+    /// extrapolated from `samples` of their elements, or all of them with 0.
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        let elements = match self {
+            Value::String(s) => s.memory_usage(),
+            Value::List(list) => list.memory_usage(samples),
+            Value::Set(set) => set.memory_usage(samples),
+            Value::ZSet(zset) => zset.memory_usage(samples),
+            Value::Hash(hash) => hash.memory_usage(samples),
+        };
+        size_of::<Value>() + elements
+    }
+
+    /// Switches collections to the encoding `config` calls for. Unless
+    /// `shrink`, they only ever go from compact encodings to full ones, like
+    /// in Redis where only loading a value makes it compact again.
+    pub fn convert(&mut self, config: &EncodingConfig, shrink: bool) {
+        match self {
+            Value::String(_) => (),
+            Value::List(list) => list.convert(config, shrink),
+            Value::Set(set) => set.convert(config, shrink),
+            Value::ZSet(zset) => zset.convert(config, shrink),
+            Value::Hash(hash) => hash.convert(config, shrink),
+        }
+    }
+}
+
+/// Bytes every key uses on top of its name and value.
+pub const KEY_OVERHEAD: usize = size_of::<Entry>() + size_of::<String>();
+
+/// Estimated bytes used by a key and its value.
+fn entry_size(key: &str, value: &Value, samples: usize) -> usize {
+    KEY_OVERHEAD + key.len() + value.memory_usage(samples)
+}
+
+struct Entry {
+    /// Shared with snapshots being saved, and copied by writes while it is.
+    value: Arc<Value>,
+    /// Unix time in milliseconds after which the key is gone.
+    expires_at: Option<u64>,
+    /// Estimated bytes used by the key and value, as counted in the used
+    /// memory.
+    size: usize,
+    /// Unix time in milliseconds of the last access, for LRU eviction.
+    accessed_at: AtomicU64,
+    /// Logarithmic access counter for LFU eviction, which decays with the
+    /// time passed since `accessed_at`.
+    frequency: AtomicU8,
+}
+
+impl Entry {
+    fn new(value: Value, expires_at: Option<u64>) -> Entry {
+        Entry {
+            value: Arc::new(value),
+            expires_at,
+            size: 0,
+            accessed_at: AtomicU64::new(now_ms()),
+            frequency: AtomicU8::new(LFU_INIT_VAL),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now_ms())
+    }
+
+    /// Counts an access to the key.
+    fn access(&self, config: &MemoryConfig) {
+        let now = now_ms();
+        let frequency = lfu_increment(self.frequency(now, config), config.lfu_log_factor);
+        self.frequency.store(frequency, Ordering::Relaxed);
+        self.accessed_at.store(now, Ordering::Relaxed);
+    }
+
+    /// The LFU counter at `now`, once decayed.
+    fn frequency(&self, now: u64, config: &MemoryConfig) -> u8 {
+        let accessed_at = self.accessed_at.load(Ordering::Relaxed);
+        lfu_decay(self.frequency.load(Ordering::Relaxed), accessed_at, now, config.lfu_decay_time)
+    }
+}
+
+/// A key picked at random for eviction, with what policies rank it by.
+pub struct Sample {
+    pub key: String,
+    /// Milliseconds since the key was last accessed.
+    pub idle: u64,
+    /// Decayed LFU counter.
+    pub frequency: u8,
+    pub expires_at: Option<u64>,
+}
+
+/// What every database shares.
+#[derive(Default)]
+struct Shared {
+    /// Number of changes since startup, used to decide when to save.
+    dirty: AtomicU64,
+    /// Estimated bytes used by every key and value.
+    used_memory: AtomicU64,
+    /// Highest `used_memory` seen.
+    peak_memory: AtomicU64,
+    memory_config: RwLock<MemoryConfig>,
+    encoding_config: RwLock<EncodingConfig>,
+}
+
+/// Modification version of a key some connection is WATCHing, along with
+/// how many watches are currently held on it.
+struct Watched {
+    version: u64,
+    watchers: usize,
+}
+
+/// One database, shared by every connection.
+///
+/// Every write goes through here so that keys being WATCHed get their
+/// version bumped, which is what lets EXEC detect concurrent changes.
+/// Versions are only tracked while someone is watching the key.
+///
+/// Keys past their expiry time are never returned, even before the
+/// `Expirator` gets to remove them.
+///
+/// Reads and writes count as accesses to the key, which LRU and LFU eviction
+/// go by, and the size of every value is added to the used memory.
+///
+/// Small collections are kept in compact encodings, and converted to full
+/// ones as writes make them outgrow the limits of the `EncodingConfig`.
+///
+/// Values are shared with snapshots until the next write copies them, so
+/// taking a snapshot doesn't copy the dataset.
+#[derive(Default)]
+pub struct Keyspace {
+    data: DashMap<String, Entry>,
+    watched: DashMap<String, Watched>,
+    shared: Arc<Shared>,
+}
+
+impl Keyspace {
+    pub fn new() -> Keyspace {
+        Keyspace::default()
+    }
+
+    fn sharing(shared: Arc<Shared>) -> Keyspace {
+        Keyspace { shared, ..Default::default() }
+    }
+
+    fn memory_config(&self) -> MemoryConfig {
+        *self.shared.memory_config.read().unwrap()
+    }
+
+    fn encoding_config(&self) -> EncodingConfig {
+        *self.shared.encoding_config.read().unwrap()
+    }
+
+    /// Runs `f` on the value stored at `key`, if any.
+    pub fn view<R>(&self, key: &str, f: impl FnOnce(&Value) -> R) -> Option<R> {
+        match self.data.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                entry.access(&self.memory_config());
+                Some(f(&entry.value))
+            },
+            Some(entry) => {
+                drop(entry);
+                self.remove_if_expired(key);
+                None
+            },
+            None => None,
+        }
+    }
+
+    /// Runs `f` on the value stored at `key`, if any, without counting it
+    /// as an access.
+    pub fn peek<R>(&self, key: &str, f: impl FnOnce(&Value) -> R) -> Option<R> {
+        self.data.get(key)
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| f(&entry.value))
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.view(key, |value| value.clone())
+    }
+
+    /// Stores `value` at `key`, replacing whatever was there along with its
+    /// expiry time. The value gets the most compact encoding it fits in.
+    pub fn set(&self, key: String, mut value: Value, expires_at: Option<u64>) {
+        value.convert(&self.encoding_config(), true);
+        self.touch(&key);
+        let size = entry_size(&key, &value, SIZE_SAMPLES);
+        let entry = Entry { size, ..Entry::new(value, expires_at) };
+        let replaced = self.data.insert(key, entry).map_or(0, |old| old.size);
+        self.resize(replaced, size);
+    }
+
+    /// Runs `f` on the value stored at `key`, which is created with `init`
+    /// when missing. The expiry time of an existing key is kept. `f` also
+    /// says whether it changed the value, as only changes count as writes
+    /// for watchers and save points.
+    pub fn update<R>(&self, key: &str, init: impl FnOnce() -> Value, f: impl FnOnce(&mut Value) -> (R, bool)) -> R {
+        let mut entry = match self.data.entry(key.to_string()) {
+            MapEntry::Occupied(mut occupied) => {
+                if occupied.get().is_expired() {
+                    let expired = occupied.insert(Entry::new(init(), None));
+                    self.resize(expired.size, 0);
+                }
+                occupied.into_ref()
+            },
+            MapEntry::Vacant(vacant) => vacant.insert(Entry::new(init(), None)),
+        };
+        let value = Arc::make_mut(&mut entry.value);
+        let (result, changed) = f(value);
+        value.convert(&self.encoding_config(), false);
+        let size = entry_size(key, value, SIZE_SAMPLES);
+        entry.access(&self.memory_config());
+        let old = std::mem::replace(&mut entry.size, size);
+        drop(entry);
+        self.resize(old, size);
+        if changed {
+            self.touch(key);
+        }
+        result
+    }
+
+    pub fn remove(&self, key: &str) -> Option<Value> {
+        let removed = self.data.remove(key)
+            .inspect(|(_, entry)| self.resize(entry.size, 0))
+            .filter(|(_, entry)| !entry.is_expired())
+            .map(|(_, entry)| Arc::unwrap_or_clone(entry.value));
+        if removed.is_some() {
+            self.touch(key);
+        }
+        removed
+    }
+
+    /// Removes every key.
+    pub fn clear(&self) {
+        let mut freed = 0;
+        self.data.retain(|_, entry| {
+            freed += entry.size;
+            false
+        });
+        self.resize(freed, 0);
+        self.shared.dirty.fetch_add(1, Ordering::Relaxed);
+        self.touch_watched();
+    }
+
+    /// Bumps the version of every watched key, as if they all changed.
+    pub fn touch_watched(&self) {
+        for mut watched in self.watched.iter_mut() {
+            watched.version += 1;
+        }
+    }
+
+    /// Removes `key` if its expiry time has passed, returning whether it did.
+    pub fn remove_if_expired(&self, key: &str) -> bool {
+        let removed = self.data.remove_if(key, |_, entry| entry.is_expired());
+        if let Some((_, entry)) = &removed {
+            self.resize(entry.size, 0);
+            self.touch(key);
+        }
+        removed.is_some()
+    }
+
+    /// Changes when `key` expires, returning false if there's no such key.
+    pub fn set_expiry(&self, key: &str, expires_at: Option<u64>) -> bool {
+        let updated = match self.data.get_mut(key) {
+            Some(mut entry) if !entry.is_expired() => {
+                entry.expires_at = expires_at;
+                true
+            },
+            _ => false,
+        };
+        if updated {
+            self.touch(key);
+        }
+        updated
+    }
+
+    /// Unix time in milliseconds when `key` expires. `None` when the key
+    /// doesn't exist, `Some(None)` when it has no expiry.
+    pub fn expires_at(&self, key: &str) -> Option<Option<u64>> {
+        self.data.get(key)
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| entry.expires_at)
+    }
+
+    /// Milliseconds left before `key` expires, -1 if it doesn't expire.
+    pub fn ttl_ms(&self, key: &str) -> Option<i64> {
+        self.expires_at(key)
+            .map(|expires_at| expires_at.map_or(-1, |at| at.saturating_sub(now_ms()) as i64))
+    }
+
+    /// Milliseconds since `key` was last accessed.
+    pub fn idle_time(&self, key: &str) -> Option<u64> {
+        self.data.get(key)
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| now_ms().saturating_sub(entry.accessed_at.load(Ordering::Relaxed)))
+    }
+
+    /// The decayed LFU counter of `key`.
+    pub fn frequency(&self, key: &str) -> Option<u8> {
+        self.data.get(key)
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| entry.frequency(now_ms(), &self.memory_config()))
+    }
+
+    /// Estimated bytes used by `key` and its value, sampling `samples`
+    /// elements of collections, or all of them with 0.
+    pub fn memory_usage(&self, key: &str, samples: usize) -> Option<usize> {
+        self.data.get(key)
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| entry_size(key, &entry.value, samples))
+    }
+
+    /// How many keys there are, and how many of them have an expiry time.
+    pub fn counts(&self) -> (usize, usize) {
+        self.data.iter()
+            .filter(|entry| !entry.is_expired())
+            .fold((0, 0), |(keys, volatile), entry| (keys + 1, volatile + entry.expires_at.is_some() as usize))
+    }
+
+    /// Makes `key` look unused for the last `idle` milliseconds.
+    pub fn set_idle_time(&self, key: &str, idle: u64) {
+        if let Some(entry) = self.data.get(key) {
+            entry.accessed_at.store(now_ms().saturating_sub(idle), Ordering::Relaxed);
+        }
+    }
+
+    /// Sets the LFU counter of `key`.
+    pub fn set_frequency(&self, key: &str, frequency: u8) {
+        if let Some(entry) = self.data.get(key) {
+            entry.frequency.store(frequency, Ordering::Relaxed);
+            entry.accessed_at.store(now_ms(), Ordering::Relaxed);
+        }
+    }
+
+    /// Up to `count` keys that haven't expired, starting from a random one,
+    /// and only among keys with an expiry time when `volatile`.
+    ///
+    /// The map can't be indexed, so this walks it up to where it starts.
+    pub fn sample(&self, count: usize, volatile: bool) -> Vec<Sample> {
+        let len = self.data.len();
+        if len == 0 || count == 0 {
+            return vec![];
+        }
+        let (now, config) = (now_ms(), self.memory_config());
+        let start = thread_rng().gen_range(0..len);
+        self.data.iter().skip(start).chain(self.data.iter().take(start))
+            .filter(|entry| !entry.is_expired() && (!volatile || entry.expires_at.is_some()))
+            .take(count)
+            .map(|entry| Sample {
+                key: entry.key().to_owned(),
+                idle: now.saturating_sub(entry.accessed_at.load(Ordering::Relaxed)),
+                frequency: entry.frequency(now, &config),
+                expires_at: entry.expires_at,
+            })
+            .collect()
+    }
+
+    /// Every key that hasn't expired, along with its expiry time. Values
+    /// aren't copied but shared, which keeps this cheap enough to run while
+    /// clients wait, and writes copy them from then on.
+    pub fn entries(&self) -> Vec<(String, Arc<Value>, Option<u64>)> {
+        self.data.iter()
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| (entry.key().to_owned(), entry.value.clone(), entry.expires_at))
+            .collect()
+    }
+
+    /// Every key that hasn't expired.
+    pub fn keys(&self) -> Vec<String> {
+        self.data.iter()
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| entry.key().to_owned())
+            .collect()
+    }
+
+    /// Starts watching `key`, returning its current version.
+    pub fn watch(&self, key: &str) -> u64 {
+        let mut watched = self.watched
+            .entry(key.to_string())
+            .or_insert(Watched { version: 0, watchers: 0 });
+        watched.watchers += 1;
+        watched.version
+    }
+
+    pub fn unwatch(&self, key: &str) {
+        self.watched.remove_if_mut(key, |_, watched| {
+            watched.watchers -= 1;
+            watched.watchers == 0
+        });
+    }
+
+    /// Current version of a watched key.
+    pub fn version(&self, key: &str) -> u64 {
+        self.watched.get(key).map(|watched| watched.version).unwrap_or(0)
+    }
+
+    /// Accounts for an entry going from `old` to `new` bytes.
+    fn resize(&self, old: usize, new: usize) {
+        let used = self.shared.used_memory.fetch_add(new as u64, Ordering::Relaxed) + new as u64;
+        self.shared.peak_memory.fetch_max(used, Ordering::Relaxed);
+        self.shared.used_memory.fetch_sub(old as u64, Ordering::Relaxed);
+    }
+
+    fn touch(&self, key: &str) {
+        self.shared.dirty.fetch_add(1, Ordering::Relaxed);
+        if let Some(mut watched) = self.watched.get_mut(key) {
+            watched.version += 1;
+        }
+    }
+}
+
+/// The numbered databases clients SELECT from.
+///
+/// SWAPDB and lazy flushes replace whole databases, so commands look theirs
+/// up each time they run rather than holding on to it.
+pub struct Databases {
+    databases: Vec<RwLock<Arc<Keyspace>>>,
+    shared: Arc<Shared>,
+}
+
+impl Databases {
+    pub fn new(count: usize) -> Databases {
+        let shared = Arc::new(Shared::default());
+        Databases {
+            databases: (0..count).map(|_| RwLock::new(Arc::new(Keyspace::sharing(shared.clone())))).collect(),
+            shared,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.databases.len()
+    }
+
+    /// The database numbered `db`, which must be less than `count`.
+    pub fn get(&self, db: usize) -> Arc<Keyspace> {
+        self.databases[db].read().unwrap().clone()
+    }
+
+    /// Exchanges two databases. Keys WATCHed in either of them count as
+    /// changed.
+    pub fn swap(&self, a: usize, b: usize) {
+        if a == b {
+            return;
+        }
+        let (first, second) = (a.min(b), a.max(b));
+        let mut first = self.databases[first].write().unwrap();
+        let mut second = self.databases[second].write().unwrap();
+        std::mem::swap(&mut *first, &mut *second);
+        first.touch_watched();
+        second.touch_watched();
+        self.shared.dirty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Removes every key of `db`. When `lazy`, the database is replaced by
+    /// an empty one right away and the old one freed by another thread.
+    pub fn flush(&self, db: usize, lazy: bool) {
+        if !lazy {
+            self.get(db).clear();
+            return;
+        }
+        let empty = Arc::new(Keyspace::sharing(self.shared.clone()));
+        let old = std::mem::replace(&mut *self.databases[db].write().unwrap(), empty);
+        old.touch_watched();
+        thread::spawn(move || old.clear());
+    }
+
+    /// Number of changes made to any database since startup.
+    pub fn dirty(&self) -> u64 {
+        self.shared.dirty.load(Ordering::Relaxed)
+    }
+
+    /// Estimated bytes used by the keys and values of every database. Keys
+    /// of lazily flushed databases count until they're freed.
+    pub fn used_memory(&self) -> u64 {
+        self.shared.used_memory.load(Ordering::Relaxed)
+    }
+
+    pub fn peak_memory(&self) -> u64 {
+        self.shared.peak_memory.load(Ordering::Relaxed)
+    }
+
+    /// Starts tracking the peak from the current usage, as CONFIG RESETSTAT does.
+    pub fn reset_peak_memory(&self) {
+        self.shared.peak_memory.store(self.used_memory(), Ordering::Relaxed);
+    }
+
+    pub fn memory_config(&self) -> MemoryConfig {
+        *self.shared.memory_config.read().unwrap()
+    }
+
+    pub fn set_memory_config(&self, config: MemoryConfig) {
+        *self.shared.memory_config.write().unwrap() = config;
+    }
+
+    pub fn encoding_config(&self) -> EncodingConfig {
+        *self.shared.encoding_config.read().unwrap()
+    }
+
+    /// Changes the limits of compact encodings, which values only follow
+    /// the next time they're written.
+    pub fn set_encoding_config(&self, config: EncodingConfig) {
+        *self.shared.encoding_config.write().unwrap() = config;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::encoding::Listpack;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.into())
+    }
+
+    #[test]
+    fn test_writes_bump_watched_version() {
+        let keyspace = Keyspace::new();
+        let version = keyspace.watch("stock");
+
+        keyspace.set("stock".to_string(), string("10"), None);
+        assert_ne!(version, keyspace.version("stock"));
+
+        let version = keyspace.version("stock");
+        keyspace.remove("stock");
+        assert_ne!(version, keyspace.version("stock"));
+    }
+
+    #[test]
+    fn test_updates_without_changes_leave_version() {
+        let keyspace = Keyspace::new();
+        let add = |member: &str| keyspace.update("set", || Value::Set(Set::default()), |value| match value {
+            Value::Set(set) => ((), set.insert(member.to_string())),
+            _ => ((), false),
+        });
+        add("a");
+        let version = keyspace.watch("set");
+        let dirty = keyspace.shared.dirty.load(Ordering::Relaxed);
+
+        add("a");
+        assert_eq!(version, keyspace.version("set"));
+        assert_eq!(dirty, keyspace.shared.dirty.load(Ordering::Relaxed));
+        add("b");
+        assert_ne!(version, keyspace.version("set"));
+    }
+
+    #[test]
+    fn test_unwatch_forgets_key() {
+        let keyspace = Keyspace::new();
+        keyspace.watch("stock");
+        keyspace.watch("stock");
+
+        keyspace.unwatch("stock");
+        assert!(keyspace.watched.contains_key("stock"));
+
+        keyspace.unwatch("stock");
+        assert!(!keyspace.watched.contains_key("stock"));
+    }
+
+    #[test]
+    fn test_expired_keys_are_hidden() {
+        let keyspace = Keyspace::new();
+        keyspace.set("gone".to_string(), string("x"), Some(now_ms() - 1));
+        keyspace.set("kept".to_string(), string("y"), Some(now_ms() + 60_000));
+
+        assert_eq!(None, keyspace.get("gone"));
+        assert_eq!(Some(string("y")), keyspace.get("kept"));
+        assert_eq!(vec!["kept".to_string()], keyspace.keys());
+        assert!(!keyspace.remove_if_expired("kept"));
+    }
+
+    #[test]
+    fn test_swap_and_flush_databases() {
+        let databases = Databases::new(DEFAULT_DATABASES);
+        databases.get(0).set("a".to_string(), string("0"), None);
+        databases.get(1).set("b".to_string(), string("1"), None);
+        let (watching, version) = (databases.get(0), databases.get(0).watch("a"));
+
+        databases.swap(0, 1);
+        assert_eq!(Some(string("1")), databases.get(0).get("b"));
+        assert_eq!(Some(string("0")), databases.get(1).get("a"));
+        assert_ne!(version, watching.version("a"));
+
+        let dirty = databases.dirty();
+        databases.flush(1, true);
+        assert!(databases.get(1).keys().is_empty());
+        assert_eq!(vec!["b".to_string()], databases.get(0).keys());
+        databases.flush(0, false);
+        assert!(databases.get(0).keys().is_empty());
+        assert!(databases.dirty() > dirty);
+    }
+
+    #[test]
+    fn test_used_memory_follows_writes() {
+        let databases = Databases::new(2);
+        let keyspace = databases.get(0);
+        keyspace.set("a".to_string(), string("1"), None);
+        let one = databases.used_memory();
+        assert!(one > 0);
+
+        keyspace.update("list", || Value::List(List::default()), |value| {
+            if let Value::List(list) = value {
+                (0..100).for_each(|i| list.push_back(i.to_string()));
+            }
+            ((), true)
+        });
+        databases.get(1).set("b".to_string(), string("1"), None);
+        assert!(databases.used_memory() > 2 * one);
+
+        keyspace.remove("list");
+        assert_eq!(2 * one, databases.used_memory());
+        databases.flush(1, false);
+        keyspace.clear();
+        assert_eq!(0, databases.used_memory());
+    }
+
+    #[test]
+    fn test_encodings() {
+        assert_eq!("int", string("-42").encoding());
+        assert_eq!("embstr", string("042").encoding());
+        assert_eq!("raw", string(&"x".repeat(45)).encoding());
+        assert_eq!("listpack", Value::List(List::default()).encoding());
+        assert_eq!("quicklist", Value::List(["a".to_string()].into_iter().collect()).encoding());
+        assert_eq!("skiplist", Value::ZSet([("a".to_string(), 1.0)].into_iter().collect()).encoding());
+    }
+
+    #[test]
+    fn test_collections_outgrow_compact_encodings() {
+        let databases = Databases::new(1);
+        let config = EncodingConfig { set_max_intset_entries: 3, set_max_listpack_entries: 4, ..Default::default() };
+        databases.set_encoding_config(config);
+        let keyspace = databases.get(0);
+        let add = |member: &str| keyspace.update("set", || Value::Set(Set::default()), |value| {
+            match value {
+                Value::Set(set) => ((), set.insert(member.to_string())),
+                _ => ((), false),
+            }
+        });
+        let encoding = || keyspace.peek("set", Value::encoding).unwrap();
+
+        ["1", "2", "3"].into_iter().for_each(add);
+        assert_eq!("intset", encoding());
+        add("a");
+        assert_eq!("listpack", encoding());
+        add("b");
+        assert_eq!("hashtable", encoding());
+
+        let set: Set = ["4", "5"].map(String::from).into_iter().collect();
+        keyspace.set("set".to_string(), Value::Set(set.clone()), None);
+        assert_eq!("intset", encoding());
+        assert_eq!(Some(Value::Set(set)), keyspace.get("set"));
+
+        // compact values loaded from RDB or RESTORE payloads can be over the limits
+        let listpack: Listpack = ["a", "b", "c", "d", "e"].into_iter().collect();
+        keyspace.set("set".to_string(), Value::Set(Set::Listpack(listpack)), None);
+        assert_eq!("hashtable", encoding());
+    }
+
+    #[test]
+    fn test_entries_share_values_until_written() {
+        let keyspace = Keyspace::new();
+        keyspace.set("list".to_string(), Value::List(["a"].map(String::from).into_iter().collect()), None);
+        let entries = keyspace.entries();
+        let shared = keyspace.data.get("list").unwrap().value.clone();
+        assert!(Arc::ptr_eq(&shared, &entries[0].1));
+
+        keyspace.update("list", || Value::List(List::default()), |value| {
+            if let Value::List(list) = value {
+                list.push_back("b".to_string());
+            }
+            ((), true)
+        });
+        // the write copied the value, leaving the snapshot as it was
+        assert_eq!(Value::List(["a"].map(String::from).into_iter().collect()), *entries[0].1);
+        assert_eq!(Some(Value::List(["a", "b"].map(String::from).into_iter().collect())), keyspace.get("list"));
+    }
+
+    #[test]
+    fn test_accesses_are_tracked() {
+        let keyspace = Keyspace::new();
+        keyspace.set("a".to_string(), string("1"), None);
+        keyspace.set_idle_time("a", 10_000);
+        assert!(keyspace.sample(1, false)[0].idle >= 10_000);
+        assert!(keyspace.sample(1, true).is_empty());
+
+        keyspace.peek("a", |_| ());
+        assert!(keyspace.idle_time("a").unwrap() >= 10_000);
+
+        keyspace.get("a");
+        let sample = keyspace.sample(1, false).pop().unwrap();
+        assert!(sample.idle < 10_000);
+        assert_eq!(LFU_INIT_VAL + 1, sample.frequency);
+        assert_eq!(Some(LFU_INIT_VAL + 1), keyspace.frequency("a"));
+    }
+
+    #[test]
+    fn test_clear_touches_watched_keys() {
+        let keyspace = Keyspace::new();
+        keyspace.set("stock".to_string(), string("10"), None);
+        let version = keyspace.watch("stock");
+
+        keyspace.clear();
+        assert_eq!(None, keyspace.get("stock"));
+        assert_ne!(version, keyspace.version("stock"));
+    }
+}
@@ -0,0 +1,25 @@
+// Commands and responses are named after their RESP counterparts
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod protocol;
+pub mod interpreter;
+pub mod commands;
+pub mod cluster;
+pub mod config;
+pub mod aof;
+pub mod crc16;
+pub mod crc64;
+pub mod encoding;
+pub mod eviction;
+pub mod expirator;
+pub mod functions;
+pub mod glob;
+pub mod gossip;
+pub mod keyspace;
+pub mod rdb;
+pub mod replication;
+pub mod saver;
+pub mod scripting;
+pub mod sentinel;
+pub mod session;
+pub mod stream;
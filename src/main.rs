@@ -1,68 +1,178 @@
-mod protocol;
-mod interpreter;
-mod commands;
-mod expirator;
-mod replication;
-mod stream;
-
-use commands::{CommandRequest, FromRESP, ToRESP};
-use interpreter::Interpreter;
-use protocol::RESP;
-use expirator::Expirator;
-use replication::{gen_replica_id, Replicator};
-use stream::CommandStream;
-
-use dashmap::DashMap;
-use log::info;
-use env_logger;
-use std::env;
-use std::str::from_utf8;
+use redis_starter_rust::aof::{self, Aof, AppendFsync};
+use redis_starter_rust::cluster::{Cluster, BUS_PORT_OFFSET};
+use redis_starter_rust::commands::CommandResponse;
+use redis_starter_rust::config::Config;
+use redis_starter_rust::expirator::{Expirator, Expiry};
+use redis_starter_rust::gossip::ClusterBus;
+use redis_starter_rust::interpreter::Interpreter;
+use redis_starter_rust::keyspace::Databases;
+use redis_starter_rust::rdb;
+use redis_starter_rust::replication::{gen_replica_id, MasterLink, Replicas, Replicator, PING_REPLICA_PERIOD};
+use redis_starter_rust::saver::{parse_save_rules, Saver};
+use redis_starter_rust::sentinel::{Sentinel, SentinelConfig, DEFAULT_SENTINEL_PORT};
+use redis_starter_rust::session::Session;
+use redis_starter_rust::stream::{CommandStream, InvalidRequest};
+
+use anyhow::anyhow;
+use log::{error, info, log_enabled, warn, Level, LevelFilter};
+use std::{env, fs, process};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::{mpsc, Mutex};
 use tokio::net::TcpListener;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::JoinSet;
+
+/// Set in the environment of the server once it runs in the background.
+const DAEMONIZED: &str = "REDIS_DAEMONIZED";
 
-use crate::commands::ReplicationRole;
+/// Where daemonized servers write their process id when no pidfile is set.
+const DEFAULT_PIDFILE: &str = "/var/run/redis.pid";
+
+extern "C" {
+    /// Starts a new session, leaving the terminal's, from the C library.
+    fn setsid() -> i32;
+    /// Makes `new` refer to the same open file as `old`.
+    fn dup2(old: i32, new: i32) -> i32;
+}
 
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
     let args: Vec<String> = env::args().collect();
-
-    let mut port = "6379";
-    let mut replication_role = ReplicationRole::Master;
-    let mut replica_of = None;
+    let program = args.first().map_or("redis-starter-rust", String::as_str);
+    let options = args.get(1..).unwrap_or_default();
+    // only on their own, so that they can still be the value of an option
+    match options {
+        [arg] if arg == "-v" || arg == "--version" => {
+            println!("Redis server v={} bits={}", env!("CARGO_PKG_VERSION"), usize::BITS);
+            return;
+        },
+        [arg] if arg == "-h" || arg == "--help" => {
+            print!("{}", usage(program));
+            return;
+        },
+        _ => (),
+    }
+    let config = Config::from_args(options).unwrap_or_else(|err| {
+        eprintln!("{program}: {err:#}\nTry '{program} --help' for more information.");
+        process::exit(1);
+    });
+    if config.flag("daemonize") && env::var_os(DAEMONIZED).is_none() {
+        daemonize(&args);
+    }
+    init_logger(&config.get("loglevel"));
+    let config = Arc::new(config);
+    let sentinel_mode = config.flag("sentinel");
+    let port = match sentinel_mode && config.is_default("port") {
+        true => DEFAULT_SENTINEL_PORT,
+        false => config.number("port"),
+    };
     let replica_id = gen_replica_id();
     info!("{args:?}");
+    exit_on_signals(write_pidfile(&config));
 
-    match args.as_slice() {
-        [_, flag, p] if flag == "--port" => port = p,
-        [_, r_flag, r, p_flag, p] if p_flag == "--port" && r_flag == "--replicaof" => {
-            port = p;
-            replication_role = ReplicationRole::Slave;
-            let parts: Vec<&str> = r.split(' ').collect();
-            replica_of = Some(format!("{}:{}", parts[0], parts[1]))
-        },
-        [_, p_flag, p, r_flag, r] if p_flag == "--port" && r_flag == "--replicaof" => {
-            port = p;
-            replication_role = ReplicationRole::Slave;
-            let parts: Vec<&str> = r.split(' ').collect();
-            replica_of = Some(format!("{}:{}", parts[0], parts[1]))
-        },
-        _ => ()
+    if sentinel_mode {
+        let monitor = config.get("monitor");
+        if monitor.is_empty() {
+            exit_with(anyhow!("sentinel mode needs a master to --monitor"));
+        }
+        let mut sentinel_config = SentinelConfig::monitor(&monitor).unwrap_or_else(|err| exit_with(err));
+        sentinel_config.down_after = Duration::from_millis(config.number("down-after-milliseconds"));
+        sentinel_config.failover_timeout = Duration::from_millis(config.number("failover-timeout"));
+        sentinel_config.sentinels = host_and_port_pairs(&config.get("known-sentinel"));
+        let listeners = listen(&config, port).await;
+        detach_stderr();
+        let sentinel = Arc::new(Sentinel::new(sentinel_config));
+        info!(target: "main", "running as sentinel {}, listening on port {port:?}", sentinel.id());
+        sentinel.run(listeners).await;
+        return;
     }
 
-    let (tx, rx) = mpsc::channel::<(String, Duration)>(32);
-    let address = "127.0.0.1:".to_string() + port;
-    let listener = TcpListener::bind(address).await.unwrap();
-    info!(target: "main", "running as {replication_role:?}, with replica_id: {replica_id:?}, listening on port {port:?}");
-    let cache = Arc::new(DashMap::new());
-    let tx_protected = Arc::new(Mutex::new(tx));
-    let interpreter = Interpreter::new(replica_id, replication_role, cache.clone(), tx_protected);
+    let (tx, rx) = mpsc::unbounded_channel::<Expiry>();
+    let dir = PathBuf::from(config.get("dir"));
+    let rdb_path = dir.join(config.get("dbfilename"));
+    let save_rules = parse_save_rules(&config.get("save")).expect("save rules were checked");
+    let saver = Arc::new(Saver::new(rdb_path.clone(), save_rules));
+
+    let (appenddirname, appendfilename) = (config.get("appenddirname"), config.get("appendfilename"));
+    let aof_existed = aof::exists(&dir, &appenddirname, &appendfilename);
+    let aof = config.flag("appendonly").then(|| {
+        let fsync = config.get("appendfsync").parse::<AppendFsync>().expect("appendfsync was checked");
+        let aof = Aof::open(&dir, &appenddirname, &appendfilename, fsync, config.flag("aof-use-rdb-preamble"))
+            .unwrap_or_else(|err| exit_with(err));
+        Arc::new(aof)
+    });
+
+    let diskless_sync_delay = config.flag("repl-diskless-sync")
+        .then(|| Duration::from_secs(config.number("repl-diskless-sync-delay")));
+    let replicas = Arc::new(Replicas::new(replica_id.clone(), config.number("repl-backlog-size"), diskless_sync_delay));
+    let link = Arc::new(MasterLink::new(
+        host_and_port_pairs(&config.get("replicaof")).pop(),
+        config.flag("replica-read-only"),
+        config.flag("replica-serve-stale-data"),
+        config.number("replica-priority"),
+    ));
+
+    // other nodes need an address they can reach us on
+    let announced_ip = config.bind_addresses().into_iter()
+        .map(|address| address.ip)
+        .find(|ip| !ip.is_unspecified())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let cluster = config.flag("cluster-enabled").then(|| {
+        let node_timeout = Duration::from_millis(config.number("cluster-node-timeout"));
+        let cluster = Cluster::open(dir.join(config.get("cluster-config-file")), announced_ip.to_string(), port, node_timeout)
+            .unwrap_or_else(|err| exit_with(err));
+        Arc::new(cluster)
+    });
+
+    let databases = Arc::new(Databases::new(config.number("databases")));
+    databases.set_memory_config(config.memory_config());
+    databases.set_encoding_config(config.encoding_config());
+    let interpreter = Interpreter::new(databases, tx, saver, aof.clone(), replicas, link.clone(), cluster.clone(), config.clone());
+
+    // the AOF is more up to date than snapshots whenever it's in use
+    let loaded = if let Some(aof) = aof.as_ref().filter(|_| aof_existed) {
+        let mut db = 0;
+        Some(aof.load(|snapshot| interpreter.load_snapshot(snapshot).map(|_| ()), |command| interpreter.load_command(&mut db, command))
+            .map(|commands| format!("the AOF with {commands} commands")))
+    } else if rdb_path.exists() {
+        Some(fs::read(&rdb_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| rdb::parse(&data))
+            .and_then(|snapshot| interpreter.load_snapshot(snapshot))
+            .map(|keys| format!("{keys} keys from {rdb_path:?}")))
+    } else {
+        None
+    };
+    match loaded {
+        Some(Ok(loaded)) => info!(target: "main", "loaded {loaded}"),
+        Some(Err(err)) => exit_with(err.context("failed loading data")),
+        None => (),
+    }
+    if aof.is_some() && !aof_existed {
+        interpreter.rewrite_aof().unwrap_or_else(|err| exit_with(err));
+    }
+
+    let listeners = listen(&config, port).await;
+    info!(target: "main", "running as {:?}, with replica_id: {replica_id:?}, listening on port {port:?}", link.role());
+    if let Some(cluster) = &cluster {
+        let bus_port = port.checked_add(BUS_PORT_OFFSET)
+            .unwrap_or_else(|| exit_with(anyhow!("port {port} leaves no room for the cluster bus port")));
+        let bus_listener = TcpListener::bind((announced_ip, bus_port)).await
+            .unwrap_or_else(|err| exit_with(anyhow!("failed listening on cluster bus port {bus_port}: {err}")));
+        info!(target: "main", "cluster node {}, bus listening on port {bus_port}", cluster.myself());
+        tokio::spawn(Arc::new(ClusterBus::new(cluster.clone())).run(bus_listener));
+    }
+    detach_stderr();
     let rx_protected = Arc::new(Mutex::new(rx));
-    let expirator = Expirator::new(rx_protected.clone(), cache.clone());
+    let expirator = Expirator::new(rx_protected.clone());
     let expirator_clone = expirator.clone();
 
     tokio::spawn(async move {
@@ -70,29 +180,263 @@ async fn main() {
         expirator_clone.listen().await;
     });
 
-    match replica_of {
-        Some(master_address) => {
-            let replicator = Replicator::new(master_address);
-            tokio::spawn(async move {
-                replicator.replicate().await
-            });
-        },
-        None => (),
+    let saving_interpreter = interpreter.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            saving_interpreter.save_if_needed().await;
+        }
+    });
+
+    if let Some(aof) = aof.filter(|aof| aof.fsync_policy() == AppendFsync::EverySec) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                let aof = aof.clone();
+                if let Ok(Err(err)) = tokio::task::spawn_blocking(move || aof.fsync()).await {
+                    error!(target: "main", "failed syncing the AOF: {err}");
+                }
+            }
+        });
+    }
+
+    let pinging_interpreter = interpreter.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PING_REPLICA_PERIOD);
+        loop {
+            interval.tick().await;
+            if let Err(err) = pinging_interpreter.ping_replicas() {
+                error!(target: "main", "failed pinging replicas: {err}");
+            }
+        }
+    });
+
+    let temp_dir = (config.get("repl-diskless-load") == "disabled").then_some(dir);
+    let replicator = Replicator::new(port, interpreter.clone(), link, temp_dir);
+    tokio::spawn(async move { replicator.run().await });
+
+    let mut serving = JoinSet::new();
+    for listener in listeners {
+        serving.spawn(serve(listener, interpreter.clone()));
     }
-    
+    while serving.join_next().await.is_some() {}
+}
+
+/// Answers the clients connecting to `listener`.
+async fn serve(listener: TcpListener, interpreter: Interpreter) {
     loop {
-        let stream = listener.accept().await.unwrap().0;
+        let (stream, address) = listener.accept().await.unwrap();
         info!(target: "main", "receiving request");
         let mut server_stream = CommandStream::from_tcp_stream(stream);
         let interp_clone = interpreter.clone();
         tokio::spawn(async move {
+            let mut session = Session::new(address);
             loop {
-                let command = server_stream.receive_request().await.unwrap();
-                info!(target: "main", "parsed as command: {command:?}");
-                let command_response = interp_clone.respond(command).await.unwrap();
+                let command_response = match server_stream.receive_request().await {
+                    Ok(command) => {
+                        info!(target: "main", "parsed as command: {command:?}");
+                        interp_clone.handle(&mut session, command).await
+                            .unwrap_or_else(|err| CommandResponse::ERR(format!("ERR {err}")))
+                    },
+                    Err(err) if err.is::<InvalidRequest>() => {
+                        session.fail_transaction();
+                        CommandResponse::ERR(err.to_string())
+                    },
+                    Err(err) => {
+                        info!(target: "main", "closing connection: {err}");
+                        break;
+                    },
+                };
                 info!(target: "main", "answering with : {command_response:?}");
-                let _ = server_stream.write_response(command_response).await.unwrap();
+                let written = server_stream.write_response(command_response).await;
+                if let Some(feed) = session.take_replica_feed() {
+                    if let Err(err) = interp_clone.serve_replica(&mut server_stream, feed).await {
+                        info!(target: "main", "replica {address} disconnected: {err}");
+                    }
+                    break;
+                }
+                if written.is_err() {
+                    break;
+                }
             }
+            interp_clone.unwatch(&mut session);
         });
     }
 }
+
+/// Listens on `port` of every bind address, skipping optional ones that
+/// aren't available.
+async fn listen(config: &Config, port: u16) -> Vec<TcpListener> {
+    let mut addresses = config.bind_addresses();
+    // IPv6 wildcards usually accept IPv4 connections too, which IPv4 ones
+    // listening first would prevent
+    addresses.sort_by_key(|address| !(address.ip.is_ipv6() && address.ip.is_unspecified()));
+    let mut listeners: Vec<TcpListener> = vec![];
+    for address in addresses {
+        match TcpListener::bind((address.ip, port)).await {
+            Ok(listener) => listeners.push(listener),
+            Err(err) if err.kind() == ErrorKind::AddrInUse && address.ip.is_unspecified() &&
+                listeners.iter().any(|listener| listener.local_addr().is_ok_and(|local| local.ip().is_unspecified())) => {
+                info!(target: "main", "{}:{port} is already covered by the IPv6 wildcard", address.ip);
+            },
+            Err(err) if address.optional => warn!(target: "main", "skipping {}:{port}: {err}", address.ip),
+            Err(err) => exit_with(anyhow!("failed listening on {}: {err}", SocketAddr::new(address.ip, port))),
+        }
+    }
+    if listeners.is_empty() {
+        exit_with(anyhow!("failed listening on any bind address"));
+    }
+    listeners
+}
+
+/// Logs at `loglevel`, unless RUST_LOG says otherwise. Our notices are what
+/// the log crate calls warnings, informational messages being much more
+/// verbose.
+fn init_logger(loglevel: &str) {
+    let level = match loglevel {
+        "debug" => LevelFilter::Debug,
+        "verbose" => LevelFilter::Info,
+        "notice" => LevelFilter::Warn,
+        "warning" => LevelFilter::Error,
+        _ => LevelFilter::Off,
+    };
+    env_logger::Builder::new().filter_level(level).parse_default_env().init();
+}
+
+/// Runs the server again in the background, detached from the terminal,
+/// then exits. Until the server is up, its stderr comes back to us, so that
+/// startup errors still reach the terminal and our exit status.
+fn daemonize(args: &[String]) -> ! {
+    let spawned = env::current_exe().and_then(|program| {
+        let mut command = Command::new(program);
+        command.args(args.get(1..).unwrap_or_default())
+            .env(DAEMONIZED, "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        // SAFETY: setsid is async-signal-safe and only touches the child
+        unsafe {
+            command.pre_exec(|| {
+                if setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        command.spawn()
+    });
+    let mut child = spawned.unwrap_or_else(|err| {
+        eprintln!("failed daemonizing: {err}");
+        process::exit(1);
+    });
+    // ends once the server detaches its stderr or exits
+    if let Some(mut stderr) = child.stderr.take() {
+        let _ = io::copy(&mut stderr, &mut io::stderr());
+    }
+    // a server that exited has closed its stderr just before
+    for _ in 0..10 {
+        match child.try_wait() {
+            Ok(Some(status)) => process::exit(status.code().unwrap_or(1)),
+            Ok(None) => std::thread::sleep(Duration::from_millis(10)),
+            Err(_) => break,
+        }
+    }
+    process::exit(0);
+}
+
+/// Points the stderr of a daemonized server at /dev/null once it started,
+/// letting the process that launched it exit.
+fn detach_stderr() {
+    if env::var_os(DAEMONIZED).is_none() {
+        return;
+    }
+    match fs::OpenOptions::new().write(true).open("/dev/null") {
+        // SAFETY: dup2 only swaps which file descriptor 2 refers to
+        Ok(null) => if unsafe { dup2(null.as_raw_fd(), 2) } == -1 {
+            warn!(target: "main", "failed detaching stderr: {}", io::Error::last_os_error());
+        },
+        Err(err) => warn!(target: "main", "failed opening /dev/null: {err}"),
+    }
+}
+
+/// Writes our process id to the pidfile, which daemonized servers always
+/// have, returning where it went.
+fn write_pidfile(config: &Config) -> Option<PathBuf> {
+    let pidfile = match config.get("pidfile") {
+        pidfile if !pidfile.is_empty() => PathBuf::from(pidfile),
+        _ if config.flag("daemonize") => PathBuf::from(DEFAULT_PIDFILE),
+        _ => return None,
+    };
+    match fs::write(&pidfile, format!("{}\n", process::id())) {
+        Ok(()) => Some(pidfile),
+        Err(err) => {
+            warn!(target: "main", "failed writing pidfile {pidfile:?}: {err}");
+            None
+        },
+    }
+}
+
+/// Exits on SIGTERM or SIGINT, removing the pidfile if we wrote one.
+fn exit_on_signals(pidfile: Option<PathBuf>) {
+    tokio::spawn(async move {
+        let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+            return;
+        };
+        tokio::select! {
+            _ = terminate.recv() => warn!(target: "main", "received SIGTERM, exiting"),
+            _ = tokio::signal::ctrl_c() => warn!(target: "main", "received SIGINT, exiting"),
+        }
+        if let Some(pidfile) = pidfile {
+            let _ = fs::remove_file(pidfile);
+        }
+        process::exit(0);
+    });
+}
+
+fn usage(program: &str) -> String {
+    format!("\
+Usage: {program} [/path/to/redis.conf] [options]
+       {program} -v or --version
+       {program} -h or --help
+
+Any config directive can be given as an option, as in --maxmemory 100mb,
+overriding the config file. The most common ones are:
+  --port <port>               port to listen on (6379, or 26379 for sentinels)
+  --bind <address>...         IPv4 or IPv6 addresses to listen on, * for all,
+                              prefixed with - if optional (127.0.0.1 -::1)
+  --replicaof <host> <port>   replicate another server
+  --dir <path>                where data files are written (.)
+  --dbfilename <name>         name of the RDB file (dump.rdb)
+  --loglevel <level>          debug, verbose, notice, warning or nothing (notice)
+  --daemonize [yes|no]        run in the background (no)
+  --pidfile <path>            where to write the process id
+  --sentinel                  run as a sentinel, along with --monitor
+
+Examples:
+  {program} (run the server with the default config)
+  {program} /etc/redis/6379.conf
+  {program} --port 7777
+  {program} --port 7777 --replicaof 127.0.0.1 8888
+  {program} /etc/myredis.conf --loglevel verbose
+  {program} --sentinel --monitor mymaster 127.0.0.1 6379 2
+")
+}
+
+/// The `<host> <port>` pairs of a parameter, which the config already checked.
+fn host_and_port_pairs(value: &str) -> Vec<(String, u16)> {
+    let words: Vec<&str> = value.split_whitespace().collect();
+    words.chunks(2)
+        .map(|pair| (pair[0].to_string(), pair[1].parse().expect("ports were checked")))
+        .collect()
+}
+
+/// Reports a fatal error and exits, on stderr if logging wouldn't show it.
+fn exit_with(err: anyhow::Error) -> ! {
+    match log_enabled!(target: "main", Level::Error) {
+        true => error!(target: "main", "{err:#}"),
+        false => eprintln!("{err:#}"),
+    }
+    process::exit(1);
+}
@@ -1,142 +1,163 @@
-use std::collections::{HashMap, HashSet};
-
 use nom::{
-    bytes::complete::{take, take_until}, character::complete::{anychar, crlf, digit1}, combinator::map_res, error::Error, multi::count, IResult
+    bytes::streaming::{tag, take, take_until}, character::streaming::{crlf, digit1}, combinator::{map_res, opt, recognize}, error::{Error, ErrorKind}, multi::count, sequence::pair, IResult
 };
 
 use anyhow::{anyhow, Result};
 
+/// Longest bulk string a client may send, like Redis's `proto-max-bulk-len`.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Most elements a single array may announce.
+const MAX_ARRAY_LEN: usize = 1024 * 1024;
+/// How deep arrays may nest, so a chain of `*1\r\n` can't exhaust the stack.
+const MAX_DEPTH: usize = 32;
+
 /// Basic datatypes for RESP protocol
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum RESP {
     SimpleString(String),
     SimpleError(String),
     BulkString(String),
+    /// Bulk string holding binary data that isn't valid UTF-8
+    BulkBytes(Vec<u8>),
     NullBulkString,
+    NullArray,
     Integer(i64),
     Array(Vec<RESP>),
-    Null,
-    Boolean(bool),
-    Double(f64),
-    BigNumber(f64),
-    BulkError(Vec<String>),
-    VerbatimString(String),
-    Map(HashMap<RESP, RESP>),
-    Set(HashSet<RESP>),
-    Push(Vec<RESP>),
- }
-
-impl PartialEq for RESP {
-    fn eq(&self, other: &Self) -> bool {
-        return match (self, other) {
-            (RESP::SimpleString(x), RESP::SimpleString(y)) => x == y,
-            (RESP::SimpleError(x), RESP::SimpleError(y)) => x == y,
-            (RESP::BulkString(x), RESP::BulkString(y)) => x == y,
-            (RESP::NullBulkString, RESP::NullBulkString) => true,
-            (RESP::Integer(x), RESP::Integer(y)) => x == y,
-            (RESP::Array(x), RESP::Array(y)) => x == y,
-            (RESP::Null, RESP::Null) => true,
-            (RESP::Boolean(x), RESP::Boolean(y)) => x == y,
-            (RESP::Double(x), RESP::Double(y)) => x == y,
-            (RESP::BigNumber(x), RESP::BigNumber(y)) => x == y,
-            (RESP::BulkError(x), RESP::BulkError(y)) => x == y,
-            (RESP::VerbatimString(x), RESP::VerbatimString(y)) => x == y,
-            (RESP::Map(x), RESP::Map(y)) => false, // todo: IDK what to do here :/
-            (RESP::Set(x), RESP::Set(y)) => false, // todo: idk what to do here
-            (RESP::Push(x), RESP::Push(y)) => x == y,
-            (_, _) => false
-        }
-    }
 }
 
 /// RESP implementation for commands
 impl RESP {
-    pub fn decode(input: &str) -> Result<RESP> {
-        RESP::parse(input)
-            .map_err(|err| anyhow!("parsing error: {:?}", err))
-            .map(|(_, cmd)| cmd)
+    /// Decodes the first value in `input`, returning it along with the number
+    /// of bytes it took, or `None` if `input` doesn't hold a full value yet.
+    pub fn decode(input: &[u8]) -> Result<Option<(RESP, usize)>> {
+        match RESP::parse(input) {
+            Ok((rest, cmd)) => Ok(Some((cmd, input.len() - rest.len()))),
+            Err(nom::Err::Incomplete(_)) => Ok(None),
+            Err(err) => Err(anyhow!("parsing error: {:?}", err)),
+        }
     }
     
-    pub fn encode(self: &RESP) -> String {
+    pub fn encode(&self) -> Vec<u8> {
         match self {
-            RESP::SimpleString(s) => format!("+{}\r\n", s),
-            RESP::SimpleError(s) => format!("-{}\r\n", s),
-            RESP::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s),
-            RESP::NullBulkString => "$-1\r\n".to_string(),
-            RESP::Integer(i) => format!(":{}", i),
+            RESP::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
+            RESP::SimpleError(s) => format!("-{}\r\n", s).into_bytes(),
+            RESP::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s).into_bytes(),
+            RESP::BulkBytes(b) => [format!("${}\r\n", b.len()).as_bytes(), b, b"\r\n"].concat(),
+            RESP::NullBulkString => b"$-1\r\n".to_vec(),
+            RESP::NullArray => b"*-1\r\n".to_vec(),
+            RESP::Integer(i) => format!(":{}\r\n", i).into_bytes(),
             RESP::Array(arr) =>
-                format!(
-                    "*{}\r\n{}",
-                    arr.len(),
-                    arr.iter().fold(
-                        String::new(),
-                        |acc, curr| acc + curr.encode().as_str()
-                    )
+                arr.iter().fold(
+                    format!("*{}\r\n", arr.len()).into_bytes(),
+                    |mut acc, curr| {
+                        acc.extend(curr.encode());
+                        acc
+                    }
                 ),
-            RESP::Null => "_\r\n".to_string(),
-            RESP::Boolean(_) => todo!(),
-            RESP::Double(_) => todo!(),
-            RESP::BigNumber(_) => todo!(),
-            RESP::BulkError(_) => todo!(),
-            RESP::VerbatimString(_) => todo!(),
-            RESP::Map(_) => todo!(),
-            RESP::Set(_) => todo!(),
-            RESP::Push(_) => todo!(),
+        }
+    }
+
+    /// Returns the contents of a bulk string, whether it holds text or not.
+    pub fn bytes(&self) -> Option<Vec<u8>> {
+        match self {
+            RESP::BulkString(s) => Some(s.as_bytes().to_vec()),
+            RESP::BulkBytes(b) => Some(b.to_vec()),
+            _ => None,
         }
     }
     
-    fn parse(input: &str) -> IResult<&str, RESP, Error<&str>> {
-        let (input, first) = anychar::<&str, Error<&str>>(input).unwrap();
+    fn parse(input: &[u8]) -> IResult<&[u8], RESP> {
+        RESP::parse_nested(input, 0)
+    }
+
+    fn parse_nested(input: &[u8], depth: usize) -> IResult<&[u8], RESP> {
+        let (input, first) = take(1usize)(input)?;
         
-        match first {
-            '+' => parse_simple_string(input),
-            '-' => todo!(),
-            ':' => parse_integer(input),
-            '$' => parse_bulk_str(input),
-            '*' => parse_array(input),
-            '_' => todo!(),
-            '#' => todo!(),
-            ',' => todo!(),
-            '(' => todo!(),
-            '!' => todo!(),
-            '=' => todo!(),
-            '%' => todo!(),
-            '~' => todo!(),
-            '>' => todo!(),
-            _   => todo!(),
+        match first[0] {
+            b'+' => parse_simple_string(input),
+            b'-' => parse_simple_error(input),
+            b':' => parse_integer(input),
+            b'$' => parse_bulk_str(input),
+            b'*' => parse_array(input, depth),
+            // RESP3 types, which clients only get after HELLO 3, and inline commands
+            _ => Err(nom::Err::Failure(Error::new(input, ErrorKind::Char))),
         }
     }
 
 }
 
-fn parse_array(input: &str) -> IResult<&str, RESP> {
-    let (input, len) = map_res(digit1, |digit_str: &str| digit_str.parse::<usize>())(input)?;
-    let (input, _) = crlf(input)?;
+fn parse_array(input: &[u8], depth: usize) -> IResult<&[u8], RESP> {
+    if depth >= MAX_DEPTH {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+    }
+    let (input, len) = parse_length(input, MAX_ARRAY_LEN)?;
 
-    count(RESP::parse, len)(input)
-        .map(|(input, cmds)| (input, RESP::Array(cmds)))
+    match len {
+        None => Ok((input, RESP::NullArray)),
+        Some(len) => count(|input| RESP::parse_nested(input, depth + 1), len)(input)
+            .map(|(input, cmds)| (input, RESP::Array(cmds))),
+    }
 }
 
-fn parse_bulk_str(input: &str) -> IResult<&str, RESP> {
-    let (input, len) = map_res(digit1, |digit_str: &str| digit_str.parse::<usize>())(input)?;
-    let (input, _) = crlf(input)?;
+fn parse_bulk_str(input: &[u8]) -> IResult<&[u8], RESP> {
+    let (input, len) = parse_length(input, MAX_BULK_LEN)?;
+    let Some(len) = len else {
+        return Ok((input, RESP::NullBulkString));
+    };
     let (input, data) = take(len)(input)?;
     let (input, _) = crlf(input)?;
 
-
-    Ok((input, RESP::BulkString(data.to_string())))
+    match std::str::from_utf8(data) {
+        Ok(data) => Ok((input, RESP::BulkString(data.to_string()))),
+        Err(_) => Ok((input, RESP::BulkBytes(data.to_vec()))),
+    }
 }
 
-fn parse_integer(rest: &str) -> IResult<&str, RESP> {
-    todo!()
+fn parse_integer(input: &[u8]) -> IResult<&[u8], RESP> {
+    let (input, i) = parse_signed(input)?;
+    let (input, _) = crlf(input)?;
+
+    Ok((input, RESP::Integer(i)))
 }
 
-fn parse_simple_string(input: &str) -> IResult<&str, RESP> {
-    let (input, data) = take_until::<&str, &str, Error<&str>>("\r\n")(input)?;
+fn parse_simple_string(input: &[u8]) -> IResult<&[u8], RESP> {
+    let (input, data) = parse_line(input)?;
 
     Ok((input, RESP::SimpleString(data.to_string())))
 }
 
+fn parse_simple_error(input: &[u8]) -> IResult<&[u8], RESP> {
+    let (input, data) = parse_line(input)?;
+
+    Ok((input, RESP::SimpleError(data.to_string())))
+}
+
+fn parse_line(input: &[u8]) -> IResult<&[u8], &str> {
+    let (input, data) = map_res(take_until("\r\n"), std::str::from_utf8)(input)?;
+    let (input, _) = crlf(input)?;
+
+    Ok((input, data))
+}
+
+fn parse_signed(input: &[u8]) -> IResult<&[u8], i64> {
+    map_res(
+        map_res(recognize(pair(opt(tag("-")), digit1)), std::str::from_utf8),
+        |digits: &str| digits.parse::<i64>()
+    )(input)
+}
+
+/// Parses the length header of bulk strings and arrays, where `-1` stands
+/// for the null value. Lengths above `max` are a protocol error.
+fn parse_length(input: &[u8], max: usize) -> IResult<&[u8], Option<usize>> {
+    let (rest, len) = parse_signed(input)?;
+    let (rest, _) = crlf(rest)?;
+
+    match usize::try_from(len) {
+        Ok(len) if len > max => Err(nom::Err::Failure(Error::new(input, ErrorKind::TooLarge))),
+        len => Ok((rest, len.ok())),
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -151,7 +172,7 @@ mod tests {
 
         assert_eq!(
             expected,
-            RESP::parse(string).unwrap().1
+            RESP::parse(string.as_bytes()).unwrap().1
         );
     }
 
@@ -163,7 +184,7 @@ mod tests {
 
         assert_eq!(
             expected,
-            RESP::parse(string).unwrap().1
+            RESP::parse(string.as_bytes()).unwrap().1
         );
     }
 
@@ -180,10 +201,32 @@ mod tests {
 
         assert_eq!(
             expected,
-            RESP::parse(string).unwrap().1
+            RESP::parse(string.as_bytes()).unwrap().1
         );
     }
 
+    #[test]
+    fn test_decode_unknown_type() {
+        assert!(RESP::decode(b"_\r\n").is_err());
+        assert!(RESP::decode(b"PING\r\n").is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_oversized_values() {
+        assert!(RESP::decode(b"$536870913\r\n").is_err());
+        assert!(RESP::decode(b"*1048577\r\n").is_err());
+        assert_eq!(None, RESP::decode(b"$536870912\r\n").unwrap());
+        assert_eq!(None, RESP::decode(b"*1048576\r\n").unwrap());
+    }
+
+    #[test]
+    fn test_decode_rejects_deep_nesting() {
+        let nested = |depth: usize| [b"*1\r\n".repeat(depth), b":1\r\n".to_vec()].concat();
+        assert!(RESP::decode(&nested(MAX_DEPTH)).unwrap().is_some());
+        assert!(RESP::decode(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(RESP::decode(&b"*1\r\n".repeat(100_000)).is_err());
+    }
+
     #[test]
     fn test_encode() {
         let cmd = RESP::Array(
@@ -196,7 +239,7 @@ mod tests {
         let expected = "*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n";
 
         assert_eq!(
-            expected,
+            expected.as_bytes(),
             cmd.encode()
         );
     }
@@ -212,8 +255,36 @@ mod tests {
         
         assert_eq!(
             cmd,
-            RESP::parse(cmd.encode().as_str()).unwrap().1
+            RESP::parse(&cmd.encode()).unwrap().1
         );        
     }
-    
+
+    #[test]
+    fn test_binary_roundtrip() {
+        let cmd = RESP::BulkBytes(vec![0xf5, 0x00, 0xff]);
+
+        assert_eq!(
+            cmd,
+            RESP::parse(&cmd.encode()).unwrap().1
+        );
+    }
+
+    #[test]
+    fn test_decode_incomplete() {
+        assert_eq!(None, RESP::decode(b"*2\r\n$4\r\nECHO\r\n$3\r\nhe").unwrap());
+    }
+
+    #[test]
+    fn test_decode_pipelined() {
+        let input = b"*1\r\n$4\r\nPING\r\n:-3\r\n$-1\r\n";
+
+        let (first, n) = RESP::decode(input).unwrap().unwrap();
+        assert_eq!(RESP::Array(vec![RESP::BulkString("PING".to_string())]), first);
+
+        let (second, m) = RESP::decode(&input[n..]).unwrap().unwrap();
+        assert_eq!(RESP::Integer(-3), second);
+
+        let (third, _) = RESP::decode(&input[n + m..]).unwrap().unwrap();
+        assert_eq!(RESP::NullBulkString, third);
+    }
 }
@@ -0,0 +1,790 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+
+use crate::crc64::crc64;
+use crate::encoding::{Hash, Intset, List, Listpack, ListpackEntry, Set, Str, ZSet};
+use crate::keyspace::{now_ms, Value};
+
+/// RDB format version written by this server (the one from Redis 7.2).
+pub const RDB_VERSION: u16 = 11;
+
+/// Newest RDB format version we know how to read.
+pub const MAX_RDB_VERSION: u16 = 12;
+
+pub const RDB_OPCODE_SLOT_INFO: u8 = 244;
+pub const RDB_OPCODE_FUNCTION2: u8 = 245;
+pub const RDB_OPCODE_FUNCTION_PRE_GA: u8 = 246;
+pub const RDB_OPCODE_MODULE_AUX: u8 = 247;
+pub const RDB_OPCODE_IDLE: u8 = 248;
+pub const RDB_OPCODE_FREQ: u8 = 249;
+pub const RDB_OPCODE_AUX: u8 = 250;
+pub const RDB_OPCODE_RESIZEDB: u8 = 251;
+pub const RDB_OPCODE_EXPIRETIME_MS: u8 = 252;
+pub const RDB_OPCODE_EXPIRETIME: u8 = 253;
+pub const RDB_OPCODE_SELECTDB: u8 = 254;
+pub const RDB_OPCODE_EOF: u8 = 255;
+
+pub const RDB_TYPE_STRING: u8 = 0;
+pub const RDB_TYPE_LIST: u8 = 1;
+pub const RDB_TYPE_SET: u8 = 2;
+pub const RDB_TYPE_ZSET: u8 = 3;
+pub const RDB_TYPE_HASH: u8 = 4;
+pub const RDB_TYPE_ZSET_2: u8 = 5;
+pub const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+pub const RDB_TYPE_SET_INTSET: u8 = 11;
+pub const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+pub const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+pub const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+pub const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+pub const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+pub const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+pub const RDB_TYPE_SET_LISTPACK: u8 = 20;
+
+const QUICKLIST_NODE_CONTAINER_PLAIN: u64 = 1;
+
+const RDB_ENCVAL: u8 = 3;
+const RDB_ENC_INT8: u64 = 0;
+const RDB_ENC_INT16: u64 = 1;
+const RDB_ENC_INT32: u64 = 2;
+const RDB_ENC_LZF: u64 = 3;
+
+/// Longest string we accept, like Redis's default proto-max-bulk-len.
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+/// Most an LZF back reference gives, 264 bytes out of 3 bytes of input.
+const LZF_MAX_EXPANSION: usize = 88;
+
+/// Appends `len` using the RDB variable length encoding.
+pub fn write_length(buf: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        buf.push(len as u8);
+    } else if len < 1 << 14 {
+        buf.extend([0x40 | (len >> 8) as u8, len as u8]);
+    } else if len <= u32::MAX as u64 {
+        buf.push(0x80);
+        buf.extend((len as u32).to_be_bytes());
+    } else {
+        buf.push(0x81);
+        buf.extend(len.to_be_bytes());
+    }
+}
+
+/// Appends a length prefixed string.
+pub fn write_string(buf: &mut Vec<u8>, data: &[u8]) {
+    write_length(buf, data.len() as u64);
+    buf.extend(data);
+}
+
+/// Wraps a serialized value the way DUMP does: the RDB version followed by
+/// a CRC64 of everything before it, both little endian.
+pub fn dump_payload(mut payload: Vec<u8>) -> Vec<u8> {
+    payload.extend(RDB_VERSION.to_le_bytes());
+    let crc = crc64(0, &payload);
+    payload.extend(crc.to_le_bytes());
+    payload
+}
+
+/// Checks the footer added by `dump_payload`, returning the serialized
+/// value without it.
+pub fn verify_payload(payload: &[u8]) -> Result<&[u8]> {
+    let invalid = || anyhow!("DUMP payload version or checksum are wrong");
+    if payload.len() < 10 {
+        return Err(invalid());
+    }
+    let (body, crc) = payload.split_at(payload.len() - 8);
+    let version = u16::from_le_bytes([body[body.len() - 2], body[body.len() - 1]]);
+    if version > MAX_RDB_VERSION || crc64(0, body).to_le_bytes() != crc {
+        return Err(invalid());
+    }
+    Ok(&body[..body.len() - 2])
+}
+
+/// Serializes a single value the way DUMP does: its type and encoding
+/// followed by the payload footer.
+pub fn dump_value(value: &Value) -> Vec<u8> {
+    let mut payload = vec![value_type(value)];
+    write_value_body(&mut payload, value);
+    dump_payload(payload)
+}
+
+/// Reads back a value serialized by `dump_value` (or by Redis's DUMP).
+pub fn restore_value(payload: &[u8]) -> Result<Value> {
+    let body = verify_payload(payload)?;
+    let mut reader = RdbReader::new(body);
+    let value_type = reader.read_u8()?;
+    let value = reader.read_value(value_type).map_err(|_| anyhow!("Bad data format"))?;
+    if reader.pos != body.len() {
+        return Err(anyhow!("Bad data format"));
+    }
+    Ok(value)
+}
+
+/// Cursor over RDB encoded data.
+pub struct RdbReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RdbReader<'a> {
+    pub fn new(input: &'a [u8]) -> RdbReader<'a> {
+        RdbReader { input, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.input.len()
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self.pos.checked_add(n)
+            .and_then(|end| self.input.get(self.pos..end))
+            .ok_or_else(|| anyhow!("unexpected end of RDB data"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    /// How many of `len` elements to make room for up front. Lengths come
+    /// from untrusted data, and every element takes at least a byte.
+    fn capacity(&self, len: u64) -> usize {
+        len.min(self.input.len().saturating_sub(self.pos) as u64) as usize
+    }
+
+    /// Reads a length, which may instead be the marker of a specially
+    /// encoded string, in which case the flag is set.
+    fn read_length_or_encoding(&mut self) -> Result<(u64, bool)> {
+        let first = self.read_u8()?;
+        match first >> 6 {
+            0 => Ok(((first & 0x3f) as u64, false)),
+            1 => Ok(((((first & 0x3f) as u64) << 8) | self.read_u8()? as u64, false)),
+            2 if first == 0x80 => Ok((u32::from_be_bytes(self.read_bytes(4)?.try_into()?) as u64, false)),
+            2 if first == 0x81 => Ok((u64::from_be_bytes(self.read_bytes(8)?.try_into()?), false)),
+            RDB_ENCVAL => Ok(((first & 0x3f) as u64, true)),
+            _ => Err(anyhow!("unknown RDB length encoding {first:#x}")),
+        }
+    }
+
+    pub fn read_length(&mut self) -> Result<u64> {
+        match self.read_length_or_encoding()? {
+            (len, false) => Ok(len),
+            (_, true) => Err(anyhow!("expected a length, got an encoded string")),
+        }
+    }
+
+    /// Reads a string, which may be stored as an integer or LZF compressed.
+    pub fn read_string(&mut self) -> Result<Vec<u8>> {
+        match self.read_length_or_encoding()? {
+            (len, false) => Ok(self.read_bytes(len as usize)?.to_vec()),
+            (RDB_ENC_INT8, true) => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            (RDB_ENC_INT16, true) => Ok(i16::from_le_bytes(self.read_bytes(2)?.try_into()?).to_string().into_bytes()),
+            (RDB_ENC_INT32, true) => Ok(i32::from_le_bytes(self.read_bytes(4)?.try_into()?).to_string().into_bytes()),
+            (RDB_ENC_LZF, true) => {
+                let compressed_len = self.read_length()? as usize;
+                let len = self.read_length()? as usize;
+                lzf_decompress(self.read_bytes(compressed_len)?, len)
+            },
+            (encoding, true) => Err(anyhow!("unknown RDB string encoding {encoding}")),
+        }
+    }
+}
+
+/// A key read from an RDB file.
+pub struct SnapshotEntry {
+    pub key: String,
+    /// Shared with the keyspace the snapshot was taken from.
+    pub value: Arc<Value>,
+    /// Unix time in milliseconds
+    pub expires_at: Option<u64>,
+}
+
+/// Everything stored in an RDB file.
+#[derive(Default)]
+pub struct Snapshot {
+    pub aux: Vec<(String, String)>,
+    /// Keys of each database, by database number
+    pub databases: BTreeMap<u64, Vec<SnapshotEntry>>,
+    /// Sources of function libraries
+    pub functions: Vec<String>,
+}
+
+/// Parses a whole RDB file, verifying its checksum. Keys that are already
+/// expired are left out.
+pub fn parse(data: &[u8]) -> Result<Snapshot> {
+    parse_prefix(data).map(|(snapshot, _)| snapshot)
+}
+
+/// Like `parse`, for data starting with an RDB file, as an AOF with an RDB
+/// preamble does. Also returns where the RDB file ends.
+pub fn parse_prefix(data: &[u8]) -> Result<(Snapshot, usize)> {
+    let mut reader = RdbReader::new(data);
+    let magic = reader.read_bytes(9)?;
+    if &magic[..5] != b"REDIS" {
+        return Err(anyhow!("wrong signature trying to load DB from file"));
+    }
+    let version: u16 = std::str::from_utf8(&magic[5..])?.parse()?;
+    if version > MAX_RDB_VERSION {
+        return Err(anyhow!("can't handle RDB format version {version}"));
+    }
+
+    let mut snapshot = Snapshot::default();
+    let mut db = 0;
+    let mut expires_at = None;
+    let now = now_ms();
+    loop {
+        let opcode = reader.read_u8()?;
+        match opcode {
+            RDB_OPCODE_EOF => break,
+            RDB_OPCODE_AUX => {
+                let key = lossy(reader.read_string()?);
+                let value = lossy(reader.read_string()?);
+                debug!(target: "rdb", "aux field {key}: {value}");
+                snapshot.aux.push((key, value));
+            },
+            RDB_OPCODE_SELECTDB => db = reader.read_length()?,
+            RDB_OPCODE_RESIZEDB => {
+                let size = reader.read_length()?;
+                let _expires_size = reader.read_length()?;
+                let size = reader.capacity(size);
+                snapshot.databases.entry(db).or_default().reserve(size);
+            },
+            RDB_OPCODE_EXPIRETIME_MS => {
+                expires_at = Some(u64::from_le_bytes(reader.read_bytes(8)?.try_into()?));
+            },
+            RDB_OPCODE_EXPIRETIME => {
+                expires_at = Some(u32::from_le_bytes(reader.read_bytes(4)?.try_into()?) as u64 * 1000);
+            },
+            RDB_OPCODE_IDLE => {
+                reader.read_length()?;
+            },
+            RDB_OPCODE_FREQ => {
+                reader.read_u8()?;
+            },
+            RDB_OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.read_length()?;
+                }
+            },
+            RDB_OPCODE_FUNCTION2 => snapshot.functions.push(lossy(reader.read_string()?)),
+            RDB_OPCODE_FUNCTION_PRE_GA => return Err(anyhow!("pre-release function format not supported")),
+            RDB_OPCODE_MODULE_AUX => return Err(anyhow!("module data is not supported")),
+            value_type => {
+                let key = lossy(reader.read_string()?);
+                let value = reader.read_value(value_type)?;
+                match expires_at.take() {
+                    Some(expires_at) if expires_at <= now => debug!(target: "rdb", "skipping expired key {key}"),
+                    expires_at => snapshot.databases.entry(db).or_default().push(SnapshotEntry { key, value: Arc::new(value), expires_at }),
+                }
+            },
+        }
+    }
+
+    if version >= 5 {
+        let checksum_start = reader.pos;
+        let expected = u64::from_le_bytes(reader.read_bytes(8)?.try_into()?);
+        if expected == 0 {
+            warn!(target: "rdb", "RDB file was saved with checksum disabled: no check performed");
+        } else if crc64(0, &data[..checksum_start]) != expected {
+            return Err(anyhow!("wrong RDB checksum"));
+        }
+    }
+    Ok((snapshot, reader.pos))
+}
+
+/// Serializes `snapshot` into a complete RDB file, checksum included.
+pub fn serialize(snapshot: &Snapshot) -> Vec<u8> {
+    let mut buf = format!("REDIS{RDB_VERSION:04}").into_bytes();
+    for (key, value) in &snapshot.aux {
+        buf.push(RDB_OPCODE_AUX);
+        write_string(&mut buf, key.as_bytes());
+        write_string(&mut buf, value.as_bytes());
+    }
+    for code in &snapshot.functions {
+        buf.push(RDB_OPCODE_FUNCTION2);
+        write_string(&mut buf, code.as_bytes());
+    }
+    for (db, entries) in &snapshot.databases {
+        buf.push(RDB_OPCODE_SELECTDB);
+        write_length(&mut buf, *db);
+        buf.push(RDB_OPCODE_RESIZEDB);
+        write_length(&mut buf, entries.len() as u64);
+        write_length(&mut buf, entries.iter().filter(|entry| entry.expires_at.is_some()).count() as u64);
+        for entry in entries {
+            if let Some(expires_at) = entry.expires_at {
+                buf.push(RDB_OPCODE_EXPIRETIME_MS);
+                buf.extend(expires_at.to_le_bytes());
+            }
+            write_value(&mut buf, &entry.key, &entry.value);
+        }
+    }
+    buf.push(RDB_OPCODE_EOF);
+    let crc = crc64(0, &buf);
+    buf.extend(crc.to_le_bytes());
+    buf
+}
+
+/// Appends the type, key and value of an entry, always using the plain
+/// (non compact) encodings.
+fn write_value(buf: &mut Vec<u8>, key: &str, value: &Value) {
+    buf.push(value_type(value));
+    write_string(buf, key.as_bytes());
+    write_value_body(buf, value);
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => RDB_TYPE_STRING,
+        Value::List(_) => RDB_TYPE_LIST,
+        Value::Set(_) => RDB_TYPE_SET,
+        Value::ZSet(_) => RDB_TYPE_ZSET_2,
+        Value::Hash(_) => RDB_TYPE_HASH,
+    }
+}
+
+fn write_value_body(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(Str::Int(n)) => write_integer(buf, *n),
+        Value::String(Str::Raw(s)) => write_string(buf, s.as_bytes()),
+        Value::List(list) => {
+            write_length(buf, list.len() as u64);
+            list.iter().for_each(|element| write_string(buf, element.as_bytes()));
+        },
+        Value::Set(set) => {
+            write_length(buf, set.len() as u64);
+            set.iter().for_each(|member| write_string(buf, member.as_bytes()));
+        },
+        Value::ZSet(zset) => {
+            write_length(buf, zset.len() as u64);
+            for (member, score) in zset.iter() {
+                write_string(buf, member.as_bytes());
+                buf.extend(score.to_le_bytes());
+            }
+        },
+        Value::Hash(hash) => {
+            write_length(buf, hash.len() as u64);
+            for (field, value) in hash.iter() {
+                write_string(buf, field.as_bytes());
+                write_string(buf, value.as_bytes());
+            }
+        },
+    }
+}
+
+/// Appends an integer string, in as few bytes as the integer fits in.
+fn write_integer(buf: &mut Vec<u8>, n: i64) {
+    let encoded = (RDB_ENCVAL as u64) << 6;
+    if let Ok(n) = i8::try_from(n) {
+        buf.extend([(encoded | RDB_ENC_INT8) as u8, n as u8]);
+    } else if let Ok(n) = i16::try_from(n) {
+        buf.push((encoded | RDB_ENC_INT16) as u8);
+        buf.extend(n.to_le_bytes());
+    } else if let Ok(n) = i32::try_from(n) {
+        buf.push((encoded | RDB_ENC_INT32) as u8);
+        buf.extend(n.to_le_bytes());
+    } else {
+        write_string(buf, n.to_string().as_bytes());
+    }
+}
+
+fn lossy(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
+}
+
+impl RdbReader<'_> {
+    fn read_value(&mut self, value_type: u8) -> Result<Value> {
+        match value_type {
+            RDB_TYPE_STRING => Ok(Value::String(lossy(self.read_string()?).into())),
+            RDB_TYPE_LIST => {
+                let len = self.read_length()?;
+                let list = (0..len).map(|_| self.read_string().map(lossy)).collect::<Result<List>>()?;
+                Ok(Value::List(list))
+            },
+            RDB_TYPE_SET => {
+                let len = self.read_length()?;
+                let set = (0..len).map(|_| self.read_string().map(lossy)).collect::<Result<Set>>()?;
+                Ok(Value::Set(set))
+            },
+            RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                let len = self.read_length()?;
+                let mut zset = HashMap::with_capacity(self.capacity(len));
+                for _ in 0..len {
+                    let member = lossy(self.read_string()?);
+                    let score = match value_type {
+                        RDB_TYPE_ZSET => self.read_string_double()?,
+                        _ => f64::from_le_bytes(self.read_bytes(8)?.try_into()?),
+                    };
+                    zset.insert(member, score);
+                }
+                Ok(Value::ZSet(ZSet::Skiplist(zset)))
+            },
+            RDB_TYPE_HASH => {
+                let len = self.read_length()?;
+                let mut hash = HashMap::with_capacity(self.capacity(len));
+                for _ in 0..len {
+                    hash.insert(lossy(self.read_string()?), lossy(self.read_string()?));
+                }
+                Ok(Value::Hash(Hash::Hashtable(hash)))
+            },
+            RDB_TYPE_LIST_ZIPLIST => Ok(Value::List(ziplist_entries(&self.read_string()?)?.into_iter().collect())),
+            RDB_TYPE_SET_INTSET => Ok(Value::Set(Set::Intset(Intset::from_bytes(self.read_string()?)?))),
+            RDB_TYPE_SET_LISTPACK => Ok(Value::Set(Set::Listpack(distinct(Listpack::from_bytes(self.read_string()?)?, false)?))),
+            RDB_TYPE_ZSET_ZIPLIST => {
+                let zset = pairs(ziplist_entries(&self.read_string()?)?)?.into_iter()
+                    .map(|(member, score)| Ok((member, score.parse::<f64>()?)))
+                    .collect::<Result<ZSet>>()?;
+                Ok(Value::ZSet(zset))
+            },
+            RDB_TYPE_ZSET_LISTPACK => {
+                let listpack = distinct(Listpack::from_bytes(self.read_string()?)?, true)?;
+                let scores_are_numbers = listpack.iter().skip(1).step_by(2).all(|score| match score {
+                    ListpackEntry::Int(_) => true,
+                    ListpackEntry::Str(s) => std::str::from_utf8(s).is_ok_and(|s| s.parse::<f64>().is_ok()),
+                });
+                match scores_are_numbers {
+                    true => Ok(Value::ZSet(ZSet::Listpack(listpack))),
+                    false => Err(anyhow!("invalid score in a sorted set")),
+                }
+            },
+            RDB_TYPE_HASH_ZIPLIST => Ok(Value::Hash(pairs(ziplist_entries(&self.read_string()?)?)?.into_iter().collect())),
+            RDB_TYPE_HASH_LISTPACK => Ok(Value::Hash(Hash::Listpack(distinct(Listpack::from_bytes(self.read_string()?)?, true)?))),
+            RDB_TYPE_LIST_QUICKLIST | RDB_TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.read_length()?;
+                let mut list = VecDeque::new();
+                for _ in 0..nodes {
+                    if value_type == RDB_TYPE_LIST_QUICKLIST {
+                        list.extend(ziplist_entries(&self.read_string()?)?);
+                        continue;
+                    }
+                    let container = self.read_length()?;
+                    let blob = self.read_string()?;
+                    match container {
+                        QUICKLIST_NODE_CONTAINER_PLAIN => list.push_back(lossy(blob)),
+                        _ => list.extend(Listpack::from_bytes(blob)?.iter().map(|entry| entry.to_cow().into_owned())),
+                    }
+                }
+                Ok(Value::List(List::Quicklist(list)))
+            },
+            x => Err(anyhow!("unsupported RDB value type {x}")),
+        }
+    }
+
+    /// Reads a score in the old textual format, where the length byte has
+    /// special values for NaN and infinities.
+    fn read_string_double(&mut self) -> Result<f64> {
+        match self.read_u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => Ok(std::str::from_utf8(self.read_bytes(len as usize)?)?.parse()?),
+        }
+    }
+}
+
+fn pairs(entries: Vec<String>) -> Result<Vec<(String, String)>> {
+    if !entries.len().is_multiple_of(2) {
+        return Err(anyhow!("odd number of entries in a hash or sorted set"));
+    }
+    let mut entries = entries.into_iter();
+    Ok(std::iter::from_fn(|| Some((entries.next()?, entries.next()?))).collect())
+}
+
+/// Checks that a listpack holding a set has no duplicate members, or with
+/// `paired`, that one holding a hash or sorted set has pairs of entries and
+/// no duplicate fields or members, which lookups rely on.
+fn distinct(listpack: Listpack, paired: bool) -> Result<Listpack> {
+    if paired && !listpack.len().is_multiple_of(2) {
+        return Err(anyhow!("odd number of entries in a hash or sorted set"));
+    }
+    let mut seen = HashSet::new();
+    match listpack.iter().step_by(if paired { 2 } else { 1 }).all(|key| seen.insert(key.to_cow())) {
+        true => Ok(listpack),
+        false => Err(anyhow!("duplicate entries in a listpack")),
+    }
+}
+
+/// Elements of a ziplist: a header with the total size, the offset of the
+/// last entry and the count, followed by the entries and a 0xFF terminator.
+fn ziplist_entries(blob: &[u8]) -> Result<Vec<String>> {
+    let mut reader = RdbReader::new(blob);
+    reader.read_bytes(10)?;
+    let mut entries = vec![];
+    loop {
+        let prevlen = reader.read_u8()?;
+        if prevlen == 0xff {
+            break;
+        }
+        if prevlen == 0xfe {
+            reader.read_bytes(4)?;
+        }
+        let encoding = reader.read_u8()?;
+        let entry = match encoding {
+            0x00..=0x3f => lossy(reader.read_bytes((encoding & 0x3f) as usize)?.to_vec()),
+            0x40..=0x7f => {
+                let len = (((encoding & 0x3f) as usize) << 8) | reader.read_u8()? as usize;
+                lossy(reader.read_bytes(len)?.to_vec())
+            },
+            0x80 => {
+                let len = u32::from_be_bytes(reader.read_bytes(4)?.try_into()?) as usize;
+                lossy(reader.read_bytes(len)?.to_vec())
+            },
+            0xc0 => i16::from_le_bytes(reader.read_bytes(2)?.try_into()?).to_string(),
+            0xd0 => i32::from_le_bytes(reader.read_bytes(4)?.try_into()?).to_string(),
+            0xe0 => i64::from_le_bytes(reader.read_bytes(8)?.try_into()?).to_string(),
+            0xf0 => {
+                let bytes = reader.read_bytes(3)?;
+                (i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8).to_string()
+            },
+            0xfe => (reader.read_u8()? as i8).to_string(),
+            0xf1..=0xfd => ((encoding & 0x0f) as i64 - 1).to_string(),
+            x => return Err(anyhow!("invalid ziplist entry encoding {x:#x}")),
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Decompresses LZF data, as used by Redis for long strings.
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>> {
+    let invalid = || anyhow!("invalid LZF compressed string");
+    // the length is untrusted: check it's one the input could decompress to
+    if len > MAX_STRING_LEN || len > input.len().saturating_mul(LZF_MAX_EXPANSION) {
+        return Err(invalid());
+    }
+    let mut output = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let literal = input.get(i..i + ctrl + 1).ok_or_else(invalid)?;
+            output.extend_from_slice(literal);
+            i += ctrl + 1;
+        } else {
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or_else(invalid)? as usize;
+                i += 1;
+            }
+            let offset = ((ctrl & 0x1f) << 8) + *input.get(i).ok_or_else(invalid)? as usize + 1;
+            i += 1;
+            let start = output.len().checked_sub(offset).ok_or_else(invalid)?;
+            for j in 0..run + 2 {
+                output.push(output[start + j]);
+            }
+        }
+    }
+    if output.len() != len {
+        return Err(invalid());
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_length_roundtrip() {
+        for len in [0, 63, 64, 16383, 16384, u32::MAX as u64, u64::MAX] {
+            let mut buf = vec![];
+            write_length(&mut buf, len);
+            assert_eq!(len, RdbReader::new(&buf).read_length().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_read_encoded_strings() {
+        let mut reader = RdbReader::new(&[0xc0, 0xfe, 0xc1, 0x39, 0x30, 0xc2, 0x87, 0xd6, 0x12, 0x00]);
+        assert_eq!(b"-2".to_vec(), reader.read_string().unwrap());
+        assert_eq!(b"12345".to_vec(), reader.read_string().unwrap());
+        assert_eq!(b"1234567".to_vec(), reader.read_string().unwrap());
+    }
+
+    #[test]
+    fn test_read_lzf_string() {
+        // 32 times "a": one literal followed by a back reference
+        let mut reader = RdbReader::new(&[0xc3, 0x05, 0x20, 0x00, 0x61, 0xe0, 0x16, 0x00]);
+        assert_eq!(vec![b'a'; 32], reader.read_string().unwrap());
+    }
+
+    #[test]
+    fn test_payload_checksum() {
+        let payload = dump_payload(b"\x00\x03foo".to_vec());
+        assert_eq!(b"\x00\x03foo", verify_payload(&payload).unwrap());
+
+        let mut corrupted = payload.clone();
+        corrupted[2] = b'g';
+        assert!(verify_payload(&corrupted).is_err());
+    }
+
+    #[test]
+    fn test_dump_value_roundtrip() {
+        let value = Value::Hash([("field".to_string(), "value".to_string())].into_iter().collect());
+        let payload = dump_value(&value);
+        assert_eq!(value, restore_value(&payload).unwrap());
+
+        let mut truncated = payload[..payload.len() - 10].to_vec();
+        truncated.pop();
+        assert!(restore_value(&dump_payload(truncated)).is_err());
+    }
+
+    #[test]
+    fn test_restore_redis_payload() {
+        // what Redis 7 gives for DUMP mykey after SET mykey 10
+        let payload = b"\x00\xc0\n\n\x00n\x9fWE\x0e\xaec\xbb";
+        assert_eq!(Value::String("10".into()), restore_value(payload).unwrap());
+
+        let mut listpack = vec![RDB_TYPE_HASH_LISTPACK];
+        write_string(&mut listpack, &[18, 0, 0, 0, 4, 0, 0x81, b'f', 2, 0x81, b'v', 2, 0x81, b'g', 2, 0x05, 1, 0xff]);
+        let hash = restore_value(&dump_payload(listpack)).unwrap();
+        assert_eq!(Value::Hash([("f", "v"), ("g", "5")].map(|(k, v)| (k.to_string(), v.to_string())).into_iter().collect()), hash);
+    }
+
+    #[test]
+    fn test_restore_rejects_inconsistent_compact_values() {
+        let restore = |value_type: u8, bytes: &[u8]| {
+            let mut payload = vec![value_type];
+            write_string(&mut payload, bytes);
+            restore_value(&dump_payload(payload))
+        };
+        let listpack = |entries: &[&str]| entries.iter().copied().collect::<Listpack>().as_bytes().to_vec();
+
+        assert!(restore(RDB_TYPE_SET_INTSET, &[2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 2, 0]).is_ok());
+        assert!(restore(RDB_TYPE_SET_INTSET, &[2, 0, 0, 0, 2, 0, 0, 0, 2, 0, 1, 0]).is_err());
+        assert!(restore(RDB_TYPE_SET_INTSET, &[2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 1, 0]).is_err());
+        assert!(restore(RDB_TYPE_SET_LISTPACK, &listpack(&["a", "b", "a"])).is_err());
+        assert!(restore(RDB_TYPE_HASH_LISTPACK, &listpack(&["f", "v", "f", "w"])).is_err());
+        assert!(restore(RDB_TYPE_HASH_LISTPACK, &listpack(&["f", "v", "v", "f"])).is_ok());
+        assert!(restore(RDB_TYPE_ZSET_LISTPACK, &listpack(&["a", "1", "a", "2"])).is_err());
+        assert!(restore(RDB_TYPE_ZSET_LISTPACK, &listpack(&["a", "one"])).is_err());
+        assert!(restore(RDB_TYPE_ZSET_LISTPACK, &listpack(&["a", "1.5", "b", "1.5"])).is_ok());
+    }
+
+    #[test]
+    fn test_restore_rejects_huge_lengths() {
+        let huge = 1 << 50;
+        let mut zset = vec![RDB_TYPE_ZSET_2];
+        write_length(&mut zset, huge);
+        let mut hash = vec![RDB_TYPE_HASH];
+        write_length(&mut hash, huge);
+        let mut lzf = vec![RDB_TYPE_STRING, 0xc3];
+        write_length(&mut lzf, 2);
+        write_length(&mut lzf, huge);
+        lzf.extend([0, b'a']);
+        let mut string = vec![RDB_TYPE_STRING];
+        write_length(&mut string, u64::MAX);
+
+        let mut lzf_too_long = vec![RDB_TYPE_STRING, 0xc3];
+        write_length(&mut lzf_too_long, 2);
+        write_length(&mut lzf_too_long, 2 * LZF_MAX_EXPANSION as u64 + 1);
+        lzf_too_long.extend([0, b'a']);
+
+        for payload in [zset, hash, lzf, lzf_too_long, string] {
+            assert_eq!("Bad data format", restore_value(&dump_payload(payload)).unwrap_err().to_string());
+        }
+    }
+
+    fn snapshot_file(body: &[u8]) -> Vec<u8> {
+        let mut data = b"REDIS0011".to_vec();
+        data.extend_from_slice(body);
+        data.push(RDB_OPCODE_EOF);
+        let checksum = crc64(0, &data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_parse_snapshot() {
+        let mut body = vec![RDB_OPCODE_AUX];
+        write_string(&mut body, b"redis-ver");
+        write_string(&mut body, b"7.2.4");
+        body.extend_from_slice(&[RDB_OPCODE_SELECTDB, 0, RDB_OPCODE_RESIZEDB, 4, 1]);
+
+        body.push(RDB_OPCODE_EXPIRETIME_MS);
+        body.extend_from_slice(&(now_ms() + 60_000).to_le_bytes());
+        body.push(RDB_TYPE_STRING);
+        write_string(&mut body, b"session");
+        write_string(&mut body, b"abc");
+
+        body.push(RDB_OPCODE_EXPIRETIME);
+        body.extend_from_slice(&1u32.to_le_bytes());
+        body.push(RDB_TYPE_STRING);
+        write_string(&mut body, b"stale");
+        write_string(&mut body, b"gone");
+
+        body.push(RDB_TYPE_LIST_QUICKLIST_2);
+        write_string(&mut body, b"queue");
+        body.extend_from_slice(&[1, 2]);
+        write_string(&mut body, &[12, 0, 0, 0, 2, 0, 0x81, b'a', 2, 12, 1, 0xff]);
+
+        body.push(RDB_TYPE_SET_INTSET);
+        write_string(&mut body, b"ids");
+        write_string(&mut body, &[2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0x2c, 0x01]);
+
+        body.push(RDB_TYPE_HASH_ZIPLIST);
+        write_string(&mut body, b"user");
+        write_string(&mut body, &[18, 0, 0, 0, 13, 0, 0, 0, 2, 0, 0, 1, b'f', 3, 0xf2, 0xff]);
+
+        let snapshot = parse(&snapshot_file(&body)).unwrap();
+        assert_eq!(vec![("redis-ver".to_string(), "7.2.4".to_string())], snapshot.aux);
+
+        let entries = &snapshot.databases[&0];
+        let keys: Vec<&str> = entries.iter().map(|entry| entry.key.as_str()).collect();
+        assert_eq!(vec!["session", "queue", "ids", "user"], keys);
+        assert!(entries[0].expires_at.is_some());
+        assert_eq!(Value::List(["a", "12"].map(String::from).into_iter().collect()), *entries[1].value);
+        assert_eq!(Value::Set(["1", "300"].map(String::from).into_iter().collect()), *entries[2].value);
+        assert_eq!(Value::Hash([("f".to_string(), "1".to_string())].into_iter().collect()), *entries[3].value);
+    }
+
+    #[test]
+    fn test_parse_rejects_huge_lengths() {
+        let mut body = vec![RDB_OPCODE_SELECTDB, 0, RDB_OPCODE_RESIZEDB];
+        write_length(&mut body, 1 << 50);
+        write_length(&mut body, 1 << 50);
+        body.push(RDB_TYPE_HASH);
+        write_string(&mut body, b"user");
+        write_length(&mut body, 1 << 50);
+        assert!(parse(&snapshot_file(&body)).is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_checksum() {
+        let mut body = vec![RDB_TYPE_STRING];
+        write_string(&mut body, b"key");
+        write_string(&mut body, b"value");
+        let mut data = snapshot_file(&body);
+        assert!(parse(&data).is_ok());
+
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        assert!(parse(&data).is_err());
+    }
+
+    #[test]
+    fn test_serialize_roundtrip() {
+        let mut snapshot = Snapshot::default();
+        snapshot.aux.push(("redis-ver".to_string(), "7.2.4".to_string()));
+        snapshot.functions.push("#!lua name=lib\nredis.register_function('f', function() return 1 end)".to_string());
+        snapshot.databases.insert(0, vec![
+            SnapshotEntry { key: "name".to_string(), value: Arc::new(Value::String("redis".into())), expires_at: Some(now_ms() + 60_000) },
+            SnapshotEntry { key: "scores".to_string(), value: Arc::new(Value::ZSet([("a".to_string(), 1.5)].into_iter().collect())), expires_at: None },
+            SnapshotEntry { key: "queue".to_string(), value: Arc::new(Value::List(["x", "y"].map(String::from).into_iter().collect())), expires_at: None },
+        ]);
+
+        let parsed = parse(&serialize(&snapshot)).unwrap();
+        assert_eq!(snapshot.aux, parsed.aux);
+        assert_eq!(snapshot.functions, parsed.functions);
+        let (expected, actual) = (&snapshot.databases[&0], &parsed.databases[&0]);
+        assert_eq!(expected.len(), actual.len());
+        for (expected, actual) in expected.iter().zip(actual) {
+            assert_eq!(expected.key, actual.key);
+            assert_eq!(expected.value, actual.value);
+            assert_eq!(expected.expires_at, actual.expires_at);
+        }
+    }
+}
@@ -1,9 +1,43 @@
-use log::info;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
 use rand::{thread_rng, RngCore};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch};
+use tokio::time::{self, Instant};
+
+use crate::commands::{CommandRequest, ReplconfCommand, ReplicationInfo, ReplicationRole, ToRESP};
+use crate::interpreter::Interpreter;
+use crate::keyspace::now_ms;
+use crate::protocol::RESP;
+use crate::rdb;
+use crate::stream::{CommandStream, InvalidRequest};
+
+/// Replication id meaning there's none, as used for `master_replid2`.
+const NO_REPLID: &str = "0000000000000000000000000000000000000000";
 
-use crate::{commands::CommandRequest, stream::CommandStream};
+/// How often replicas tell their master how far they got.
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
 
+/// How often masters ping their replicas, so they can tell a silent master
+/// from a dead one.
+pub const PING_REPLICA_PERIOD: Duration = Duration::from_secs(10);
+
+/// How long a replica waits for its master before dropping the link.
+const REPL_TIMEOUT: Duration = Duration::from_secs(60);
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
 
 pub fn gen_replica_id() -> String {
     let mut rng = thread_rng();
@@ -13,22 +47,758 @@ pub fn gen_replica_id() -> String {
     hex_string
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplicaState {
+    /// Waiting for the snapshot to be sent
+    SendBulk,
+    Online,
+}
+
+impl ReplicaState {
+    pub fn to_str(&self) -> &str {
+        match self {
+            ReplicaState::SendBulk => "send_bulk",
+            ReplicaState::Online => "online",
+        }
+    }
+}
+
+/// What INFO reports about a connected replica.
+#[derive(Debug, Clone)]
+pub struct ReplicaSummary {
+    pub ip: IpAddr,
+    pub port: u16,
+    pub state: ReplicaState,
+    /// Replication offset the replica last acknowledged.
+    pub offset: u64,
+}
+
+struct Replica {
+    summary: ReplicaSummary,
+    feed: UnboundedSender<Vec<u8>>,
+}
+
+/// Receiving end of the commands propagated to a replica, handed to its
+/// connection once the snapshot is sent.
+pub struct ReplicaFeed {
+    id: u64,
+    commands: UnboundedReceiver<Vec<u8>>,
+}
+
+/// Most recent part of the replication stream, kept so replicas that lost
+/// their connection can catch up without a full resync.
+struct Backlog {
+    buffer: Vec<u8>,
+    /// Where the next byte goes in `buffer`, which wraps around.
+    next: usize,
+    histlen: usize,
+    /// Replication offset of the last byte fed.
+    end: u64,
+}
+
+impl Backlog {
+    fn new(size: usize, offset: u64) -> Backlog {
+        Backlog { buffer: vec![0; size.max(1)], next: 0, histlen: 0, end: offset }
+    }
+
+    fn size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn first_byte_offset(&self) -> u64 {
+        self.end + 1 - self.histlen as u64
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        self.end += bytes.len() as u64;
+        let size = self.size();
+        // only the last `size` bytes can fit anyway
+        let bytes = &bytes[bytes.len().saturating_sub(size)..];
+        let first = bytes.len().min(size - self.next);
+        self.buffer[self.next..self.next + first].copy_from_slice(&bytes[..first]);
+        self.buffer[..bytes.len() - first].copy_from_slice(&bytes[first..]);
+        self.next = (self.next + bytes.len()) % size;
+        self.histlen = (self.histlen + bytes.len()).min(size);
+    }
+
+    /// Everything from `offset` on, if it's still there.
+    fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.first_byte_offset() || offset > self.end + 1 {
+            return None;
+        }
+        let len = (self.end + 1 - offset) as usize;
+        let start = (self.next + self.size() - len) % self.size();
+        let mut bytes = Vec::with_capacity(len);
+        bytes.extend_from_slice(&self.buffer[start..(start + len).min(self.size())]);
+        bytes.extend_from_slice(&self.buffer[..len - bytes.len()]);
+        Some(bytes)
+    }
+}
+
+/// The replication ids a replica can ask to continue from: ours, and the one
+/// of our former master up to `second_offset`.
+struct ReplicationIds {
+    replid: String,
+    replid2: String,
+    second_offset: i64,
+}
+
+#[derive(Default)]
+struct Feeds {
+    replicas: BTreeMap<u64, Replica>,
+    /// Created once there's a replication stream to keep.
+    backlog: Option<Backlog>,
+}
+
+/// What a replica waiting for a diskless sync gets once the snapshot is
+/// taken: where the stream starts, the snapshot, and the rest of the stream.
+pub struct DisklessSync {
+    pub replid: String,
+    pub offset: u64,
+    pub rdb: Arc<Vec<u8>>,
+    pub feed: ReplicaFeed,
+}
+
+struct DisklessWaiter {
+    ip: IpAddr,
+    port: u16,
+    sync: oneshot::Sender<DisklessSync>,
+}
+
+/// A diskless sync whose replicas were registered when the snapshot was
+/// taken, waiting for it to be serialized.
+pub struct StartedDisklessSync {
+    replid: String,
+    offset: u64,
+    waiting: Vec<(oneshot::Sender<DisklessSync>, ReplicaFeed)>,
+}
+
+/// Replicas connected to this server, which get every write command, and
+/// the replication stream they get: its ids, offset and backlog.
+pub struct Replicas {
+    ids: Mutex<ReplicationIds>,
+    feeds: Mutex<Feeds>,
+    backlog_size: usize,
+    /// How long to wait for more replicas before streaming a snapshot, if
+    /// snapshots are streamed without being saved first.
+    diskless_sync_delay: Option<Duration>,
+    diskless_waiting: Mutex<Vec<DisklessWaiter>>,
+    next_id: AtomicU64,
+    /// Bytes of replication stream produced so far, or processed so far when
+    /// we're a replica ourselves.
+    offset: AtomicU64,
+    /// Signaled whenever a replica acknowledges an offset.
+    acks: watch::Sender<()>,
+}
+
+impl Replicas {
+    pub fn new(replid: String, backlog_size: usize, diskless_sync_delay: Option<Duration>) -> Replicas {
+        Replicas {
+            ids: Mutex::new(ReplicationIds { replid, replid2: NO_REPLID.to_string(), second_offset: -1 }),
+            feeds: Mutex::new(Feeds::default()),
+            backlog_size,
+            diskless_sync_delay,
+            diskless_waiting: Mutex::new(vec![]),
+            next_id: AtomicU64::new(0),
+            offset: AtomicU64::new(0),
+            acks: watch::channel(()).0,
+        }
+    }
+
+    pub fn replid(&self) -> String {
+        self.ids.lock().unwrap().replid.clone()
+    }
+
+    /// Replication id and offset to ask a master to continue from, if we
+    /// have a stream to continue.
+    pub fn psync_position(&self) -> Option<(String, i64)> {
+        let ids = self.ids.lock().unwrap();
+        let feeds = self.feeds.lock().unwrap();
+        feeds.backlog.as_ref().map(|_| (ids.replid.clone(), self.offset() as i64 + 1))
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset.load(Ordering::Acquire)
+    }
+
+    /// Starts over from a snapshot of a master with `replid`, taken at
+    /// `offset`. Our own replicas can't follow and get disconnected.
+    pub fn reset(&self, replid: String, offset: u64) {
+        let mut ids = self.ids.lock().unwrap();
+        let mut feeds = self.feeds.lock().unwrap();
+        *ids = ReplicationIds { replid, replid2: NO_REPLID.to_string(), second_offset: -1 };
+        feeds.replicas.clear();
+        feeds.backlog = Some(Backlog::new(self.backlog_size, offset));
+        self.offset.store(offset, Ordering::Release);
+    }
+
+    /// Starts a new history when we stop being a replica, keeping the old
+    /// id so our former master's other replicas can continue from us.
+    pub fn shift_replid(&self) {
+        self.switch_replid(gen_replica_id());
+    }
+
+    /// Whether any replica is connected.
+    pub fn is_empty(&self) -> bool {
+        self.feeds.lock().unwrap().replicas.is_empty()
+    }
+
+    /// Our master continued the stream under a new `replid`, after a
+    /// failover. Replicas may still ask for the old one, and ours get
+    /// disconnected so they reconnect and learn the new one.
+    pub fn switch_replid(&self, replid: String) {
+        let mut ids = self.ids.lock().unwrap();
+        if ids.replid != replid {
+            let previous = std::mem::replace(&mut ids.replid, replid);
+            ids.replid2 = previous;
+            ids.second_offset = self.offset() as i64 + 1;
+            self.feeds.lock().unwrap().replicas.clear();
+        }
+    }
+
+    /// Adds a replica that's about to get a snapshot. Commands propagated
+    /// from now on are queued until the snapshot is sent.
+    pub fn register(&self, ip: IpAddr, port: u16) -> ReplicaFeed {
+        let mut feeds = self.feeds.lock().unwrap();
+        let offset = self.offset();
+        feeds.backlog.get_or_insert_with(|| Backlog::new(self.backlog_size, offset));
+        self.add(&mut feeds, ip, port, ReplicaState::SendBulk, None)
+    }
+
+    /// Adds a replica that asked to continue from `offset` of `replid`, if
+    /// the backlog still has everything it missed, which gets queued first.
+    pub fn register_continue(&self, ip: IpAddr, port: u16, replid: &str, offset: i64) -> Option<ReplicaFeed> {
+        let ids = self.ids.lock().unwrap();
+        let known = replid == ids.replid || (replid == ids.replid2 && offset <= ids.second_offset);
+        let mut feeds = self.feeds.lock().unwrap();
+        let missed = feeds.backlog.as_ref()
+            .filter(|_| known)
+            .and_then(|backlog| backlog.since(u64::try_from(offset).ok()?))?;
+        Some(self.add(&mut feeds, ip, port, ReplicaState::Online, Some(missed)))
+    }
+
+    fn add(&self, feeds: &mut Feeds, ip: IpAddr, port: u16, state: ReplicaState, missed: Option<Vec<u8>>) -> ReplicaFeed {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (feed, commands) = mpsc::unbounded_channel();
+        if let Some(missed) = missed.filter(|missed| !missed.is_empty()) {
+            let _ = feed.send(missed);
+        }
+        let summary = ReplicaSummary { ip, port, state, offset: 0 };
+        feeds.replicas.insert(id, Replica { summary, feed });
+        ReplicaFeed { id, commands }
+    }
+
+    pub fn diskless_sync_delay(&self) -> Option<Duration> {
+        self.diskless_sync_delay
+    }
+
+    /// Queues a replica for the next diskless sync. Returns whether it's the
+    /// first one, which has to start the sync after the delay.
+    pub fn join_diskless_sync(&self, ip: IpAddr, port: u16) -> (bool, oneshot::Receiver<DisklessSync>) {
+        let mut waiting = self.diskless_waiting.lock().unwrap();
+        let (sync, receiver) = oneshot::channel();
+        waiting.push(DisklessWaiter { ip, port, sync });
+        (waiting.len() == 1, receiver)
+    }
+
+    /// Registers every replica waiting for a diskless sync, so that they
+    /// get the writes made after the snapshot being taken along with this.
+    pub fn start_diskless_sync(&self) -> StartedDisklessSync {
+        let waiting = std::mem::take(&mut *self.diskless_waiting.lock().unwrap());
+        StartedDisklessSync {
+            replid: self.replid(),
+            offset: self.offset(),
+            waiting: waiting.into_iter().map(|waiter| (waiter.sync, self.register(waiter.ip, waiter.port))).collect(),
+        }
+    }
+
+    /// Hands `rdb`, the snapshot taken when `started` was, to its replicas.
+    pub fn finish_diskless_sync(&self, started: StartedDisklessSync, rdb: Vec<u8>) {
+        let rdb = Arc::new(rdb);
+        info!(target: "replication", "streaming a {} bytes snapshot to {} replicas", rdb.len(), started.waiting.len());
+        for (waiter, feed) in started.waiting {
+            let id = feed.id;
+            let sync = DisklessSync { replid: started.replid.clone(), offset: started.offset, rdb: rdb.clone(), feed };
+            if waiter.send(sync).is_err() {
+                self.feeds.lock().unwrap().replicas.remove(&id);
+            }
+        }
+    }
+
+    /// Sends `command`, already in RESP form, to every replica.
+    pub fn propagate(&self, command: &[u8]) {
+        let mut feeds = self.feeds.lock().unwrap();
+        self.offset.fetch_add(command.len() as u64, Ordering::AcqRel);
+        if let Some(backlog) = feeds.backlog.as_mut() {
+            backlog.feed(command);
+        }
+        for replica in feeds.replicas.values() {
+            let _ = replica.feed.send(command.to_vec());
+        }
+    }
+
+    /// Number of replicas that acknowledged at least `offset`.
+    pub fn acked(&self, offset: u64) -> usize {
+        self.feeds.lock().unwrap().replicas.values().filter(|replica| replica.summary.offset >= offset).count()
+    }
+
+    /// Waits until `numreplicas` replicas acknowledged `offset` or `timeout`
+    /// passes, asking them for an ack if needed. Returns how many did.
+    pub async fn wait(&self, numreplicas: usize, offset: u64, timeout: Option<Duration>) -> usize {
+        let mut acks = self.acks.subscribe();
+        if self.acked(offset) >= numreplicas {
+            return self.acked(offset);
+        }
+        let getack = CommandRequest::REPLCONF(ReplconfCommand::GetAck("*".to_string()));
+        if let Ok(getack) = getack.to_resp() {
+            self.propagate(&getack.encode());
+        }
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        while self.acked(offset) < numreplicas {
+            let acked = match deadline {
+                Some(deadline) => time::timeout_at(deadline, acks.changed()).await.is_ok(),
+                None => acks.changed().await.is_ok(),
+            };
+            if !acked {
+                break;
+            }
+        }
+        self.acked(offset)
+    }
+
+    /// What INFO reports about replication, given the `link` to our master.
+    pub fn info(&self, link: &MasterLink) -> ReplicationInfo {
+        let ids = self.ids.lock().unwrap();
+        let feeds = self.feeds.lock().unwrap();
+        ReplicationInfo {
+            role: link.role(),
+            master: link.info(),
+            connected_slaves: feeds.replicas.values().map(|replica| replica.summary.clone()).collect(),
+            master_replid: ids.replid.clone(),
+            master_replid2: ids.replid2.clone(),
+            master_repl_offset: self.offset(),
+            second_repl_offset: ids.second_offset,
+            repl_backlog_active: feeds.backlog.is_some(),
+            repl_backlog_size: self.backlog_size as u64,
+            repl_backlog_first_byte_offset: feeds.backlog.as_ref().map_or(0, Backlog::first_byte_offset),
+            repl_backlog_histlen: feeds.backlog.as_ref().map_or(0, |backlog| backlog.histlen as u64),
+        }
+    }
+
+    /// Streams propagated commands to a replica until it disconnects.
+    pub async fn serve(&self, command_stream: &mut CommandStream, mut feed: ReplicaFeed) -> Result<()> {
+        self.set_state(feed.id, ReplicaState::Online);
+        let result = loop {
+            tokio::select! {
+                command = feed.commands.recv() => match command {
+                    Some(command) => if let Err(err) = command_stream.write_bytes(&command).await {
+                        break Err(err);
+                    },
+                    None => break Ok(()),
+                },
+                request = command_stream.receive_request() => match request {
+                    Ok(CommandRequest::REPLCONF(ReplconfCommand::Ack(offset))) => self.ack(feed.id, offset),
+                    Ok(request) => debug!(target: "replication", "ignoring {request:?} from replica"),
+                    Err(err) if err.is::<InvalidRequest>() => debug!(target: "replication", "ignoring invalid request from replica: {err}"),
+                    Err(err) => break Err(err),
+                },
+            }
+        };
+        self.feeds.lock().unwrap().replicas.remove(&feed.id);
+        result
+    }
+
+    fn ack(&self, id: u64, offset: u64) {
+        if let Some(replica) = self.feeds.lock().unwrap().replicas.get_mut(&id) {
+            replica.summary.offset = offset;
+        }
+        self.acks.send_replace(());
+    }
+
+    fn set_state(&self, id: u64, state: ReplicaState) {
+        if let Some(replica) = self.feeds.lock().unwrap().replicas.get_mut(&id) {
+            replica.summary.state = state;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkStatus {
+    /// Not connected, waiting to retry
+    Down,
+    Connecting,
+    /// Connected and receiving a snapshot
+    Sync,
+    Up,
+}
+
+/// How INFO describes the link to our master.
+#[derive(Debug, Clone)]
+pub struct MasterLinkInfo {
+    pub host: String,
+    pub port: u16,
+    pub status: LinkStatus,
+    /// Seconds since we last heard from the master, -1 if we never did.
+    pub last_io_seconds_ago: i64,
+    pub read_only: bool,
+    pub priority: u64,
+}
+
+/// The master we replicate, if any, and the state of our link to it. Which
+/// master is set can change at any time through REPLICAOF.
+pub struct MasterLink {
+    master: watch::Sender<Option<(String, u16)>>,
+    status: Mutex<LinkStatus>,
+    /// Unix time in ms we last received something from the master, 0 if never.
+    last_io: AtomicU64,
+    /// Whether clients are kept from writing while we're a replica.
+    read_only: AtomicBool,
+    /// Whether clients are served while the link is down.
+    serve_stale_data: AtomicBool,
+    /// How much sentinels should prefer us when promoting a replica, lower
+    /// is better and 0 means never.
+    priority: AtomicU64,
+}
+
+impl MasterLink {
+    pub fn new(master: Option<(String, u16)>, read_only: bool, serve_stale_data: bool, priority: u64) -> MasterLink {
+        MasterLink {
+            master: watch::channel(master).0,
+            status: Mutex::new(LinkStatus::Down),
+            last_io: AtomicU64::new(0),
+            read_only: AtomicBool::new(read_only),
+            serve_stale_data: AtomicBool::new(serve_stale_data),
+            priority: AtomicU64::new(priority),
+        }
+    }
+
+    /// Changes how clients are served while we're a replica, as CONFIG SET
+    /// does.
+    pub fn configure(&self, read_only: bool, serve_stale_data: bool, priority: u64) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+        self.serve_stale_data.store(serve_stale_data, Ordering::Relaxed);
+        self.priority.store(priority, Ordering::Relaxed);
+    }
+
+    pub fn master(&self) -> Option<(String, u16)> {
+        self.master.borrow().clone()
+    }
+
+    pub fn role(&self) -> ReplicationRole {
+        match self.master.borrow().is_some() {
+            true => ReplicationRole::Slave,
+            false => ReplicationRole::Master,
+        }
+    }
+
+    /// Starts replicating `master`, or stops replicating with `None`.
+    pub fn set_master(&self, master: Option<(String, u16)>) {
+        *self.status.lock().unwrap() = LinkStatus::Down;
+        self.last_io.store(0, Ordering::Relaxed);
+        self.master.send_replace(master);
+    }
+
+    /// Whether client writes have to be refused.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed) && self.master.borrow().is_some()
+    }
+
+    /// Whether clients have to be told the data may be stale instead of
+    /// being served.
+    pub fn refuses_stale_data(&self) -> bool {
+        !self.serve_stale_data.load(Ordering::Relaxed) && self.master.borrow().is_some() && self.status() != LinkStatus::Up
+    }
+
+    pub fn status(&self) -> LinkStatus {
+        *self.status.lock().unwrap()
+    }
+
+    fn set_status(&self, status: LinkStatus) {
+        *self.status.lock().unwrap() = status;
+    }
+
+    fn touch(&self) {
+        self.last_io.store(now_ms(), Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        Duration::from_millis(now_ms().saturating_sub(self.last_io.load(Ordering::Relaxed)))
+    }
+
+    pub fn info(&self) -> Option<MasterLinkInfo> {
+        let (host, port) = self.master()?;
+        let last_io_seconds_ago = match self.last_io.load(Ordering::Relaxed) {
+            0 => -1,
+            _ => self.idle().as_secs() as i64,
+        };
+        Some(MasterLinkInfo {
+            host,
+            port,
+            status: self.status(),
+            last_io_seconds_ago,
+            read_only: self.read_only.load(Ordering::Relaxed),
+            priority: self.priority.load(Ordering::Relaxed),
+        })
+    }
+}
+
 pub struct Replicator {
-    master_address: String
+    /// Port this server accepts clients on, announced to the master.
+    listening_port: u16,
+    interpreter: Interpreter,
+    link: Arc<MasterLink>,
+    /// Where snapshots from the master are saved before being loaded, if
+    /// they aren't loaded straight from the socket.
+    temp_dir: Option<PathBuf>,
 }
 
 impl Replicator {
-    pub fn new(master_address: String) -> Replicator {
+    pub fn new(listening_port: u16, interpreter: Interpreter, link: Arc<MasterLink>, temp_dir: Option<PathBuf>) -> Replicator {
         Replicator {
-            master_address
+            listening_port,
+            interpreter,
+            link,
+            temp_dir,
         }
     }
 
-    pub async fn replicate(&self) {
-        info!(target: "replicator", "replicating {:?}", self.master_address);
-        let master_address = self.master_address.to_owned();
-        let tcp_stream = TcpStream::connect(master_address).await.unwrap();
+    /// Replicates whichever master is set, reconnecting with a growing delay
+    /// whenever the link breaks, until the process ends.
+    pub async fn run(&self) {
+        let mut masters = self.link.master.subscribe();
+        loop {
+            let Some((host, port)) = masters.borrow_and_update().clone() else {
+                let _ = masters.changed().await;
+                continue;
+            };
+            let mut backoff = MIN_RECONNECT_DELAY;
+            loop {
+                tokio::select! {
+                    result = self.replicate(&host, port) => {
+                        if let Err(err) = result {
+                            warn!(target: "replicator", "lost the link with {host}:{port}, retrying in {backoff:?}: {err}");
+                        }
+                    },
+                    _ = masters.changed() => break,
+                }
+                // a link that made it up deserves a quick retry
+                backoff = match self.link.status() {
+                    LinkStatus::Up => MIN_RECONNECT_DELAY,
+                    _ => (backoff * 2).min(MAX_RECONNECT_DELAY),
+                };
+                self.link.set_status(LinkStatus::Down);
+                tokio::select! {
+                    _ = time::sleep(backoff) => (),
+                    _ = masters.changed() => break,
+                }
+            }
+            self.link.set_status(LinkStatus::Down);
+            info!(target: "replicator", "stopped replicating {host}:{port}");
+        }
+    }
+
+    /// Performs the handshake with the master, loads the snapshot it sends
+    /// back unless it lets us continue, and then applies the commands it
+    /// streams.
+    async fn replicate(&self, host: &str, port: u16) -> Result<()> {
+        info!(target: "replicator", "replicating {host}:{port}");
+        self.link.set_status(LinkStatus::Connecting);
+        let tcp_stream = TcpStream::connect((host, port)).await?;
         let mut command_stream = CommandStream::from_tcp_stream(tcp_stream);
-        command_stream.write_request(CommandRequest::PING).await.unwrap();
+
+        command_stream.write_request(CommandRequest::PING).await?;
+        expect_reply(&mut command_stream, "PONG").await?;
+        command_stream.write_request(CommandRequest::REPLCONF(ReplconfCommand::ListeningPort(self.listening_port))).await?;
+        expect_reply(&mut command_stream, "OK").await?;
+        command_stream.write_request(CommandRequest::REPLCONF(ReplconfCommand::Capa(vec!["eof".to_string(), "psync2".to_string()]))).await?;
+        expect_reply(&mut command_stream, "OK").await?;
+
+        // a replica that was already in sync, or a former master, may be
+        // able to continue where it left off
+        let (replid, offset) = self.interpreter.psync_position().unwrap_or(("?".to_string(), -1));
+        command_stream.write_request(CommandRequest::PSYNC(replid, offset)).await?;
+        match command_stream.receive_response().await? {
+            RESP::SimpleString(reply) if reply.starts_with("FULLRESYNC ") => {
+                let parts: Vec<&str> = reply.split(' ').collect();
+                let (replid, offset) = match parts.as_slice() {
+                    [_, replid, offset] => (replid.to_string(), offset.parse::<u64>()?),
+                    _ => return Err(anyhow!("unexpected reply to PSYNC: {reply}")),
+                };
+                info!(target: "replicator", "full resync from master with replid {replid} at offset {offset}");
+                self.link.set_status(LinkStatus::Sync);
+                let rdb = match &self.temp_dir {
+                    Some(dir) => {
+                        let path = dir.join(format!("temp-{}.{}.rdb", now_ms() / 1000, process::id()));
+                        let rdb = receive_rdb_file(&mut command_stream, &path).await;
+                        let _ = fs::remove_file(&path);
+                        rdb?
+                    },
+                    None => {
+                        let mut rdb = vec![];
+                        command_stream.receive_rdb(&mut rdb).await?;
+                        rdb
+                    },
+                };
+                let keys = self.interpreter.full_sync(rdb::parse(&rdb)?, replid, offset).await?;
+                info!(target: "replicator", "loaded {keys} keys from the master, {} bytes", rdb.len());
+            },
+            RESP::SimpleString(reply) if reply == "CONTINUE" || reply.starts_with("CONTINUE ") => {
+                if let Some(replid) = reply.strip_prefix("CONTINUE ") {
+                    self.interpreter.switch_replid(replid.to_string());
+                }
+                info!(target: "replicator", "partial resync from master at offset {offset}");
+            },
+            x => return Err(anyhow!("unexpected reply to PSYNC: {x:?}")),
+        }
+        self.link.set_status(LinkStatus::Up);
+        self.link.touch();
+
+        let mut acks = time::interval(ACK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = acks.tick() => {
+                    if self.link.idle() > REPL_TIMEOUT {
+                        return Err(anyhow!("no data from the master for {REPL_TIMEOUT:?}"));
+                    }
+                    let ack = CommandRequest::REPLCONF(ReplconfCommand::Ack(self.interpreter.repl_offset()));
+                    command_stream.write_request(ack).await?;
+                },
+                received = command_stream.receive_request_bytes() => {
+                    let (bytes, command) = received?;
+                    self.link.touch();
+                    match command {
+                        Ok(CommandRequest::REPLCONF(ReplconfCommand::GetAck(_))) => {
+                            // acknowledges everything up to, but not including, the GETACK
+                            let ack = CommandRequest::REPLCONF(ReplconfCommand::Ack(self.interpreter.repl_offset()));
+                            command_stream.write_request(ack).await?;
+                        },
+                        Ok(command) => self.interpreter.apply_replicated(command).await?,
+                        Err(err) => warn!(target: "replicator", "ignoring invalid command from the master: {err}"),
+                    }
+                    // the stream moves on by exactly what the master sent
+                    self.interpreter.forward_replicated(&bytes);
+                },
+            }
+        }
+    }
+}
+
+/// Saves the snapshot the master sends to `path` as it arrives, then reads
+/// it back.
+async fn receive_rdb_file(command_stream: &mut CommandStream, path: &Path) -> Result<Vec<u8>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    command_stream.receive_rdb(&mut file).await?;
+    file.into_inner()?.sync_all()?;
+    Ok(fs::read(path)?)
+}
+
+async fn expect_reply(command_stream: &mut CommandStream, expected: &str) -> Result<()> {
+    match command_stream.receive_response().await? {
+        RESP::SimpleString(reply) | RESP::BulkString(reply) if reply == expected => Ok(()),
+        x => Err(anyhow!("expected {expected} from the master, got: {x:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_backlog_wraps_around() {
+        let mut backlog = Backlog::new(8, 0);
+        backlog.feed(b"abcde");
+        assert_eq!(1, backlog.first_byte_offset());
+        assert_eq!(Some(b"cde".to_vec()), backlog.since(3));
+
+        backlog.feed(b"fghij");
+        assert_eq!(8, backlog.histlen);
+        assert_eq!(3, backlog.first_byte_offset());
+        assert_eq!(Some(b"cdefghij".to_vec()), backlog.since(3));
+        assert_eq!(Some(b"j".to_vec()), backlog.since(10));
+        assert_eq!(Some(vec![]), backlog.since(11));
+        assert_eq!(None, backlog.since(2));
+        assert_eq!(None, backlog.since(12));
+
+        backlog.feed(b"0123456789");
+        assert_eq!(Some(b"23456789".to_vec()), backlog.since(13));
+    }
+
+    #[test]
+    fn test_register_continue() {
+        let replicas = Replicas::new("a".repeat(40), 16, None);
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        assert!(replicas.register_continue(localhost, 6380, &"a".repeat(40), 1).is_none());
+
+        let _full = replicas.register(localhost, 6380);
+        replicas.propagate(b"*1\r\n$4\r\nPING\r\n");
+        let mut feed = replicas.register_continue(localhost, 6381, &"a".repeat(40), 9).unwrap();
+        assert_eq!(b"PING\r\n".to_vec(), feed.commands.try_recv().unwrap());
+        assert!(replicas.register_continue(localhost, 6381, &"b".repeat(40), 9).is_none());
+
+        replicas.switch_replid("b".repeat(40));
+        assert!(replicas.register_continue(localhost, 6381, &"a".repeat(40), 15).is_some());
+        assert!(replicas.register_continue(localhost, 6381, &"b".repeat(40), 15).is_some());
+        replicas.propagate(b"*1\r\n$4\r\nPING\r\n");
+        assert!(replicas.register_continue(localhost, 6381, &"a".repeat(40), 16).is_none());
+    }
+
+    #[test]
+    fn test_propagate_moves_offset() {
+        let replicas = Replicas::new(NO_REPLID.to_string(), 1024, None);
+        let mut feed = replicas.register(IpAddr::from([127, 0, 0, 1]), 6380);
+        replicas.propagate(b"*1\r\n$4\r\nPING\r\n");
+        assert_eq!(14, replicas.offset());
+        assert_eq!(b"*1\r\n$4\r\nPING\r\n".to_vec(), feed.commands.try_recv().unwrap());
+    }
+
+    #[test]
+    fn test_diskless_sync_is_shared() {
+        let replicas = Replicas::new(NO_REPLID.to_string(), 1024, Some(Duration::ZERO));
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        let (first, mut sync) = replicas.join_diskless_sync(localhost, 6380);
+        let (second, mut other) = replicas.join_diskless_sync(localhost, 6381);
+        assert!(first && !second);
+
+        let started = replicas.start_diskless_sync();
+        // writes made while the snapshot is serialized come after it
+        replicas.propagate(b"*1\r\n$4\r\nPING\r\n");
+        assert!(sync.try_recv().is_err());
+        replicas.finish_diskless_sync(started, b"REDIS".to_vec());
+        let (mut sync, other) = (sync.try_recv().unwrap(), other.try_recv().unwrap());
+        assert_eq!(0, sync.offset);
+        assert_eq!(b"*1\r\n$4\r\nPING\r\n".to_vec(), sync.feed.commands.try_recv().unwrap());
+        assert!(Arc::ptr_eq(&sync.rdb, &other.rdb));
+        assert_eq!(2, replicas.info(&MasterLink::new(None, true, true, 100)).connected_slaves.len());
+        assert!(replicas.join_diskless_sync(localhost, 6382).0);
+    }
+
+    #[test]
+    fn test_master_link_modes() {
+        let link = MasterLink::new(Some(("localhost".to_string(), 6379)), true, false, 100);
+        assert!(link.is_read_only());
+        assert!(link.refuses_stale_data());
+        link.set_status(LinkStatus::Up);
+        assert!(!link.refuses_stale_data());
+
+        link.set_master(None);
+        assert!(matches!(link.role(), ReplicationRole::Master));
+        assert!(!link.is_read_only());
+        assert!(!link.refuses_stale_data());
+        assert!(link.info().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_wait_counts_acks() {
+        let replicas = Replicas::new(NO_REPLID.to_string(), 1024, None);
+        let first = replicas.register(IpAddr::from([127, 0, 0, 1]), 6380);
+        let second = replicas.register(IpAddr::from([127, 0, 0, 1]), 6381);
+        replicas.propagate(b"*1\r\n$4\r\nPING\r\n");
+        replicas.ack(first.id, 14);
+        assert_eq!(1, replicas.acked(14));
+        assert_eq!(1, replicas.wait(2, 14, Some(Duration::from_millis(10))).await);
+
+        replicas.ack(second.id, 14);
+        assert_eq!(2, replicas.wait(2, 14, None).await);
     }
 }
@@ -0,0 +1,155 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{anyhow, Result};
+use log::{error, info};
+
+use crate::keyspace::now_ms;
+use crate::rdb::{self, Snapshot};
+
+/// Rules used when none are configured, same as Redis.
+pub const DEFAULT_SAVE_RULES: &str = "3600 1 300 100 60 10000";
+
+/// A `save <seconds> <changes>` rule: save once at least `changes` writes
+/// happened and more than `seconds` passed since the last save.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// Parses save rules written as `<seconds> <changes>` pairs, as in
+/// `"3600 1 300 100"`. An empty string means no rules at all.
+pub fn parse_save_rules(rules: &str) -> Result<Vec<SaveRule>> {
+    let numbers = rules.split_whitespace()
+        .map(|n| n.parse::<u64>().map_err(|_| anyhow!("Invalid save parameters")))
+        .collect::<Result<Vec<u64>>>()?;
+    if !numbers.len().is_multiple_of(2) {
+        return Err(anyhow!("Invalid save parameters"));
+    }
+    Ok(numbers.chunks(2).map(|pair| SaveRule { seconds: pair[0], changes: pair[1] }).collect())
+}
+
+/// Writes RDB snapshots to disk, either in the foreground (SAVE) or in a
+/// blocking thread (BGSAVE and save rules), and remembers when the last
+/// one succeeded.
+///
+/// Files are written to a temporary file first and renamed over the target,
+/// so a crash while saving never leaves a truncated snapshot behind.
+pub struct Saver {
+    path: PathBuf,
+    rules: RwLock<Vec<SaveRule>>,
+    /// Unix time in seconds of the last successful save, as LASTSAVE reports it.
+    last_save: AtomicU64,
+    /// Keyspace dirty counter at the time of the last successful save.
+    saved_dirty: AtomicU64,
+    in_progress: AtomicBool,
+}
+
+impl Saver {
+    pub fn new(path: PathBuf, rules: Vec<SaveRule>) -> Saver {
+        Saver {
+            path,
+            rules: RwLock::new(rules),
+            last_save: AtomicU64::new(now_ms() / 1000),
+            saved_dirty: AtomicU64::new(0),
+            in_progress: AtomicBool::new(false),
+        }
+    }
+
+    /// Replaces the save rules, as CONFIG SET save does.
+    pub fn set_rules(&self, rules: Vec<SaveRule>) {
+        *self.rules.write().unwrap() = rules;
+    }
+
+    pub fn last_save(&self) -> u64 {
+        self.last_save.load(Ordering::Relaxed)
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.in_progress.load(Ordering::Relaxed)
+    }
+
+    /// Whether any save rule is met, given the current keyspace `dirty` counter.
+    pub fn should_save(&self, dirty: u64) -> bool {
+        let changes = dirty.saturating_sub(self.saved_dirty.load(Ordering::Relaxed));
+        let elapsed = (now_ms() / 1000).saturating_sub(self.last_save());
+        !self.in_progress() && self.rules.read().unwrap().iter().any(|rule| changes >= rule.changes && elapsed > rule.seconds)
+    }
+
+    /// Writes `snapshot`, taken when the keyspace `dirty` counter was at
+    /// `dirty`, blocking until it's on disk.
+    pub fn save(&self, snapshot: &Snapshot, dirty: u64) -> Result<()> {
+        if self.in_progress() {
+            return Err(anyhow!("ERR Background save already in progress"));
+        }
+        self.write(snapshot, dirty)
+    }
+
+    /// Writes `snapshot` from a blocking thread, failing if another
+    /// background save is still running.
+    pub fn save_in_background(self: &Arc<Self>, snapshot: Snapshot, dirty: u64) -> Result<()> {
+        if self.in_progress.swap(true, Ordering::AcqRel) {
+            return Err(anyhow!("ERR Background save already in progress"));
+        }
+        let saver = self.clone();
+        tokio::task::spawn_blocking(move || {
+            match saver.write(&snapshot, dirty) {
+                Ok(()) => info!(target: "saver", "background saving terminated with success"),
+                Err(err) => error!(target: "saver", "background saving error: {err}"),
+            }
+            saver.in_progress.store(false, Ordering::Release);
+        });
+        Ok(())
+    }
+
+    fn write(&self, snapshot: &Snapshot, dirty: u64) -> Result<()> {
+        let temp = self.path.with_file_name(format!("temp-{}.rdb", process::id()));
+        let result = File::create(&temp)
+            .and_then(|mut file| {
+                file.write_all(&rdb::serialize(snapshot))?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&temp, &self.path));
+        if let Err(err) = result {
+            let _ = fs::remove_file(&temp);
+            return Err(anyhow!("ERR Failed saving the DB: {err}"));
+        }
+        info!(target: "saver", "DB saved on disk at {:?}", self.path);
+        self.last_save.store(now_ms() / 1000, Ordering::Relaxed);
+        self.saved_dirty.store(dirty, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_save_rules() {
+        assert_eq!(
+            vec![SaveRule { seconds: 3600, changes: 1 }, SaveRule { seconds: 300, changes: 100 }],
+            parse_save_rules("3600 1 300 100").unwrap()
+        );
+        assert!(parse_save_rules("").unwrap().is_empty());
+        assert!(parse_save_rules("3600").is_err());
+        assert!(parse_save_rules("3600 many").is_err());
+    }
+
+    #[test]
+    fn test_should_save() {
+        let saver = Saver::new(PathBuf::from("dump.rdb"), vec![SaveRule { seconds: 0, changes: 2 }]);
+        saver.last_save.store(now_ms() / 1000 - 1, Ordering::Relaxed);
+        assert!(!saver.should_save(1));
+        assert!(saver.should_save(2));
+
+        saver.saved_dirty.store(2, Ordering::Relaxed);
+        assert!(!saver.should_save(3));
+    }
+}
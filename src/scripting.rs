@@ -0,0 +1,414 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use log::{debug, info, warn};
+use mlua::{HookTriggers, Lua, LuaOptions, MultiValue, StdLib, Table, Value};
+use thiserror::Error;
+
+use crate::commands::{CommandRequest, CommandResponse, FromRESP, ToRESP};
+use crate::protocol::RESP;
+
+/// How long a script can run before other clients start getting BUSY
+/// errors and SCRIPT KILL can stop it (`lua-time-limit` in Redis).
+pub const BUSY_SCRIPT_THRESHOLD: Duration = Duration::from_millis(5000);
+
+/// How many Lua instructions run between checks for SCRIPT KILL.
+const KILL_CHECK_INTERVAL: u32 = 100_000;
+
+/// An error reply from a command run through `redis.call`, which is handed
+/// back to the client untouched when the script doesn't catch it.
+#[derive(Error, Debug)]
+#[error("{0}")]
+struct ReplyError(String);
+
+struct RunningScript {
+    started: Instant,
+    wrote: bool,
+}
+
+/// Embedded Lua interpreter along with the cache of scripts, keyed by SHA1.
+///
+/// Scripts are run with whatever dispatch function the caller provides for
+/// `redis.call`, so they go through the same path as network commands.
+pub struct Scripting {
+    lua: Mutex<Lua>,
+    scripts: DashMap<String, String>,
+    running: Mutex<Option<RunningScript>>,
+    killed: Arc<AtomicBool>,
+}
+
+impl Default for Scripting {
+    fn default() -> Scripting {
+        Scripting::new()
+    }
+}
+
+impl Scripting {
+    pub fn new() -> Scripting {
+        let killed = Arc::new(AtomicBool::new(false));
+        Scripting {
+            lua: Mutex::new(new_lua(killed.clone())),
+            scripts: DashMap::new(),
+            running: Mutex::new(None),
+            killed,
+        }
+    }
+
+    /// Adds `body` to the script cache, returning its SHA1.
+    pub fn load(&self, body: String) -> String {
+        let sha = sha1_hex(&body);
+        self.scripts.insert(sha.clone(), body);
+        sha
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        self.scripts.contains_key(&sha.to_lowercase())
+    }
+
+    pub fn flush(&self) {
+        self.scripts.clear();
+        *self.lua.lock().unwrap() = new_lua(self.killed.clone());
+    }
+
+    /// Whether a script has been running for long enough that other clients
+    /// should be refused with BUSY.
+    pub fn is_busy(&self) -> bool {
+        self.running.lock().unwrap()
+            .as_ref()
+            .is_some_and(|running| running.started.elapsed() >= BUSY_SCRIPT_THRESHOLD)
+    }
+
+    /// Asks the running script to stop, which is only allowed as long as it
+    /// hasn't written anything.
+    pub fn kill(&self) -> Result<()> {
+        match self.running.lock().unwrap().as_ref() {
+            None => Err(anyhow!("NOTBUSY No scripts in execution right now.")),
+            Some(running) if running.wrote => Err(anyhow!(
+                "UNKILLABLE Sorry the script already executed write commands against the dataset. \
+                 You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command."
+            )),
+            Some(_) => {
+                info!(target: "scripting", "killing running script");
+                self.killed.store(true, Ordering::SeqCst);
+                Ok(())
+            },
+        }
+    }
+
+    /// Runs the cached script `sha`, routing `redis.call` and `redis.pcall`
+    /// through `call`.
+    pub fn eval_sha<F>(&self, sha: &str, keys: Vec<String>, args: Vec<String>, call: F) -> CommandResponse
+    where
+        F: Fn(CommandRequest) -> Result<CommandResponse>,
+    {
+        let sha = sha.to_lowercase();
+        match self.scripts.get(&sha).map(|body| body.to_owned()) {
+            Some(body) => self.run_script(&sha, &body, keys, args, call),
+            None => CommandResponse::ERR("NOSCRIPT No matching script. Please use EVAL.".to_string()),
+        }
+    }
+
+    /// Caches and runs `body`, routing `redis.call` and `redis.pcall` through
+    /// `call`.
+    pub fn eval<F>(&self, body: String, keys: Vec<String>, args: Vec<String>, call: F) -> CommandResponse
+    where
+        F: Fn(CommandRequest) -> Result<CommandResponse>,
+    {
+        let sha = self.load(body.clone());
+        self.run_script(&sha, &body, keys, args, call)
+    }
+
+    fn run_script<F>(&self, sha: &str, body: &str, keys: Vec<String>, args: Vec<String>, call: F) -> CommandResponse
+    where
+        F: Fn(CommandRequest) -> Result<CommandResponse>,
+    {
+        let lua = self.lua.lock().unwrap();
+        self.run(&lua, &format!("f_{sha}"), false, call, || {
+            lua.globals().set("KEYS", keys)?;
+            lua.globals().set("ARGV", args)?;
+            lua.load(body).set_name("@user_script").call(())
+        })
+    }
+
+    /// Creates an interpreter that can be stopped with SCRIPT KILL, for
+    /// running code through `run`.
+    pub(crate) fn create_lua(&self) -> Lua {
+        new_lua(self.killed.clone())
+    }
+
+    /// Runs `invoke` on `lua` as the script `name`, with `redis.call` and
+    /// `redis.pcall` routed through `call`. Read only scripts are not allowed
+    /// to run write commands.
+    pub(crate) fn run<'lua, F, I>(&self, lua: &'lua Lua, name: &str, read_only: bool, call: F, invoke: I) -> CommandResponse
+    where
+        F: Fn(CommandRequest) -> Result<CommandResponse>,
+        I: FnOnce() -> mlua::Result<Value<'lua>>,
+    {
+        debug!(target: "scripting", "running script {name}");
+        self.killed.store(false, Ordering::SeqCst);
+        *self.running.lock().unwrap() = Some(RunningScript { started: Instant::now(), wrote: false });
+
+        let dispatch = |request: Vec<String>| -> Result<CommandResponse> {
+            let cmd = CommandRequest::from_resp(RESP::Array(
+                request.into_iter().map(RESP::BulkString).collect()
+            )).map_err(|_| anyhow!("ERR Unknown Redis command called from script"))?;
+            if !cmd.allowed_in_script() {
+                return Err(anyhow!("ERR This Redis command is not allowed from script"));
+            }
+            if cmd.is_write() {
+                if read_only {
+                    return Err(anyhow!("ERR Write commands are not allowed from read-only scripts."));
+                }
+                if let Some(running) = self.running.lock().unwrap().as_mut() {
+                    running.wrote = true;
+                }
+            }
+            call(cmd)
+        };
+
+        let result = lua.scope(|scope| {
+            let redis: Table = lua.globals().get("redis")?;
+            redis.set("call", scope.create_function(|lua, values: MultiValue| {
+                match dispatch(command_args(values)?) {
+                    Ok(CommandResponse::ERR(err)) => Err(mlua::Error::external(ReplyError(err))),
+                    Ok(response) => to_lua(lua, response),
+                    Err(err) => Err(mlua::Error::external(ReplyError(err.to_string()))),
+                }
+            })?)?;
+            redis.set("pcall", scope.create_function(|lua, values: MultiValue| {
+                match dispatch(command_args(values)?) {
+                    Ok(response) => to_lua(lua, response),
+                    Err(err) => to_lua(lua, CommandResponse::ERR(err.to_string())),
+                }
+            })?)?;
+
+            Ok(from_lua(invoke()?))
+        });
+
+        *self.running.lock().unwrap() = None;
+        match result {
+            Ok(response) => response,
+            Err(_) if self.killed.swap(false, Ordering::SeqCst) => {
+                CommandResponse::ERR("ERR Script killed by user with SCRIPT KILL...".to_string())
+            },
+            Err(err) => match find_reply_error(&err) {
+                Some(reply) => CommandResponse::ERR(reply.0.to_string()),
+                None => CommandResponse::ERR(format!("ERR Error running script (call to {name}): {}", first_line(&err))),
+            },
+        }
+    }
+}
+
+/// Error replies can't span multiple lines, so this leaves Lua tracebacks out.
+pub(crate) fn first_line(err: &mlua::Error) -> String {
+    err.to_string().lines().next().unwrap_or_default().to_string()
+}
+
+fn new_lua(killed: Arc<AtomicBool>) -> Lua {
+    let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH, LuaOptions::default())
+        .expect("failed to create the Lua interpreter");
+    register_redis_lib(&lua).expect("failed to register the redis Lua library");
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(KILL_CHECK_INTERVAL),
+        move |_, _| match killed.load(Ordering::SeqCst) {
+            true => Err(mlua::Error::RuntimeError("Script killed by user with SCRIPT KILL...".to_string())),
+            false => Ok(()),
+        }
+    );
+    lua
+}
+
+/// The parts of the `redis` table that don't depend on the script being run.
+/// `redis.call` and `redis.pcall` are set for each execution.
+fn register_redis_lib(lua: &Lua) -> mlua::Result<()> {
+    let redis = lua.create_table()?;
+    redis.set("error_reply", lua.create_function(|lua, message: String| {
+        let table = lua.create_table()?;
+        table.set("err", message)?;
+        Ok(table)
+    })?)?;
+    redis.set("status_reply", lua.create_function(|lua, message: String| {
+        let table = lua.create_table()?;
+        table.set("ok", message)?;
+        Ok(table)
+    })?)?;
+    redis.set("sha1hex", lua.create_function(|_, body: String| Ok(sha1_hex(&body)))?)?;
+    redis.set("log", lua.create_function(|_, (level, message): (i64, String)| {
+        match level {
+            0 => debug!(target: "script", "{message}"),
+            1 | 2 => info!(target: "script", "{message}"),
+            _ => warn!(target: "script", "{message}"),
+        }
+        Ok(())
+    })?)?;
+    redis.set("LOG_DEBUG", 0)?;
+    redis.set("LOG_VERBOSE", 1)?;
+    redis.set("LOG_NOTICE", 2)?;
+    redis.set("LOG_WARNING", 3)?;
+    lua.globals().set("redis", redis)
+}
+
+fn find_reply_error(err: &mlua::Error) -> Option<&ReplyError> {
+    match err {
+        mlua::Error::CallbackError { cause, .. } => find_reply_error(cause),
+        mlua::Error::ExternalError(external) => external.downcast_ref::<ReplyError>(),
+        _ => None,
+    }
+}
+
+/// Arguments of `redis.call`, which can only be strings or numbers.
+fn command_args(values: MultiValue) -> mlua::Result<Vec<String>> {
+    if values.is_empty() {
+        return Err(mlua::Error::RuntimeError(
+            "Please specify at least one argument for this redis lib call".to_string()
+        ));
+    }
+    values.into_iter()
+        .map(|value| match value {
+            Value::String(s) => Ok(s.to_string_lossy().into_owned()),
+            Value::Integer(i) => Ok(i.to_string()),
+            Value::Number(n) => Ok(n.to_string()),
+            _ => Err(mlua::Error::RuntimeError(
+                "Lua redis lib command arguments must be strings or integers".to_string()
+            )),
+        })
+        .collect()
+}
+
+/// Converts a command reply to Lua following the Redis rules: nulls become
+/// `false`, status and error replies become tables with an `ok` or `err`
+/// field.
+fn to_lua(lua: &Lua, response: CommandResponse) -> mlua::Result<Value<'_>> {
+    let resp = response.to_resp().map_err(mlua::Error::external)?;
+    resp_to_lua(lua, resp)
+}
+
+fn resp_to_lua(lua: &Lua, resp: RESP) -> mlua::Result<Value<'_>> {
+    match resp {
+        RESP::Integer(i) => Ok(Value::Integer(i)),
+        RESP::BulkString(s) => Ok(Value::String(lua.create_string(&s)?)),
+        RESP::BulkBytes(b) => Ok(Value::String(lua.create_string(&b)?)),
+        RESP::SimpleString(s) => {
+            let table = lua.create_table()?;
+            table.set("ok", s)?;
+            Ok(Value::Table(table))
+        },
+        RESP::SimpleError(s) => {
+            let table = lua.create_table()?;
+            table.set("err", s)?;
+            Ok(Value::Table(table))
+        },
+        RESP::Array(items) => {
+            let table = lua.create_table()?;
+            for item in items {
+                table.raw_push(resp_to_lua(lua, item)?)?;
+            }
+            Ok(Value::Table(table))
+        },
+        RESP::NullBulkString | RESP::NullArray => Ok(Value::Boolean(false)),
+    }
+}
+
+/// Converts the value returned by a script into a reply: numbers are
+/// truncated to integers, `false` and `nil` become null, and tables are
+/// read as arrays up to their first `nil` unless they have an `ok` or
+/// `err` field.
+fn from_lua(value: Value) -> CommandResponse {
+    match value {
+        Value::Nil | Value::Boolean(false) => CommandResponse::NIL,
+        Value::Boolean(true) => CommandResponse::INT(1),
+        Value::Integer(i) => CommandResponse::INT(i),
+        Value::Number(n) => CommandResponse::INT(n as i64),
+        Value::String(s) => CommandResponse::STR(s.to_string_lossy().into_owned()),
+        Value::Table(table) => {
+            if let Ok(Value::String(err)) = table.raw_get::<_, Value>("err") {
+                return CommandResponse::ERR(err.to_string_lossy().into_owned());
+            }
+            if let Ok(Value::String(ok)) = table.raw_get::<_, Value>("ok") {
+                return CommandResponse::STATUS(ok.to_string_lossy().into_owned());
+            }
+            CommandResponse::ARRAY(
+                table.sequence_values::<Value>()
+                    .map_while(|value| value.ok())
+                    .map(from_lua)
+                    .collect()
+            )
+        },
+        _ => CommandResponse::NIL,
+    }
+}
+
+pub fn sha1_hex(body: &str) -> String {
+    sha1_smol::Sha1::from(body).digest().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn echo_call(cmd: CommandRequest) -> Result<CommandResponse> {
+        match cmd {
+            CommandRequest::GET(key) => Ok(CommandResponse::STR(key)),
+            CommandRequest::SET(..) => Ok(CommandResponse::OK),
+            CommandRequest::DEL(_) => Ok(CommandResponse::ERR("ERR boom".to_string())),
+            _ => Ok(CommandResponse::NIL),
+        }
+    }
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!("e0e1f9fabfc9d4800c877a703b823ac0578ff8db", sha1_hex("return 1"));
+    }
+
+    #[test]
+    fn test_eval_conversions() {
+        let scripting = Scripting::new();
+        let script = "return {1, 2.7, 'three', false, nil, 6}".to_string();
+        assert!(matches!(
+            scripting.eval(script, vec![], vec![], echo_call).to_resp().unwrap(),
+            RESP::Array(items) if items == vec![
+                RESP::Integer(1),
+                RESP::Integer(2),
+                RESP::BulkString("three".to_string()),
+                RESP::NullBulkString,
+            ]
+        ));
+
+        let script = "return redis.call('GET', KEYS[1])".to_string();
+        assert!(matches!(
+            scripting.eval(script, vec!["k".to_string()], vec![], echo_call),
+            CommandResponse::STR(value) if value == "k"
+        ));
+
+        let script = "return redis.call('SET', 'k', ARGV[1])".to_string();
+        assert!(matches!(
+            scripting.eval(script, vec![], vec!["v".to_string()], echo_call),
+            CommandResponse::STATUS(status) if status == "OK"
+        ));
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        let scripting = Scripting::new();
+        let script = "return redis.call('DEL', 'k')".to_string();
+        assert!(matches!(
+            scripting.eval(script, vec![], vec![], echo_call),
+            CommandResponse::ERR(err) if err == "ERR boom"
+        ));
+
+        let script = "return redis.pcall('DEL', 'k')['err']".to_string();
+        assert!(matches!(
+            scripting.eval(script, vec![], vec![], echo_call),
+            CommandResponse::STR(err) if err == "ERR boom"
+        ));
+
+        assert!(matches!(
+            scripting.eval_sha("0000", vec![], vec![], echo_call),
+            CommandResponse::ERR(err) if err.starts_with("NOSCRIPT")
+        ));
+    }
+}
@@ -0,0 +1,656 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use rand::{thread_rng, Rng};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use crate::commands::{CommandRequest, CommandResponse, InfoMode, SentinelCommand};
+use crate::protocol::RESP;
+use crate::replication::gen_replica_id;
+use crate::stream::{CommandStream, InvalidRequest};
+
+/// How often instances and other sentinels are checked.
+const TICK: Duration = Duration::from_secs(1);
+
+/// How long any query to an instance or another sentinel may take.
+const QUERY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long a replica may claim to be a master before it gets repointed,
+/// leaving time to learn about a failover from the other sentinels first.
+const MASTER_ROLE_GRACE: Duration = Duration::from_secs(4);
+
+/// Longest random wait before starting a failover, so sentinels seeing the
+/// master down at once don't all split the votes.
+const MAX_FAILOVER_DESYNC: Duration = Duration::from_millis(1000);
+
+pub const DEFAULT_SENTINEL_PORT: u16 = 26379;
+
+type Address = (String, u16);
+
+/// What a sentinel monitors and how eager it is to fail over.
+#[derive(Debug)]
+pub struct SentinelConfig {
+    pub name: String,
+    pub master: Address,
+    /// How many sentinels must agree the master is down before failing over.
+    pub quorum: usize,
+    pub down_after: Duration,
+    pub failover_timeout: Duration,
+    /// The other sentinels monitoring the same master.
+    pub sentinels: Vec<Address>,
+}
+
+impl SentinelConfig {
+    /// Parses `<name> <host> <port> <quorum>` as given to `--monitor`.
+    pub fn monitor(monitor: &str) -> Result<SentinelConfig> {
+        let parts: Vec<&str> = monitor.split_whitespace().collect();
+        let [name, host, port, quorum] = parts.as_slice() else {
+            return Err(anyhow!("monitor must be '<name> <host> <port> <quorum>', got '{monitor}'"));
+        };
+        let port = port.parse::<u16>().map_err(|_| anyhow!("invalid master port '{port}'"))?;
+        let quorum = match quorum.parse::<usize>() {
+            Ok(quorum) if quorum > 0 => quorum,
+            _ => return Err(anyhow!("quorum must be a positive number, got '{quorum}'")),
+        };
+        Ok(SentinelConfig {
+            name: name.to_string(),
+            master: (host.to_string(), port),
+            quorum,
+            down_after: Duration::from_secs(30),
+            failover_timeout: Duration::from_secs(180),
+            sentinels: vec![],
+        })
+    }
+}
+
+/// What we last learnt about a replica from its INFO.
+#[derive(Debug, Clone)]
+struct Instance {
+    last_ok: Option<Instant>,
+    offset: u64,
+    priority: u64,
+    /// The master it replicates, or `None` when it says it's a master.
+    master: Option<Address>,
+    /// Since when it's been claiming to be a master.
+    master_since: Option<Instant>,
+}
+
+impl Instance {
+    fn new() -> Instance {
+        Instance {
+            last_ok: None,
+            offset: 0,
+            priority: 100,
+            master: None,
+            master_since: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Peer {
+    last_ok: Option<Instant>,
+}
+
+struct State {
+    master: Address,
+    master_last_ok: Instant,
+    /// The epoch in which the current master got promoted.
+    config_epoch: u64,
+    /// The latest epoch any sentinel started an election in.
+    current_epoch: u64,
+    replicas: BTreeMap<Address, Instance>,
+    peers: BTreeMap<Address, Peer>,
+    /// The sentinel we voted for as failover leader, and in which epoch.
+    leader: Option<(String, u64)>,
+    odown_since: Option<Instant>,
+    /// When we last started or voted in an election, which we can't start
+    /// again before twice the failover timeout.
+    election: Option<Instant>,
+    failover_desync: Duration,
+}
+
+/// Monitors a master and its replicas, and promotes one of the replicas
+/// once enough sentinels agree the master is down and elect us to do it.
+pub struct Sentinel {
+    id: String,
+    config: SentinelConfig,
+    state: Mutex<State>,
+}
+
+impl Sentinel {
+    pub fn new(config: SentinelConfig) -> Sentinel {
+        let state = State {
+            master: config.master.clone(),
+            master_last_ok: Instant::now(),
+            config_epoch: 0,
+            current_epoch: 0,
+            replicas: BTreeMap::new(),
+            peers: config.sentinels.iter().map(|address| (address.clone(), Peer::default())).collect(),
+            leader: None,
+            odown_since: None,
+            election: None,
+            failover_desync: thread_rng().gen_range(Duration::ZERO..MAX_FAILOVER_DESYNC),
+        };
+        Sentinel {
+            id: gen_replica_id(),
+            config,
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Monitors the master in the background while answering clients and
+    /// other sentinels on every listener.
+    pub async fn run(self: Arc<Self>, listeners: Vec<TcpListener>) {
+        let monitor = self.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(TICK);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                monitor.tick().await;
+            }
+        });
+
+        let mut accepting = JoinSet::new();
+        for listener in listeners {
+            accepting.spawn(self.clone().accept(listener));
+        }
+        while accepting.join_next().await.is_some() {}
+    }
+
+    async fn accept(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, address) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(target: "sentinel", "failed accepting a connection: {err}");
+                    continue;
+                },
+            };
+            let sentinel = self.clone();
+            tokio::spawn(async move {
+                let mut command_stream = CommandStream::from_tcp_stream(stream);
+                loop {
+                    let response = match command_stream.receive_request().await {
+                        Ok(command) => sentinel.answer(command),
+                        Err(err) if err.is::<InvalidRequest>() => CommandResponse::ERR(err.to_string()),
+                        Err(err) => {
+                            debug!(target: "sentinel", "closing connection with {address}: {err}");
+                            break;
+                        },
+                    };
+                    if command_stream.write_response(response).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn is_sdown(&self, state: &State) -> bool {
+        state.master_last_ok.elapsed() > self.config.down_after
+    }
+
+    /// Answers clients and other sentinels.
+    fn answer(&self, command: CommandRequest) -> CommandResponse {
+        match command {
+            CommandRequest::PING => CommandResponse::PONG,
+            CommandRequest::INFO(InfoMode::Sentinel) => CommandResponse::STR(self.info()),
+            CommandRequest::SENTINEL(SentinelCommand::MyId) => CommandResponse::STR(self.id.clone()),
+            CommandRequest::SENTINEL(SentinelCommand::IsMasterDownByAddr(host, port, epoch, runid)) => {
+                let mut state = self.state();
+                let down = state.master == (host, port) && self.is_sdown(&state);
+                let (leader, leader_epoch) = match runid.as_str() {
+                    "*" => ("*".to_string(), 0),
+                    _ => self.vote(&mut state, runid, epoch),
+                };
+                CommandResponse::ARRAY(vec![
+                    CommandResponse::INT(down as i64),
+                    CommandResponse::STR(leader),
+                    CommandResponse::INT(leader_epoch as i64),
+                ])
+            },
+            CommandRequest::SENTINEL(SentinelCommand::GetMasterAddrByName(name)) if name != self.config.name => CommandResponse::NILARRAY,
+            CommandRequest::SENTINEL(
+                SentinelCommand::Master(name) | SentinelCommand::Replicas(name) | SentinelCommand::Sentinels(name)
+            ) if name != self.config.name => CommandResponse::ERR("ERR No such master with that name".to_string()),
+            CommandRequest::SENTINEL(SentinelCommand::GetMasterAddrByName(_)) => {
+                let (host, port) = self.state().master.clone();
+                CommandResponse::ARRAY(vec![CommandResponse::STR(host), CommandResponse::STR(port.to_string())])
+            },
+            CommandRequest::SENTINEL(SentinelCommand::Master(_)) => {
+                let state = self.state();
+                let flags = match (self.is_sdown(&state), state.odown_since.is_some()) {
+                    (true, true) => "master,s_down,o_down",
+                    (true, false) => "master,s_down",
+                    _ => "master",
+                };
+                fields(&[
+                    ("name", self.config.name.clone()),
+                    ("ip", state.master.0.clone()),
+                    ("port", state.master.1.to_string()),
+                    ("flags", flags.to_string()),
+                    ("last-ok-ping-reply", state.master_last_ok.elapsed().as_millis().to_string()),
+                    ("num-slaves", state.replicas.len().to_string()),
+                    ("num-other-sentinels", state.peers.len().to_string()),
+                    ("quorum", self.config.quorum.to_string()),
+                    ("config-epoch", state.config_epoch.to_string()),
+                    ("down-after-milliseconds", self.config.down_after.as_millis().to_string()),
+                    ("failover-timeout", self.config.failover_timeout.as_millis().to_string()),
+                ])
+            },
+            CommandRequest::SENTINEL(SentinelCommand::Replicas(_)) => {
+                let state = self.state();
+                CommandResponse::ARRAY(state.replicas.iter().map(|((host, port), replica)| {
+                    let down = replica.last_ok.is_none_or(|last_ok| last_ok.elapsed() > self.config.down_after);
+                    let (master_host, master_port) = replica.master.clone().unwrap_or(("?".to_string(), 0));
+                    fields(&[
+                        ("name", format!("{host}:{port}")),
+                        ("ip", host.clone()),
+                        ("port", port.to_string()),
+                        ("flags", if down { "slave,s_down" } else { "slave" }.to_string()),
+                        ("master-host", master_host),
+                        ("master-port", master_port.to_string()),
+                        ("slave-priority", replica.priority.to_string()),
+                        ("slave-repl-offset", replica.offset.to_string()),
+                    ])
+                }).collect())
+            },
+            CommandRequest::SENTINEL(SentinelCommand::Sentinels(_)) => {
+                let state = self.state();
+                CommandResponse::ARRAY(state.peers.iter().map(|((host, port), peer)| {
+                    let down = peer.last_ok.is_none_or(|last_ok| last_ok.elapsed() > self.config.down_after);
+                    fields(&[
+                        ("name", format!("{host}:{port}")),
+                        ("ip", host.clone()),
+                        ("port", port.to_string()),
+                        ("flags", if down { "sentinel,s_down" } else { "sentinel" }.to_string()),
+                    ])
+                }).collect())
+            },
+            _ => CommandResponse::ERR("ERR unknown command in sentinel mode".to_string()),
+        }
+    }
+
+    fn info(&self) -> String {
+        let state = self.state();
+        let status = if state.odown_since.is_some() { "odown" } else if self.is_sdown(&state) { "sdown" } else { "ok" };
+        [
+            "# Sentinel".to_string(),
+            "sentinel_masters:1".to_string(),
+            format!(
+                "master0:name={},status={status},address={}:{},slaves={},sentinels={}",
+                self.config.name, state.master.0, state.master.1, state.replicas.len(), state.peers.len() + 1
+            ),
+        ].join("\r\n")
+    }
+
+    /// Votes for `runid` as the leader of the election in `epoch`, unless we
+    /// already voted in it, and returns our vote.
+    fn vote(&self, state: &mut State, runid: String, epoch: u64) -> (String, u64) {
+        state.current_epoch = state.current_epoch.max(epoch);
+        let voted = state.leader.as_ref().map_or(0, |(_, leader_epoch)| *leader_epoch);
+        if voted < epoch && state.current_epoch == epoch {
+            info!(target: "sentinel", "voting for {runid} in epoch {epoch}");
+            if runid != self.id {
+                // leave the elected sentinel time to fail over
+                state.election = Some(Instant::now());
+            }
+            state.leader = Some((runid, epoch));
+        }
+        state.leader.clone().unwrap_or(("*".to_string(), 0))
+    }
+
+    async fn tick(&self) {
+        self.check_sentinels().await;
+        self.check_master().await;
+        self.check_replicas().await;
+        self.check_down().await;
+    }
+
+    /// Adopts the configuration of any sentinel that saw a later failover.
+    async fn check_sentinels(&self) {
+        let peers: Vec<Address> = self.state().peers.keys().cloned().collect();
+        for peer in peers {
+            let fields = match query(&peer, vec![CommandRequest::SENTINEL(SentinelCommand::Master(self.config.name.clone()))]).await {
+                Ok(mut replies) => match replies.pop() {
+                    Some(RESP::Array(fields)) => parse_fields(&fields),
+                    x => {
+                        debug!(target: "sentinel", "unexpected reply from sentinel {peer:?}: {x:?}");
+                        continue;
+                    },
+                },
+                Err(err) => {
+                    debug!(target: "sentinel", "sentinel {peer:?} unreachable: {err}");
+                    continue;
+                },
+            };
+            let mut state = self.state();
+            if let Some(peer) = state.peers.get_mut(&peer) {
+                peer.last_ok = Some(Instant::now());
+            }
+            let address = fields.get("ip").zip(fields.get("port").and_then(|port| port.parse::<u16>().ok()));
+            let epoch = fields.get("config-epoch").and_then(|epoch| epoch.parse::<u64>().ok());
+            if let (Some((host, port)), Some(epoch)) = (address, epoch) {
+                if epoch > state.config_epoch {
+                    self.switch_master(&mut state, (host.to_string(), port), epoch);
+                }
+            }
+        }
+    }
+
+    async fn check_master(&self) {
+        let master = self.state().master.clone();
+        let info = match instance_info(&master).await {
+            Ok(info) => info,
+            Err(err) => {
+                debug!(target: "sentinel", "master {master:?} unreachable: {err}");
+                return;
+            },
+        };
+        let mut state = self.state();
+        if state.master != master {
+            return;
+        }
+        if state.odown_since.is_some() || self.is_sdown(&state) {
+            info!(target: "sentinel", "master {}:{} is back", master.0, master.1);
+        }
+        state.master_last_ok = Instant::now();
+        state.odown_since = None;
+        for (key, value) in &info {
+            let replica = key.starts_with("slave") && key[5..].parse::<usize>().is_ok();
+            if let Some(address) = replica.then(|| replica_address(value)).flatten() {
+                state.replicas.entry(address).or_insert_with(Instance::new);
+            }
+        }
+    }
+
+    /// Refreshes what we know about replicas and points back to the master
+    /// those following another one, or claiming to be masters themselves.
+    async fn check_replicas(&self) {
+        let replicas: Vec<Address> = self.state().replicas.keys().cloned().collect();
+        for address in replicas {
+            let info = match instance_info(&address).await {
+                Ok(info) => info,
+                Err(err) => {
+                    debug!(target: "sentinel", "replica {address:?} unreachable: {err}");
+                    continue;
+                },
+            };
+            let reconfigure = {
+                let mut state = self.state();
+                let master = state.master.clone();
+                let stable = !self.is_sdown(&state);
+                let Some(replica) = state.replicas.get_mut(&address) else { continue };
+                let now = Instant::now();
+                replica.last_ok = Some(now);
+                replica.priority = info.get("slave_priority").and_then(|p| p.parse().ok()).unwrap_or(100);
+                replica.master = info.get("master_host").zip(info.get("master_port").and_then(|port| port.parse::<u16>().ok()))
+                    .map(|(host, port)| (host.to_string(), port));
+                let offset = info.get("slave_repl_offset").or(info.get("master_repl_offset"));
+                replica.offset = offset.and_then(|offset| offset.parse().ok()).unwrap_or(0);
+                match &replica.master {
+                    Some(_) => replica.master_since = None,
+                    None => { replica.master_since.get_or_insert(now); },
+                }
+                let lost = match (&replica.master, replica.master_since) {
+                    (Some(followed), _) => *followed != master,
+                    (None, Some(since)) => since.elapsed() > MASTER_ROLE_GRACE,
+                    (None, None) => false,
+                };
+                (stable && lost).then_some(master)
+            };
+            if let Some((host, port)) = reconfigure {
+                info!(target: "sentinel", "pointing replica {}:{} to {host}:{port}", address.0, address.1);
+                if let Err(err) = query(&address, vec![CommandRequest::REPLICAOF(Some((host, port)))]).await {
+                    warn!(target: "sentinel", "failed reconfiguring replica {address:?}: {err}");
+                }
+            }
+        }
+    }
+
+    /// Asks the other sentinels whether they also see the master down, and
+    /// once enough of them do, tries to get elected to fail over.
+    async fn check_down(&self) {
+        let (master, epoch) = {
+            let state = self.state();
+            if !self.is_sdown(&state) {
+                return;
+            }
+            (state.master.clone(), state.current_epoch)
+        };
+        let replies = self.ask_sentinels(&master, epoch, "*").await;
+        let down = 1 + replies.iter().filter(|(down, _, _)| *down).count();
+
+        let elect = {
+            let mut state = self.state();
+            if state.master != master {
+                return;
+            }
+            if down < self.config.quorum {
+                state.odown_since = None;
+                return;
+            }
+            let now = Instant::now();
+            let odown_since = *state.odown_since.get_or_insert_with(|| {
+                warn!(target: "sentinel", "master {}:{} is objectively down, {down} sentinels agree", master.0, master.1);
+                now
+            });
+            let retry = state.election.is_none_or(|election| now.duration_since(election) > self.config.failover_timeout * 2);
+            if now.duration_since(odown_since) < state.failover_desync || !retry {
+                return;
+            }
+            state.election = Some(now);
+            state.current_epoch += 1;
+            let epoch = state.current_epoch;
+            self.vote(&mut state, self.id.clone(), epoch);
+            epoch
+        };
+
+        info!(target: "sentinel", "asking for votes to fail over in epoch {elect}");
+        let replies = self.ask_sentinels(&master, elect, &self.id).await;
+        let votes = 1 + replies.iter().filter(|(_, leader, epoch)| *leader == self.id && *epoch == elect).count();
+        // a majority of all sentinels, counting us
+        let voters = self.config.sentinels.len() + 1;
+        let needed = self.config.quorum.max(voters / 2 + 1);
+        if votes < needed {
+            warn!(target: "sentinel", "lost the election in epoch {elect} with {votes} votes out of {needed} needed");
+            return;
+        }
+        info!(target: "sentinel", "elected leader in epoch {elect} with {votes} votes");
+        if let Err(err) = self.failover(&master, elect).await {
+            warn!(target: "sentinel", "failover in epoch {elect} failed: {err}");
+        }
+    }
+
+    /// Sends IS-MASTER-DOWN-BY-ADDR to every other sentinel, returning the
+    /// replies of those that answered.
+    async fn ask_sentinels(&self, master: &Address, epoch: u64, runid: &str) -> Vec<(bool, String, u64)> {
+        let peers: Vec<Address> = self.state().peers.keys().cloned().collect();
+        let mut replies = vec![];
+        for peer in peers {
+            let request = SentinelCommand::IsMasterDownByAddr(master.0.clone(), master.1, epoch, runid.to_string());
+            match query(&peer, vec![CommandRequest::SENTINEL(request)]).await.map(|mut replies| replies.pop()) {
+                Ok(Some(RESP::Array(reply))) => match reply.as_slice() {
+                    [RESP::Integer(down), RESP::BulkString(leader), RESP::Integer(leader_epoch)] => {
+                        replies.push((*down == 1, leader.to_string(), *leader_epoch as u64));
+                    },
+                    x => debug!(target: "sentinel", "unexpected reply from sentinel {peer:?}: {x:?}"),
+                },
+                Ok(x) => debug!(target: "sentinel", "unexpected reply from sentinel {peer:?}: {x:?}"),
+                Err(err) => debug!(target: "sentinel", "sentinel {peer:?} unreachable: {err}"),
+            }
+        }
+        replies
+    }
+
+    /// Promotes the best replica, then points the others to it.
+    async fn failover(&self, master: &Address, epoch: u64) -> Result<()> {
+        let promoted = {
+            let state = self.state();
+            select_replica(&state.replicas, self.config.down_after)
+        };
+        let promoted = promoted.ok_or_else(|| anyhow!("no replica can be promoted"))?;
+        info!(target: "sentinel", "promoting replica {}:{}", promoted.0, promoted.1);
+        match query(&promoted, vec![CommandRequest::REPLICAOF(None)]).await?.pop() {
+            Some(RESP::SimpleString(ok)) if ok == "OK" => (),
+            x => return Err(anyhow!("unexpected reply to REPLICAOF NO ONE: {x:?}")),
+        }
+
+        let others: Vec<Address> = {
+            let mut state = self.state();
+            if state.master != *master {
+                return Err(anyhow!("the master changed during the failover"));
+            }
+            self.switch_master(&mut state, promoted.clone(), epoch);
+            state.replicas.keys().filter(|replica| *replica != master).cloned().collect()
+        };
+        for replica in others {
+            info!(target: "sentinel", "pointing replica {}:{} to {}:{}", replica.0, replica.1, promoted.0, promoted.1);
+            if let Err(err) = query(&replica, vec![CommandRequest::REPLICAOF(Some(promoted.clone()))]).await {
+                warn!(target: "sentinel", "failed reconfiguring replica {replica:?}: {err}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes `master` the master as of `epoch`, keeping the former master
+    /// around as a replica to reconfigure once it's back.
+    fn switch_master(&self, state: &mut State, master: Address, epoch: u64) {
+        info!(
+            target: "sentinel", "switching master {} from {}:{} to {}:{} in epoch {epoch}",
+            self.config.name, state.master.0, state.master.1, master.0, master.1
+        );
+        let former = std::mem::replace(&mut state.master, master.clone());
+        state.replicas.remove(&master);
+        if former != master {
+            state.replicas.insert(former, Instance::new());
+        }
+        state.config_epoch = epoch;
+        state.current_epoch = state.current_epoch.max(epoch);
+        state.master_last_ok = Instant::now();
+        state.odown_since = None;
+    }
+}
+
+/// Picks the replica to promote: among those that answered recently and
+/// don't have a priority of 0, the one with the lowest priority, then the
+/// most data.
+fn select_replica(replicas: &BTreeMap<Address, Instance>, down_after: Duration) -> Option<Address> {
+    replicas.iter()
+        .filter(|(_, replica)| replica.priority != 0 && replica.last_ok.is_some_and(|last_ok| last_ok.elapsed() <= down_after))
+        .min_by(|(a, x), (b, y)| x.priority.cmp(&y.priority).then(y.offset.cmp(&x.offset)).then(a.cmp(b)))
+        .map(|(address, _)| address.clone())
+}
+
+/// Sends the requests one after the other and returns their replies.
+async fn query(address: &Address, requests: Vec<CommandRequest>) -> Result<Vec<RESP>> {
+    let exchange = async {
+        let tcp_stream = TcpStream::connect((address.0.as_str(), address.1)).await?;
+        let mut command_stream = CommandStream::from_tcp_stream(tcp_stream);
+        let mut replies = vec![];
+        for request in requests {
+            command_stream.write_request(request).await?;
+            replies.push(command_stream.receive_response().await?);
+        }
+        Ok(replies)
+    };
+    time::timeout(QUERY_TIMEOUT, exchange).await.map_err(|_| anyhow!("timed out"))?
+}
+
+/// Pings an instance and returns the fields of its replication INFO.
+async fn instance_info(address: &Address) -> Result<BTreeMap<String, String>> {
+    let replies = query(address, vec![CommandRequest::PING, CommandRequest::INFO(InfoMode::Replication)]).await?;
+    match replies.as_slice() {
+        [RESP::BulkString(pong) | RESP::SimpleString(pong), RESP::BulkString(info)] if pong == "PONG" => {
+            Ok(info.lines().filter_map(|line| line.split_once(':')).map(|(key, value)| (key.to_string(), value.to_string())).collect())
+        },
+        x => Err(anyhow!("unexpected replies: {x:?}")),
+    }
+}
+
+/// Parses the address out of a `slaveN` INFO line.
+fn replica_address(line: &str) -> Option<Address> {
+    let fields: BTreeMap<&str, &str> = line.split(',').filter_map(|field| field.split_once('=')).collect();
+    Some((fields.get("ip")?.to_string(), fields.get("port")?.parse().ok()?))
+}
+
+fn fields(pairs: &[(&str, String)]) -> CommandResponse {
+    CommandResponse::ARRAY(
+        pairs.iter()
+            .flat_map(|(name, value)| [CommandResponse::STR(name.to_string()), CommandResponse::STR(value.clone())])
+            .collect()
+    )
+}
+
+fn parse_fields(resps: &[RESP]) -> BTreeMap<String, String> {
+    resps.chunks(2)
+        .filter_map(|pair| match pair {
+            [RESP::BulkString(name), RESP::BulkString(value)] => Some((name.to_string(), value.to_string())),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn replica(priority: u64, offset: u64) -> Instance {
+        Instance { last_ok: Some(Instant::now()), offset, priority, ..Instance::new() }
+    }
+
+    #[test]
+    fn test_monitor_config() {
+        let config = SentinelConfig::monitor("mymaster 127.0.0.1 6379 2").unwrap();
+        assert_eq!("mymaster", config.name);
+        assert_eq!(("127.0.0.1".to_string(), 6379), config.master);
+        assert_eq!(2, config.quorum);
+        assert!(SentinelConfig::monitor("mymaster 127.0.0.1 6379").is_err());
+        assert!(SentinelConfig::monitor("mymaster 127.0.0.1 6379 0").is_err());
+    }
+
+    #[test]
+    fn test_select_replica() {
+        let address = |port| ("127.0.0.1".to_string(), port);
+        let mut replicas = BTreeMap::from([
+            (address(6380), replica(100, 10)),
+            (address(6381), replica(100, 20)),
+            (address(6382), replica(0, 30)),
+            (address(6383), Instance { last_ok: None, ..replica(1, 30) }),
+        ]);
+        let down_after = Duration::from_secs(30);
+        assert_eq!(Some(address(6381)), select_replica(&replicas, down_after));
+
+        replicas.insert(address(6384), replica(50, 0));
+        assert_eq!(Some(address(6384)), select_replica(&replicas, down_after));
+
+        replicas.retain(|_, replica| replica.priority == 0);
+        assert_eq!(None, select_replica(&replicas, down_after));
+    }
+
+    #[test]
+    fn test_one_vote_per_epoch() {
+        let sentinel = Sentinel::new(SentinelConfig::monitor("mymaster 127.0.0.1 6379 2").unwrap());
+        let mut state = sentinel.state();
+        assert_eq!(("a".to_string(), 1), sentinel.vote(&mut state, "a".to_string(), 1));
+        assert_eq!(("a".to_string(), 1), sentinel.vote(&mut state, "b".to_string(), 1));
+        assert_eq!(("b".to_string(), 2), sentinel.vote(&mut state, "b".to_string(), 2));
+        assert_eq!(2, state.current_epoch);
+    }
+}
@@ -0,0 +1,59 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::commands::CommandRequest;
+use crate::keyspace::Keyspace;
+use crate::replication::ReplicaFeed;
+
+/// State that belongs to a single client connection rather than to the
+/// server as a whole.
+#[derive(Default)]
+pub struct Session {
+    /// Commands queued since MULTI, `None` outside of a transaction.
+    pub(crate) transaction: Option<Vec<CommandRequest>>,
+    /// Set when a command couldn't be queued, making EXEC abort.
+    pub(crate) transaction_failed: bool,
+    /// Database selected with SELECT.
+    pub(crate) db: usize,
+    /// Keys WATCHed by this connection, with their database and the version
+    /// they had.
+    pub(crate) watched: Vec<(Arc<Keyspace>, String, u64)>,
+    /// Address of the client, `None` for the link to our master.
+    pub(crate) address: Option<SocketAddr>,
+    /// Port a replica announced with REPLCONF listening-port.
+    pub(crate) replica_listening_port: Option<u16>,
+    /// What a replica announced it supports with REPLCONF capa.
+    pub(crate) replica_capabilities: Vec<String>,
+    /// Replication offset right after the last write of this client, which
+    /// WAIT waits for replicas to acknowledge.
+    pub(crate) write_offset: u64,
+    /// Set once the client turned out to be a replica asking to sync.
+    pub(crate) replica_feed: Option<ReplicaFeed>,
+    /// Set by ASKING, letting the next command, or transaction, use a slot
+    /// this node is importing.
+    pub(crate) asking: bool,
+}
+
+impl Session {
+    pub fn new(address: SocketAddr) -> Session {
+        Session { address: Some(address), ..Default::default() }
+    }
+
+    /// Commands to stream to the client if it became a replica, after
+    /// which it doesn't send regular requests anymore.
+    pub fn take_replica_feed(&mut self) -> Option<ReplicaFeed> {
+        self.replica_feed.take()
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Records that a request was rejected, so a pending transaction
+    /// can't be executed anymore.
+    pub fn fail_transaction(&mut self) {
+        if self.in_transaction() {
+            self.transaction_failed = true;
+        }
+    }
+}
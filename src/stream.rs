@@ -1,35 +1,143 @@
-use std::str::from_utf8;
+use std::io::Write;
 
 use anyhow::{anyhow, Result};
+use bytes::{Buf, BytesMut};
 use log::debug;
+use thiserror::Error;
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
 
-use crate::{commands::{CommandRequest, CommandResponse, FromRESP, ToRESP}, protocol::RESP};
+use crate::{commands::{CommandRequest, CommandResponse, FromRESP, RdbFormat, ToRESP}, protocol::RESP};
+
+/// Length of the marks delimiting RDB payloads of unknown size.
+pub const EOF_MARK_LEN: usize = 40;
+
+/// The peer went away while we were waiting for a request.
+#[derive(Error, Debug)]
+#[error("connection closed by peer")]
+pub struct ConnectionClosed;
+
+/// A well formed RESP value that doesn't map to any command. Unlike other
+/// errors, the connection can keep being used after this one.
+#[derive(Error, Debug)]
+#[error("ERR {0}")]
+pub struct InvalidRequest(String);
 
 pub struct RESPStream {
-    tcp_stream: TcpStream
+    tcp_stream: TcpStream,
+    buffer: BytesMut,
 }
 
 impl RESPStream {
     pub async fn write(&mut self, resp: RESP) -> Result<usize> {
-        self.tcp_stream.write(
-            resp.encode().as_bytes()
-        ).await.map_err(|err| anyhow!("got io error: {err:?}"))
+        let bytes = resp.encode();
+        self.tcp_stream.write_all(
+            &bytes
+        ).await.map_err(|err| anyhow!("got io error: {err:?}"))?;
+        Ok(bytes.len())
+    }
+
+    /// Writes an RDB file the way masters send it after FULLRESYNC: as a
+    /// bulk string without the trailing CRLF, or when the size isn't
+    /// announced, between `$EOF:<mark>` and the same 40 bytes mark.
+    pub async fn write_rdb(&mut self, rdb: &[u8], format: RdbFormat) -> Result<usize> {
+        let mut bytes = match &format {
+            RdbFormat::Length => format!("${}\r\n", rdb.len()).into_bytes(),
+            RdbFormat::EofMark(mark) => format!("$EOF:{mark}\r\n").into_bytes(),
+        };
+        bytes.extend_from_slice(rdb);
+        if let RdbFormat::EofMark(mark) = &format {
+            bytes.extend_from_slice(mark.as_bytes());
+        }
+        self.tcp_stream.write_all(&bytes).await?;
+        Ok(bytes.len())
     }
 
     pub async fn receive(&mut self) -> Result<RESP> {
-        let mut buffer = vec![0; 512];
-        let n = self.tcp_stream.read(&mut buffer).await?;
-        let str = from_utf8(&buffer[..n]).unwrap();
-        debug!(target: "resp-stream", "receiving bytes: {str:?}");
-        RESP::decode(str)
+        loop {
+            if let Some((resp, n)) = RESP::decode(&self.buffer)? {
+                debug!(target: "resp-stream", "receiving bytes: {:?}", String::from_utf8_lossy(&self.buffer[..n]));
+                self.buffer.advance(n);
+                return Ok(resp);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Like `receive`, also giving back the exact bytes the value was sent as.
+    pub async fn receive_with_bytes(&mut self) -> Result<(RESP, Vec<u8>)> {
+        loop {
+            if let Some((resp, n)) = RESP::decode(&self.buffer)? {
+                return Ok((resp, self.buffer.split_to(n).to_vec()));
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Reads an RDB file sent by a master after FULLRESYNC into `out` as it
+    /// arrives, in either format `write_rdb` uses. Masters may send newlines
+    /// to keep the connection alive while preparing it. Returns the size of
+    /// the file.
+    pub async fn receive_rdb(&mut self, out: &mut impl Write) -> Result<u64> {
+        let header = loop {
+            while self.buffer.first() == Some(&b'\n') {
+                self.buffer.advance(1);
+            }
+            if let Some(end) = self.buffer.windows(2).position(|window| window == b"\r\n") {
+                let header = String::from_utf8_lossy(&self.buffer[..end]).to_string();
+                self.buffer.advance(end + 2);
+                break header;
+            }
+            self.fill().await?;
+        };
+        let mut received = 0;
+        if let Some(mark) = header.strip_prefix("$EOF:") {
+            if mark.len() != EOF_MARK_LEN {
+                return Err(anyhow!("invalid EOF mark in RDB payload header: {header:?}"));
+            }
+            loop {
+                if let Some(end) = self.buffer.windows(EOF_MARK_LEN).position(|window| window == mark.as_bytes()) {
+                    out.write_all(&self.buffer[..end])?;
+                    self.buffer.advance(end + EOF_MARK_LEN);
+                    return Ok(received + end as u64);
+                }
+                // the mark may be split, keep what could be its beginning
+                let safe = self.buffer.len().saturating_sub(EOF_MARK_LEN - 1);
+                out.write_all(&self.buffer[..safe])?;
+                self.buffer.advance(safe);
+                received += safe as u64;
+                self.fill().await?;
+            }
+        }
+        let len: u64 = match header.strip_prefix('$').map(str::parse) {
+            Some(Ok(len)) => len,
+            _ => return Err(anyhow!("expected an RDB payload, got: {header:?}")),
+        };
+        while received < len {
+            if self.buffer.is_empty() {
+                self.fill().await?;
+            }
+            let n = self.buffer.len().min((len - received) as usize);
+            out.write_all(&self.buffer[..n])?;
+            self.buffer.advance(n);
+            received += n as u64;
+        }
+        Ok(received)
+    }
+
+    async fn fill(&mut self) -> Result<()> {
+        let n = self.tcp_stream.read_buf(&mut self.buffer).await?;
+        if n == 0 {
+            return Err(ConnectionClosed.into());
+        }
+        Ok(())
     }
 
     pub fn new(tcp_stream: TcpStream) -> RESPStream {
         RESPStream {
-            tcp_stream
+            tcp_stream,
+            buffer: BytesMut::with_capacity(512),
         }
-    }    
+    }
 }
 
 pub struct CommandStream {
@@ -42,30 +150,55 @@ impl CommandStream {
             resp_stream: RESPStream::new(tcp_stream)
         }
     }
-    
-    pub fn new(resp_stream: RESPStream) -> CommandStream {
-        CommandStream {
-            resp_stream
-        }
-    }
-    
+
     pub async fn write_response(&mut self, command: CommandResponse) -> Result<usize> {
         debug!(target: "command-stream", "writing command response: {command:?}");
-        self.resp_stream.write(
-            command.to_resp().unwrap()
-        ).await
+        let mut written = self.resp_stream.write(
+            command.to_resp()?
+        ).await?;
+        if let CommandResponse::FULLRESYNC(_, _, rdb, format) = command {
+            written += self.resp_stream.write_rdb(&rdb, format).await?;
+        }
+        Ok(written)
     }
-    
+
     pub async fn write_request(&mut self, command: CommandRequest) -> Result<usize> {
         debug!(target: "command-stream", "writing command request: {command:?}");
         self.resp_stream.write(
-            command.to_resp().unwrap()
+            command.to_resp()?
         ).await
     }
 
+    /// Writes something already in RESP form.
+    pub async fn write_bytes(&mut self, bytes: &[u8]) -> Result<usize> {
+        self.resp_stream.tcp_stream.write_all(bytes).await?;
+        Ok(bytes.len())
+    }
+
+    /// Reads a reply to a request we sent.
+    pub async fn receive_response(&mut self) -> Result<RESP> {
+        self.resp_stream.receive().await
+    }
+
+    pub async fn receive_rdb(&mut self, out: &mut impl Write) -> Result<u64> {
+        self.resp_stream.receive_rdb(out).await
+    }
+
     pub async fn receive_request(&mut self) -> Result<CommandRequest> {
-        let resp = self.resp_stream.receive().await.unwrap();
+        let resp = self.resp_stream.receive().await?;
         debug!(target: "command-stream", "receiving RESP: {resp:?}");
         CommandRequest::from_resp(resp)
-    }    
+            .map_err(|err| InvalidRequest(err.to_string()).into())
+    }
+
+    /// Reads a request along with the bytes it was sent as, which replicas
+    /// forward as is. Only fails if the stream itself does, an invalid
+    /// request is returned as such.
+    pub async fn receive_request_bytes(&mut self) -> Result<(Vec<u8>, Result<CommandRequest>)> {
+        let (resp, bytes) = self.resp_stream.receive_with_bytes().await?;
+        debug!(target: "command-stream", "receiving RESP: {resp:?}");
+        let request = CommandRequest::from_resp(resp)
+            .map_err(|err| InvalidRequest(err.to_string()).into());
+        Ok((bytes, request))
+    }
 }